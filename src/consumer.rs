@@ -1,10 +1,14 @@
-use crate::{error::Error, AppState};
+use crate::{error::Error, utils::try_load_account, AppState};
 use anchor_client::{
     anchor_lang::prelude::AccountMeta,
     solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey},
 };
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tracing::{debug, info, trace, warn};
@@ -15,42 +19,376 @@ pub struct ConsumerConfig {
     pub max_wait: Duration,
     pub max_queue_length: usize,
     pub poll_period: Duration,
+    pub markets: Vec<String>,
+    /// Polls without the queue head moving before warning that the
+    /// queue is stuck.
+    pub stuck_polls: usize,
+    /// Time without the queue head moving before warning that the queue
+    /// is stuck, and between warnings while it stays stuck.
+    pub stuck_after: Duration,
+    /// Queue length below which the startup catch-up stops.
+    pub catch_up_below: usize,
+    /// Commitment level the event queue is read at.
+    pub event_queue_commitment: CommitmentConfig,
+    /// Whether to crank the PnL of the consumed events' owners.
+    pub crank_pnl: bool,
+    /// If set, PnL is cranked on this interval for the owners consumed
+    /// since, rather than right after consuming their events.
+    pub crank_pnl_interval: Option<Duration>,
+    /// Consume each market's events once and return, see `consume_once`.
+    pub once: bool,
+}
+
+impl ConsumerConfig {
+    /// Whether PnL is cranked right after consuming events.
+    fn crank_pnl_now(&self) -> bool {
+        self.crank_pnl && self.crank_pnl_interval.is_none()
+    }
+}
+
+/// Bounds the catch-up, in case events arrive as fast as they're consumed.
+const CATCH_UP_MAX_ITERATIONS: usize = 200;
+
+/// How often the zo state is checked for listed and delisted markets.
+const MARKETS_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Delay before restarting a market's worker after it panics.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Most accounts the RPC returns for one `getMultipleAccounts`.
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+/// Target stuck queues are logged on, with the stall as fields, so they
+/// can be collected as a metric, e.g. with `--log-format json`.
+const STUCK_QUEUES_TARGET: &str = "zo_keeper::stuck_queues";
+
+/// Accounts that fit in a legacy transaction, including the payer and
+/// the program, with the instruction data of the cranks.
+const MAX_TX_ACCOUNTS: usize = 32;
+
+/// Accounts of `ConsumeEvents` and `CrankPnl` besides the owners', with
+/// the payer and the zo program.
+const CONSUME_EVENTS_FIXED_ACCOUNTS: usize = 7;
+const CRANK_PNL_FIXED_ACCOUNTS: usize = 7;
+
+/// Accounts passed per owner: the control and open orders, and for
+/// `CrankPnl` the margin.
+const CONSUME_EVENTS_OWNER_ACCOUNTS: usize = 2;
+const CRANK_PNL_OWNER_ACCOUNTS: usize = 3;
+
+/// The accounts of an event's owner passed to the cranks. The margin is
+/// only derived if PnL is cranked.
+#[derive(Clone, Copy)]
+struct Owner {
+    control: Pubkey,
+    orders: Pubkey,
+    margin: Option<Pubkey>,
+}
+
+/// Owners consumed since PnL was last cranked, when it's cranked on its
+/// own interval.
+struct PendingPnl {
+    owners: Vec<Owner>,
+    since: Instant,
+}
+
+impl PendingPnl {
+    fn new() -> Self {
+        Self {
+            owners: Vec::new(),
+            since: Instant::now(),
+        }
+    }
+
+    fn add(&mut self, owners: &[Owner]) {
+        for o in owners {
+            if !self.owners.iter().any(|x| x.control == o.control) {
+                self.owners.push(*o);
+            }
+        }
+    }
+
+    /// The owners to crank, if any and `interval` has passed since the
+    /// last crank.
+    fn take(&mut self, interval: Duration) -> Option<Vec<Owner>> {
+        if self.owners.is_empty() || self.since.elapsed() < interval {
+            return None;
+        }
+
+        self.since = Instant::now();
+        Some(std::mem::take(&mut self.owners))
+    }
+}
+
+/// Tracks how long a non-empty event queue has gone without its head
+/// moving.
+struct Progress {
+    head: u64,
+    polls: usize,
+    since: Instant,
+    /// When the current stall was last warned about, if it has been.
+    warned_at: Option<Instant>,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            head: 1u64 << 48,
+            polls: 0,
+            since: Instant::now(),
+            warned_at: None,
+        }
+    }
+
+    /// Moves on to `head`, returning how long the queue was stuck for if
+    /// that was warned about.
+    fn reset(&mut self, head: u64) -> Option<Duration> {
+        let stalled = self.warned_at.map(|_| self.since.elapsed());
+
+        self.head = head;
+        self.polls = 0;
+        self.since = Instant::now();
+        self.warned_at = None;
+
+        stalled
+    }
+
+    /// Whether to warn that the queue is stuck. That's once it hasn't
+    /// moved for `stuck_polls` polls or `stuck_after`, then again every
+    /// `stuck_after` while it stays stuck, so a stall isn't warned about
+    /// on every poll.
+    fn warn_stuck(
+        &mut self,
+        stuck_polls: usize,
+        stuck_after: Duration,
+    ) -> bool {
+        let stuck = self.polls > 0
+            && (self.polls >= stuck_polls
+                || self.since.elapsed() >= stuck_after);
+        let due = self.warned_at.map_or(true, |t| t.elapsed() >= stuck_after);
+
+        if stuck && due {
+            self.warned_at = Some(Instant::now());
+        }
+
+        stuck && due
+    }
+}
+
+fn log_unstuck(symbol: &str, stalled: Option<Duration>) {
+    if let Some(d) = stalled {
+        info!(
+            target: STUCK_QUEUES_TARGET,
+            symbol,
+            stalled_secs = d.as_secs(),
+            "{} event queue moving again after {}s",
+            symbol,
+            d.as_secs(),
+        );
+    }
 }
 
 pub async fn run(
     st: &'static AppState,
     cfg: ConsumerConfig,
 ) -> Result<(), Error> {
-    let handles = st.load_dex_markets()?.into_iter().map(|(symbol, mkt)| {
-        let cfg = cfg.clone();
+    let mut markets = st.load_dex_markets()?;
 
-        tokio::task::spawn_blocking(move || {
-            let mut last_cranked_at = Instant::now() - cfg.max_wait;
-            let mut accounts_table = HashMap::new();
-
-            // The seq_num wraps at 1 << 32, so for the initial
-            // value pick a number larger than that.
-            let mut last_head = 1u64 << 48;
-
-            loop {
-                std::thread::sleep(cfg.poll_period);
-                consume(
-                    st,
-                    &symbol,
-                    &mkt,
-                    &cfg,
-                    &mut last_head,
-                    &mut last_cranked_at,
-                    &mut accounts_table,
-                );
+    for s in cfg.markets.iter() {
+        if !markets.iter().any(|(symbol, _)| symbol == s) {
+            warn!("unknown market {}, ignoring", s);
+        }
+    }
+
+    if cfg.once {
+        return run_once(st, &cfg, markets).await;
+    }
+
+    // Market address -> (Symbol, Stop flag)
+    let mut workers: HashMap<Pubkey, (String, Arc<AtomicBool>)> =
+        HashMap::new();
+    let mut interval = tokio::time::interval(MARKETS_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    loop {
+        update_workers(st, &cfg, &mut workers, markets);
+
+        // Check the zo state for listed and delisted markets, which
+        // is kept as it was at startup in `st`.
+        markets = loop {
+            interval.tick().await;
+
+            match tokio::task::spawn_blocking(|| st.fetch_dex_markets())
+                .await
+                .unwrap()
+            {
+                Ok(x) => break x,
+                Err(e) => warn!("failed to check for market changes: {}", e),
             }
+        };
+    }
+}
+
+/// Runs `consume_once` for each of `markets` consumed by this process,
+/// failing if any of them failed.
+async fn run_once(
+    st: &'static AppState,
+    cfg: &ConsumerConfig,
+    markets: Vec<(String, zo_abi::dex::ZoDexMarket)>,
+) -> Result<(), Error> {
+    let handles: Vec<_> = markets
+        .into_iter()
+        .filter(|(s, _)| cfg.markets.is_empty() || cfg.markets.contains(s))
+        .map(|(symbol, mkt)| {
+            let cfg = cfg.clone();
+            tokio::task::spawn_blocking(move || {
+                consume_once(st, &symbol, &mkt, &cfg)
+            })
         })
+        .collect();
+
+    let failed = futures::future::join_all(handles)
+        .await
+        .into_iter()
+        .filter(|res| !matches!(res, Ok(true)))
+        .count();
+
+    match failed {
+        0 => Ok(()),
+        n => Err(Error::PassFailed(n)),
+    }
+}
+
+/// Consumes the market's events once, however few there are, and cranks
+/// their owners' PnL if `cfg.crank_pnl`. Returns whether the events were
+/// consumed and, if cranked, their owners' PnL.
+#[tracing::instrument(skip_all, level = "error", fields(symbol = symbol))]
+fn consume_once(
+    st: &AppState,
+    symbol: &str,
+    market: &zo_abi::dex::ZoDexMarket,
+    cfg: &ConsumerConfig,
+) -> bool {
+    let events = match fetch_events(st, market, cfg) {
+        Some((_, events)) => events,
+        None => return false,
+    };
+
+    if events.is_empty() {
+        info!("no events to consume");
+        return true;
+    }
+
+    let owners = event_accounts(st, market, &events, cfg, &mut HashMap::new());
+    let consumed = consume_events(st, market, cfg.to_consume as u16, &owners);
+
+    if consumed && cfg.crank_pnl {
+        return crank_pnl_chunks(st, market, &owners);
+    }
+
+    consumed
+}
+
+/// Stops the workers of markets no longer in `markets`, and starts
+/// workers for the markets without one.
+fn update_workers(
+    st: &'static AppState,
+    cfg: &ConsumerConfig,
+    // Market address -> (Symbol, Stop flag)
+    workers: &mut HashMap<Pubkey, (String, Arc<AtomicBool>)>,
+    markets: Vec<(String, zo_abi::dex::ZoDexMarket)>,
+) {
+    let starting = workers.is_empty();
+
+    workers.retain(|key, (symbol, stop)| {
+        let listed = markets.iter().any(|(_, m)| m.own_address == *key);
+        if !listed {
+            info!("{} delisted, stopping its consumer", symbol);
+            stop.store(true, Ordering::Relaxed);
+        }
+        listed
     });
 
-    let _ = futures::future::join_all(handles).await;
-    Ok(())
+    let new: Vec<_> = markets
+        .into_iter()
+        .filter(|(s, m)| {
+            (cfg.markets.is_empty() || cfg.markets.contains(s))
+                && !workers.contains_key(&m.own_address)
+        })
+        .collect();
+
+    for (symbol, mkt) in new {
+        if !starting {
+            info!("{} listed, starting its consumer", symbol);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        workers.insert(mkt.own_address, (symbol.clone(), stop.clone()));
+
+        let cfg = cfg.clone();
+        let name = format!("{} consumer", symbol);
+
+        // A panic, e.g. on an unexpected RPC response, would otherwise
+        // stop the market from being consumed until restarted.
+        tokio::spawn(async move {
+            crate::utils::supervise_blocking(&name, RESTART_DELAY, move || {
+                run_market(st, &symbol, mkt, &cfg, &stop)
+            })
+            .await
+        });
+    }
+}
+
+/// Consumes the market's events until `stop` is set.
+fn run_market(
+    st: &'static AppState,
+    symbol: &str,
+    mkt: zo_abi::dex::ZoDexMarket,
+    cfg: &ConsumerConfig,
+    stop: &AtomicBool,
+) {
+    let mut last_cranked_at = Instant::now() - cfg.max_wait;
+    let mut accounts_table = HashMap::new();
+
+    // The seq_num wraps at 1 << 32, so for the initial
+    // value pick a number larger than that.
+    let mut last_head = 1u64 << 48;
+    let mut progress = Progress::new();
+    let mut pending_pnl = PendingPnl::new();
+    let pnl_interval = cfg.crank_pnl_interval.filter(|_| cfg.crank_pnl);
+
+    let owners = catch_up(st, symbol, &mkt, cfg, &mut accounts_table);
+    if pnl_interval.is_some() {
+        pending_pnl.add(&owners);
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(cfg.poll_period);
+        let owners = consume(
+            st,
+            symbol,
+            &mkt,
+            cfg,
+            &mut last_head,
+            &mut last_cranked_at,
+            &mut progress,
+            &mut accounts_table,
+        );
+
+        let interval = match pnl_interval {
+            Some(x) => x,
+            None => continue,
+        };
+
+        pending_pnl.add(&owners);
+        if let Some(owners) = pending_pnl.take(interval) {
+            std::thread::spawn(move || crank_pnl_chunks(st, &mkt, &owners));
+        }
+    }
 }
 
+/// Returns the owners whose events were sent to be consumed.
 #[tracing::instrument(
     skip_all,
     level = "error",
@@ -63,51 +401,54 @@ fn consume(
     cfg: &ConsumerConfig,
     last_head: &mut u64,
     last_cranked_at: &mut Instant,
+    progress: &mut Progress,
     // Control -> (Open Orders, Margin)
-    accounts_table: &mut HashMap<Pubkey, (Pubkey, Pubkey)>,
-) {
+    accounts_table: &mut HashMap<Pubkey, (Pubkey, Option<Pubkey>)>,
+) -> Vec<Owner> {
     let t = Instant::now();
 
-    let (event_q_buf, slot) = {
-        let res = st.rpc.get_account_with_commitment(
-            &market.event_q,
-            CommitmentConfig::confirmed(),
-        );
-
-        let res = match res {
-            Ok(x) => x,
-            Err(e) => {
-                let e = Error::from(e);
-                warn!("{}", e);
-                return;
-            }
-        };
-
-        let slot = res.context.slot;
-        let buf = res.value.unwrap().data;
-
-        (buf, slot)
+    let (head, events) = match fetch_events(st, market, cfg) {
+        Some(x) => x,
+        None => return Vec::new(),
     };
 
-    tracing::Span::current().record("slot", &slot);
-
-    let (events_header, events) =
-        zo_abi::dex::Event::deserialize_queue(&event_q_buf).unwrap();
-    let events = events.cloned().collect::<Vec<_>>();
-
     if events.is_empty() {
+        log_unstuck(symbol, progress.reset(head));
         trace!("no events, skipping");
-        return;
+        return Vec::new();
+    }
+
+    if head == progress.head {
+        progress.polls += 1;
+    } else {
+        log_unstuck(symbol, progress.reset(head));
+    }
+
+    if progress.warn_stuck(cfg.stuck_polls, cfg.stuck_after) {
+        warn!(
+            target: STUCK_QUEUES_TARGET,
+            symbol,
+            head,
+            events = events.len(),
+            polls = progress.polls,
+            stalled_secs = progress.since.elapsed().as_secs(),
+            "{} event queue stuck at head {} with {} events for {} polls ({}s)",
+            symbol,
+            head,
+            events.len(),
+            progress.polls,
+            progress.since.elapsed().as_secs(),
+        );
     }
 
     if last_cranked_at.elapsed() < cfg.max_wait {
-        if events_header.head == *last_head {
+        if head == *last_head {
             debug!(
                 "last cranked {}s ago and queue head still at {}, skipping",
                 last_cranked_at.elapsed().as_secs(),
-                { events_header.head },
+                head,
             );
-            return;
+            return Vec::new();
         }
 
         if events.len() < cfg.max_queue_length {
@@ -116,67 +457,277 @@ fn consume(
                 last_cranked_at.elapsed().as_secs(),
                 events.len(),
             );
-            return;
-        }
-    }
-
-    // Sorted, unique, and capped list of control pubkeys.
-    // Pubkeys are sorted by their [u64; 4] representation.
-    let mut used_control: BTreeSet<[u64; 4]> = BTreeSet::new();
-
-    for control in events.iter().map(|e| bytemuck::cast(e.control)) {
-        used_control.insert(control);
-        if used_control.len() >= cfg.to_consume {
-            break;
+            return Vec::new();
         }
     }
 
-    let mut control_accounts = Vec::with_capacity(used_control.len());
-    let mut orders_accounts = Vec::with_capacity(used_control.len());
-    let mut margin_accounts = Vec::with_capacity(used_control.len());
-
-    for control in used_control.into_iter().map(bytemuck::cast) {
-        let (oo, margin) = accounts_table.entry(control).or_insert_with(|| {
-            (
-                open_orders_pda(&control, &market.own_address),
-                margin_pda(
-                    &st.program().account(control).unwrap(),
-                    &st.zo_state_pubkey,
-                ),
-            )
-        });
-
-        control_accounts.push(AccountMeta::new(control, false));
-        orders_accounts.push(AccountMeta::new(*oo, false));
-        margin_accounts.push(AccountMeta::new(*margin, false));
-    }
+    let owners = event_accounts(st, market, &events, cfg, accounts_table);
 
     info!(
         "fetching {} events and {} unique orders took {}ms",
         events.len(),
-        orders_accounts.len(),
+        owners.len(),
         t.elapsed().as_millis()
     );
 
     let market = *market;
     let limit = cfg.to_consume as u16;
+    let crank_pnl = cfg.crank_pnl_now();
     let span = tracing::Span::current();
+    let sent = owners.clone();
 
     std::thread::spawn(move || {
         let _g = span.enter();
-        consume_events(st, &market, limit, &control_accounts, &orders_accounts);
-
-        let mid = control_accounts.len() / 2;
-        let controls = control_accounts.split_at(mid);
-        let orders = orders_accounts.split_at(mid);
-        let margins = margin_accounts.split_at(mid);
-
-        crank_pnl(st, &market, &controls.0, &orders.0, &margins.0);
-        crank_pnl(st, &market, &controls.1, &orders.1, &margins.1);
+        consume_events(st, &market, limit, &owners);
+        if crank_pnl {
+            crank_pnl_chunks(st, &market, &owners);
+        }
     });
 
-    *last_head = events_header.head;
+    *last_head = head;
     *last_cranked_at = Instant::now();
+
+    sent
+}
+
+/// Consumes events until the queue is shorter than `cfg.catch_up_below`,
+/// so that a backlog built up while the consumer was down is cleared
+/// before the steady-state gating in `consume` applies. Returns the
+/// owners whose events were consumed.
+#[tracing::instrument(skip_all, level = "error", fields(symbol = symbol))]
+fn catch_up(
+    st: &'static AppState,
+    symbol: &str,
+    market: &zo_abi::dex::ZoDexMarket,
+    cfg: &ConsumerConfig,
+    accounts_table: &mut HashMap<Pubkey, (Pubkey, Option<Pubkey>)>,
+) -> Vec<Owner> {
+    let limit = cfg.to_consume as u16;
+    let mut iterations = 0;
+    let mut consumed = 0;
+    let mut all_owners = Vec::new();
+
+    while iterations < CATCH_UP_MAX_ITERATIONS {
+        let events = match fetch_events(st, market, cfg) {
+            Some((_, events)) => events,
+            None => break,
+        };
+
+        if events.len() < cfg.catch_up_below {
+            break;
+        }
+
+        let owners = event_accounts(st, market, &events, cfg, accounts_table);
+
+        iterations += 1;
+        if !consume_events(st, market, limit, &owners) {
+            break;
+        }
+
+        consumed += consumable(&events, &owners, limit);
+        if cfg.crank_pnl_now() {
+            crank_pnl_chunks(st, market, &owners);
+        }
+        all_owners.extend(owners);
+    }
+
+    if iterations > 0 {
+        info!(
+            "caught up {} in {} iterations, consuming about {} events",
+            symbol, iterations, consumed,
+        );
+    }
+
+    all_owners
+}
+
+/// The queue head and the events in the queue, or `None` if it couldn't
+/// be fetched.
+fn fetch_events(
+    st: &AppState,
+    market: &zo_abi::dex::ZoDexMarket,
+    cfg: &ConsumerConfig,
+) -> Option<(u64, Vec<zo_abi::dex::Event>)> {
+    let res = st
+        .rpc
+        .get_account_with_commitment(
+            &market.event_q,
+            cfg.event_queue_commitment,
+        )
+        .map_err(|e| warn!("{}", Error::from(e)))
+        .ok()?;
+
+    tracing::Span::current().record("slot", &res.context.slot);
+
+    let buf = res.value.unwrap().data;
+    let (events_header, events) =
+        zo_abi::dex::Event::deserialize_queue(&buf).unwrap();
+
+    Some((events_header.head, events.cloned().collect()))
+}
+
+/// The accounts of the first events' owners, up to `cfg.to_consume`
+/// owners, in the order of their first events. The owners of the
+/// earliest events are then sent first when they're split over several
+/// transactions. Margins are only derived if PnL is cranked, as that
+/// takes fetching the control account. An owner whose control can't be
+/// fetched is still returned, without a margin, as its events have to be
+/// consumed for the queue to move.
+fn event_accounts(
+    st: &AppState,
+    market: &zo_abi::dex::ZoDexMarket,
+    events: &[zo_abi::dex::Event],
+    cfg: &ConsumerConfig,
+    // Control -> (Open Orders, Margin)
+    accounts_table: &mut HashMap<Pubkey, (Pubkey, Option<Pubkey>)>,
+) -> Vec<Owner> {
+    // Unique and capped list of control pubkeys.
+    let mut used_control: Vec<Pubkey> = Vec::new();
+
+    for control in events.iter().map(|e| bytemuck::cast(e.control)) {
+        if !used_control.contains(&control) {
+            used_control.push(control);
+        }
+        if used_control.len() >= cfg.to_consume {
+            break;
+        }
+    }
+
+    // Controls seen for the first time are fetched together for their
+    // authorities, which their margins are derived from.
+    let authorities = match cfg.crank_pnl {
+        true => {
+            let new: Vec<_> = used_control
+                .iter()
+                .filter(|c| !accounts_table.contains_key(c))
+                .copied()
+                .collect();
+            control_authorities(st, &new)
+        }
+        false => HashMap::new(),
+    };
+
+    used_control
+        .into_iter()
+        .map(|control| {
+            if let Some(&(orders, margin)) = accounts_table.get(&control) {
+                return Owner {
+                    control,
+                    orders,
+                    margin,
+                };
+            }
+
+            let orders = open_orders_pda(&control, &market.own_address);
+            let margin = authorities
+                .get(&control)
+                .map(|a| margin_pda(a, &st.zo_state_pubkey));
+
+            // Without a margin, the control is fetched again next round.
+            match (cfg.crank_pnl, margin) {
+                (true, None) => {
+                    warn!("not cranking pnl of control {} this round", control)
+                }
+                _ => {
+                    accounts_table.insert(control, (orders, margin));
+                }
+            }
+
+            Owner {
+                control,
+                orders,
+                margin,
+            }
+        })
+        .collect()
+}
+
+/// The authorities of `controls`, fetched in as few requests as possible.
+/// Controls that can't be fetched or decoded are logged and left out.
+fn control_authorities(
+    st: &AppState,
+    controls: &[Pubkey],
+) -> HashMap<Pubkey, Pubkey> {
+    let mut res = HashMap::new();
+
+    for chunk in controls.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = match st.rpc.get_multiple_accounts(chunk) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("failed to fetch controls: {}", Error::from(e));
+                continue;
+            }
+        };
+
+        for (key, account) in chunk.iter().zip(accounts) {
+            let control = account.and_then(|mut a| {
+                try_load_account::<zo_abi::Control>(key, &mut a)
+            });
+
+            match control {
+                Some(c) => {
+                    res.insert(*key, c.authority);
+                }
+                None => warn!("control {} not found or invalid", key),
+            }
+        }
+    }
+
+    res
+}
+
+/// Events consumed given the `owners` passed: those before the first
+/// event of another owner, up to `limit`.
+fn consumable(
+    events: &[zo_abi::dex::Event],
+    owners: &[Owner],
+    limit: u16,
+) -> usize {
+    events
+        .iter()
+        .take(limit as usize)
+        .take_while(|e| {
+            let control: Pubkey = bytemuck::cast(e.control);
+            owners.iter().any(|o| o.control == control)
+        })
+        .count()
+}
+
+/// Lengths of the fewest chunks of at most `max` that `n` items split
+/// into, as even as possible, so that no transaction is left with only
+/// a few accounts.
+fn chunk_lens(n: usize, max: usize) -> Vec<usize> {
+    let max = max.max(1);
+    let chunks = (n + max - 1) / max;
+
+    (0..chunks)
+        .map(|i| n / chunks + usize::from(i < n % chunks))
+        .collect()
+}
+
+/// Splits `owners` into groups whose `per_owner` accounts each fit in a
+/// transaction alongside `fixed` other accounts. Each group is sorted by
+/// the `[u64; 4]` representation of the control keys, as the cranks
+/// have always sent them.
+fn owner_chunks(
+    owners: &[Owner],
+    fixed: usize,
+    per_owner: usize,
+) -> Vec<Vec<Owner>> {
+    let max = MAX_TX_ACCOUNTS.saturating_sub(fixed) / per_owner;
+    let mut rest = owners;
+
+    chunk_lens(owners.len(), max)
+        .into_iter()
+        .map(|len| {
+            let (chunk, tail) = rest.split_at(len);
+            rest = tail;
+
+            let mut chunk = chunk.to_vec();
+            chunk.sort_by_key(|o| bytemuck::cast::<_, [u64; 4]>(o.control));
+            chunk
+        })
+        .collect()
 }
 
 fn open_orders_pda(control: &Pubkey, zo_dex_market: &Pubkey) -> Pubkey {
@@ -187,21 +738,57 @@ fn open_orders_pda(control: &Pubkey, zo_dex_market: &Pubkey) -> Pubkey {
     .0
 }
 
-fn margin_pda(control: &zo_abi::Control, state: &Pubkey) -> Pubkey {
+fn margin_pda(authority: &Pubkey, state: &Pubkey) -> Pubkey {
     Pubkey::find_program_address(
-        &[control.authority.as_ref(), state.as_ref(), b"marginv1"],
+        &[authority.as_ref(), state.as_ref(), b"marginv1"],
         &zo_abi::ID,
     )
     .0
 }
 
+/// Consumes the events of `owners`, in as many transactions as their
+/// accounts need. The transactions are sent in order, stopping at the
+/// first failure, as the events after it can't be consumed anyway.
+/// Returns whether every transaction succeeded.
 fn consume_events(
     st: &AppState,
     market: &zo_abi::dex::ZoDexMarket,
     limit: u16,
-    control_accounts: &[AccountMeta],
-    orders_accounts: &[AccountMeta],
-) {
+    owners: &[Owner],
+) -> bool {
+    let chunks = owner_chunks(
+        owners,
+        CONSUME_EVENTS_FIXED_ACCOUNTS,
+        CONSUME_EVENTS_OWNER_ACCOUNTS,
+    );
+    let mut sent = 0;
+
+    for chunk in chunks.iter() {
+        if !consume_events_tx(st, market, limit, chunk) {
+            break;
+        }
+        sent += 1;
+    }
+
+    if chunks.len() > 1 {
+        info!(
+            "consume_events: sent {} of {} transactions for {} owners",
+            sent,
+            chunks.len(),
+            owners.len(),
+        );
+    }
+
+    sent == chunks.len()
+}
+
+/// Returns whether the transaction succeeded.
+fn consume_events_tx(
+    st: &AppState,
+    market: &zo_abi::dex::ZoDexMarket,
+    limit: u16,
+    owners: &[Owner],
+) -> bool {
     let program = st.program();
     let req = program
         .request()
@@ -214,28 +801,57 @@ fn consume_events(
             event_queue: market.event_q,
         });
 
-    let res = control_accounts
+    let res = owners
         .iter()
-        .chain(orders_accounts.iter())
-        .fold(req, |r, x| r.accounts(x.clone()))
-        .send();
-
-    match res {
-        Ok(sg) => info!("consume_events: {}", sg),
+        .map(|o| o.control)
+        .chain(owners.iter().map(|o| o.orders))
+        .fold(req, |r, x| r.accounts(AccountMeta::new(x, false)));
+
+    match st.send(res) {
+        Ok(sg) => {
+            info!("consume_events: {}", sg);
+            true
+        }
         Err(e) => {
             let e = Error::from(e);
             warn!("consume_events: {}", e);
+            false
         }
     }
 }
 
+/// Cranks the PnL of `owners`, in as many transactions as their accounts
+/// need. Owners whose margin isn't known are skipped. Returns whether
+/// every transaction succeeded, each being sent either way.
+fn crank_pnl_chunks(
+    st: &AppState,
+    market: &zo_abi::dex::ZoDexMarket,
+    owners: &[Owner],
+) -> bool {
+    let owners: Vec<_> = owners
+        .iter()
+        .filter(|o| o.margin.is_some())
+        .copied()
+        .collect();
+    let chunks = owner_chunks(
+        &owners,
+        CRANK_PNL_FIXED_ACCOUNTS,
+        CRANK_PNL_OWNER_ACCOUNTS,
+    );
+
+    let mut ok = true;
+    for chunk in chunks {
+        ok &= crank_pnl(st, market, &chunk);
+    }
+
+    ok
+}
+
 fn crank_pnl(
     st: &AppState,
     market: &zo_abi::dex::ZoDexMarket,
-    control_accounts: &[AccountMeta],
-    orders_accounts: &[AccountMeta],
-    margin_accounts: &[AccountMeta],
-) {
+    owners: &[Owner],
+) -> bool {
     let program = st.program();
     let req = program
         .request()
@@ -248,18 +864,113 @@ fn crank_pnl(
             market: market.own_address,
         });
 
-    let res = control_accounts
-        .iter()
-        .chain(orders_accounts.iter())
-        .chain(margin_accounts.iter())
-        .fold(req, |r, x| r.accounts(x.clone()))
-        .send();
-
-    match res {
-        Ok(sg) => info!("crank_pnl: {}", sg),
+    let req =
+        owners
+            .iter()
+            .map(|o| o.control)
+            .chain(owners.iter().map(|o| o.orders))
+            .chain(owners.iter().map(|o| {
+                o.margin.expect("owners without a margin are skipped")
+            }))
+            .fold(req, |r, x| r.accounts(AccountMeta::new(x, false)));
+
+    match st.send(req) {
+        Ok(sg) => {
+            info!("crank_pnl: {}", sg);
+            true
+        }
         Err(e) => {
             let e = Error::from(e);
             warn!("crank_pnl: {}", e);
+            false
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(n: usize) -> Vec<Owner> {
+        (0..n)
+            .map(|_| Owner {
+                control: Pubkey::new_unique(),
+                orders: Pubkey::new_unique(),
+                margin: Some(Pubkey::new_unique()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_progress_warns_once_per_interval() {
+        let hour = Duration::from_secs(3600);
+        let mut progress = Progress::new();
+        progress.reset(7);
+
+        // Warned once the polls run out, then not again within the hour.
+        let warned: Vec<bool> = (0..5)
+            .map(|_| {
+                progress.polls += 1;
+                progress.warn_stuck(3, hour)
+            })
+            .collect();
+        assert_eq!(warned, vec![false, false, true, false, false]);
+
+        // The head moving ends the stall, and a new one is warned again.
+        assert!(progress.reset(8).is_some());
+        assert_eq!(progress.reset(9), None);
+        progress.polls = 3;
+        assert!(progress.warn_stuck(3, hour));
+
+        // An interval that has passed warns on every poll.
+        assert!(progress.warn_stuck(3, Duration::ZERO));
+        assert!(progress.warn_stuck(3, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_chunk_lens() {
+        assert_eq!(chunk_lens(0, 8), Vec::<usize>::new());
+        assert_eq!(chunk_lens(5, 8), vec![5]);
+        assert_eq!(chunk_lens(8, 8), vec![8]);
+        assert_eq!(chunk_lens(12, 8), vec![6, 6]);
+        assert_eq!(chunk_lens(17, 8), vec![6, 6, 5]);
+        assert_eq!(chunk_lens(3, 0), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_owner_chunks() {
+        // One, two and several transactions of CrankPnl.
+        let cases = [(8, vec![8]), (12, vec![6, 6]), (30, vec![8, 8, 7, 7])];
+
+        for (n, lens) in cases {
+            let xs = owners(n);
+            let chunks = owner_chunks(
+                &xs,
+                CRANK_PNL_FIXED_ACCOUNTS,
+                CRANK_PNL_OWNER_ACCOUNTS,
+            );
+
+            assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), lens);
+
+            // Each chunk has the next owners in event order, sorted.
+            let mut i = 0;
+            for c in chunks.iter() {
+                let mut expected: Vec<_> =
+                    xs[i..i + c.len()].iter().map(|o| o.control).collect();
+                expected.sort_by_key(|k| bytemuck::cast::<_, [u64; 4]>(*k));
+
+                let got: Vec<_> = c.iter().map(|o| o.control).collect();
+                assert_eq!(got, expected);
+                i += c.len();
+            }
+        }
+
+        // The default of 12 owners still fits a single ConsumeEvents.
+        let chunks = owner_chunks(
+            &owners(12),
+            CONSUME_EVENTS_FIXED_ACCOUNTS,
+            CONSUME_EVENTS_OWNER_ACCOUNTS,
+        );
+        assert_eq!(chunks.len(), 1);
+    }
+}
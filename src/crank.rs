@@ -1,16 +1,54 @@
-use crate::{error::Error, AppState};
-use anchor_client::solana_sdk::{
-    compute_budget::ComputeBudgetInstruction,
-    instruction::{AccountMeta, Instruction},
+use crate::{error::Error, utils::supervised, AppState};
+use anchor_client::{
+    solana_client::rpc_config::RpcTransactionConfig,
+    solana_sdk::{
+        commitment_config::CommitmentConfig,
+        compute_budget::ComputeBudgetInstruction,
+        instruction::{AccountMeta, Instruction},
+        signature::Signature,
+    },
 };
-use std::{marker::Send, sync::Arc, time::Duration};
+use parking_lot::{const_mutex, Mutex};
+use solana_transaction_status::UiTransactionEncoding;
+use std::{marker::Send, str::FromStr, sync::Arc, time::Duration};
 use tokio::time::{Interval, MissedTickBehavior};
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
+
+/// An instruction the crank sends periodically.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrankTask {
+    /// `cache_oracle`, for every oracle.
+    Oracle,
+    /// `cache_interest`, for every collateral.
+    Interest,
+    /// `update_funding`, for every market.
+    Funding,
+}
+
+impl FromStr for CrankTask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oracle" => Ok(Self::Oracle),
+            "interest" => Ok(Self::Interest),
+            "funding" => Ok(Self::Funding),
+            _ => Err(format!("unknown crank task {}", s)),
+        }
+    }
+}
 
 pub struct CrankConfig {
     pub cache_oracle_interval: Duration,
     pub cache_interest_interval: Duration,
     pub update_funding_interval: Duration,
+    /// The tasks run by this process, so they can be split across hosts.
+    pub tasks: Vec<CrankTask>,
+    /// Times a transaction is sent again, with a fresh blockhash, after
+    /// a retryable error.
+    pub dispatch_retries: usize,
+    /// Run each task once and return, rather than on its interval.
+    pub once: bool,
 }
 
 const CACHE_ORACLE_CHUNK_SIZE: usize = 28;
@@ -19,13 +57,150 @@ const CACHE_INTEREST_CU_PER_ACCOUNT: usize = 30_000;
 const UPDATE_FUNDING_CHUNK_SIZE: usize = 4;
 const UPDATE_FUNDING_CU_PER_ACCOUNT: usize = 100_000;
 
+/// The most compute units a transaction can request.
+const MAX_CU: usize = 1_400_000;
+/// Headroom requested over the average observed usage.
+const CU_SAFETY_FACTOR: f64 = 1.3;
+/// Weight of each new observation in the moving average.
+const CU_EMA_ALPHA: f64 = 0.2;
+/// Relative change in the estimate worth logging.
+const CU_LOG_THRESHOLD: f64 = 0.1;
+/// Factor the estimate is raised by when a transaction runs out of units.
+const CU_EXCEEDED_FACTOR: f64 = 1.5;
+/// Confirmed transactions per one whose consumed units are fetched.
+const CU_SAMPLE_EVERY: usize = 10;
+
+/// Compute units to request per account for one chunk of an instruction
+/// type, as chunks can differ, e.g. in their mix of oracles. This starts
+/// at a fixed guess, and then follows an exponential moving average of
+/// the units consumed by a sample of confirmed transactions. It's raised
+/// whenever a transaction runs out of units, as those aren't sampled.
+struct CuEstimate {
+    name: &'static str,
+    initial: usize,
+    // The average units per account, and the estimate last logged.
+    state: Mutex<Option<(f64, f64)>>,
+    // Confirmed transactions so far, to sample from.
+    confirmed: Mutex<usize>,
+}
+
+impl CuEstimate {
+    const fn new(name: &'static str, initial: usize) -> Self {
+        Self {
+            name,
+            initial,
+            state: const_mutex(None),
+            confirmed: const_mutex(0),
+        }
+    }
+
+    fn per_account(&self) -> f64 {
+        match *self.state.lock() {
+            Some((ema, _)) => ema * CU_SAFETY_FACTOR,
+            None => self.initial as f64,
+        }
+    }
+
+    /// Whether to fetch the units consumed by a confirmed transaction,
+    /// which is the first and then every `CU_SAMPLE_EVERY`th.
+    fn sample(&self) -> bool {
+        let mut confirmed = self.confirmed.lock();
+        let sample = *confirmed % CU_SAMPLE_EVERY == 0;
+        *confirmed += 1;
+        sample
+    }
+
+    /// Raises the estimate after a transaction ran out of compute units,
+    /// by `CU_EXCEEDED_FACTOR` and to at least the initial guess, since
+    /// the average would never learn of it otherwise.
+    fn exceeded(&self) {
+        let per_account = self.per_account();
+        let estimate =
+            (per_account * CU_EXCEEDED_FACTOR).max(self.initial as f64);
+
+        warn!(
+            "{} ran out of compute units, per account: {:.0} -> {:.0}",
+            self.name, per_account, estimate
+        );
+        *self.state.lock() = Some((estimate / CU_SAFETY_FACTOR, estimate));
+    }
+
+    /// Compute units to request for `n` accounts.
+    fn units(&self, n: usize) -> u32 {
+        let units = (self.per_account() * n as f64).ceil() as usize;
+        units.clamp(1, MAX_CU) as u32
+    }
+
+    fn observe(&self, consumed: u64, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        let per_account = consumed as f64 / n as f64;
+        let mut state = self.state.lock();
+
+        let (ema, logged) = match *state {
+            Some((ema, logged)) => (
+                CU_EMA_ALPHA * per_account + (1. - CU_EMA_ALPHA) * ema,
+                logged,
+            ),
+            None => (per_account, self.initial as f64),
+        };
+
+        let estimate = ema * CU_SAFETY_FACTOR;
+        let logged = if (estimate - logged).abs() > CU_LOG_THRESHOLD * logged {
+            info!(
+                "{} compute units per account: {:.0} -> {:.0}",
+                self.name, logged, estimate
+            );
+            estimate
+        } else {
+            logged
+        };
+
+        *state = Some((ema, logged));
+    }
+}
+
+/// Total compute units consumed by the zo program's instructions, from
+/// the `Program <id> consumed N of M compute units` lines of the logs,
+/// or `None` if there are no such lines or one can't be parsed.
+fn consumed_units(logs: &[String]) -> Option<u64> {
+    let prefix = format!("Program {} consumed ", zo_abi::ID);
+
+    let units = logs
+        .iter()
+        .filter_map(|l| l.strip_prefix(&prefix))
+        .map(|l| l.split(' ').next().and_then(|n| n.parse::<u64>().ok()))
+        .collect::<Option<Vec<_>>>()?;
+
+    if units.is_empty() {
+        None
+    } else {
+        Some(units.iter().sum())
+    }
+}
+
 pub async fn run(st: &'static AppState, cfg: CrankConfig) -> Result<(), Error> {
-    let cache_oracle_tasks = st
-        .iter_oracles()
-        .filter(|x| String::from(x.symbol) != "LUNA")
-        .collect::<Vec<_>>()
-        .chunks(CACHE_ORACLE_CHUNK_SIZE)
-        .map(|x| {
+    info!("running crank tasks: {:?}", cfg.tasks);
+
+    let retries = cfg.dispatch_retries;
+    let once = cfg.once;
+
+    // Disabled tasks are left with nothing to crank.
+    let oracles = if cfg.tasks.contains(&CrankTask::Oracle) {
+        st.iter_oracles()
+            .filter(|x| String::from(x.symbol) != "LUNA")
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+    let oracle_chunks = oracles.chunks(CACHE_ORACLE_CHUNK_SIZE);
+    let num_oracle_chunks = oracle_chunks.len();
+
+    let cache_oracle_tasks = oracle_chunks
+        .enumerate()
+        .map(|(i, x)| {
             let symbols: Vec<_> = x.iter().map(|o| o.symbol.into()).collect();
             let accounts: Vec<_> = x
                 .iter()
@@ -44,54 +219,208 @@ pub async fn run(st: &'static AppState, cfg: CrankConfig) -> Result<(), Error> {
 
             let symbols = Arc::new(symbols);
             let accounts = Arc::new(accounts);
+            let cu = Arc::new(CuEstimate::new(
+                "cache_oracle",
+                CACHE_ORACLE_CU_PER_ACCOUNT,
+            ));
 
-            loop_blocking(interval(cfg.cache_oracle_interval), move || {
-                cache_oracle(st, &symbols, &accounts)
-            })
+            let interval = staggered_interval(
+                cfg.cache_oracle_interval,
+                i,
+                num_oracle_chunks,
+            );
+
+            loop_blocking(
+                format!("cache_oracle {:?}", symbols),
+                interval,
+                once,
+                move || cache_oracle(st, &symbols, &accounts, &cu, retries),
+            )
         })
         .collect::<Vec<_>>();
 
     let cache_interest_task =
-        loop_blocking(interval(cfg.cache_interest_interval), move || {
-            cache_interest(st)
+        cfg.tasks.contains(&CrankTask::Interest).then(|| {
+            let cu = Arc::new(CuEstimate::new(
+                "cache_interest",
+                CACHE_INTEREST_CU_PER_ACCOUNT,
+            ));
+
+            loop_blocking(
+                "cache_interest".to_string(),
+                interval(cfg.cache_interest_interval),
+                once,
+                move || cache_interest(st, &cu, retries),
+            )
         });
 
-    let update_funding_tasks = st
-        .load_dex_markets()?
-        .into_iter()
-        .filter(|(s, _)| s != "LUNA-PERP")
-        .collect::<Vec<_>>()
-        .chunks(UPDATE_FUNDING_CHUNK_SIZE)
-        .map(|v| {
+    let dex_markets = if cfg.tasks.contains(&CrankTask::Funding) {
+        st.load_dex_markets()?
+            .into_iter()
+            .filter(|(s, _)| s != "LUNA-PERP")
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+    let market_chunks = dex_markets.chunks(UPDATE_FUNDING_CHUNK_SIZE);
+    let num_market_chunks = market_chunks.len();
+
+    let update_funding_tasks = market_chunks
+        .enumerate()
+        .map(|(i, v)| {
             let (s, m): (Vec<_>, Vec<_>) = v.iter().cloned().unzip();
             let symbols = Arc::new(s);
             let markets = Arc::new(m);
+            let cu = Arc::new(CuEstimate::new(
+                "update_funding",
+                UPDATE_FUNDING_CU_PER_ACCOUNT,
+            ));
 
-            loop_blocking(interval(cfg.update_funding_interval), move || {
-                update_funding(st, &symbols, &markets)
-            })
+            let interval = staggered_interval(
+                cfg.update_funding_interval,
+                i,
+                num_market_chunks,
+            );
+
+            loop_blocking(
+                format!("update_funding {:?}", symbols),
+                interval,
+                once,
+                move || update_funding(st, &symbols, &markets, &cu, retries),
+            )
         })
         .collect::<Vec<_>>();
 
-    futures::join!(
+    let (oracle, interest, funding) = futures::join!(
         futures::future::join_all(cache_oracle_tasks),
-        cache_interest_task,
+        futures::future::join_all(cache_interest_task),
         futures::future::join_all(update_funding_tasks),
     );
 
-    Ok(())
+    // The tasks only return when run once.
+    let failed = oracle
+        .iter()
+        .chain(&interest)
+        .chain(&funding)
+        .filter(|ok| !**ok)
+        .count();
+
+    match failed {
+        0 => Ok(()),
+        n => Err(Error::PassFailed(n)),
+    }
 }
 
 fn interval(d: Duration) -> Interval {
-    let mut interval = tokio::time::interval(d);
+    staggered_interval(d, 0, 1)
+}
+
+/// An interval of period `d` for the `i`th of `n` tasks, whose first tick
+/// is delayed by `i / n` of the period, so that the tasks' transactions
+/// are spread over the period rather than sent all at once.
+fn staggered_interval(d: Duration, i: usize, n: usize) -> Interval {
+    let offset = d.mul_f64(i as f64 / n.max(1) as f64);
+    let mut interval =
+        tokio::time::interval_at(tokio::time::Instant::now() + offset, d);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     interval
 }
 
-fn dispatch(st: &AppState, req: anchor_client::RequestBuilder) {
+/// Whether sending a transaction again, with a fresh blockhash, may
+/// succeed after `e`. Program errors will fail again, as will anything
+/// unexpected, so only an expired blockhash, a node behind the cluster,
+/// or a failure to reach the node is retried.
+fn is_retryable(e: &Error) -> bool {
+    use anchor_client::solana_client::{
+        client_error::ClientErrorKind,
+        rpc_request::{RpcError, RpcResponseErrorData},
+    };
+    use anchor_client::solana_sdk::transaction::TransactionError;
+
+    let tx_retryable =
+        |e: &TransactionError| matches!(e, TransactionError::BlockhashNotFound);
+
+    match e {
+        Error::ConfirmationTimeout(_) => true,
+        Error::TransactionError(e) => tx_retryable(e),
+        Error::SolanaClient(e) => match e.kind() {
+            ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) => true,
+            ClientErrorKind::TransactionError(e) => tx_retryable(e),
+            ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                data,
+                ..
+            }) => match data {
+                RpcResponseErrorData::NodeUnhealthy { .. } => true,
+                RpcResponseErrorData::SendTransactionPreflightFailure(r) => {
+                    r.err.as_ref().map_or(false, tx_retryable)
+                }
+                RpcResponseErrorData::Empty => false,
+            },
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether `e` is a transaction running out of compute units, which
+/// fails the same way again unless it's given more.
+fn is_budget_exceeded(e: &Error) -> bool {
+    use anchor_client::solana_client::{
+        client_error::ClientErrorKind,
+        rpc_request::{RpcError, RpcResponseErrorData},
+    };
+    use anchor_client::solana_sdk::{
+        instruction::InstructionError, transaction::TransactionError,
+    };
+
+    let exceeded = |e: &TransactionError| {
+        matches!(
+            e,
+            TransactionError::InstructionError(
+                _,
+                InstructionError::ComputationalBudgetExceeded
+            )
+        )
+    };
+
+    match e {
+        Error::TransactionError(e) => exceeded(e),
+        Error::SolanaClient(e) => match e.kind() {
+            ClientErrorKind::TransactionError(e) => exceeded(e),
+            ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                data: RpcResponseErrorData::SendTransactionPreflightFailure(r),
+                ..
+            }) => r.err.as_ref().map_or(false, exceeded),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_insufficient_funds(e: &Error) -> bool {
+    match e {
+        Error::TransactionError(e) => crate::funds::is_insufficient_funds(e),
+        Error::SolanaClient(e) => {
+            crate::funds::is_client_insufficient_funds(e.kind())
+        }
+        _ => false,
+    }
+}
+
+/// Sends the request, up to `retries` more times after a retryable
+/// error, and updates `cu` with the units it consumed for `n` accounts
+/// once it's confirmed, or raises it if it ran out of units. Nothing is
+/// sent while the payer can't pay fees, see `crate::funds`. Returns
+/// whether the transaction was confirmed.
+fn dispatch(
+    st: &AppState,
+    req: anchor_client::RequestBuilder,
+    cu: &CuEstimate,
+    n: usize,
+    retries: usize,
+) -> bool {
     use anchor_client::solana_sdk::{
-        commitment_config::CommitmentConfig, signer::Signer as _,
-        transaction::Transaction,
+        hash::Hash, signer::Signer as _, transaction::Transaction,
     };
 
     const GET_STATUS_RETRIES: usize = 25;
@@ -101,30 +430,34 @@ fn dispatch(st: &AppState, req: anchor_client::RequestBuilder) {
     // client's `send_and_confirm_transaction` function, but does not
     // retry `usize::MAX` times as that ends up spawning too many
     // processes.
-    let aux = move || -> Result<_, Error> {
-        let ixs = req.instructions().unwrap();
-        let (bh, ..) = st.rpc.get_latest_blockhash_with_commitment(
-            CommitmentConfig::processed(),
-        )?;
+    fn send_and_confirm(
+        st: &AppState,
+        ixs: &[Instruction],
+        bh: Hash,
+        is_nonce: bool,
+    ) -> Result<Signature, Error> {
         let payer = st.payer_key();
         let tx = Transaction::new_signed_with_payer(
-            &ixs,
+            ixs,
             Some(&payer.pubkey()),
             // NOTE: For cranking, no other signer is required.
             &[payer],
             bh,
         );
-        let sg = st.rpc.send_transaction(&tx)?;
+        let sg = st.tx_rpc.send_transaction(&tx)?;
 
         for _ in 0..GET_STATUS_RETRIES {
             match st.rpc.get_signature_status(&sg)? {
                 Some(Ok(_)) => return Ok(sg),
                 Some(Err(e)) => return Err(e.into()),
                 None => {
-                    if !st.rpc.is_blockhash_valid(
-                        &bh,
-                        CommitmentConfig::processed(),
-                    )? {
+                    // A nonce doesn't expire, so wait out all the retries.
+                    if !is_nonce
+                        && !st.rpc.is_blockhash_valid(
+                            &bh,
+                            CommitmentConfig::processed(),
+                        )?
+                    {
                         break;
                     }
 
@@ -134,31 +467,148 @@ fn dispatch(st: &AppState, req: anchor_client::RequestBuilder) {
         }
 
         Err(Error::ConfirmationTimeout(sg))
+    }
+
+    if crate::funds::is_paused(st) {
+        debug!("Skipped, payer can't pay fees");
+        return false;
+    }
+
+    let ixs = req.instructions().unwrap();
+
+    // A fresh blockhash is fetched for every attempt.
+    let aux = || -> Result<_, Error> {
+        let ixs = ixs.clone();
+
+        match st.nonce() {
+            Some(nonce) => {
+                nonce.with(&st.rpc, &st.payer(), |advance, bh| {
+                    let ixs: Vec<_> =
+                        std::iter::once(advance).chain(ixs).collect();
+                    send_and_confirm(st, &ixs, bh, true)
+                })?
+            }
+            None => {
+                let (bh, ..) = st.rpc.get_latest_blockhash_with_commitment(
+                    CommitmentConfig::processed(),
+                )?;
+                send_and_confirm(st, &ixs, bh, false)
+            }
+        }
     };
 
-    match aux() {
-        Ok(sg) => info!("{}", sg),
-        Err(e) => warn!("{}", e),
+    for attempt in 0..=retries {
+        match aux() {
+            Ok(sg) => {
+                info!("{}", sg);
+
+                // The units consumed are only in the transaction's logs,
+                // which take another request to fetch, so only a sample
+                // of transactions are fetched.
+                if cu.sample() {
+                    observe_units(st, &sg, cu, n);
+                }
+                return true;
+            }
+            Err(e) if is_insufficient_funds(&e) => {
+                crate::funds::report(st);
+                return false;
+            }
+            Err(e) if is_budget_exceeded(&e) => {
+                warn!("{}", e);
+                cu.exceeded();
+                return false;
+            }
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                warn!("Retrying ({}/{}): {}", attempt + 1, retries, e);
+            }
+            Err(e) => {
+                warn!("{}", e);
+                return false;
+            }
+        }
+    }
+
+    false
+}
+
+fn observe_units(st: &AppState, sg: &Signature, cu: &CuEstimate, n: usize) {
+    let res = st.rpc.get_transaction_with_config(
+        sg,
+        RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(CommitmentConfig::confirmed()),
+            max_supported_transaction_version: None,
+        },
+    );
+
+    let logs = match res {
+        Ok(tx) => tx.transaction.meta.and_then(|x| x.log_messages),
+        Err(e) => {
+            debug!("Failed to fetch logs of {}: {}", sg, e);
+            return;
+        }
     };
+
+    match logs.as_deref().and_then(consumed_units) {
+        Some(consumed) => cu.observe(consumed, n),
+        None => debug!("No compute units logged by {}", sg),
+    }
 }
 
-async fn loop_blocking<F>(mut interval: Interval, f: F)
+/// Runs `f` on the blocking pool on every tick. Each run is a task of its
+/// own, so a run that panics is logged with `name` and the next tick runs
+/// `f` afresh.
+///
+/// If `once`, `f` is run right away instead, and whether it succeeded is
+/// returned.
+async fn loop_blocking<F>(
+    name: String,
+    mut interval: Interval,
+    once: bool,
+    f: F,
+) -> bool
 where
-    F: Fn() + Send + Clone + 'static,
+    F: Fn() -> bool + Send + Clone + 'static,
 {
+    let name: Arc<str> = name.into();
+
+    if once {
+        let handle = tokio::task::spawn_blocking(move || supervised(f));
+
+        return handle.await.unwrap_or_else(|e| {
+            warn!("{} panicked: {}", name, e);
+            false
+        });
+    }
+
     loop {
         interval.tick().await;
-        tokio::task::spawn_blocking(f.clone());
+        let f = f.clone();
+        let handle = tokio::task::spawn_blocking(move || supervised(f));
+        let name = name.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle.await {
+                warn!("{} panicked: {}", name, e);
+            }
+        });
     }
 }
 
 #[tracing::instrument(skip_all, level = "error", fields(symbols = ?s))]
-fn cache_oracle(st: &AppState, s: &[String], accs: &[AccountMeta]) {
+fn cache_oracle(
+    st: &AppState,
+    s: &[String],
+    accs: &[AccountMeta],
+    cu: &CuEstimate,
+    retries: usize,
+) -> bool {
     let program = st.program();
     let req = program
         .request()
         .instruction(ComputeBudgetInstruction::set_compute_unit_limit(
-            (s.len() * CACHE_ORACLE_CU_PER_ACCOUNT) as u32,
+            cu.units(s.len()),
         ))
         .args(zo_abi::instruction::CacheOracle {
             symbols: s.to_owned(),
@@ -173,18 +623,19 @@ fn cache_oracle(st: &AppState, s: &[String], accs: &[AccountMeta]) {
 
     let req = accs.iter().fold(req, |r, x| r.accounts(x.clone()));
 
-    dispatch(st, req);
+    dispatch(st, req, cu, s.len(), retries)
 }
 
 #[tracing::instrument(skip_all, level = "error")]
-fn cache_interest(st: &AppState) {
+fn cache_interest(st: &AppState, cu: &CuEstimate, retries: usize) -> bool {
+    let n = st.zo_state.total_collaterals as usize;
+
     dispatch(
         st,
         st.program()
             .request()
             .instruction(ComputeBudgetInstruction::set_compute_unit_limit(
-                st.zo_state.total_collaterals as u32
-                    * CACHE_INTEREST_CU_PER_ACCOUNT as u32,
+                cu.units(n),
             ))
             .args(zo_abi::instruction::CacheInterestRates {
                 start: 0,
@@ -195,7 +646,10 @@ fn cache_interest(st: &AppState) {
                 state: st.zo_state_pubkey,
                 cache: st.zo_cache_pubkey,
             }),
-    );
+        cu,
+        n,
+        retries,
+    )
 }
 
 #[tracing::instrument(skip_all, level = "error", fields(symbol = ?symbol))]
@@ -203,15 +657,17 @@ fn update_funding(
     st: &AppState,
     symbol: &[String],
     m: &[zo_abi::dex::ZoDexMarket],
-) {
+    cu: &CuEstimate,
+    retries: usize,
+) -> bool {
     use anchor_lang::{InstructionData, ToAccountMetas};
 
     let program = st.program();
+    // One instruction per market in the chunk, so the estimate is per
+    // market and the final, smaller chunk requests less.
+    let n = m.len();
     let req = program.request().instruction(
-        ComputeBudgetInstruction::set_compute_unit_limit(
-            st.zo_state.total_collaterals as u32
-                * UPDATE_FUNDING_CU_PER_ACCOUNT as u32,
-        ),
+        ComputeBudgetInstruction::set_compute_unit_limit(cu.units(n)),
     );
 
     let req = m.iter().fold(req, |acc, m| {
@@ -231,5 +687,120 @@ fn update_funding(
         })
     });
 
-    dispatch(st, req);
+    dispatch(st, req, cu, n, retries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consumed_units() {
+        let logs = vec![
+            format!("Program {} invoke [1]", zo_abi::ID),
+            "Program log: Instruction: UpdatePerpFunding".to_string(),
+            format!(
+                "Program {} consumed 41000 of 400000 compute units",
+                zo_abi::ID
+            ),
+            format!("Program {} success", zo_abi::ID),
+            format!(
+                "Program {} consumed 39000 of 359000 compute units",
+                zo_abi::ID
+            ),
+        ];
+
+        assert_eq!(consumed_units(&logs), Some(80_000));
+        assert_eq!(consumed_units(&logs[..2]), None);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        use anchor_client::solana_sdk::{
+            instruction::InstructionError, transaction::TransactionError,
+        };
+
+        assert!(is_retryable(&Error::ConfirmationTimeout(
+            Signature::default()
+        )));
+        assert!(is_retryable(&Error::TransactionError(
+            TransactionError::BlockhashNotFound
+        )));
+        assert!(!is_retryable(&Error::TransactionError(
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(6000)
+            )
+        )));
+        assert!(!is_retryable(&Error::InvalidAccount(Default::default())));
+    }
+
+    #[test]
+    fn test_cu_estimate() {
+        let cu = CuEstimate::new("test", 100);
+        assert_eq!(cu.units(4), 400);
+
+        // The first observation replaces the guess.
+        cu.observe(400, 4);
+        assert_eq!(cu.units(4), 520);
+
+        cu.observe(2_000, 4);
+        let ema = 0.2 * 500. + 0.8 * 100.;
+        assert_eq!(cu.units(1), (ema * CU_SAFETY_FACTOR).ceil() as u32);
+
+        assert_eq!(cu.units(1_000_000), MAX_CU as u32);
+    }
+
+    #[test]
+    fn test_cu_estimate_exceeded() {
+        let cu = CuEstimate::new("test", 100);
+
+        // An estimate below the guess is reset to it.
+        cu.observe(40, 4);
+        assert_eq!(cu.units(1), 13);
+        cu.exceeded();
+        assert_eq!(cu.units(1), 100);
+
+        // Then raised on each failure.
+        cu.exceeded();
+        assert_eq!(cu.units(1), 150);
+        cu.exceeded();
+        assert_eq!(cu.units(1), 225);
+
+        // Observations are averaged in from there.
+        cu.observe(225, 1);
+        let ema = 0.2 * 225. + 0.8 * (225. / CU_SAFETY_FACTOR);
+        assert_eq!(cu.units(1), (ema * CU_SAFETY_FACTOR).ceil() as u32);
+    }
+
+    #[test]
+    fn test_cu_estimate_sample() {
+        let cu = CuEstimate::new("test", 100);
+        let sampled: Vec<usize> = (0..25).filter(|_| cu.sample()).collect();
+
+        assert_eq!(sampled, vec![0, 10, 20]);
+    }
+
+    #[test]
+    fn test_is_budget_exceeded() {
+        use anchor_client::solana_sdk::{
+            instruction::InstructionError, transaction::TransactionError,
+        };
+
+        assert!(is_budget_exceeded(&Error::TransactionError(
+            TransactionError::InstructionError(
+                1,
+                InstructionError::ComputationalBudgetExceeded
+            )
+        )));
+        assert!(!is_budget_exceeded(&Error::TransactionError(
+            TransactionError::InstructionError(
+                1,
+                InstructionError::Custom(6000)
+            )
+        )));
+        assert!(!is_budget_exceeded(&Error::ConfirmationTimeout(
+            Signature::default()
+        )));
+    }
 }
@@ -4,11 +4,11 @@ use mongodb::{
     options::{IndexOptions, InsertManyOptions},
     Collection, Database, IndexModel,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Trade {
     pub symbol: String,
     pub time: i64,
@@ -22,19 +22,32 @@ pub struct Trade {
     pub control: String,
     #[serde(rename = "seqNum")]
     pub seq_num: u16,
+    /// Slot of the transaction, also kept on the other documents keyed
+    /// by `sig`. Defaults to zero for documents spooled before it was
+    /// added.
+    #[serde(default)]
+    pub slot: u64,
+    /// Whether `time` is when the keeper received the transaction, as
+    /// its block time wasn't available, rather than the block time.
+    #[serde(default, rename = "approxTime")]
+    pub approx_time: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Funding {
     pub symbol: String,
     #[serde(rename = "fundingIndex")]
     pub funding_index: String,
     pub hourly: f64,
+    /// Simple annualized rate, `hourly * 24 * 365`. Defaults to zero for
+    /// documents spooled before the field was added.
+    #[serde(default)]
+    pub apr: f64,
     #[serde(rename = "time")]
     pub time: i64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct RealizedPnl {
     pub symbol: String,
     pub sig: String,
@@ -47,9 +60,13 @@ pub struct RealizedPnl {
     #[serde(rename = "qtyReceived")]
     pub qty_received: i64,
     pub time: i64,
+    #[serde(default)]
+    pub slot: u64,
+    #[serde(default, rename = "approxTime")]
+    pub approx_time: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Liquidation {
     pub sig: String,
     #[serde(rename = "liquidationEvent")]
@@ -67,9 +84,13 @@ pub struct Liquidation {
     #[serde(rename = "quoteToLiqor")]
     pub quote_to_liqor: i64,
     pub time: i64,
+    #[serde(default)]
+    pub slot: u64,
+    #[serde(default, rename = "approxTime")]
+    pub approx_time: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Bankruptcy {
     pub sig: String,
     #[serde(rename = "baseSymbol")]
@@ -87,18 +108,26 @@ pub struct Bankruptcy {
     #[serde(rename = "socializedLoss")]
     pub socialized_loss: i64,
     pub time: i64,
+    #[serde(default)]
+    pub slot: u64,
+    #[serde(default, rename = "approxTime")]
+    pub approx_time: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct BalanceChange {
     pub time: i64,
     pub sig: String,
     pub margin: String,
     pub symbol: String,
     pub amount: i64,
+    #[serde(default)]
+    pub slot: u64,
+    #[serde(default, rename = "approxTime")]
+    pub approx_time: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Swap {
     pub time: i64,
     pub sig: String,
@@ -111,15 +140,28 @@ pub struct Swap {
     pub base_delta: i64,
     #[serde(rename = "quoteDelta")]
     pub quote_delta: i64,
+    #[serde(default)]
+    pub slot: u64,
+    #[serde(default, rename = "approxTime")]
+    pub approx_time: bool,
 }
 
 #[derive(Serialize)]
 pub struct OpenInterest {
     time: i64,
-    values: HashMap<String, i64>,
+    values: HashMap<String, OiRow>,
 }
 
-#[derive(Serialize)]
+/// Open interest of a market, in base lots. `short` is the size of the
+/// short positions, so it is never negative.
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct OiRow {
+    pub long: i64,
+    pub short: i64,
+    pub net: i64,
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OtcFill {
     pub time: i64,
@@ -129,6 +171,10 @@ pub struct OtcFill {
     pub maker_margin: String,
     pub d_base: i64,
     pub d_quote: i64,
+    #[serde(default)]
+    pub slot: u64,
+    #[serde(default)]
+    pub approx_time: bool,
 }
 
 #[tracing::instrument(
@@ -205,6 +251,11 @@ macro_rules! simple_update_impl {
     { $( ($T:ty, $coll:expr, $idx:expr) ),* $(,)? } => {
         $(
             impl $T {
+                // Funding isn't spooled, as a failed update is retried
+                // from the next cache, so its name goes unused.
+                #[allow(dead_code)]
+                pub const COLL: &'static str = $coll;
+
                 pub async fn update(
                     db: &Database,
                     xs: &[$T],
@@ -220,6 +271,39 @@ macro_rules! simple_update_impl {
                 }
             }
         )*
+
+        /// Inserts documents that were spooled to disk as JSON lines,
+        /// using the same collection and indices as `update`. Lines that
+        /// fail to deserialize are logged and dropped.
+        pub async fn update_spooled(
+            db: &Database,
+            coll: &str,
+            lines: &[String],
+        ) -> Result<(), MongoError> {
+            $(
+                if coll == $coll {
+                    let xs: Vec<$T> = lines
+                        .iter()
+                        .filter_map(|l| match serde_json::from_str(l) {
+                            Ok(x) => Some(x),
+                            Err(e) => {
+                                warn!("dropping spooled document: {}", e);
+                                None
+                            }
+                        })
+                        .collect();
+
+                    return <$T>::update(db, &xs).await;
+                }
+            )*
+
+            warn!(
+                "unknown spooled collection {}, dropping {} documents",
+                coll,
+                lines.len(),
+            );
+            Ok(())
+        }
     }
 }
 
@@ -257,7 +341,7 @@ impl OpenInterest {
     pub async fn insert(
         db: &Database,
         time: i64,
-        values: HashMap<String, i64>,
+        values: HashMap<String, OiRow>,
     ) -> Result<(), MongoError> {
         insert(
             &db.collection::<Self>("oi"),
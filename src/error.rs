@@ -4,6 +4,14 @@ pub enum Error {
     OraclesSkipped(Vec<String>),
     #[error("Failed to confirm: {0}")]
     ConfirmationTimeout(anchor_client::solana_sdk::signature::Signature),
+    #[error("Account {0} not found")]
+    AccountNotFound(anchor_client::solana_sdk::pubkey::Pubkey),
+    #[error("Invalid account {0}")]
+    InvalidAccount(anchor_client::solana_sdk::pubkey::Pubkey),
+    #[error("{0} of the pass's tasks failed")]
+    PassFailed(usize),
+    #[error("Invalid config: {0}")]
+    Config(String),
 
     // Library errors
     #[error("{0}: {0:?}")]
@@ -22,4 +30,8 @@ pub enum Error {
     Db(#[from] mongodb::error::Error),
     #[error("{0}")]
     Var(#[from] std::env::VarError),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
 }
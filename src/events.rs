@@ -1,8 +1,10 @@
 // NOTE: Modified implementation of anchor's parser because anchor's impl has a few issues
 
-use crate::{db, AppState, Error};
+use crate::{db, spool::Spool, state::StateIndices, AppState, Error};
 use anchor_client::anchor_lang::Event;
 use futures::TryFutureExt;
+use serde::Serialize;
+use std::time::SystemTime;
 use tracing::warn;
 use zo_abi::events;
 
@@ -10,25 +12,71 @@ use zo_abi::events;
 pub async fn process(
     st: &AppState,
     db: &mongodb::Database,
+    spool: &Spool,
     ss: Vec<String>,
     sig: String,
-    time: i64,
+    slot: u64,
+    block_time: Option<i64>,
 ) {
-    let (rpnl, liq, bank, bal, swap, otc, fill, oracle) =
-        parse(st, ss.iter(), sig, time);
-
-    let on_err = |e| {
-        let e = Error::from(e);
-        warn!("{}", e);
+    // Without a block time, the time the logs were received is close,
+    // but the documents are flagged in case it's far off.
+    let (time, approx_time) = match block_time {
+        Some(t) => (t, false),
+        None => (
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            true,
+        ),
     };
+
+    let (rpnl, liq, bank, bal, swap, otc, fill, oracle) = parse(
+        &st.zo_state,
+        &st.zo_indices,
+        ss.iter(),
+        sig,
+        slot,
+        time,
+        approx_time,
+    );
+
     let _ = futures::join!(
-        db::RealizedPnl::update(db, &rpnl).map_err(on_err),
-        db::Liquidation::update(db, &liq).map_err(on_err),
-        db::Bankruptcy::update(db, &bank).map_err(on_err),
-        db::BalanceChange::update(db, &bal).map_err(on_err),
-        db::OtcFill::update(db, &otc).map_err(on_err),
-        db::Trade::update(db, &fill).map_err(on_err),
-        db::Swap::update(db, &swap).map_err(on_err),
+        db::RealizedPnl::update(db, &rpnl).map_err(on_err(
+            spool,
+            db::RealizedPnl::COLL,
+            &rpnl
+        )),
+        db::Liquidation::update(db, &liq).map_err(on_err(
+            spool,
+            db::Liquidation::COLL,
+            &liq
+        )),
+        db::Bankruptcy::update(db, &bank).map_err(on_err(
+            spool,
+            db::Bankruptcy::COLL,
+            &bank
+        )),
+        db::BalanceChange::update(db, &bal).map_err(on_err(
+            spool,
+            db::BalanceChange::COLL,
+            &bal
+        )),
+        db::OtcFill::update(db, &otc).map_err(on_err(
+            spool,
+            db::OtcFill::COLL,
+            &otc
+        )),
+        db::Trade::update(db, &fill).map_err(on_err(
+            spool,
+            db::Trade::COLL,
+            &fill
+        )),
+        db::Swap::update(db, &swap).map_err(on_err(
+            spool,
+            db::Swap::COLL,
+            &swap
+        )),
     );
 
     match oracle {
@@ -40,11 +88,35 @@ pub async fn process(
     }
 }
 
+/// Logs a failed insert and spools the batch to disk, so it is retried
+/// once the database is reachable again.
+fn on_err<'a, T: Serialize>(
+    spool: &'a Spool,
+    coll: &'a str,
+    xs: &'a [T],
+) -> impl FnOnce(mongodb::error::Error) + 'a {
+    move |e| {
+        let e = Error::from(e);
+        warn!("{}", e);
+
+        match spool.write(coll, xs) {
+            Ok(()) => warn!("spooled {} {} documents", xs.len(), coll),
+            Err(e) => warn!("failed to spool {} documents: {}", coll, e),
+        }
+    }
+}
+
+const PROGRAM_LOG: &str = "Program log: ";
+const PROGRAM_DATA: &str = "Program data: ";
+
 fn parse<'a>(
-    st: &AppState,
+    state: &zo_abi::State,
+    indices: &StateIndices,
     logs: impl Iterator<Item = &'a String> + 'a,
     sig: String,
+    slot: u64,
     time: i64,
+    approx_time: bool,
 ) -> (
     Vec<db::RealizedPnl>,
     Vec<db::Liquidation>,
@@ -55,14 +127,6 @@ fn parse<'a>(
     Vec<db::Trade>,
     Option<events::CacheOracleNoops>,
 ) {
-    const PROGRAM_LOG: &str = "Program log: ";
-    const PROGRAM_DATA: &str = "Program data: ";
-
-    let prog_start_str = format!("Program {} invoke", zo_abi::ID);
-    let prog_end_str = format!("Program {} success", zo_abi::ID);
-
-    let mut is_zo_log = false;
-
     let mut rpnl = Vec::new();
     let mut liq = Vec::new();
     let mut bank = Vec::new();
@@ -72,17 +136,7 @@ fn parse<'a>(
     let mut fill = Vec::new();
     let mut oracle = None;
 
-    for l in logs {
-        if !is_zo_log {
-            is_zo_log = l.starts_with(&prog_start_str);
-            continue;
-        }
-
-        if l.starts_with(&prog_end_str) {
-            is_zo_log = false;
-            continue;
-        }
-
+    for l in zo_logs(logs) {
         let bytes = match l
             .strip_prefix(PROGRAM_DATA)
             .or_else(|| l.strip_prefix(PROGRAM_LOG))
@@ -97,10 +151,7 @@ fn parse<'a>(
                 continue;
             }
 
-            let symbol = st
-                .iter_markets()
-                .find(|x| x.dex_market == e.market_key)
-                .unwrap()
+            let symbol = state.perp_markets[indices.markets[&e.market_key]]
                 .symbol
                 .into();
 
@@ -113,6 +164,8 @@ fn parse<'a>(
                 qty_paid: e.qty_paid,
                 qty_received: e.qty_received,
                 time,
+                slot,
+                approx_time,
             });
 
             continue;
@@ -129,6 +182,8 @@ fn parse<'a>(
                 assets_to_liqor: e.assets_to_liqor,
                 quote_to_liqor: e.quote_to_liqor,
                 time,
+                slot,
+                approx_time,
             });
 
             continue;
@@ -145,6 +200,8 @@ fn parse<'a>(
                 insurance_loss: e.insurance_loss,
                 socialized_loss: e.socialized_loss,
                 time,
+                slot,
+                approx_time,
             });
 
             continue;
@@ -153,46 +210,60 @@ fn parse<'a>(
         if let Some(e) = load::<events::DepositLog>(&bytes) {
             bal.push(db::BalanceChange {
                 time,
+                slot,
+                approx_time,
                 sig: sig.clone(),
                 margin: e.margin_key.to_string(),
-                symbol: st.zo_state.collaterals[e.col_index as usize]
+                symbol: state.collaterals[e.col_index as usize]
                     .oracle_symbol
                     .into(),
                 amount: e.deposit_amount as i64,
             });
+
+            continue;
         }
 
         if let Some(e) = load::<events::WithdrawLog>(&bytes) {
             bal.push(db::BalanceChange {
                 time,
+                slot,
+                approx_time,
                 sig: sig.clone(),
                 margin: e.margin_key.to_string(),
-                symbol: st.zo_state.collaterals[e.col_index as usize]
+                symbol: state.collaterals[e.col_index as usize]
                     .oracle_symbol
                     .into(),
                 amount: -(e.withdraw_amount as i64),
-            })
+            });
+
+            continue;
         }
 
         if let Some(e) = load::<events::SwapLog>(&bytes) {
             swap.push(db::Swap {
                 time,
+                slot,
+                approx_time,
                 sig: sig.clone(),
                 margin: e.margin_key.to_string(),
-                base_symbol: st.zo_state.collaterals[e.base_index as usize]
+                base_symbol: state.collaterals[e.base_index as usize]
                     .oracle_symbol
                     .into(),
-                quote_symbol: st.zo_state.collaterals[e.quote_index as usize]
+                quote_symbol: state.collaterals[e.quote_index as usize]
                     .oracle_symbol
                     .into(),
                 base_delta: e.base_delta,
                 quote_delta: e.quote_delta,
             });
+
+            continue;
         }
 
         if let Some(e) = load::<events::OtcFill>(&bytes) {
             otc.push(db::OtcFill {
                 time,
+                slot,
+                approx_time,
                 sig: sig.clone(),
                 market: e.market.to_string(),
                 taker_margin: e.taker_margin.to_string(),
@@ -200,20 +271,14 @@ fn parse<'a>(
                 d_base: e.d_base,
                 d_quote: e.d_quote,
             });
+
             continue;
         }
 
         if let Some(e) = load::<events::EventFillLog>(&bytes) {
-            let (symbol, base_mul) = st
-                .iter_markets()
-                .find(|m| m.dex_market == e.market_key)
-                .map(|m| {
-                    (
-                        String::from(m.symbol),
-                        10f64.powi(m.asset_decimals.into()),
-                    )
-                })
-                .unwrap();
+            let m = &state.perp_markets[indices.markets[&e.market_key]];
+            let symbol = String::from(m.symbol);
+            let base_mul = 10f64.powi(m.asset_decimals.into());
 
             let quote_mul = 10f64.powi(6);
 
@@ -245,6 +310,8 @@ fn parse<'a>(
             fill.push(db::Trade {
                 symbol,
                 time,
+                slot,
+                approx_time,
                 sig: sig.clone(),
                 price,
                 size,
@@ -255,7 +322,9 @@ fn parse<'a>(
                 // Renamed to `seq_num` to remain compatible with the
                 // previous schema.
                 seq_num: e.discriminator,
-            })
+            });
+
+            continue;
         }
 
         if let Some(e) = load::<events::CacheOracleNoops>(&bytes) {
@@ -266,6 +335,43 @@ fn parse<'a>(
     (rpnl, liq, bank, bal, swap, otc, fill, oracle)
 }
 
+/// Returns the `Program log: ` and `Program data: ` lines emitted by the
+/// zo program itself. Invocations are tracked as a stack, so logs of
+/// programs invoked by zo, or of programs invoking zo through CPI, are
+/// skipped, while zo logs after a nested invocation returns are kept.
+fn zo_logs<'a>(logs: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+    let zo_id = zo_abi::ID.to_string();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut res = Vec::new();
+
+    for l in logs {
+        if l.starts_with(PROGRAM_LOG) || l.starts_with(PROGRAM_DATA) {
+            if stack.last() == Some(&zo_id.as_str()) {
+                res.push(l.as_str());
+            }
+            continue;
+        }
+
+        let (id, rest) =
+            match l.strip_prefix("Program ").and_then(|x| x.split_once(' ')) {
+                Some(x) => x,
+                None => continue,
+            };
+
+        if rest.starts_with("invoke [") {
+            stack.push(id);
+        } else if rest == "success" || rest.starts_with("failed") {
+            // Pop up to and including the matching invocation, in case
+            // a nested program's terminator was truncated from the logs.
+            if let Some(i) = stack.iter().rposition(|x| *x == id) {
+                stack.truncate(i);
+            }
+        }
+    }
+
+    res
+}
+
 #[inline(always)]
 fn load<T: Event>(buf: &[u8]) -> Option<T> {
     match buf[..8] == T::discriminator() {
@@ -273,3 +379,363 @@ fn load<T: Event>(buf: &[u8]) -> Option<T> {
         false => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_client::solana_sdk::pubkey::Pubkey;
+    use bytemuck::Zeroable;
+    use zo_abi::Symbol;
+
+    const OTHER: &str = "11111111111111111111111111111111";
+
+    fn logs(xs: &[String]) -> Vec<&str> {
+        zo_logs(xs.iter())
+    }
+
+    #[test]
+    fn test_zo_logs_top_level() {
+        let zo = zo_abi::ID;
+        let xs = vec![
+            format!("Program {} invoke [1]", zo),
+            "Program log: A".to_string(),
+            format!("Program {} consumed 1000 of 200000 compute units", zo),
+            format!("Program {} success", zo),
+            "Program log: not zo".to_string(),
+        ];
+
+        assert_eq!(logs(&xs), vec!["Program log: A"]);
+    }
+
+    #[test]
+    fn test_zo_logs_invoked_by_cpi() {
+        let zo = zo_abi::ID;
+        let xs = vec![
+            format!("Program {} invoke [1]", OTHER),
+            "Program log: other before".to_string(),
+            format!("Program {} invoke [2]", zo),
+            "Program data: A".to_string(),
+            format!("Program {} success", zo),
+            "Program log: other after".to_string(),
+            format!("Program {} success", OTHER),
+        ];
+
+        assert_eq!(logs(&xs), vec!["Program data: A"]);
+    }
+
+    #[test]
+    fn test_zo_logs_nested_reentry() {
+        let zo = zo_abi::ID;
+        let xs = vec![
+            format!("Program {} invoke [1]", zo),
+            "Program data: A".to_string(),
+            format!("Program {} invoke [2]", OTHER),
+            "Program log: other".to_string(),
+            format!("Program {} invoke [3]", zo),
+            "Program data: B".to_string(),
+            format!("Program {} success", zo),
+            "Program log: other again".to_string(),
+            format!("Program {} success", OTHER),
+            "Program data: C".to_string(),
+            format!("Program {} success", zo),
+        ];
+
+        assert_eq!(
+            logs(&xs),
+            vec!["Program data: A", "Program data: B", "Program data: C"],
+        );
+    }
+
+    #[test]
+    fn test_zo_logs_inner_failure() {
+        let zo = zo_abi::ID;
+        let xs = vec![
+            format!("Program {} invoke [1]", zo),
+            format!("Program {} invoke [2]", OTHER),
+            "Program log: other".to_string(),
+            format!("Program {} failed: custom program error: 0x1", OTHER),
+            "Program data: A".to_string(),
+            format!("Program {} success", zo),
+        ];
+
+        assert_eq!(logs(&xs), vec!["Program data: A"]);
+    }
+
+    #[test]
+    fn test_zo_logs_failed_then_success() {
+        let zo = zo_abi::ID;
+        let xs = vec![
+            format!("Program {} invoke [1]", OTHER),
+            format!("Program {} invoke [2]", zo),
+            "Program data: A".to_string(),
+            format!("Program {} failed: custom program error: 0x1", zo),
+            "Program log: other".to_string(),
+            format!("Program {} invoke [2]", zo),
+            "Program data: B".to_string(),
+            format!("Program {} success", zo),
+            "Program log: other again".to_string(),
+            format!("Program {} success", OTHER),
+        ];
+
+        assert_eq!(logs(&xs), vec!["Program data: A", "Program data: B"]);
+    }
+
+    fn fixture_state() -> zo_abi::State {
+        let mut state = zo_abi::State::zeroed();
+
+        state.collaterals[0].oracle_symbol = Symbol::try_from("USDC").unwrap();
+        state.collaterals[1].oracle_symbol = Symbol::try_from("SOL").unwrap();
+
+        state.perp_markets[0].symbol = Symbol::try_from("SOL-PERP").unwrap();
+        state.perp_markets[0].dex_market = Pubkey::new_unique();
+        state.perp_markets[0].asset_decimals = 9;
+
+        state
+    }
+
+    fn data<T: Event>(e: &T) -> String {
+        format!("{}{}", PROGRAM_DATA, base64::encode(e.data()))
+    }
+
+    /// Logs of a transaction invoking zo once, emitting `xs`.
+    fn zo_tx(xs: Vec<String>) -> Vec<String> {
+        let zo = zo_abi::ID;
+        let mut res = vec![format!("Program {} invoke [1]", zo)];
+        res.extend(xs);
+        res.push(format!("Program {} success", zo));
+        res
+    }
+
+    fn fill(
+        market_key: Pubkey,
+        is_long: bool,
+        is_maker: bool,
+        qty_paid: u64,
+        qty_received: u64,
+        fee_or_rebate: u64,
+    ) -> events::EventFillLog {
+        events::EventFillLog {
+            market_key,
+            margin: Pubkey::new_unique(),
+            control: Pubkey::new_unique(),
+            is_long,
+            is_maker,
+            qty_paid: qty_paid as _,
+            qty_received: qty_received as _,
+            fee_or_rebate: fee_or_rebate as _,
+            discriminator: 7,
+        }
+    }
+
+    #[test]
+    fn test_parse_balance_events() {
+        let state = fixture_state();
+        let indices = StateIndices::new(&state);
+        let margin = Pubkey::new_unique();
+        let xs = zo_tx(vec![
+            data(&events::DepositLog {
+                col_index: 1,
+                deposit_amount: 5_000_000_000,
+                margin_key: margin,
+            }),
+            data(&events::WithdrawLog {
+                col_index: 0,
+                withdraw_amount: 20_000_000,
+                margin_key: margin,
+            }),
+            data(&events::SwapLog {
+                margin_key: margin,
+                base_index: 1,
+                quote_index: 0,
+                base_delta: 1_000_000_000,
+                quote_delta: -25_000_000,
+            }),
+        ]);
+
+        let (_, _, _, bal, swap, _, _, _) =
+            parse(&state, &indices, xs.iter(), "sig".into(), 42, 1, false);
+
+        assert_eq!(bal.len(), 2);
+        assert!(bal.iter().all(|b| b.slot == 42));
+        assert_eq!(bal[0].symbol, "SOL");
+        assert_eq!(bal[0].amount, 5_000_000_000);
+        assert_eq!(bal[1].symbol, "USDC");
+        assert_eq!(bal[1].amount, -20_000_000);
+        assert_eq!(bal[1].margin, margin.to_string());
+
+        assert_eq!(swap.len(), 1);
+        assert_eq!(swap[0].base_symbol, "SOL");
+        assert_eq!(swap[0].quote_symbol, "USDC");
+        assert_eq!(swap[0].quote_delta, -25_000_000);
+        assert_eq!(swap[0].slot, 42);
+    }
+
+    #[test]
+    fn test_parse_liquidation_events() {
+        let state = fixture_state();
+        let indices = StateIndices::new(&state);
+        let market_key = state.perp_markets[0].dex_market;
+        let (liqor, liqee) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let xs = zo_tx(vec![
+            data(&events::RealizedPnlLog {
+                market_key,
+                margin: liqee,
+                is_long: true,
+                pnl: -3_000_000,
+                qty_paid: 1_000_000_000,
+                qty_received: 22_000_000,
+            }),
+            // Settlements of nothing aren't recorded.
+            data(&events::RealizedPnlLog {
+                market_key,
+                margin: liqee,
+                is_long: true,
+                pnl: 0,
+                qty_paid: 0,
+                qty_received: 0,
+            }),
+            data(&events::LiquidationLog {
+                liquidation_event: events::LiquidationEvent::Perp,
+                base_symbol: "SOL-PERP".to_string(),
+                quote_symbol: None,
+                liqor_margin: liqor,
+                liqee_margin: liqee,
+                assets_to_liqor: 1_000_000_000,
+                quote_to_liqor: -24_000_000,
+            }),
+            data(&events::BankruptcyLog {
+                base_symbol: "USDC".to_string(),
+                liqor_margin: liqor,
+                liqee_margin: liqee,
+                assets_to_liqor: 2_000_000,
+                quote_to_liqor: 0,
+                insurance_loss: 1_500_000,
+                socialized_loss: 500_000,
+            }),
+            data(&events::OtcFill {
+                market: market_key,
+                taker_margin: liqor,
+                maker_margin: liqee,
+                d_base: 1_000_000_000,
+                d_quote: -25_000_000,
+            }),
+        ]);
+
+        let (rpnl, liq, bank, _, _, otc, _, _) =
+            parse(&state, &indices, xs.iter(), "sig".into(), 1, 1, false);
+
+        assert_eq!(rpnl.len(), 1);
+        assert_eq!(rpnl[0].symbol, "SOL-PERP");
+        assert_eq!(rpnl[0].pnl, -3_000_000);
+
+        assert_eq!(liq.len(), 1);
+        assert_eq!(liq[0].liquidation_event, "Perp");
+        assert_eq!(liq[0].quote_symbol, "");
+        assert_eq!(liq[0].liqor_margin, liqor.to_string());
+
+        assert_eq!(bank.len(), 1);
+        assert_eq!(bank[0].insurance_loss, 1_500_000);
+        assert_eq!(bank[0].socialized_loss, 500_000);
+
+        assert_eq!(otc.len(), 1);
+        assert_eq!(otc[0].market, market_key.to_string());
+        assert_eq!(otc[0].d_quote, -25_000_000);
+    }
+
+    #[test]
+    fn test_parse_fill_prices() {
+        let state = fixture_state();
+        let indices = StateIndices::new(&state);
+        let market_key = state.perp_markets[0].dex_market;
+        let xs = zo_tx(vec![
+            // A taker buying 2 SOL for 50 USDC pays the fee on top.
+            data(&fill(
+                market_key,
+                true,
+                false,
+                50_050_000,
+                2_000_000_000,
+                50_000,
+            )),
+            // A maker buying 2 SOL for 50 USDC pays less by the rebate.
+            data(&fill(
+                market_key,
+                true,
+                true,
+                49_990_000,
+                2_000_000_000,
+                10_000,
+            )),
+            // A taker selling 1 SOL for 25 USDC receives less by the fee.
+            data(&fill(
+                market_key,
+                false,
+                false,
+                1_000_000_000,
+                24_975_000,
+                25_000,
+            )),
+            // A maker selling 1 SOL for 25 USDC receives the rebate on top.
+            data(&fill(
+                market_key,
+                false,
+                true,
+                1_000_000_000,
+                25_010_000,
+                10_000,
+            )),
+        ]);
+
+        let (_, _, _, _, _, _, trades, _) =
+            parse(&state, &indices, xs.iter(), "sig".into(), 1, 1, false);
+
+        let got: Vec<_> = trades
+            .iter()
+            .map(|t| (t.side.as_str(), t.price, t.size, t.is_maker))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("buy", 25.0, 2.0, false),
+                ("buy", 25.0, 2.0, true),
+                ("sell", 25.0, 1.0, false),
+                ("sell", 25.0, 1.0, true),
+            ],
+        );
+        assert!(trades.iter().all(|t| t.symbol == "SOL-PERP"));
+        assert!(trades.iter().all(|t| t.seq_num == 7));
+    }
+
+    #[test]
+    fn test_parse_nested_cpi() {
+        let state = fixture_state();
+        let indices = StateIndices::new(&state);
+        let zo = zo_abi::ID;
+        let margin = Pubkey::new_unique();
+        let deposit = |deposit_amount| {
+            data(&events::DepositLog {
+                col_index: 0,
+                deposit_amount,
+                margin_key: margin,
+            })
+        };
+
+        // Another program emits data that happens to decode as a zo
+        // event, both around and inside its invocation of zo.
+        let xs = vec![
+            format!("Program {} invoke [1]", OTHER),
+            deposit(1),
+            format!("Program {} invoke [2]", zo),
+            deposit(2),
+            format!("Program {} success", zo),
+            deposit(3),
+            format!("Program {} success", OTHER),
+        ];
+
+        let (_, _, _, bal, _, _, _, _) =
+            parse(&state, &indices, xs.iter(), "sig".into(), 1, 1, false);
+
+        assert_eq!(bal.len(), 1);
+        assert_eq!(bal[0].amount, 2);
+    }
+}
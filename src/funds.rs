@@ -0,0 +1,130 @@
+/*
+ * This file tracks whether the payer has run out of SOL for transaction
+ * fees. Once it has, every send fails the same way, so the sending loops
+ * pause instead of retrying at full rate, and a single alert is raised
+ * rather than one per failure. Sending resumes once a balance check shows
+ * the payer has been topped up.
+*/
+use crate::AppState;
+use anchor_client::{
+    solana_client::{
+        client_error::ClientErrorKind,
+        rpc_request::{RpcError, RpcResponseErrorData},
+    },
+    solana_sdk::{
+        native_token::LAMPORTS_PER_SOL, transaction::TransactionError,
+    },
+};
+use parking_lot::{const_mutex, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// How often the balance is checked while paused.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The balance needed to resume, so that a payer topped up with dust
+/// doesn't flap between paused and resumed.
+const RESUME_LAMPORTS: u64 = LAMPORTS_PER_SOL / 100;
+
+// When the balance was last checked, if sending is paused.
+static PAUSED: Mutex<Option<Instant>> = const_mutex(None);
+
+/// Whether the transaction failed because the payer can't pay its fee.
+/// A payer drained to zero lamports no longer exists, so that also shows
+/// up as the account not being found.
+pub fn is_insufficient_funds(e: &TransactionError) -> bool {
+    matches!(
+        e,
+        TransactionError::InsufficientFundsForFee
+            | TransactionError::AccountNotFound
+    )
+}
+
+/// `is_insufficient_funds` for a send that failed in preflight or on
+/// chain.
+pub fn is_client_insufficient_funds(kind: &ClientErrorKind) -> bool {
+    match kind {
+        ClientErrorKind::TransactionError(e) => is_insufficient_funds(e),
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(r),
+            ..
+        }) => r.err.as_ref().map_or(false, is_insufficient_funds),
+        _ => false,
+    }
+}
+
+/// Pauses sending after a send failed for lack of funds, alerting the
+/// first time.
+pub fn report(st: &AppState) {
+    let mut paused = PAUSED.lock();
+
+    if paused.is_none() {
+        error!(
+            "Payer {} can't pay transaction fees, pausing until it holds \
+            at least {} SOL",
+            st.payer(),
+            RESUME_LAMPORTS as f64 / LAMPORTS_PER_SOL as f64
+        );
+    }
+
+    *paused = Some(Instant::now());
+}
+
+/// Whether sending is paused for lack of funds. While it is, the balance
+/// is checked every `CHECK_INTERVAL`, resuming once it's been topped up.
+/// The check blocks on the RPC, so async callers should run this on the
+/// blocking pool.
+pub fn is_paused(st: &AppState) -> bool {
+    {
+        let mut paused = PAUSED.lock();
+
+        match *paused {
+            None => return false,
+            Some(t) if t.elapsed() < CHECK_INTERVAL => return true,
+            // Restart the interval, so that other callers stay paused
+            // rather than check the balance too.
+            Some(_) => *paused = Some(Instant::now()),
+        }
+    }
+
+    // The lock is released first, so callers aren't held up by the RPC.
+    match st.rpc.get_balance(&st.payer()) {
+        Ok(lamports) if lamports >= RESUME_LAMPORTS => {
+            info!("Payer {} topped up, resuming", st.payer());
+            *PAUSED.lock() = None;
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Failed to fetch payer balance: {}", e);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_insufficient_funds() {
+        use anchor_client::solana_sdk::instruction::InstructionError;
+
+        assert!(is_insufficient_funds(
+            &TransactionError::InsufficientFundsForFee
+        ));
+        assert!(is_insufficient_funds(&TransactionError::AccountNotFound));
+        assert!(!is_insufficient_funds(&TransactionError::BlockhashNotFound));
+
+        let kind = |e| ClientErrorKind::TransactionError(e);
+        assert!(is_client_insufficient_funds(&kind(
+            TransactionError::InsufficientFundsForFee
+        )));
+        assert!(!is_client_insufficient_funds(&kind(
+            TransactionError::InstructionError(0, InstructionError::Custom(1))
+        )));
+        assert!(!is_client_insufficient_funds(&ClientErrorKind::Custom(
+            "insufficient funds".to_string()
+        )));
+    }
+}
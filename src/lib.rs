@@ -1,14 +1,20 @@
 pub mod consumer;
 pub mod crank;
 pub mod liquidator;
+pub mod log;
 pub mod recorder;
 pub mod trigger;
 
 mod db;
 mod error;
 mod events;
+mod funds;
+mod nonce;
+mod spool;
 mod state;
 mod utils;
 
 pub use error::*;
+pub use nonce::DurableNonce;
 pub use state::*;
+pub use utils::is_supervised;
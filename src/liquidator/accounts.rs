@@ -8,23 +8,30 @@
  * then deal with compression.
 */
 use crate::liquidator::{
-    error::ErrorCode, liquidation, margin_utils::*, utils::*,
+    error::ErrorCode,
+    liquidation,
+    margin_utils::*,
+    math::{try_div_i80f48, try_sub_i80f48},
+    metrics,
+    utils::*,
+    LiquidatorConfig,
 };
 
 use fixed::types::I80F48;
+use parking_lot::{Mutex, RwLock};
 use serum_dex::state::{
     Market as SerumMarket, MarketState as SerumMarketState,
 };
-use solana_sdk::pubkey::Pubkey;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ops::Deref,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::Arc,
 };
 
-use tracing::{error, error_span, info};
+use tracing::{debug, error, error_span, info, warn};
 use zo_abi::{
-    dex::ZoDexMarket as MarketState, Cache, Control, FractionType, Margin,
+    dex::ZoDexMarket as MarketState, Cache, Control, Margin, PerpType,
     State, MAX_MARKETS,
 };
 
@@ -46,33 +53,85 @@ pub struct AccountTable {
     state_key: Pubkey,
     state_signer: Pubkey,
 
-    // The market state accounts
-    market_state: Vec<MarketState>,
+    // The oracle of each collateral and market in the cache, updated
+    // along with the state and the cache.
+    oracles: OracleIndex,
 
-    // The serum markets for swapping
-    serum_markets: HashMap<usize, SerumMarketState>,
-    serum_vault_signers: HashMap<usize, Pubkey>,
+    // The market state accounts. These, and the serum markets, are
+    // shared with the liquidation tasks rather than copied for each.
+    market_state: Arc<Vec<Option<MarketState>>>,
+
+    // The serum markets for swapping, and the swappable collaterals they
+    // were discovered from.
+    serum_markets: Arc<HashMap<usize, SerumMarketState>>,
+    serum_vault_signers: Arc<HashMap<usize, Pubkey>>,
+    serum_collaterals: Vec<(usize, Pubkey)>,
 
     payer_key: Pubkey,
     payer_margin_key: Pubkey,
     payer_margin: Margin,
     payer_control_key: Pubkey,
     payer_control: Control,
+    // The slot the payer's accounts were last fetched at by a liquidation,
+    // so that a slower, older fetch doesn't replace them.
+    payer_slot: u64,
+    // The payer's open orders account in each market
+    payer_oo: [Pubkey; MAX_MARKETS as usize],
 
     worker_count: u8,
     worker_index: u8,
+
+    // Recent marks of each market, oldest first
+    mark_history: Vec<VecDeque<I80F48>>,
+    // Markets found unstable by the last check, so that only changes are
+    // logged rather than every loop.
+    unstable_marks: Mutex<[bool; MAX_MARKETS as usize]>,
 }
 
+// Number of cache updates the median mark is taken over.
+const MARK_HISTORY_LEN: usize = 20;
+
 impl AccountTable {
     pub fn new(
         st: &crate::AppState,
         worker_index: u8,
         worker_count: u8,
+    ) -> Result<Self, crate::Error> {
+        let serum_collaterals = swappable_collaterals(&st.zo_state);
+        let (serum_markets, serum_vault_signers) =
+            load_serum_markets(st, &serum_collaterals)?;
+
+        Self::new_with_serum_markets(
+            st,
+            st.zo_state,
+            worker_index,
+            worker_count,
+            Arc::new(serum_markets),
+            Arc::new(serum_vault_signers),
+            serum_collaterals,
+        )
+    }
+
+    fn new_with_serum_markets(
+        st: &crate::AppState,
+        state: State,
+        worker_index: u8,
+        worker_count: u8,
+        serum_markets: Arc<HashMap<usize, SerumMarketState>>,
+        serum_vault_signers: Arc<HashMap<usize, Pubkey>>,
+        serum_collaterals: Vec<(usize, Pubkey)>,
     ) -> Result<Self, crate::Error> {
         // This fetches all on-chain accounts for a start
         // Assumes that the dex is started, i.e. there's a cache
         // Also need to load market state info.
 
+        // `state` is the single source of truth for the number of
+        // markets and collaterals, so check it once here.
+        let total_markets = active_markets(&state)
+            .map_err(|_| crate::Error::InvalidAccount(st.zo_state_pubkey))?;
+        active_collaterals(&state)
+            .map_err(|_| crate::Error::InvalidAccount(st.zo_state_pubkey))?;
+
         let payer = st.payer();
         let payer_margin_key = Pubkey::find_program_address(
             &[payer.as_ref(), st.zo_state_pubkey.as_ref(), b"marginv1"],
@@ -81,16 +140,15 @@ impl AccountTable {
         .0;
         let payer_margin = get_type_from_account::<Margin>(
             &payer_margin_key,
-            &mut st
-                .rpc
-                .get_account(&payer_margin_key)
-                .expect("Could not get payer margin account"),
-        );
+            &mut st.rpc.get_account(&payer_margin_key)?,
+        )
+        .ok_or(crate::Error::InvalidAccount(payer_margin_key))?;
         let payer_control_key = payer_margin.control;
         let payer_control = get_type_from_account::<Control>(
             &payer_control_key,
-            &mut st.rpc.get_account(&payer_control_key).unwrap(),
-        );
+            &mut st.rpc.get_account(&payer_control_key)?,
+        )
+        .ok_or(crate::Error::InvalidAccount(payer_control_key))?;
 
         let margin_table: HashMap<_, _> =
             load_program_accounts::<Margin>(&st.rpc, &zo_abi::ID)?
@@ -108,71 +166,55 @@ impl AccountTable {
                 })
                 .collect();
 
-        let market_state: Vec<_> =
-            st.load_dex_markets()?.into_iter().map(|(_, m)| m).collect();
+        // Markets with a zero lot size can't be sized for, so are
+        // excluded. They're kept as `None` so the rest keep their index.
+        let market_state = state.perp_markets[..total_markets]
+            .iter()
+            .filter(|m| m.dex_market != Pubkey::default())
+            .map(|m| {
+                let info = *MarketState::deserialize(
+                    &st.rpc.get_account_data(&m.dex_market)?,
+                )
+                .map_err(|_| crate::Error::InvalidAccount(m.dex_market))?;
 
-        let mut serum_markets: HashMap<usize, _> = HashMap::new();
-        let mut serum_vault_signers: HashMap<usize, _> = HashMap::new();
+                if !has_lot_sizes(info.coin_lot_size, info.pc_lot_size) {
+                    warn!(
+                        "{} has a zero lot size, not liquidating it",
+                        String::from(m.symbol)
+                    );
+                    return Ok(None);
+                }
 
-        for (i, collateral_info) in st.iter_collaterals().enumerate() {
-            if !collateral_info.is_swappable {
-                continue;
-            }
+                Ok(Some(info))
+            })
+            .collect::<Result<Vec<_>, crate::Error>>()?;
 
-            let serum_oo_account = st
-                .rpc
-                .get_account(&collateral_info.serum_open_orders)
-                .unwrap();
-
-            let serum_market_address =
-                Pubkey::new(&serum_oo_account.data[13..45]);
-            let mut serum_market_account =
-                st.rpc.get_account(&serum_market_address).unwrap();
-            let serum_market_account_info = get_account_info(
-                &serum_market_address,
-                &mut serum_market_account,
-            );
-
-            let market_state = SerumMarket::load(
-                &serum_market_account_info,
-                &zo_abi::SERUM_DEX_PID,
-                true,
-            )
-            .unwrap();
-            let market = market_state.deref();
-
-            serum_markets.insert(i, *market);
-
-            let vault_signer = Pubkey::create_program_address(
-                &[
-                    array_to_pubkey(&{ market.own_address }).as_ref(),
-                    &market.vault_signer_nonce.to_le_bytes(),
-                ],
-                &zo_abi::SERUM_DEX_PID,
-            )
-            .unwrap();
-
-            serum_vault_signers.insert(i, vault_signer);
-        }
+        let payer_oo = get_oo_keys(&payer_control.open_orders_agg);
 
         Ok(Self {
             margin_table,
             control_table,
             cache: st.zo_cache,
             cache_key: st.zo_cache_pubkey,
-            state: st.zo_state,
+            oracles: OracleIndex::new(&state, &st.zo_cache),
+            state,
             state_key: st.zo_state_pubkey,
             state_signer: st.zo_state_signer_pubkey,
-            market_state,
+            market_state: Arc::new(market_state),
             serum_markets,
             serum_vault_signers,
+            serum_collaterals,
             payer_key: payer,
             payer_margin_key,
             payer_margin,
             payer_control_key,
             payer_control,
+            payer_slot: 0,
+            payer_oo,
             worker_count,
             worker_index,
+            mark_history: vec![VecDeque::new(); MAX_MARKETS as usize],
+            unstable_marks: Mutex::new([false; MAX_MARKETS as usize]),
         })
     }
 
@@ -180,7 +222,39 @@ impl AccountTable {
         &mut self,
         st: &crate::AppState,
     ) -> Result<(), crate::Error> {
-        *self = Self::new(st, self.worker_index, self.worker_count)?;
+        // The serum markets are stable, so they are only fetched again
+        // if the swappable collaterals changed.
+        let serum_collaterals = swappable_collaterals(&self.state);
+        let (serum_markets, serum_vault_signers) =
+            if serum_collaterals == self.serum_collaterals {
+                (
+                    self.serum_markets.clone(),
+                    self.serum_vault_signers.clone(),
+                )
+            } else {
+                info!("Swappable collaterals changed, reloading serum markets");
+                let (markets, vault_signers) =
+                    load_serum_markets(st, &serum_collaterals)?;
+                (Arc::new(markets), Arc::new(vault_signers))
+            };
+
+        // The table is only replaced once everything loaded, so a failed
+        // refresh keeps the current one. The state kept up to date by the
+        // listener is used rather than the one from startup, so markets
+        // listed since are loaded.
+        let mut table = Self::new_with_serum_markets(
+            st,
+            self.state,
+            self.worker_index,
+            self.worker_count,
+            serum_markets,
+            serum_vault_signers,
+            serum_collaterals,
+        )?;
+        table.mark_history = std::mem::take(&mut self.mark_history);
+        table.payer_slot = self.payer_slot;
+        *table.unstable_marks.get_mut() = *self.unstable_marks.get_mut();
+        *self = table;
         Ok(())
     }
 
@@ -200,12 +274,81 @@ impl AccountTable {
         }
     }
 
+    /// The listener doesn't track the payer's accounts, so they are
+    /// replaced whenever a liquidation fetches them again, unless they
+    /// were already replaced by a fetch at a later slot.
+    pub fn update_payer(
+        &mut self,
+        margin: Margin,
+        control: Control,
+        slot: u64,
+    ) {
+        if slot < self.payer_slot {
+            return;
+        }
+
+        self.payer_margin = margin;
+        self.payer_control = control;
+        self.payer_slot = slot;
+    }
+
     pub fn update_cache(&mut self, cache: Cache) {
         self.cache = cache;
+        self.oracles = OracleIndex::new(&self.state, &self.cache);
+
+        for (history, mark) in self.mark_history.iter_mut().zip(cache.marks) {
+            if history.len() == MARK_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(mark.price.into());
+        }
     }
 
+    /// Square markets whose mark is more than `band`, as a fraction, away
+    /// from its recent median. Accounts are valued at the mark in these
+    /// markets, so a single glitched mark could make them liquidatable.
+    fn unstable_markets(&self, band: I80F48) -> [bool; MAX_MARKETS as usize] {
+        let mut unstable = [false; MAX_MARKETS as usize];
+        let mut was_unstable = self.unstable_marks.lock();
+
+        let total_markets = active_markets(&self.state).unwrap_or(0);
+
+        for i in 0..total_markets {
+            if self.state.perp_markets[i].perp_type != PerpType::Square {
+                continue;
+            }
+
+            let symbol = String::from(self.state.perp_markets[i].symbol);
+            let mark: I80F48 = self.cache.marks[i].price.into();
+            let median = off_median(&self.mark_history[i], mark, band);
+
+            match (median, was_unstable[i]) {
+                (Some(median), false) => warn!(
+                    "{} mark {} is off its median {}, skipping liquidations",
+                    symbol, mark, median
+                ),
+                (None, true) => info!(
+                    "{} mark {} is back near its median, resuming",
+                    symbol, mark
+                ),
+                _ => {}
+            }
+
+            unstable[i] = median.is_some();
+        }
+
+        *was_unstable = unstable;
+        unstable
+    }
+
+    /// Keeps the current state if `state` has more markets or collaterals
+    /// than supported.
     pub fn update_state(&mut self, state: State) {
-        self.state = state;
+        if active_markets(&state).is_ok() && active_collaterals(&state).is_ok()
+        {
+            self.state = state;
+            self.oracles = OracleIndex::new(&self.state, &self.cache);
+        }
     }
 
     /// The number of control accounts.
@@ -233,15 +376,291 @@ impl AccountTable {
         &self.payer_control
     }
 
+    /// The total value of the payer's margin account, in smol USD.
+    pub fn payer_account_value(&self) -> Result<I80F48, ErrorCode> {
+        get_total_account_value(
+            &self.payer_margin,
+            &self.payer_control,
+            &self.state,
+            &self.cache,
+            &self.oracles,
+        )
+    }
+
     pub fn get_control_from_margin(
         &self,
         margin: &Margin,
     ) -> Option<(&Pubkey, &Control)> {
         self.control_table.get_key_value(&margin.control)
     }
+
+    /// A summary of the table as JSON, for debugging. With `fractions`,
+    /// also includes each account's margin fractions and the decisions
+    /// made on them. Fractions are strings to keep their precision.
+    pub fn dump(&self, fractions: bool) -> serde_json::Value {
+        let mut serum_markets: Vec<_> = self.serum_markets.keys().collect();
+        serum_markets.sort_unstable();
+
+        let mut res = serde_json::json!({
+            "state": self.state_key.to_string(),
+            "cache": self.cache_key.to_string(),
+            "workerIndex": self.worker_index,
+            "workerCount": self.worker_count,
+            "margins": self.margin_table.len(),
+            "controls": self.control_table.len(),
+            "dexMarkets": self.market_state.len(),
+            "serumMarkets": serum_markets,
+            "payer": {
+                "key": self.payer_key.to_string(),
+                "margin": self.payer_margin_key.to_string(),
+                "control": self.payer_control_key.to_string(),
+                "openOrders": self
+                    .payer_oo
+                    .iter()
+                    .map(|k| k.to_string())
+                    .collect::<Vec<_>>(),
+                "value": self
+                    .payer_account_value()
+                    .map(|x| x.to_string())
+                    .ok(),
+            },
+        });
+
+        if fractions {
+            let tolerance = I80F48::from_num(DEFAULT_TOLERANCE);
+            let accounts: Vec<_> = self
+                .margin_table
+                .iter()
+                .filter_map(|(key, margin)| {
+                    let (_, control) = self.get_control_from_margin(margin)?;
+                    let mf = |x| {
+                        get_mf_wrapped(
+                            x,
+                            margin,
+                            control,
+                            &self.state,
+                            &self.cache,
+                            &self.oracles,
+                        )
+                        .map(|x| x.to_string())
+                        .ok()
+                    };
+                    let decision = check_liquidatable(
+                        margin,
+                        control,
+                        &self.state,
+                        &self.cache,
+                        &self.oracles,
+                        tolerance,
+                        tolerance,
+                    )
+                    .ok();
+
+                    Some(serde_json::json!({
+                        "margin": key.to_string(),
+                        "authority": margin.authority.to_string(),
+                        "mf": mf(MfReturnOption::Mf),
+                        "mmf": mf(MfReturnOption::Mmf),
+                        "imf": mf(MfReturnOption::Imf),
+                        "cmf": mf(MfReturnOption::Cmf),
+                        "cancel": decision.map(|x| x.0),
+                        "liquidate": decision.map(|x| x.1),
+                    }))
+                })
+                .collect();
+
+            res["accounts"] = accounts.into();
+        }
+
+        res
+    }
+}
+
+/// The median of the marks in `history` if `mark` is more than `band`, as
+/// a fraction, away from it.
+fn off_median(
+    history: &VecDeque<I80F48>,
+    mark: I80F48,
+    band: I80F48,
+) -> Option<I80F48> {
+    let mut history: Vec<I80F48> = history.iter().copied().collect();
+    if history.is_empty() {
+        return None;
+    }
+    history.sort();
+
+    let median = history[history.len() / 2];
+
+    if (mark - median).abs() > median.abs() * band {
+        Some(median)
+    } else {
+        None
+    }
+}
+
+/// Whether the account has a position or open orders in any of the
+/// `unstable` markets.
+fn has_unstable_position(
+    table: &AccountTable,
+    margin: &Margin,
+    unstable: &[bool; MAX_MARKETS as usize],
+) -> bool {
+    let control = match table.get_control_from_margin(margin) {
+        Some((_, control)) => control,
+        None => return false,
+    };
+
+    control
+        .open_orders_agg
+        .iter()
+        .zip(unstable)
+        .any(|(oo, &unstable)| {
+            unstable
+                && (oo.pos_size != 0
+                    || oo.coin_on_bids != 0
+                    || oo.coin_on_asks != 0)
+        })
 }
 
-pub type Db = Arc<Mutex<AccountTable>>;
+/// How far an account is above maintenance margin, as a fraction of its
+/// maintenance margin. Accounts below maintenance score below zero, and
+/// bankrupt accounts below -1. Accounts that can't be valued, or whose
+/// score overflows, score last.
+fn health_score(table: &AccountTable, margin: &Margin) -> I80F48 {
+    let control = match table.get_control_from_margin(margin) {
+        Some((_key, control)) => control,
+        None => return I80F48::MAX,
+    };
+    let mf = |x| {
+        get_mf_wrapped(
+            x,
+            margin,
+            control,
+            &table.state,
+            &table.cache,
+            &table.oracles,
+        )
+    };
+
+    match (mf(MfReturnOption::Mf), mf(MfReturnOption::Mmf)) {
+        (Ok(mf), Ok(mmf)) if mmf.is_positive() => try_sub_i80f48(mf, mmf)
+            .and_then(|x| try_div_i80f48(x, mmf))
+            .unwrap_or(I80F48::MAX),
+        _ => I80F48::MAX,
+    }
+}
+
+/// The index and serum open orders account of each swappable collateral.
+fn swappable_collaterals(state: &State) -> Vec<(usize, Pubkey)> {
+    state
+        .collaterals
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.mint != Pubkey::default() && c.is_swappable)
+        .map(|(i, c)| (i, c.serum_open_orders))
+        .collect()
+}
+
+/// Fetches the serum market of each swappable collateral and derives its
+/// vault signer, both keyed by collateral index.
+fn load_serum_markets(
+    st: &crate::AppState,
+    collaterals: &[(usize, Pubkey)],
+) -> Result<
+    (HashMap<usize, SerumMarketState>, HashMap<usize, Pubkey>),
+    crate::Error,
+> {
+    let mut serum_markets: HashMap<usize, _> = HashMap::new();
+    let mut serum_vault_signers: HashMap<usize, _> = HashMap::new();
+
+    for &(i, serum_open_orders) in collaterals {
+        let serum_oo_account = st.rpc.get_account(&serum_open_orders)?;
+
+        let serum_market_address = Pubkey::new(
+            account_slice(&serum_open_orders, &serum_oo_account.data, 13..45)
+                .map_err(|_| crate::Error::InvalidAccount(serum_open_orders))?,
+        );
+        let mut serum_market_account =
+            st.rpc.get_account(&serum_market_address)?;
+        let serum_market_account_info =
+            get_account_info(&serum_market_address, &mut serum_market_account);
+
+        let market_state = SerumMarket::load(
+            &serum_market_account_info,
+            &zo_abi::SERUM_DEX_PID,
+            true,
+        )
+        .map_err(|_| crate::Error::InvalidAccount(serum_market_address))?;
+        let market = market_state.deref();
+
+        if !has_lot_sizes(market.coin_lot_size, market.pc_lot_size) {
+            warn!(
+                "Serum market {} has a zero lot size, not swapping {}",
+                serum_market_address, i
+            );
+            continue;
+        }
+
+        serum_markets.insert(i, *market);
+
+        let vault_signer = Pubkey::create_program_address(
+            &[
+                array_to_pubkey(&{ market.own_address }).as_ref(),
+                &market.vault_signer_nonce.to_le_bytes(),
+            ],
+            &zo_abi::SERUM_DEX_PID,
+        )
+        .map_err(|_| crate::Error::InvalidAccount(serum_market_address))?;
+
+        serum_vault_signers.insert(i, vault_signer);
+    }
+
+    Ok((serum_markets, serum_vault_signers))
+}
+
+/// The payer's margin and control accounts as of the latest confirmed
+/// block, and the slot they were read at. Every liquidation changes them,
+/// so the copies in the table are stale once a loop has liquidated more
+/// than one account.
+fn fetch_payer(
+    st: &crate::AppState,
+    margin_key: &Pubkey,
+    control_key: &Pubkey,
+) -> Option<(Margin, Control, u64)> {
+    let (accounts, slot) = match st.rpc.get_multiple_accounts_with_commitment(
+        &[*margin_key, *control_key],
+        CommitmentConfig::confirmed(),
+    ) {
+        Ok(res) => (res.value, res.context.slot),
+        Err(e) => {
+            warn!("Failed to fetch payer accounts: {}", e);
+            return None;
+        }
+    };
+
+    match accounts.as_slice() {
+        [Some(margin), Some(control)] => Some((
+            get_type_from_account::<Margin>(margin_key, &mut margin.clone())?,
+            get_type_from_account::<Control>(
+                control_key,
+                &mut control.clone(),
+            )?,
+            slot,
+        )),
+        _ => {
+            warn!("Payer accounts not found");
+            None
+        }
+    }
+}
+
+// `parking_lot` locks are not poisoned when a holder panics, so a panic
+// while updating one account can't wedge the listener and the
+// liquidation loop on every later lock.
+pub type Db = Arc<RwLock<AccountTable>>;
+
+/// A liquidation or cancel, returning whether it succeeded.
+type Task = Box<dyn FnOnce() -> bool + Send>;
 
 #[derive(Clone)]
 pub struct DbWrapper {
@@ -253,43 +672,77 @@ impl DbWrapper {
         st: &crate::AppState,
         worker_index: u8,
         worker_count: u8,
-    ) -> Self {
-        DbWrapper {
-            db: Arc::new(Mutex::new(
-                AccountTable::new(st, worker_index, worker_count).unwrap(),
-            )),
-        }
+    ) -> Result<Self, crate::Error> {
+        Ok(DbWrapper {
+            db: Arc::new(RwLock::new(AccountTable::new(
+                st,
+                worker_index,
+                worker_count,
+            )?)),
+        })
     }
 
+    /// Checks every account, acting on those that need it. Returns the
+    /// number of accounts checked, and of those acted on that failed.
     pub async fn check_all_accounts(
         &self,
         st: &'static crate::AppState,
+        cfg: &'static LiquidatorConfig,
         dex_program: &Pubkey,
         serum_dex_program: &Pubkey,
-    ) -> Result<usize, ErrorCode> {
-        let (size, handles) =
-            self.check_all_accounts_aux(st, dex_program, serum_dex_program)?;
+    ) -> Result<(usize, usize), ErrorCode> {
+        let (size, tasks) = self.check_all_accounts_aux(
+            st,
+            cfg,
+            dex_program,
+            serum_dex_program,
+        )?;
+
+        // Permits are taken one at a time in the order the tasks were
+        // queued, and a task is only started once it holds one, so the
+        // most underwater accounts are acted on first.
+        let permits = Arc::new(tokio::sync::Semaphore::new(
+            cfg.max_concurrent_liquidations.get(),
+        ));
+        let mut handles = Vec::with_capacity(tasks.len());
+
+        for task in tasks {
+            // The semaphore is never closed.
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            handles.push(tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                task()
+            }));
+        }
+
         match futures::future::try_join_all(handles).await {
-            Ok(_) => Ok(size),
+            Ok(res) => Ok((size, res.into_iter().filter(|ok| !ok).count())),
             Err(_) => Err(ErrorCode::LiquidationFailure),
         }
     }
 
+    /// Decides which accounts to act on, returning the number of accounts
+    /// checked and a task acting on each, most underwater first. Each
+    /// task returns whether it succeeded.
     pub fn check_all_accounts_aux(
         &self,
         st: &'static crate::AppState,
+        cfg: &'static LiquidatorConfig,
         dex_program: &Pubkey,
         serum_dex_program: &Pubkey,
-    ) -> Result<(usize, Vec<tokio::task::JoinHandle<()>>), ErrorCode> {
+    ) -> Result<(usize, Vec<Task>), ErrorCode> {
         let db_clone = self.get_clone();
-        let db: &mut MutexGuard<AccountTable> =
-            &mut db_clone.lock().map_err(|_| ErrorCode::LockFailure)?;
+        let db = db_clone.read();
 
-        let mut handles: Vec<tokio::task::JoinHandle<_>> = Vec::new();
+        // Each task gets a copy of the accounts it needs, so the lock is
+        // released as soon as the decisions are made, rather than copying
+        // the whole margin table to release it early.
+        let mut tasks: Vec<Task> = Vec::new();
+        let capacity = Arc::new(liquidation::PayerCapacity::default());
         let span = error_span!("check_all_accounts");
-        for (key, margin) in db.margin_table.clone().into_iter() {
-            let (cancel_orders, liquidate) =
-                DbWrapper::is_liquidatable(&margin, &db, &db.state, &db.cache)?;
+        let actionable = Self::find_actionable(cfg, &db)?;
+        for (key, cancel_orders, liquidate) in actionable {
+            let margin = db.margin_table[&key];
             if liquidate {
                 span.in_scope(|| {
                     info!(
@@ -307,8 +760,7 @@ impl DbWrapper {
                 let payer_margin = *db.payer_margin();
                 let payer_control_key = db.payer_control_key();
                 let payer_control = *db.payer_control();
-                let payer_oo: [Pubkey; MAX_MARKETS as usize] =
-                    get_oo_keys(&payer_control.open_orders_agg);
+                let payer_oo = db.payer_oo;
                 let control_pair = db.get_control_from_margin(&margin).unwrap();
                 let control = *control_pair.1;
                 let cache = db.cache;
@@ -319,11 +771,31 @@ impl DbWrapper {
                 let market_state = db.market_state.clone();
                 let serum_markets = db.serum_markets.clone();
                 let serum_vault_signers = db.serum_vault_signers.clone();
+                let table = self.get_clone();
+                let capacity = capacity.clone();
 
                 // TODO: Refactor to have a struct for this, right now it's a mess
                 let span_clone = span.clone();
-                let handle = tokio::task::spawn_blocking(move || {
+                tasks.push(Box::new(move || {
+                    // Liquidations are sized by the payer's capacity, so
+                    // earlier liquidations in this loop must be counted.
+                    // Those still in flight are counted by reserving from
+                    // the pass's capacity.
+                    let (payer_margin, payer_control) = match fetch_payer(
+                        st,
+                        &payer_margin_key,
+                        &payer_control_key,
+                    ) {
+                        Some((margin, control, slot)) => {
+                            table.write().update_payer(margin, control, slot);
+                            (margin, control)
+                        }
+                        None => (payer_margin, payer_control),
+                    };
+
                     let result = liquidation::liquidate(
+                        st,
+                        cfg,
                         &st.program(),
                         &dex_program,
                         &payer_pubkey,
@@ -332,6 +804,7 @@ impl DbWrapper {
                         &payer_control,
                         &payer_control_key,
                         &payer_oo,
+                        &capacity,
                         &key,
                         &margin,
                         &control,
@@ -340,16 +813,52 @@ impl DbWrapper {
                         &state,
                         &state_key,
                         &state_signer,
-                        market_state.clone(),
-                        serum_markets,
+                        &market_state,
+                        &serum_markets,
                         &serum_dex_program,
-                        serum_vault_signers,
+                        &serum_vault_signers,
                     );
 
+                    metrics::record_opportunity(match result {
+                        Ok(liquidation::LiquidationOutcome::Filtered) => {
+                            Some(metrics::Skip::Filtered)
+                        }
+                        _ => None,
+                    });
+
                     match result {
-                        Ok(()) => {
+                        Ok(liquidation::LiquidationOutcome::Filtered) => {
+                            span_clone.in_scope(|| {
+                                debug!(
+                                    "{} left to another bot",
+                                    margin.authority
+                                );
+                            });
+                        }
+                        Ok(liquidation::LiquidationOutcome::Noop) => {
+                            span_clone.in_scope(|| {
+                                debug!(
+                                    "Nothing to liquidate for {}",
+                                    margin.authority
+                                );
+                            });
+                        }
+                        Ok(outcome) => {
+                            span_clone.in_scope(|| {
+                                info!(
+                                    "{}: {}",
+                                    margin.authority,
+                                    outcome.describe(&state)
+                                );
+                            });
+                        }
+                        // Usually another bot got to the account first.
+                        Err(ErrorCode::UnrecoverableTransactionError) => {
                             span_clone.in_scope(|| {
-                                info!("Liquidated {}", margin.authority);
+                                info!(
+                                    "{} no longer liquidatable",
+                                    margin.authority
+                                );
                             });
                         }
                         Err(e) => {
@@ -359,12 +868,18 @@ impl DbWrapper {
                                     margin.authority, e
                                 )
                             });
+                            return false;
                         }
                     }
-                });
 
-                handles.push(handle);
+                    true
+                }));
+            } else if cancel_orders && cfg.is_filtered() {
+                // Cancelling isn't specific to a market or collateral, so
+                // it's left to the bots that aren't filtered.
+                metrics::record_opportunity(Some(metrics::Skip::Filtered));
             } else if cancel_orders {
+                metrics::record_opportunity(None);
                 span.in_scope(|| {
                     info!(
                         "Found cancellable account: {}",
@@ -383,8 +898,10 @@ impl DbWrapper {
                 let market_state = db.market_state.clone();
 
                 let span_clone = span.clone();
-                let handle = tokio::task::spawn_blocking(move || {
+                tasks.push(Box::new(move || {
                     let result = liquidation::cancel(
+                        st,
+                        cfg,
                         &st.program(),
                         &dex_program,
                         &payer_pubkey,
@@ -396,11 +913,20 @@ impl DbWrapper {
                         &state,
                         &state_key,
                         &state_signer,
-                        market_state.clone(),
+                        &market_state,
                     );
 
                     match result {
-                        Ok(()) => (),
+                        Ok(()) => true,
+                        Err(ErrorCode::UnrecoverableTransactionError) => {
+                            span_clone.in_scope(|| {
+                                info!(
+                                    "{} no longer cancellable",
+                                    margin.authority
+                                );
+                            });
+                            true
+                        }
                         Err(e) => {
                             span_clone.in_scope(|| {
                                 error!(
@@ -408,17 +934,111 @@ impl DbWrapper {
                                     margin.authority, e
                                 )
                             });
+                            false
                         }
                     }
-                });
-                handles.push(handle);
+                }));
             }
         }
 
-        Ok((db.size(), handles))
+        let size = db.size();
+        drop(db);
+
+        Ok((size, tasks))
+    }
+
+    /// Decides which accounts should have their orders cancelled and which
+    /// should be liquidated, most underwater first. The margin table is
+    /// split across threads, as the margin math dominates the loop on
+    /// large tables.
+    fn find_actionable(
+        cfg: &LiquidatorConfig,
+        table: &AccountTable,
+    ) -> Result<Vec<(Pubkey, bool, bool)>, ErrorCode> {
+        let margins: Vec<_> = table.margin_table.iter().collect();
+        let unstable = match cfg.mark_band {
+            Some(band) => table.unstable_markets(I80F48::from_num(band)),
+            None => [false; MAX_MARKETS as usize],
+        };
+        let unstable = &unstable;
+        let threads =
+            std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = ((margins.len() + threads - 1) / threads).max(1);
+
+        std::thread::scope(|s| {
+            let handles: Vec<_> = margins
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move || {
+                        let mut res = Vec::new();
+                        for (key, margin) in chunk {
+                            // An account whose margin math overflows is
+                            // skipped rather than failing the whole loop.
+                            let (cancel_orders, liquidate) =
+                                match DbWrapper::is_liquidatable(
+                                    cfg,
+                                    margin,
+                                    table,
+                                    &table.state,
+                                    &table.cache,
+                                ) {
+                                    Ok(x) => x,
+                                    Err(e) => {
+                                        debug!(
+                                            "Skipping {}: {:?}",
+                                            margin.authority, e
+                                        );
+                                        continue;
+                                    }
+                                };
+                            if !(cancel_orders || liquidate) {
+                                continue;
+                            }
+                            if has_unstable_position(table, margin, unstable) {
+                                metrics::record_opportunity(Some(
+                                    metrics::Skip::UnstableMark,
+                                ));
+                            } else {
+                                res.push((
+                                    **key,
+                                    cancel_orders,
+                                    liquidate,
+                                    health_score(table, margin),
+                                ));
+                            }
+                        }
+                        Ok::<_, ErrorCode>(res)
+                    })
+                })
+                .collect();
+
+            let mut res = Vec::new();
+            for handle in handles {
+                // Propagate panic.
+                res.extend(handle.join().unwrap()?);
+            }
+            res.sort_by(|a, b| a.3.cmp(&b.3));
+
+            if !res.is_empty() {
+                debug!(
+                    "Acting on accounts in order: {}",
+                    res.iter()
+                        .map(|(k, _, _, score)| format!(
+                            "{} ({:.4})",
+                            table.margin_table[k].authority,
+                            score
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+
+            Ok(res.into_iter().map(|(k, c, l, _)| (k, c, l)).collect())
+        })
     }
 
     fn is_liquidatable(
+        cfg: &LiquidatorConfig,
         margin: &Margin,
         table: &AccountTable,
         state: &State,
@@ -438,27 +1058,39 @@ impl DbWrapper {
                 return Ok((false, false));
             }
         };
-        let has_oo = has_open_orders(cache, control)?;
 
-        let is_above_cancel = check_mf(
-            FractionType::Cancel,
+        let (cancel_orders, liquidate) = check_liquidatable(
             margin,
             control,
             state,
             cache,
-            I80F48::from_num(0.99995f64),
-        );
+            &table.oracles,
+            I80F48::from_num(cfg.cancel_tolerance),
+            I80F48::from_num(cfg.maintenance_tolerance),
+        )?;
 
-        let is_above_maintenance = check_mf(
-            FractionType::Maintenance,
-            margin,
-            control,
-            state,
-            cache,
-            I80F48::from_num(0.99995f64),
-        );
+        if (cancel_orders || liquidate) && cfg.min_account_value > 0.0 {
+            // Bankrupt accounts are always acted on, as they still need
+            // to be settled.
+            let value = get_total_account_value(
+                margin,
+                control,
+                state,
+                cache,
+                &table.oracles,
+            )?;
+            let min_value = I80F48::from_num(
+                cfg.min_account_value
+                    * 10f64.powi(state.collaterals[0].decimals as i32),
+            );
 
-        Ok((!is_above_cancel && has_oo, !is_above_maintenance))
+            if !value.is_negative() && value < min_value {
+                metrics::record_opportunity(Some(metrics::Skip::MinValue));
+                return Ok((false, false));
+            }
+        }
+
+        Ok((cancel_orders, liquidate))
     }
 
     pub fn get_clone(&self) -> Db {
@@ -473,8 +1105,34 @@ impl DbWrapper {
         &self,
         st: &crate::AppState,
     ) -> Result<(), crate::Error> {
-        let mut db = self.db.lock().unwrap();
+        let mut db = self.db.write();
         db.refresh_accounts(st)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marks(xs: &[f64]) -> VecDeque<I80F48> {
+        xs.iter().map(|&x| I80F48::from_num(x)).collect()
+    }
+
+    #[test]
+    fn test_off_median() {
+        let band = I80F48::from_num(0.25);
+        let x = I80F48::from_num;
+
+        // One glitched mark in the history doesn't move the median.
+        let history = marks(&[100., 101., 99., 500., 100.]);
+        assert_eq!(off_median(&history, x(120), band), None);
+        assert_eq!(off_median(&history, x(130), band), Some(x(100)));
+        assert_eq!(off_median(&history, x(70), band), Some(x(100)));
+
+        // At the edge of the band is still within it.
+        assert_eq!(off_median(&history, x(125), band), None);
+
+        assert_eq!(off_median(&VecDeque::new(), x(1000), band), None);
+    }
+}
@@ -3,7 +3,6 @@ pub enum ErrorCode {
     MathFailure,
     #[allow(dead_code)]
     InexistentControl,
-    LockFailure,
     CollateralFailure,
     NoCollateral,
     NoPositions,
@@ -15,4 +14,9 @@ pub enum ErrorCode {
     NoAsks,
     UnrecoverableTransactionError,
     LiquidationOverExposure,
+    TransactionTooLarge,
+    MalformedAccount,
+    UnsupportedState,
+    InsufficientFunds,
+    MissingOracle,
 }
@@ -0,0 +1,86 @@
+/*
+ * This file watches the payer's SOL balance and the value of its margin
+ * account, and warns when either runs low, so operators can top the bot
+ * up before it runs out of gas or piles up inventory after many
+ * liquidations. Warnings reach Discord through `crate::log`.
+*/
+use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+use fixed::types::I80F48;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::liquidator::{accounts::DbWrapper, LiquidatorConfig};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[tracing::instrument(skip_all, level = "error", name = "payer_health")]
+pub async fn watch_payer(
+    st: &'static crate::AppState,
+    cfg: &'static LiquidatorConfig,
+    database: DbWrapper,
+) {
+    if cfg.min_payer_sol.is_none() && cfg.min_payer_value.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    // Whether an alert is outstanding, so each is only sent once until
+    // the value recovers.
+    let mut sol_alerted = false;
+    let mut value_alerted = false;
+
+    loop {
+        interval.tick().await;
+
+        if let Some(min_sol) = cfg.min_payer_sol {
+            let balance = tokio::task::spawn_blocking(move || {
+                st.rpc.get_balance(&st.payer())
+            })
+            .await
+            .unwrap();
+
+            match balance {
+                Ok(lamports) => {
+                    let sol = lamports as f64 / LAMPORTS_PER_SOL as f64;
+                    let is_low = sol < min_sol;
+                    if is_low && !sol_alerted {
+                        warn!(
+                            "Liquidator payer {} has {} SOL, below {} SOL",
+                            st.payer(),
+                            sol,
+                            min_sol
+                        );
+                    }
+                    sol_alerted = is_low;
+                }
+                Err(e) => warn!("Failed to fetch payer balance: {}", e),
+            }
+        }
+
+        if let Some(min_value) = cfg.min_payer_value {
+            let value = match database.get().read().payer_account_value() {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Failed to value payer margin: {:?}", e);
+                    continue;
+                }
+            };
+            // The margin account is valued in smol USD.
+            let decimals = st.zo_state.collaterals[0].decimals as u32;
+            let value =
+                (value / I80F48::from_num(10u64.pow(decimals))).to_num::<f64>();
+            let is_low = value < min_value;
+            if is_low && !value_alerted {
+                warn!(
+                    "Liquidator payer {} margin is worth {} USD, below {} USD",
+                    st.payer(),
+                    value,
+                    min_value
+                );
+            }
+            value_alerted = is_low;
+        }
+    }
+}
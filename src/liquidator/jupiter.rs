@@ -0,0 +1,590 @@
+/*
+ * This file is responsible for rebalancing through the Jupiter
+ * aggregator, as an alternative to the zo serum markets in `swap.rs`.
+ * Jupiter swaps between wallet token accounts, so each swap is wrapped
+ * in a withdrawal from and a deposit back into the liqor's margin.
+*/
+use anchor_client::Program;
+
+use anchor_lang::{
+    prelude::ToAccountMetas,
+    solana_program::instruction::{AccountMeta, Instruction},
+    InstructionData,
+};
+
+use parking_lot::{const_mutex, Mutex};
+
+use serde::Deserialize;
+
+use solana_sdk::{pubkey::Pubkey, system_program};
+use spl_token::ID as TOKEN_ID;
+
+use std::{str::FromStr, sync::Arc};
+
+use tracing::{debug, warn};
+
+use zo_abi::{accounts, instruction, State};
+
+use crate::liquidator::error::ErrorCode;
+
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str =
+    "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+
+const NO_ROUTE_ERROR_CODE: &str = "COULD_NOT_FIND_ANY_ROUTE";
+
+// Compute units budgeted per instruction, see `liquidation.rs`.
+const WITHDRAW_CU: u32 = 50_000;
+const DEPOSIT_CU: u32 = 50_000;
+const CREATE_ATA_CU: u32 = 30_000;
+const JUPITER_SETUP_CU: u32 = 30_000;
+const JUPITER_SWAP_CU: u32 = 400_000;
+
+/// The client shared by every liquidation, see `Jupiter::shared`.
+static SHARED: Mutex<Option<Arc<Jupiter>>> = const_mutex(None);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuoteError {
+    error_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SwapInstructions {
+    #[serde(default)]
+    setup_instructions: Vec<JupiterInstruction>,
+    swap_instruction: JupiterInstruction,
+    cleanup_instruction: Option<JupiterInstruction>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JupiterInstruction {
+    program_id: String,
+    accounts: Vec<JupiterAccountMeta>,
+    data: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JupiterAccountMeta {
+    pubkey: String,
+    is_signer: bool,
+    is_writable: bool,
+}
+
+impl TryFrom<JupiterInstruction> for Instruction {
+    type Error = ErrorCode;
+
+    fn try_from(ix: JupiterInstruction) -> Result<Self, Self::Error> {
+        let pubkey = |s: &str| {
+            Pubkey::from_str(s).map_err(|e| {
+                warn!("Invalid pubkey {} from Jupiter: {}", s, e);
+                ErrorCode::SwapError
+            })
+        };
+
+        Ok(Instruction {
+            program_id: pubkey(&ix.program_id)?,
+            accounts: ix
+                .accounts
+                .iter()
+                .map(|a| {
+                    Ok(AccountMeta {
+                        pubkey: pubkey(&a.pubkey)?,
+                        is_signer: a.is_signer,
+                        is_writable: a.is_writable,
+                    })
+                })
+                .collect::<Result<_, ErrorCode>>()?,
+            data: base64::decode(&ix.data).map_err(|e| {
+                warn!("Invalid instruction data from Jupiter: {}", e);
+                ErrorCode::SwapError
+            })?,
+        })
+    }
+}
+
+impl SwapInstructions {
+    /// The setup, swap and cleanup instructions, in order, each paired
+    /// with its compute unit budget.
+    fn into_ixs(self) -> Result<Vec<(Instruction, u32)>, ErrorCode> {
+        let setup = self
+            .setup_instructions
+            .into_iter()
+            .map(|ix| (ix, JUPITER_SETUP_CU));
+        let swap = std::iter::once((self.swap_instruction, JUPITER_SWAP_CU));
+        let cleanup = self
+            .cleanup_instruction
+            .into_iter()
+            .map(|ix| (ix, JUPITER_SETUP_CU));
+
+        setup
+            .chain(swap)
+            .chain(cleanup)
+            .map(|(ix, units)| Ok((Instruction::try_from(ix)?, units)))
+            .collect()
+    }
+}
+
+/// The quote in the body of a quote response, or `None` if the response
+/// says there is no route.
+fn parse_quote(
+    success: bool,
+    body: &str,
+) -> Result<Option<serde_json::Value>, ErrorCode> {
+    if success {
+        return serde_json::from_str(body).map(Some).map_err(|e| {
+            warn!("Failed to parse Jupiter quote: {}", e);
+            ErrorCode::SwapError
+        });
+    }
+
+    match serde_json::from_str::<QuoteError>(body) {
+        Ok(QuoteError {
+            error_code: Some(code),
+        }) if code == NO_ROUTE_ERROR_CODE => Ok(None),
+        _ => {
+            warn!("Jupiter quote failed: {}", body);
+            Err(ErrorCode::SwapError)
+        }
+    }
+}
+
+/// The least a quote is sure to receive, after slippage.
+fn min_out(quote: &serde_json::Value) -> Result<u64, ErrorCode> {
+    quote["otherAmountThreshold"]
+        .as_str()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| {
+            warn!("Jupiter quote is missing otherAmountThreshold");
+            ErrorCode::SwapError
+        })
+}
+
+fn associated_token_address(owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[&owner.to_bytes(), &TOKEN_ID.to_bytes(), &mint.to_bytes()],
+        &Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap(),
+    )
+    .0
+}
+
+fn create_associated_token_account_ix(
+    payer: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap(),
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(associated_token_address(payer, mint), false),
+            AccountMeta::new_readonly(*payer, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(TOKEN_ID, false),
+        ],
+        // CreateIdempotent, which succeeds if the account already exists.
+        data: vec![1],
+    }
+}
+
+pub struct Jupiter {
+    client: reqwest::blocking::Client,
+    url: String,
+    slippage_bps: u16,
+}
+
+impl Jupiter {
+    pub fn new(url: String, slippage_bps: u16) -> Self {
+        Self {
+            client: reqwest::blocking::Client::new(),
+            url,
+            slippage_bps,
+        }
+    }
+
+    /// The client shared by every liquidation, built on first use. A
+    /// blocking client can't be built inside the runtime, so this must be
+    /// called from a blocking task, as liquidations are.
+    pub fn shared(url: &str, slippage_bps: u16) -> Arc<Self> {
+        SHARED
+            .lock()
+            .get_or_insert_with(|| {
+                Arc::new(Self::new(url.to_string(), slippage_bps))
+            })
+            .clone()
+    }
+
+    /// Fetches an exact-in quote, or `None` if Jupiter has no route.
+    fn quote(
+        &self,
+        input_mint: &Pubkey,
+        output_mint: &Pubkey,
+        amount: u64,
+    ) -> Result<Option<serde_json::Value>, ErrorCode> {
+        let resp = self
+            .client
+            .get(format!("{}/quote", self.url))
+            .query(&[
+                ("inputMint", input_mint.to_string()),
+                ("outputMint", output_mint.to_string()),
+                ("amount", amount.to_string()),
+                ("slippageBps", self.slippage_bps.to_string()),
+                ("asLegacyTransaction", "true".to_string()),
+            ])
+            .send()
+            .map_err(|e| {
+                warn!("Failed to fetch Jupiter quote: {}", e);
+                ErrorCode::SwapError
+            })?;
+
+        let success = resp.status().is_success();
+        let body = resp.text().map_err(|e| {
+            warn!("Failed to read Jupiter quote: {}", e);
+            ErrorCode::SwapError
+        })?;
+
+        parse_quote(success, &body)
+    }
+
+    fn swap_instructions(
+        &self,
+        payer: &Pubkey,
+        quote: &serde_json::Value,
+    ) -> Result<Vec<(Instruction, u32)>, ErrorCode> {
+        let resp: SwapInstructions = self
+            .client
+            .post(format!("{}/swap-instructions", self.url))
+            .json(&serde_json::json!({
+                "quoteResponse": quote,
+                "userPublicKey": payer.to_string(),
+                "asLegacyTransaction": true,
+            }))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .map_err(|e| {
+                warn!("Failed to fetch Jupiter swap instructions: {}", e);
+                ErrorCode::SwapError
+            })?;
+
+        resp.into_ixs()
+    }
+
+    /// Instructions, each paired with its compute unit budget, that
+    /// withdraw `amount` of collateral `input_index` from the liqor's
+    /// margin, swap it through Jupiter, and deposit the proceeds into
+    /// collateral `output_index`. Returns `None` if Jupiter has no route.
+    pub fn rebalance_ixs(
+        &self,
+        program: &Program,
+        payer: &Pubkey,
+        state: &State,
+        state_key: &Pubkey,
+        state_signer: &Pubkey,
+        payer_margin: &Pubkey,
+        payer_control: &Pubkey,
+        input_index: usize,
+        output_index: usize,
+        amount: u64,
+        allow_borrow: bool,
+    ) -> Result<Option<Vec<(Instruction, u32)>>, ErrorCode> {
+        let input_mint = state.collaterals[input_index].mint;
+        let output_mint = state.collaterals[output_index].mint;
+
+        let quote = match self.quote(&input_mint, &output_mint, amount)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let min_out = min_out(&quote)?;
+
+        debug!(
+            "Jupiter route {} -> {}: {} -> {}",
+            input_index, output_index, amount, min_out
+        );
+
+        let swap = self.swap_instructions(payer, &quote)?;
+
+        Ok(Some(wrap_swap(
+            &program.id(),
+            payer,
+            state,
+            state_key,
+            state_signer,
+            payer_margin,
+            payer_control,
+            input_index,
+            output_index,
+            amount,
+            allow_borrow,
+            min_out,
+            swap,
+        )))
+    }
+}
+
+/// Wraps the Jupiter instructions `swap` in a withdrawal of `amount` of
+/// collateral `input_index` from the liqor's margin and a deposit of the
+/// `min_out` it's sure to receive into collateral `output_index`. Only the
+/// minimum output after slippage is deposited, so the deposit can't fail
+/// on a worse fill.
+fn wrap_swap(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    state: &State,
+    state_key: &Pubkey,
+    state_signer: &Pubkey,
+    payer_margin: &Pubkey,
+    payer_control: &Pubkey,
+    input_index: usize,
+    output_index: usize,
+    amount: u64,
+    allow_borrow: bool,
+    min_out: u64,
+    swap: Vec<(Instruction, u32)>,
+) -> Vec<(Instruction, u32)> {
+    let input_mint = state.collaterals[input_index].mint;
+    let output_mint = state.collaterals[output_index].mint;
+
+    let mut ixs = vec![
+        (
+            create_associated_token_account_ix(payer, &input_mint),
+            CREATE_ATA_CU,
+        ),
+        (
+            Instruction {
+                accounts: accounts::Withdraw {
+                    state: *state_key,
+                    state_signer: *state_signer,
+                    cache: state.cache,
+                    authority: *payer,
+                    margin: *payer_margin,
+                    control: *payer_control,
+                    token_account: associated_token_address(payer, &input_mint),
+                    vault: state.vaults[input_index],
+                    token_program: TOKEN_ID,
+                    heimdall: zo_abi::ZO_HEIMDALL_ID,
+                }
+                .to_account_metas(None),
+                data: instruction::Withdraw {
+                    allow_borrow,
+                    amount,
+                }
+                .data(),
+                program_id: *program_id,
+            },
+            WITHDRAW_CU,
+        ),
+    ];
+
+    ixs.extend(swap);
+
+    ixs.push((
+        Instruction {
+            accounts: accounts::Deposit {
+                state: *state_key,
+                state_signer: *state_signer,
+                cache: state.cache,
+                authority: *payer,
+                margin: *payer_margin,
+                token_account: associated_token_address(payer, &output_mint),
+                vault: state.vaults[output_index],
+                token_program: TOKEN_ID,
+            }
+            .to_account_metas(None),
+            data: instruction::Deposit {
+                repay_only: false,
+                amount: min_out,
+            }
+            .data(),
+            program_id: *program_id,
+        },
+        DEPOSIT_CU,
+    ));
+
+    ixs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn jupiter_ix(program_id: &Pubkey, account: &Pubkey) -> serde_json::Value {
+        serde_json::json!({
+            "programId": program_id.to_string(),
+            "accounts": [{
+                "pubkey": account.to_string(),
+                "isSigner": false,
+                "isWritable": true,
+            }],
+            "data": base64::encode([1u8, 2, 3]),
+        })
+    }
+
+    #[test]
+    fn test_parse_quote() {
+        let quote = parse_quote(true, r#"{"otherAmountThreshold":"990"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(min_out(&quote).unwrap(), 990);
+
+        let no_route = r#"{"errorCode":"COULD_NOT_FIND_ANY_ROUTE"}"#;
+        assert!(parse_quote(false, no_route).unwrap().is_none());
+
+        let other = r#"{"errorCode":"TOKEN_NOT_TRADABLE"}"#;
+        assert!(matches!(
+            parse_quote(false, other),
+            Err(ErrorCode::SwapError)
+        ));
+        assert!(matches!(
+            parse_quote(true, "not json"),
+            Err(ErrorCode::SwapError)
+        ));
+    }
+
+    #[test]
+    fn test_min_out_missing() {
+        let quote = serde_json::json!({ "outAmount": "1000" });
+        assert!(matches!(min_out(&quote), Err(ErrorCode::SwapError)));
+
+        let quote = serde_json::json!({ "otherAmountThreshold": 990 });
+        assert!(matches!(min_out(&quote), Err(ErrorCode::SwapError)));
+    }
+
+    #[test]
+    fn test_swap_instructions() {
+        let program_id = Pubkey::new_unique();
+        let account = Pubkey::new_unique();
+
+        let resp: SwapInstructions =
+            serde_json::from_value(serde_json::json!({
+                "setupInstructions": [jupiter_ix(&program_id, &account)],
+                "swapInstruction": jupiter_ix(&program_id, &account),
+                "cleanupInstruction": jupiter_ix(&program_id, &account),
+                "addressLookupTableAddresses": [],
+            }))
+            .unwrap();
+        let ixs = resp.into_ixs().unwrap();
+
+        let units: Vec<u32> = ixs.iter().map(|x| x.1).collect();
+        assert_eq!(
+            units,
+            vec![JUPITER_SETUP_CU, JUPITER_SWAP_CU, JUPITER_SETUP_CU]
+        );
+
+        let ix = &ixs[1].0;
+        assert_eq!(ix.program_id, program_id);
+        assert_eq!(ix.accounts, vec![AccountMeta::new(account, false)]);
+        assert_eq!(ix.data, vec![1, 2, 3]);
+
+        // Setup and cleanup are optional.
+        let resp: SwapInstructions =
+            serde_json::from_value(serde_json::json!({
+                "swapInstruction": jupiter_ix(&program_id, &account),
+            }))
+            .unwrap();
+        assert_eq!(resp.into_ixs().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_swap_instructions_invalid() {
+        let mut ix = jupiter_ix(&Pubkey::new_unique(), &Pubkey::new_unique());
+        ix["programId"] = "not a pubkey".into();
+        let resp: SwapInstructions = serde_json::from_value(
+            serde_json::json!({ "swapInstruction": ix }),
+        )
+        .unwrap();
+        assert!(matches!(resp.into_ixs(), Err(ErrorCode::SwapError)));
+
+        let mut ix = jupiter_ix(&Pubkey::new_unique(), &Pubkey::new_unique());
+        ix["data"] = "!".into();
+        let resp: SwapInstructions = serde_json::from_value(
+            serde_json::json!({ "swapInstruction": ix }),
+        )
+        .unwrap();
+        assert!(matches!(resp.into_ixs(), Err(ErrorCode::SwapError)));
+    }
+
+    #[test]
+    fn test_wrap_swap() {
+        let program_id = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let margin = Pubkey::new_unique();
+
+        let mut state = State::zeroed();
+        state.collaterals[0].mint = Pubkey::new_unique();
+        state.collaterals[1].mint = Pubkey::new_unique();
+        state.vaults[0] = Pubkey::new_unique();
+        state.vaults[1] = Pubkey::new_unique();
+
+        let swap_ix = Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: Vec::new(),
+            data: Vec::new(),
+        };
+
+        let ixs = wrap_swap(
+            &program_id,
+            &payer,
+            &state,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &margin,
+            &Pubkey::new_unique(),
+            1,
+            0,
+            500,
+            false,
+            990,
+            vec![(swap_ix.clone(), JUPITER_SWAP_CU)],
+        );
+
+        let units: Vec<u32> = ixs.iter().map(|x| x.1).collect();
+        assert_eq!(
+            units,
+            vec![CREATE_ATA_CU, WITHDRAW_CU, JUPITER_SWAP_CU, DEPOSIT_CU]
+        );
+        assert_eq!(ixs[2].0, swap_ix);
+
+        // The input is withdrawn to the payer's token account for it, and
+        // only the minimum output deposited from the one for the output.
+        let withdraw = &ixs[1].0;
+        assert_eq!(withdraw.program_id, program_id);
+        assert_eq!(
+            withdraw.data,
+            instruction::Withdraw {
+                allow_borrow: false,
+                amount: 500,
+            }
+            .data()
+        );
+        let input_ata =
+            associated_token_address(&payer, &state.collaterals[1].mint);
+        assert!(withdraw.accounts.iter().any(|a| a.pubkey == input_ata));
+        assert!(withdraw
+            .accounts
+            .iter()
+            .any(|a| a.pubkey == state.vaults[1]));
+        assert_eq!(
+            withdraw.accounts.last().map(|a| a.pubkey),
+            Some(zo_abi::ZO_HEIMDALL_ID)
+        );
+
+        let deposit = &ixs[3].0;
+        assert_eq!(
+            deposit.data,
+            instruction::Deposit {
+                repay_only: false,
+                amount: 990,
+            }
+            .data()
+        );
+        let output_ata =
+            associated_token_address(&payer, &state.collaterals[0].mint);
+        assert!(deposit.accounts.iter().any(|a| a.pubkey == output_ata));
+        assert!(deposit.accounts.iter().any(|a| a.pubkey == state.vaults[0]));
+    }
+}
@@ -13,12 +13,13 @@ use solana_sdk::{
     commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature,
 };
 
+use parking_lot::Mutex;
+
 use std::collections::HashMap;
 
 use zo_abi::{
     accounts as ix_accounts, dex::ZoDexMarket as MarketState, instruction,
-    Cache, Control, Margin, State, WrappedI80F48, DUST_THRESHOLD,
-    MAX_COLLATERALS, MAX_MARKETS,
+    Cache, Control, Margin, State, MAX_COLLATERALS, MAX_MARKETS,
 };
 
 use std::cell::RefCell;
@@ -26,31 +27,65 @@ use std::cell::RefCell;
 use tracing::{debug, error, error_span, info, warn};
 
 use crate::liquidator::{
-    accounts::*, error::ErrorCode, margin_utils::*, math::*, swap, utils::*,
+    accounts::*, error::ErrorCode, jupiter::Jupiter, listener, margin_utils::*,
+    math::*, metrics, swap, utils::*, LiquidatorConfig, RebalanceVenue,
 };
 
+// Compute units budgeted per instruction. Transactions request the sum
+// over the instructions they bundle.
+const CANCEL_CU: u32 = 400_000;
+const LIQUIDATE_PERP_CU: u32 = 400_000;
+const LIQUIDATE_SPOT_CU: u32 = 200_000;
+const SETTLE_BANKRUPTCY_CU: u32 = 250_000;
+const REBALANCE_CU: u32 = 300_000;
+const SWAP_CU: u32 = 150_000;
+
+/// Spot liquidations are sized at this multiple of
+/// `estimate_spot_liquidation_size`. The estimate ignores the weight
+/// changes as the position shrinks, and the program caps the transfer at
+/// what brings the account back to initial margin, so overshooting only
+/// costs compute. This was written as `from_str_binary("1.1")`, which is
+/// 1.5 rather than 1.1, and 1.5 is what has been running since.
+const SPOT_SIZE_FUDGE: f64 = 1.5;
+
 #[tracing::instrument(skip_all, level = "error")]
-pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
+pub async fn liquidate_loop(
+    st: &'static crate::AppState,
+    cfg: &'static LiquidatorConfig,
+    database: DbWrapper,
+) {
     info!("starting liquidator v0.1.0...");
 
+    let tick = std::time::Duration::from_millis(250);
     let mut last_refresh = std::time::Instant::now();
-    let mut interval =
-        tokio::time::interval(std::time::Duration::from_millis(250));
+    let mut last_timing = std::time::Instant::now();
+    let mut loop_times = metrics::LoopTimes::new(tick);
+    let mut interval = tokio::time::interval(tick);
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
     loop {
         interval.tick().await;
 
+        // Liquidations would only fail while the payer can't pay fees.
+        let paused =
+            tokio::task::spawn_blocking(move || crate::funds::is_paused(st))
+                .await
+                .unwrap();
+        if paused {
+            continue;
+        }
+
         let loop_start = std::time::Instant::now();
         match database
             .check_all_accounts(
-                &st,
+                st,
+                cfg,
                 &zo_abi::ZO_DEX_PID,
                 &zo_abi::SERUM_DEX_PID,
             )
             .await
         {
-            Ok(n) => {
+            Ok((n, _)) => {
                 debug!(
                     "Checked {} accounts in {} ms",
                     n,
@@ -61,23 +96,166 @@ pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
                 error!("Had an oopsie-doopsie {:?}", e);
             }
         };
+        loop_times.record(loop_start.elapsed());
+
+        if last_timing.elapsed().as_secs() >= 60 {
+            loop_times.log_summary();
+            metrics::log_opportunities();
+            match listener::since_last_update() {
+                Some(d) => {
+                    info!("Last account update {} ms ago", d.as_millis())
+                }
+                None => warn!("No account updates received yet"),
+            }
+            last_timing = std::time::Instant::now();
+        }
 
         if last_refresh.elapsed().as_secs() > 300 {
             match database.refresh_accounts(st) {
                 Ok(_) => info!("Refreshed account table"),
                 Err(e) => warn!("Failed to refresh: {}", e),
             }
+            metrics::log_summary();
             last_refresh = std::time::Instant::now();
         }
     }
 }
 
+/// What a call to `liquidate` did to an account.
+#[derive(Clone, Copy, Debug)]
+pub enum LiquidationOutcome {
+    /// Liquidated `lots` of the perp position in market `index`.
+    Perp { index: usize, lots: u64 },
+    /// Liquidated the largest perp positions, in `markets` markets.
+    Perps { markets: usize },
+    /// Liquidated the `asset` collateral against the `quote` collateral,
+    /// worth `usdc` in smol USD.
+    Spot {
+        asset: usize,
+        quote: usize,
+        usdc: I80F48,
+    },
+    /// Cancelled open orders to free up margin.
+    Cancelled,
+    /// Settled the bankruptcy of an account.
+    Bankruptcy,
+    /// Left to another bot by `--only-markets` or `--only-collaterals`.
+    Filtered,
+    /// There was nothing to liquidate.
+    Noop,
+}
+
+impl LiquidationOutcome {
+    /// What was done, naming markets and collaterals by their symbols in
+    /// `state`.
+    pub fn describe(&self, state: &State) -> String {
+        match *self {
+            Self::Perp { index, lots } => format!(
+                "liquidated {} lots of {}",
+                lots, state.perp_markets[index].symbol
+            ),
+            Self::Perps { markets } => {
+                format!("liquidated its {} largest positions", markets)
+            }
+            Self::Spot { asset, quote, usdc } => format!(
+                "liquidated {} against {}, worth {:.2} USD",
+                state.collaterals[asset].oracle_symbol,
+                state.collaterals[quote].oracle_symbol,
+                usdc.to_num::<f64>() / 1_000_000.0
+            ),
+            Self::Cancelled => "cancelled its open orders".to_string(),
+            Self::Bankruptcy => "settled its bankruptcy".to_string(),
+            Self::Filtered => "left to another bot".to_string(),
+            Self::Noop => "nothing to liquidate".to_string(),
+        }
+    }
+}
+
+/// Whether an account with collateral values `colls` has no collateral
+/// above `dust_threshold` left to liquidate, and owes more than it holds,
+/// so must be settled as bankrupt.
+fn is_spot_bankrupt(colls: &[I80F48], dust_threshold: I80F48) -> bool {
+    colls.iter().all(|col| *col < dust_threshold)
+        && colls.iter().sum::<I80F48>().is_negative()
+}
+
+/// What an account's collateral calls for, before its perp positions are
+/// weighed against it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SpotBranch {
+    /// Settle the account as bankrupt.
+    Bankruptcy,
+    /// Liquidate the borrow of `asset` against collateral `quote`.
+    Spot { asset: usize, quote: usize },
+    /// Nothing to do with the collateral.
+    None,
+}
+
+/// Picks the `SpotBranch` for collateral values `colls`. Collateral worth
+/// at least `dust_threshold` may be liquidated against, the highest
+/// weighted first, and so is never dust for bankruptcy. Borrows worth less
+/// are left alone.
+fn spot_branch(
+    state: &State,
+    colls: &[I80F48],
+    dust_threshold: I80F48,
+) -> SpotBranch {
+    if is_spot_bankrupt(colls, dust_threshold) {
+        return SpotBranch::Bankruptcy;
+    }
+
+    let (asset, min_col) = match colls.iter().enumerate().min_by_key(|a| a.1) {
+        Some(x) => x,
+        None => return SpotBranch::None,
+    };
+
+    let mut quote = None;
+    let mut current_weight = 1000;
+    for (i, coll) in colls.iter().enumerate() {
+        if *coll >= dust_threshold
+            && state.collaterals[i].weight <= current_weight
+        {
+            current_weight = state.collaterals[i].weight;
+            quote = Some(i);
+        }
+    }
+
+    match quote {
+        Some(quote) if min_col.is_negative() && -*min_col >= dust_threshold => {
+            SpotBranch::Spot { asset, quote }
+        }
+        _ => SpotBranch::None,
+    }
+}
+
+/// The dex market at `index`, which may be missing if the market was
+/// listed after the table was last refreshed, or excluded by the table
+/// when it was loaded, e.g. for having a zero lot size.
+fn market_at(
+    markets: &[Option<MarketState>],
+    index: usize,
+) -> Result<MarketState, ErrorCode> {
+    match markets.get(index) {
+        Some(Some(market)) => Ok(*market),
+        Some(None) => {
+            debug!("Market {} is excluded, skipping", index);
+            Err(ErrorCode::UnsupportedState)
+        }
+        None => {
+            warn!("Market {} isn't loaded yet, skipping", index);
+            Err(ErrorCode::UnsupportedState)
+        }
+    }
+}
+
 #[tracing::instrument(
     skip_all,
     level = "error",
     fields(authority = %margin.authority),
 )]
 pub fn liquidate(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
     program: &Program,
     dex_program: &Pubkey,
     payer_pubkey: &Pubkey,
@@ -86,6 +264,7 @@ pub fn liquidate(
     payer_control: &Control,
     payer_control_key: &Pubkey,
     payer_oo: &[Pubkey; MAX_MARKETS as usize],
+    capacity: &PayerCapacity,
     margin_key: &Pubkey,
     margin: &Margin,
     control: &Control,
@@ -94,20 +273,25 @@ pub fn liquidate(
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
-    market_infos: Vec<MarketState>,
-    serum_markets: HashMap<usize, SerumMarketState>,
+    market_infos: &[Option<MarketState>],
+    serum_markets: &HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
-    serum_vault_signers: HashMap<usize, Pubkey>,
-) -> Result<(), ErrorCode> {
+    serum_vault_signers: &HashMap<usize, Pubkey>,
+) -> Result<LiquidationOutcome, ErrorCode> {
     // Given an account to liquidate
     // Go through its positions and pick the largest one.
     // Liquidate that position.
 
+    // Every valuation below uses the same cache, so its oracles are only
+    // looked up once.
+    let oracles = OracleIndex::new(state, cache);
+
     // Start by sorting the collateral
     let colls = get_actual_collateral_vec(
         margin,
         &RefCell::new(*state).borrow(),
         &RefCell::new(*cache).borrow(),
+        &oracles,
         false,
     );
 
@@ -121,27 +305,16 @@ pub fn liquidate(
             return Err(ErrorCode::CollateralFailure);
         }
     };
-    let collateral_tuple = colls.iter().enumerate();
     let (col_index, min_col) =
-        match collateral_tuple.clone().min_by_key(|a| a.1) {
+        match colls.iter().enumerate().min_by_key(|a| a.1) {
             Some(x) => x,
             None => return Err(ErrorCode::NoCollateral),
         };
 
-    // Find the highest weighted asset that is positive.
-    let mut quote_info: Option<(usize, &I80F48)> = None;
-    let mut current_weight = 1000;
-    for (i, coll) in collateral_tuple {
-        if coll > &I80F48::from_num(DUST_THRESHOLD)
-            && state.collaterals[i].weight <= current_weight
-        {
-            current_weight = state.collaterals[i].weight;
-            quote_info = Some((i, &coll));
-        }
-    }
+    let spot = spot_branch(state, &colls, cfg.dust_threshold);
 
     // Sort the positions
-    let positions: Vec<I80F48> = control
+    let notionals: Vec<I80F48> = control
         .open_orders_agg
         .iter()
         .zip(cache.marks)
@@ -150,7 +323,7 @@ pub fn liquidate(
         })
         .collect();
 
-    let positions = positions.iter().enumerate();
+    let positions = notionals.iter().enumerate();
 
     let position: Option<(usize, &I80F48)> =
         match positions.max_by_key(|a| a.1.abs()) {
@@ -183,25 +356,138 @@ pub fn liquidate(
         &[&margin.control.to_bytes()[..], &dex_market.to_bytes()[..]],
         dex_program,
     );
-    let market_info = market_infos[position_index];
 
-    let is_spot_bankrupt = colls.iter().all(|col| col < &DUST_THRESHOLD)
-        && colls.iter().sum::<I80F48>().is_negative();
+    let is_spot_bankrupt = spot == SpotBranch::Bankruptcy;
+    let liquidate_spot = matches!(spot, SpotBranch::Spot { .. });
+
+    // The largest positions this bot may act on, should the largest
+    // overall be one of them.
+    let top_positions: Vec<usize> =
+        largest_positions(&notionals, cfg.perp_top_k)
+            .into_iter()
+            .filter(|&i| cfg.market_allowed(state, i))
+            .collect();
 
-    if has_positions
-        && (min_col.abs() <= max_position_notional.abs() || is_spot_bankrupt)
+    let liquidate_perp = has_positions
+        && (min_col.abs() <= max_position_notional.abs() || is_spot_bankrupt);
+
+    let outcome = if liquidate_perp
+        && !cfg.market_allowed(state, position_index)
     {
-        liquidate_perp_position(
+        debug!(
+            "Leaving {} to another bot",
+            String::from(state.perp_markets[position_index].symbol)
+        );
+
+        LiquidationOutcome::Filtered
+    } else if liquidate_perp && top_positions.len() > 1 {
+        // Liquidate enough of each of the largest positions to bring the
+        // account back over maintenance, rather than only the largest.
+        let fraction = perp_reduction_fraction(
+            margin,
+            control,
+            state,
+            cache,
+            &oracles,
+            &top_positions,
+        )?;
+
+        let wanted = top_positions
+            .iter()
+            .map(|&i| {
+                let dex_market = state.perp_markets[i].dex_market;
+                let (liqee_open_orders, _nonce) = Pubkey::find_program_address(
+                    &[
+                        &margin.control.to_bytes()[..],
+                        &dex_market.to_bytes()[..],
+                    ],
+                    dex_program,
+                );
+                let market_info = market_at(market_infos, i)?;
+
+                let position_lots =
+                    I80F48::from_num(control.open_orders_agg[i].pos_size).abs()
+                        / I80F48::from_num(market_info.coin_lot_size);
+                let lots = (position_lots * fraction).ceil().to_num::<u64>();
+
+                Ok(PerpTarget {
+                    index: i,
+                    market_info,
+                    dex_market,
+                    liqee_open_orders,
+                    liqor_oo_key: payer_oo[i],
+                    liqee_was_long: notionals[i].is_positive(),
+                })
+                .map(|target| (target, lots))
+            })
+            .collect::<Result<Vec<_>, ErrorCode>>()?;
+
+        // Enough of the liqor's capacity for every position is reserved,
+        // then shared between them, largest first.
+        let want = wanted.iter().try_fold(I80F48::ZERO, |acc, (t, lots)| {
+            try_add_i80f48(acc, lots_notional(*lots, cache, t)?)
+        })?;
+        let total = liqor_capacity(
+            payer_margin,
+            payer_control,
+            state,
+            cache,
+            &oracles,
+        )?;
+        let reserved = capacity.reserve(total, want);
+
+        let wants = wanted
+            .iter()
+            .map(|(t, lots)| Ok((*lots, notional_lots(reserved, cache, t)?)))
+            .collect::<Result<Vec<_>, ErrorCode>>()?;
+        let targets: Vec<(PerpTarget, u64)> = wanted
+            .iter()
+            .map(|(t, _)| *t)
+            .zip(allocate_capacity(&wants))
+            .collect();
+
+        liquidate_perp_positions(
+            st,
+            cfg,
+            program,
+            payer_pubkey,
+            payer_margin,
+            payer_margin_key,
+            payer_control,
+            margin,
+            margin_key,
+            cache_key,
+            state,
+            state_key,
+            state_signer,
+            dex_program,
+            &targets,
+        )
+        .map_err(|e| {
+            capacity.release(reserved);
+            e
+        })?;
+
+        LiquidationOutcome::Perps {
+            markets: targets.len(),
+        }
+    } else if liquidate_perp {
+        let market_info = market_at(market_infos, position_index)?;
+        let lots = liquidate_perp_position(
+            st,
+            cfg,
             program,
             payer_pubkey,
             payer_margin,
             payer_margin_key,
             payer_control,
             &payer_oo[position_index],
+            capacity,
             margin,
             margin_key,
             &open_orders,
             cache,
+            &oracles,
             cache_key,
             state,
             state_key,
@@ -210,13 +496,22 @@ pub fn liquidate(
             &market_info,
             &dex_market,
             position_index,
-            max_position_notional.is_positive(),
+            max_position_notional,
         )?;
+
+        LiquidationOutcome::Perp {
+            index: position_index,
+            lots,
+        }
+    } else if is_spot_bankrupt && !has_positions && cfg.is_filtered() {
+        LiquidationOutcome::Filtered
     } else if is_spot_bankrupt && !has_positions {
         let oo_index_result = largest_open_order(cache, control)?;
 
         if let Some(_order_index) = oo_index_result {
             cancel(
+                st,
+                cfg,
                 program,
                 dex_program,
                 payer_pubkey,
@@ -230,12 +525,18 @@ pub fn liquidate(
                 state_signer,
                 market_infos,
             )?;
+
+            LiquidationOutcome::Cancelled
         } else {
             settle_bankruptcy(
+                st,
+                cfg,
                 program,
                 state,
                 state_key,
                 state_signer,
+                cache,
+                &oracles,
                 cache_key,
                 payer_pubkey,
                 payer_margin_key,
@@ -247,25 +548,37 @@ pub fn liquidate(
                 serum_dex_program,
                 serum_vault_signers,
             )?;
-        };
-    } else if *min_col < 0u64 && quote_info.is_some() {
+
+            LiquidationOutcome::Bankruptcy
+        }
+    } else if liquidate_spot && !cfg.collateral_allowed(state, col_index) {
+        debug!(
+            "Leaving {} to another bot",
+            String::from(state.collaterals[col_index].oracle_symbol)
+        );
+
+        LiquidationOutcome::Filtered
+    } else if liquidate_spot {
         // Close a spot position
-        let quote_idx = if let Some((q_idx, _q_coll)) = quote_info {
-            q_idx
-        } else {
-            0
+        let quote_idx = match spot {
+            SpotBranch::Spot { quote, .. } => quote,
+            _ => 0,
         };
 
-        liquidate_spot_position(
+        let usdc = liquidate_spot_position(
+            st,
+            cfg,
             program,
             payer_pubkey,
             payer_margin,
             payer_margin_key,
             payer_control,
+            capacity,
             margin,
             margin_key,
             control,
             cache,
+            &oracles,
             cache_key,
             state,
             state_key,
@@ -276,10 +589,21 @@ pub fn liquidate(
             serum_dex_program,
             serum_vault_signers,
         )?;
+
+        LiquidationOutcome::Spot {
+            asset: col_index,
+            quote: quote_idx,
+            usdc,
+        }
+    } else if cfg.is_filtered() && largest_open_order(cache, control)?.is_some()
+    {
+        LiquidationOutcome::Filtered
     } else if let Some(_order_index) = largest_open_order(cache, control)? {
         // Must cancel perp open orders
         info!("Closing {}'s {} perp order", margin.authority, col_index);
         cancel(
+            st,
+            cfg,
             program,
             dex_program,
             payer_pubkey,
@@ -293,14 +617,18 @@ pub fn liquidate(
             state_signer,
             market_infos,
         )?;
-    }
 
-    // TODO: Refactor so that you return an enum
-    // TODO: enum specifies swap type and relevant params.
-    Ok(())
+        LiquidationOutcome::Cancelled
+    } else {
+        LiquidationOutcome::Noop
+    };
+
+    Ok(outcome)
 }
 
 pub fn cancel(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
     program: &Program,
     dex_program: &Pubkey,
     payer_pubkey: &Pubkey,
@@ -312,7 +640,7 @@ pub fn cancel(
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
-    market_info: Vec<MarketState>,
+    market_info: &[Option<MarketState>],
 ) -> Result<(), ErrorCode> {
     let span = error_span!("cancel");
 
@@ -332,9 +660,11 @@ pub fn cancel(
         &[&margin.control.to_bytes()[..], &dex_market.to_bytes()[..]],
         dex_program,
     );
-    let market_info = market_info[oo_index];
+    let market_info = market_at(market_info, oo_index)?;
 
     cancel_orders(
+        st,
+        cfg,
         program,
         payer_pubkey,
         margin_key,
@@ -355,6 +685,8 @@ pub fn cancel(
 }
 
 fn cancel_orders(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
     program: &Program,
     payer_pubkey: &Pubkey,
     margin_key: &Pubkey,
@@ -375,9 +707,9 @@ fn cancel_orders(
 
     let span = error_span!("cancel_orders");
     let signature = retry_send(
+        st,
         || {
-            program
-                .request()
+            with_compute_budget(program.request(), CANCEL_CU, cfg.priority_fee)
                 .accounts(ix_accounts::ForceCancelAllPerpOrders {
                     pruner: *payer_pubkey,
                     state: *state_key,
@@ -397,6 +729,7 @@ fn cancel_orders(
                 .options(CommitmentConfig::confirmed())
         },
         5,
+        cfg.simulate_first,
     );
 
     match signature {
@@ -406,39 +739,131 @@ fn cancel_orders(
             });
             Ok(())
         }
+        Err(ErrorCode::UnrecoverableTransactionError) => {
+            Err(ErrorCode::UnrecoverableTransactionError)
+        }
         Err(_e) => Err(ErrorCode::CancelFailure),
     }
 }
 
-// Need the ix for liquidating a single account for a particular market.
-fn liquidate_perp_position(
+/// A liqee's perp position in one market, and the accounts liquidating it
+/// touches.
+#[derive(Clone, Copy)]
+struct PerpTarget {
+    index: usize,
+    market_info: MarketState,
+    dex_market: Pubkey,
+    liqee_open_orders: Pubkey,
+    liqor_oo_key: Pubkey,
+    liqee_was_long: bool,
+}
+
+/// The notional, in smol USD, the payer can still take on in one pass
+/// over the accounts, shared by the pass's liquidations. Each reserves
+/// what it is sized at before sending, so liquidations running at once
+/// don't each size off the payer's whole capacity. A liquidation that
+/// fails gives its reservation back. One that lands keeps it for the rest
+/// of the pass, whichever of its sizes landed.
+#[derive(Default)]
+pub struct PayerCapacity {
+    // Unset until the pass's first reservation.
+    left: Mutex<Option<I80F48>>,
+}
+
+impl PayerCapacity {
+    /// Reserves up to `want` of what is left, which is at most `total`,
+    /// the capacity of the payer as last fetched. Returns how much was
+    /// reserved.
+    fn reserve(&self, total: I80F48, want: I80F48) -> I80F48 {
+        let mut left = self.left.lock();
+        let left = left.get_or_insert(total);
+
+        *left = (*left).min(total).max(I80F48::ZERO);
+        let reserved = want.max(I80F48::ZERO).min(*left);
+        *left -= reserved;
+
+        reserved
+    }
+
+    /// Gives back a reservation that wasn't used.
+    fn release(&self, reserved: I80F48) {
+        if let Some(left) = self.left.lock().as_mut() {
+            *left += reserved;
+        }
+    }
+}
+
+/// The most notional, in smol USD, the liqor takes on, at 5x leverage.
+fn liqor_capacity(
+    liqor_margin: &Margin,
+    liqor_control: &Control,
+    state: &State,
+    cache: &Cache,
+    oracles: &OracleIndex,
+) -> Result<I80F48, ErrorCode> {
+    let value = get_total_account_value(
+        liqor_margin,
+        liqor_control,
+        state,
+        cache,
+        oracles,
+    )?;
+
+    try_mul_i80f48(value, I80F48::from_num(5u8)) // 5x leverage
+}
+
+/// The lots of the target's market worth `notional` at the mark.
+fn notional_lots(
+    notional: I80F48,
+    cache: &Cache,
+    target: &PerpTarget,
+) -> Result<i64, ErrorCode> {
+    let mark: I80F48 = cache.marks[target.index].price.into();
+    let lot = I80F48::from_num(target.market_info.coin_lot_size);
+
+    Ok(try_div_i80f48(try_div_i80f48(notional, mark)?, lot)?
+        .max(I80F48::ZERO)
+        .to_num())
+}
+
+/// The notional of `lots` of the target's market at the mark.
+fn lots_notional(
+    lots: u64,
+    cache: &Cache,
+    target: &PerpTarget,
+) -> Result<I80F48, ErrorCode> {
+    let mark: I80F48 = cache.marks[target.index].price.into();
+    let size = try_mul_i80f48(
+        I80F48::from_num(lots),
+        I80F48::from_num(target.market_info.coin_lot_size),
+    )?;
+
+    try_mul_i80f48(size, mark)
+}
+
+/// The instructions liquidating `lots` of the target position, each with
+/// its compute units: cancelling the liqee's orders and the liquidation
+/// itself, then the order closing the position the liqor took on, if one
+/// could be built.
+fn perp_position_ixs(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
     program: &Program,
     payer_pubkey: &Pubkey,
     liqor_margin: &Margin,
     liqor_margin_key: &Pubkey,
     liqor_control: &Control,
-    liqor_oo_key: &Pubkey,
     liqee_margin: &Margin,
     liqee_margin_key: &Pubkey,
-    liqee_open_orders: &Pubkey,
-    cache: &Cache,
     cache_key: &Pubkey,
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
     dex_program: &Pubkey,
-    market_info: &MarketState,
-    dex_market: &Pubkey,
-    index: usize,
-    liqee_was_long: bool,
-) -> Result<(), ErrorCode> {
-    let span = error_span!(
-        "liquidate_perp_position",
-        "{}",
-        liqee_margin.authority.to_string()
-    );
-    // Can probably save some of these variables in the ds.
-    // e.g. the state_signer and open_orders.
+    target: &PerpTarget,
+    lots: u64,
+) -> (Atomic, Option<(Instruction, u32)>) {
+    let market_info = &target.market_info;
 
     let cancel_ix = Instruction {
         accounts: ix_accounts::ForceCancelAllPerpOrders {
@@ -448,8 +873,8 @@ fn liquidate_perp_position(
             state_signer: *state_signer,
             liqee_margin: *liqee_margin_key,
             liqee_control: liqee_margin.control,
-            liqee_oo: *liqee_open_orders,
-            dex_market: *dex_market,
+            liqee_oo: target.liqee_open_orders,
+            dex_market: target.dex_market,
             req_q: market_info.req_q,
             event_q: market_info.event_q,
             market_bids: market_info.bids,
@@ -461,24 +886,7 @@ fn liquidate_perp_position(
         program_id: program.id(),
     };
 
-    let mut asset_transfer_lots =
-        get_total_account_value(liqor_margin, liqor_control, state, cache)
-            .checked_div(cache.marks[index].price.into())
-            .unwrap()
-            .to_num::<i64>()
-            .safe_div(market_info.coin_lot_size)
-            .unwrap()
-            .safe_mul(5i64) // 5x leverage
-            .unwrap();
-
-    debug!(
-        "{} | {} {}",
-        liqee_margin.authority,
-        asset_transfer_lots,
-        String::from(state.perp_markets[index].symbol)
-    );
-
-    let mut liq_ix = Instruction {
+    let liq_ix = Instruction {
         accounts: ix_accounts::LiquidatePerpPosition {
             state: *state_key,
             cache: *cache_key,
@@ -486,12 +894,12 @@ fn liquidate_perp_position(
             liqor: *payer_pubkey,
             liqor_margin: *liqor_margin_key,
             liqor_control: liqor_margin.control,
-            liqor_oo: *liqor_oo_key,
+            liqor_oo: target.liqor_oo_key,
             liqee: liqee_margin.authority,
             liqee_margin: *liqee_margin_key,
             liqee_control: liqee_margin.control,
-            liqee_oo: *liqee_open_orders,
-            dex_market: *dex_market,
+            liqee_oo: target.liqee_open_orders,
+            dex_market: target.dex_market,
             req_q: market_info.req_q,
             event_q: market_info.event_q,
             market_bids: market_info.bids,
@@ -500,13 +908,16 @@ fn liquidate_perp_position(
         }
         .to_account_metas(None),
         data: instruction::LiquidatePerpPosition {
-            asset_transfer_lots: asset_transfer_lots as u64,
+            asset_transfer_lots: lots,
         }
         .data(),
         program_id: program.id(),
     };
 
-    let rebalance_ix: Option<Instruction> = match swap::close_position_ix(
+    let ixs = vec![(cancel_ix, CANCEL_CU), (liq_ix, LIQUIDATE_PERP_CU)];
+
+    let close = match swap::close_position_ix(
+        &st.rpc,
         program,
         state,
         state_key,
@@ -516,122 +927,364 @@ fn liquidate_perp_position(
         liqor_control,
         market_info,
         dex_program,
-        index,
-        liqee_was_long,
+        target.index,
+        target.liqee_was_long,
+        lots,
+        cfg.close_slippage_bps,
     ) {
-        Ok(ix) => Some(ix),
+        Ok(x) => x.map(|ix| (ix, REBALANCE_CU)),
         Err(_e) => {
-            span.in_scope(|| warn!("Unable to create rebalance instruction"));
+            warn!("Unable to create rebalance instruction");
             None
         }
     };
 
-    let reduction_max = 5;
+    (ixs, close)
+}
 
-    let mut signature;
-    for _reduction in 0..reduction_max {
-        signature = retry_send(
-            || {
-                let request = program
-                    .request()
-                    .instruction(cancel_ix.clone())
-                    .instruction(liq_ix.clone())
-                    .options(CommitmentConfig::confirmed());
-                if let Some(ix) = rebalance_ix.clone() {
-                    request.instruction(ix)
-                } else {
-                    request
-                }
-            },
+// Need the ix for liquidating a single account for a particular market.
+fn liquidate_perp_position(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
+    program: &Program,
+    payer_pubkey: &Pubkey,
+    liqor_margin: &Margin,
+    liqor_margin_key: &Pubkey,
+    liqor_control: &Control,
+    liqor_oo_key: &Pubkey,
+    capacity: &PayerCapacity,
+    liqee_margin: &Margin,
+    liqee_margin_key: &Pubkey,
+    liqee_open_orders: &Pubkey,
+    cache: &Cache,
+    oracles: &OracleIndex,
+    cache_key: &Pubkey,
+    state: &State,
+    state_key: &Pubkey,
+    state_signer: &Pubkey,
+    dex_program: &Pubkey,
+    market_info: &MarketState,
+    dex_market: &Pubkey,
+    index: usize,
+    notional: I80F48,
+) -> Result<u64, ErrorCode> {
+    let span = error_span!(
+        "liquidate_perp_position",
+        "{}",
+        liqee_margin.authority.to_string()
+    );
+    let symbol = String::from(state.perp_markets[index].symbol);
+    // Can probably save some of these variables in the ds.
+    // e.g. the state_signer and open_orders.
+
+    let target = PerpTarget {
+        index,
+        market_info: *market_info,
+        dex_market: *dex_market,
+        liqee_open_orders: *liqee_open_orders,
+        liqor_oo_key: *liqor_oo_key,
+        liqee_was_long: notional.is_positive(),
+    };
+
+    // At most the whole position is reserved, so the rest of the liqor's
+    // capacity is left to the pass's other liquidations.
+    let total =
+        liqor_capacity(liqor_margin, liqor_control, state, cache, oracles)?;
+    let reserved = capacity.reserve(total, notional.abs());
+    let asset_transfer_lots = notional_lots(reserved, cache, &target)?;
+
+    debug!(
+        "{} | {} {}",
+        liqee_margin.authority,
+        asset_transfer_lots,
+        String::from(state.perp_markets[index].symbol)
+    );
+
+    let sizes = reduced_sizes(
+        I80F48::from_num(asset_transfer_lots),
+        I80F48::from_num(cfg.reduction_factor),
+        cfg.reduction_max,
+    );
+
+    let landed = retry_reduced(sizes, |lots| {
+        let lots: u64 = lots.max(I80F48::ZERO).to_num();
+        if lots == 0 {
+            return Ok(None);
+        }
+
+        let (ixs, close) = span.in_scope(|| {
+            perp_position_ixs(
+                st,
+                cfg,
+                program,
+                payer_pubkey,
+                liqor_margin,
+                liqor_margin_key,
+                liqor_control,
+                liqee_margin,
+                liqee_margin_key,
+                cache_key,
+                state,
+                state_key,
+                state_signer,
+                dex_program,
+                &target,
+                lots,
+            )
+        });
+
+        let sent = send_bundle(
+            st,
+            program,
+            payer_pubkey,
+            cfg.priority_fee,
+            rebalanced_bundle(
+                ixs,
+                close.into_iter().map(|x| vec![x]).collect(),
+            ),
             5,
+            cfg.simulate_first,
         );
 
-        match signature {
-            Ok(tx) => {
-                span.in_scope(|| {
-                    info!(
-                        "Liquidated {}'s perp. tx: {:?}",
-                        liqee_margin.authority, tx
+        let outcome = match sent.error {
+            None => metrics::Outcome::Success,
+            Some(_) if sent.landed > 0 => metrics::Outcome::Success,
+            Some(ErrorCode::LiquidationOverExposure) => {
+                metrics::Outcome::Reduced
+            }
+            Some(_) => metrics::Outcome::Failure,
+        };
+        metrics::record(metrics::Kind::Perp, &symbol, outcome);
+
+        for sig in &sent.signatures {
+            metrics::record_tx(
+                metrics::Kind::Perp,
+                &liqee_margin.authority,
+                payer_pubkey,
+                sig,
+            );
+        }
+
+        span.in_scope(|| liquidation_result(sent.landed, sent.error))?;
+        span.in_scope(|| {
+            info!(
+                "Liquidated {}'s perp. tx: {:?}",
+                liqee_margin.authority, sent.signatures
+            )
+        });
+        Ok(Some(lots))
+    });
+
+    if !matches!(landed, Ok(Some(_))) {
+        capacity.release(reserved);
+    }
+
+    landed?.ok_or_else(|| {
+        metrics::record(
+            metrics::Kind::Perp,
+            &symbol,
+            metrics::Outcome::Failure,
+        );
+        ErrorCode::LiquidationFailure
+    })
+}
+
+/// Liquidates several perp positions in one bundle, `lots` of each
+/// target. Over-exposure scales down together the targets that haven't
+/// landed yet, so none is liquidated twice when the bundle is split.
+fn liquidate_perp_positions(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
+    program: &Program,
+    payer_pubkey: &Pubkey,
+    liqor_margin: &Margin,
+    liqor_margin_key: &Pubkey,
+    liqor_control: &Control,
+    liqee_margin: &Margin,
+    liqee_margin_key: &Pubkey,
+    cache_key: &Pubkey,
+    state: &State,
+    state_key: &Pubkey,
+    state_signer: &Pubkey,
+    dex_program: &Pubkey,
+    targets: &[(PerpTarget, u64)],
+) -> Result<(), ErrorCode> {
+    let span = error_span!(
+        "liquidate_perp_positions",
+        "{}",
+        liqee_margin.authority.to_string()
+    );
+
+    let scales = reduced_sizes(
+        I80F48::ONE,
+        I80F48::from_num(cfg.reduction_factor),
+        cfg.reduction_max,
+    );
+
+    let mut remaining = targets.to_vec();
+
+    let landed = retry_reduced(scales, |scale| {
+        let sized: Vec<_> = remaining
+            .iter()
+            .map(|(target, lots)| {
+                (target, (I80F48::from_num(*lots) * scale).to_num::<u64>())
+            })
+            .filter(|(_, lots)| *lots > 0)
+            .collect();
+
+        // Each target's instructions are a group, so its liquidation and
+        // close always land together.
+        let bundle: Vec<_> = sized
+            .iter()
+            .map(|&(target, lots)| {
+                let (mut ixs, close) = span.in_scope(|| {
+                    perp_position_ixs(
+                        st,
+                        cfg,
+                        program,
+                        payer_pubkey,
+                        liqor_margin,
+                        liqor_margin_key,
+                        liqor_control,
+                        liqee_margin,
+                        liqee_margin_key,
+                        cache_key,
+                        state,
+                        state_key,
+                        state_signer,
+                        dex_program,
+                        target,
+                        lots,
                     )
                 });
-                return Ok(());
+                ixs.extend(close);
+                ixs
+            })
+            .collect();
+
+        if bundle.is_empty() {
+            return Ok(None);
+        }
+
+        let sent = send_bundle(
+            st,
+            program,
+            payer_pubkey,
+            cfg.priority_fee,
+            bundle,
+            5,
+            cfg.simulate_first,
+        );
+
+        let outcome = match sent.error {
+            None => metrics::Outcome::Success,
+            Some(ErrorCode::LiquidationOverExposure) => {
+                metrics::Outcome::Reduced
             }
-            Err(e) => match e {
-                ErrorCode::LiquidationOverExposure => {
-                    asset_transfer_lots /= 2;
-                    liq_ix.data = instruction::LiquidatePerpPosition {
-                        asset_transfer_lots: asset_transfer_lots as u64,
-                    }
-                    .data();
-                }
-                _ => {
-                    return Err(ErrorCode::LiquidationFailure);
-                }
-            },
+            Some(_) => metrics::Outcome::Failure,
+        };
+        for (i, (target, _)) in sized.iter().enumerate() {
+            let symbol = String::from(state.perp_markets[target.index].symbol);
+            let outcome = if i < sent.landed {
+                metrics::Outcome::Success
+            } else {
+                outcome
+            };
+            metrics::record(metrics::Kind::Perp, &symbol, outcome);
         }
-    }
 
-    Err(ErrorCode::LiquidationFailure)
+        for sig in &sent.signatures {
+            metrics::record_tx(
+                metrics::Kind::Perp,
+                &liqee_margin.authority,
+                payer_pubkey,
+                sig,
+            );
+        }
+
+        if sent.landed > 0 {
+            span.in_scope(|| {
+                info!(
+                    "Liquidated {} of {}'s perps. tx: {:?}",
+                    sent.landed, liqee_margin.authority, sent.signatures
+                )
+            });
+        }
+
+        // Targets that landed are left out of any retry.
+        let landed: Vec<usize> = sized[..sent.landed]
+            .iter()
+            .map(|(target, _)| target.index)
+            .collect();
+        remaining.retain(|(target, _)| !landed.contains(&target.index));
+
+        sent_result(sent.error)?;
+        Ok(Some(()))
+    })?;
+
+    landed.ok_or(ErrorCode::LiquidationFailure)
 }
 
 fn liquidate_spot_position(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
     program: &Program,
     payer_pubkey: &Pubkey,
     liqor_margin: &Margin,
     liqor_margin_key: &Pubkey,
     liqor_control: &Control,
+    capacity: &PayerCapacity,
     liqee_margin: &Margin,
     liqee_margin_key: &Pubkey,
     liqee_control: &Control,
     cache: &Cache,
+    oracles: &OracleIndex,
     cache_key: &Pubkey,
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
     asset_index: usize,
     quote_index: usize,
-    serum_markets: HashMap<usize, SerumMarketState>,
+    serum_markets: &HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
-    serum_vault_signers: HashMap<usize, Pubkey>,
-) -> Result<(), ErrorCode> {
+    serum_vault_signers: &HashMap<usize, Pubkey>,
+) -> Result<I80F48, ErrorCode> {
     let span = error_span!("liquidate_spot_position");
 
     let asset_collateral_info = state.collaterals[asset_index];
     let quote_collateral_info = state.collaterals[quote_index];
+    let symbol = String::from(asset_collateral_info.oracle_symbol);
 
-    let quote_price: I80F48 =
-        get_oracle(cache, &quote_collateral_info.oracle_symbol)
-            .unwrap()
-            .price
-            .into();
-
-    let asset_price: I80F48 =
-        get_oracle(cache, &asset_collateral_info.oracle_symbol)
-            .unwrap()
-            .price
-            .into();
+    let quote_price: I80F48 = oracles
+        .collateral(cache, quote_index)
+        .ok_or(ErrorCode::MissingOracle)?
+        .price
+        .into();
 
-    let asset_transfer_lots =
-        get_total_account_value(liqor_margin, liqor_control, state, cache)
-            * I80F48::from_num(5u8);
+    let asset_price: I80F48 = oracles
+        .collateral(cache, asset_index)
+        .ok_or(ErrorCode::MissingOracle)?
+        .price
+        .into();
 
     let size_estimate = estimate_spot_liquidation_size(
         liqee_margin,
         liqee_control,
         state,
         cache,
+        oracles,
         asset_index,
         quote_index,
     );
 
-    let fudge = I80F48::from_str_binary("1.1").unwrap();
-    let mut usdc_amount = match size_estimate {
-        Some(size_estimate) => {
-            let amount = size_estimate * fudge;
-            amount.min(asset_transfer_lots)
-        }
+    let fudge = I80F48::from_num(SPOT_SIZE_FUDGE);
+    let total =
+        liqor_capacity(liqor_margin, liqor_control, state, cache, oracles)?;
+    let reserved = match size_estimate {
+        Some(size_estimate) => capacity.reserve(total, size_estimate * fudge),
         None => I80F48::ZERO,
     };
+    let usdc_amount = reserved;
 
     debug!(
         "{}: {}sUSD s{} -> s{}",
@@ -663,144 +1316,406 @@ fn liquidate_spot_position(
         program_id: program.id(),
     };
 
-    let mut swap_ixs: Vec<Instruction> = Vec::new();
+    let sizes = reduced_sizes(
+        usdc_amount,
+        I80F48::from_num(cfg.reduction_factor),
+        cfg.reduction_max,
+    );
 
-    if let (Some(serum_market), Some(serum_vault_signer)) = (
-        serum_markets.get(&quote_index),
-        serum_vault_signers.get(&quote_index),
-    ) {
-        // Rebalance the quote (which is what was received)
-        // Make sure that it's not a zero-transfer
-        if usdc_amount.abs() / quote_price
-            > I80F48::from_num(2 * serum_market.coin_lot_size)
-        {
-            debug!(
-                "Rebalancing {} s{}",
-                usdc_amount,
-                String::from(asset_collateral_info.oracle_symbol)
-            );
-            let remove_quote = swap::make_swap_ix(
-                program,
-                payer_pubkey,
-                state,
-                state_key,
-                state_signer,
-                liqor_margin_key,
-                &liqor_margin.control,
-                serum_market,
-                serum_dex_program,
-                serum_vault_signer,
-                999_999_999_999_999u64,
-                false,
-                quote_index,
-            )?;
+    let landed = retry_reduced(sizes, |usdc_amount| {
+        let asset_transfer_amount =
+            -(usdc_amount / asset_price).to_num::<i64>();
+        if asset_transfer_amount == 0 {
+            return Ok(None);
+        }
 
-            swap_ixs.push(remove_quote);
+        liq_ix.data = instruction::LiquidateSpotPosition {
+            asset_transfer_amount,
         }
-    }
+        .data();
 
-    if let (Some(serum_market), Some(serum_vault_signer)) = (
-        serum_markets.get(&asset_index),
-        serum_vault_signers.get(&asset_index),
-    ) {
-        // Rebalance the asset (which is what was given)
-        if usdc_amount.abs() / asset_price
-            >= I80F48::from_num(2 * serum_market.coin_lot_size)
-                * (I80F48::ONE / (fudge - I80F48::ONE) + I80F48::ONE)
-        {
-            debug!(
-                "Rebalancing {} s{}",
-                usdc_amount / asset_price,
-                String::from(asset_collateral_info.oracle_symbol)
-            );
-            let remove_debt = swap::make_swap_ix(
-                // amount is what is what is being sold always usdc here
-                program,
-                payer_pubkey,
-                state,
-                state_key,
-                state_signer,
-                liqor_margin_key,
-                &liqor_margin.control,
-                serum_market,
-                serum_dex_program,
-                serum_vault_signer,
-                usdc_amount.ceil().to_num(),
-                true,
-                asset_index,
-            )?;
+        // The rebalance is built for each size, so it never swaps more
+        // than the liquidation gave.
+        let mut swap_groups: Vec<Atomic> = Vec::new();
 
-            let remove_excess = swap::make_swap_ix(
+        if cfg.rebalance_venue == RebalanceVenue::Jupiter {
+            match jupiter_rebalance_ixs(
+                cfg,
                 program,
                 payer_pubkey,
+                liqor_margin,
+                liqor_margin_key,
                 state,
                 state_key,
                 state_signer,
-                liqor_margin_key,
-                &liqor_margin.control,
-                serum_market,
-                serum_dex_program,
-                serum_vault_signer,
-                999_999_999_999_999u64,
-                false,
                 asset_index,
-            )?;
+                quote_index,
+                (usdc_amount.abs() / quote_price).to_num(),
+                usdc_amount.abs().ceil().to_num(),
+            ) {
+                // Each leg is sent in a transaction of its own, so must
+                // fit in one.
+                Ok(Some(legs))
+                    if legs.iter().all(|leg| {
+                        fits_own_transaction(st, cfg, payer_pubkey, leg)
+                    }) =>
+                {
+                    swap_groups = legs
+                }
+                Ok(Some(_)) => span.in_scope(|| {
+                    info!(
+                        "Jupiter route doesn't fit in a transaction, \
+                         falling back to serum"
+                    )
+                }),
+                Ok(None) => span.in_scope(|| {
+                    info!("No Jupiter route, falling back to serum")
+                }),
+                Err(e) => span.in_scope(|| {
+                    warn!("Jupiter failed, falling back to serum: {:?}", e)
+                }),
+            }
+        }
 
-            swap_ixs.push(remove_debt);
-            swap_ixs.push(remove_excess);
+        let use_serum = swap_groups.is_empty();
+
+        if let (true, Some(serum_market), Some(serum_vault_signer)) = (
+            use_serum,
+            serum_markets.get(&quote_index),
+            serum_vault_signers.get(&quote_index),
+        ) {
+            // Rebalance the quote (which is what was received)
+            // Make sure that it's not a zero-transfer
+            if usdc_amount.abs() / quote_price
+                > I80F48::from_num(2 * serum_market.coin_lot_size)
+            {
+                debug!(
+                    "Rebalancing {} s{}",
+                    usdc_amount,
+                    String::from(asset_collateral_info.oracle_symbol)
+                );
+                let remove_quote = swap::make_swap_ix(
+                    program,
+                    payer_pubkey,
+                    state,
+                    state_key,
+                    state_signer,
+                    liqor_margin_key,
+                    &liqor_margin.control,
+                    serum_market,
+                    serum_dex_program,
+                    serum_vault_signer,
+                    999_999_999_999_999u64,
+                    false,
+                    quote_index,
+                    quote_price,
+                    I80F48::from_num(cfg.max_swap_slippage),
+                )?;
+
+                swap_groups.push(vec![(remove_quote, SWAP_CU)]);
+            }
         }
-    }
 
-    let reduction_max = 5;
-    for _reduction in 0..reduction_max {
-        let signature = retry_send(
-            || {
-                let mut request_builder = program
-                    .request()
-                    .instruction(liq_ix.clone())
-                    .options(CommitmentConfig::confirmed());
+        if let (true, Some(serum_market), Some(serum_vault_signer)) = (
+            use_serum,
+            serum_markets.get(&asset_index),
+            serum_vault_signers.get(&asset_index),
+        ) {
+            // Rebalance the asset (which is what was given)
+            if usdc_amount.abs() / asset_price
+                >= I80F48::from_num(2 * serum_market.coin_lot_size)
+                    * (I80F48::ONE / (fudge - I80F48::ONE) + I80F48::ONE)
+            {
+                debug!(
+                    "Rebalancing {} s{}",
+                    usdc_amount / asset_price,
+                    String::from(asset_collateral_info.oracle_symbol)
+                );
+                let remove_debt = swap::make_swap_ix(
+                    // amount is what is what is being sold always usdc here
+                    program,
+                    payer_pubkey,
+                    state,
+                    state_key,
+                    state_signer,
+                    liqor_margin_key,
+                    &liqor_margin.control,
+                    serum_market,
+                    serum_dex_program,
+                    serum_vault_signer,
+                    usdc_amount.ceil().to_num(),
+                    true,
+                    asset_index,
+                    asset_price,
+                    I80F48::from_num(cfg.max_swap_slippage),
+                )?;
+
+                let remove_excess = swap::make_swap_ix(
+                    program,
+                    payer_pubkey,
+                    state,
+                    state_key,
+                    state_signer,
+                    liqor_margin_key,
+                    &liqor_margin.control,
+                    serum_market,
+                    serum_dex_program,
+                    serum_vault_signer,
+                    999_999_999_999_999u64,
+                    false,
+                    asset_index,
+                    asset_price,
+                    I80F48::from_num(cfg.max_swap_slippage),
+                )?;
+
+                swap_groups.push(vec![(remove_debt, SWAP_CU)]);
+                swap_groups.push(vec![(remove_excess, SWAP_CU)]);
+            }
+        }
 
-                for ix in swap_ixs.clone() {
-                    request_builder = request_builder.instruction(ix);
-                }
-                request_builder
-            },
+        let sent = send_bundle(
+            st,
+            program,
+            payer_pubkey,
+            cfg.priority_fee,
+            rebalanced_bundle(
+                vec![(liq_ix.clone(), LIQUIDATE_SPOT_CU)],
+                swap_groups,
+            ),
             5,
+            cfg.simulate_first,
         );
 
-        match signature {
-            Ok(tx) => {
-                span.in_scope(|| {
-                    info!(
-                        "Liquidated {}'s spot. tx: {:?}",
-                        liqee_margin.authority, tx
-                    )
-                });
-                return Ok(());
+        let outcome = match sent.error {
+            None => metrics::Outcome::Success,
+            Some(_) if sent.landed > 0 => metrics::Outcome::Success,
+            Some(ErrorCode::LiquidationOverExposure) => {
+                metrics::Outcome::Reduced
             }
-            Err(e) => match e {
-                ErrorCode::LiquidationOverExposure => {
-                    usdc_amount /= 2;
-                    liq_ix.data = instruction::LiquidateSpotPosition {
-                        asset_transfer_amount: -(usdc_amount / asset_price)
-                            .to_num::<i64>(),
-                    }
-                    .data();
-                }
-                _ => {
-                    return Err(ErrorCode::LiquidationFailure);
-                }
-            },
+            Some(_) => metrics::Outcome::Failure,
+        };
+        metrics::record(metrics::Kind::Spot, &symbol, outcome);
+
+        for sig in &sent.signatures {
+            metrics::record_tx(
+                metrics::Kind::Spot,
+                &liqee_margin.authority,
+                payer_pubkey,
+                sig,
+            );
         }
+
+        span.in_scope(|| liquidation_result(sent.landed, sent.error))?;
+        span.in_scope(|| {
+            info!(
+                "Liquidated {}'s spot. tx: {:?}",
+                liqee_margin.authority, sent.signatures
+            )
+        });
+        Ok(Some(usdc_amount))
+    });
+
+    if !matches!(landed, Ok(Some(_))) {
+        capacity.release(reserved);
     }
-    return Err(ErrorCode::LiquidationFailure);
+
+    landed?.ok_or_else(|| {
+        metrics::record(
+            metrics::Kind::Spot,
+            &symbol,
+            metrics::Outcome::Failure,
+        );
+        ErrorCode::LiquidationFailure
+    })
+}
+
+/// Whether the group `ixs` fits in a transaction on its own.
+fn fits_own_transaction(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
+    payer_pubkey: &Pubkey,
+    ixs: &Atomic,
+) -> bool {
+    let nonce = st.nonce().map(|n| n.key());
+
+    fits_transaction(
+        payer_pubkey,
+        cfg.priority_fee,
+        nonce.as_ref(),
+        std::slice::from_ref(ixs),
+    )
+}
+
+/// The bundle sending the liquidation `liq` followed by the groups
+/// rebalancing it. Each rebalancing group is separate, so a bundle too
+/// large for one transaction is split with the liquidation landing first.
+fn rebalanced_bundle(liq: Atomic, rebalance: Vec<Atomic>) -> Vec<Atomic> {
+    std::iter::once(liq).chain(rebalance).collect()
+}
+
+/// Jupiter instructions selling the quote received in a spot liquidation
+/// for USDC, then buying back the asset given with USDC, a group for each
+/// leg. Returns `None` if either leg has no route.
+fn jupiter_rebalance_ixs(
+    cfg: &LiquidatorConfig,
+    program: &Program,
+    payer_pubkey: &Pubkey,
+    liqor_margin: &Margin,
+    liqor_margin_key: &Pubkey,
+    state: &State,
+    state_key: &Pubkey,
+    state_signer: &Pubkey,
+    asset_index: usize,
+    quote_index: usize,
+    quote_amount: u64,
+    usdc_amount: u64,
+) -> Result<Option<Vec<Atomic>>, ErrorCode> {
+    let jupiter = Jupiter::shared(&cfg.jupiter_url, cfg.jupiter_slippage_bps);
+
+    // The quote leg needs no borrowing since it was just received, while
+    // the USDC leg may borrow against it.
+    let legs = [
+        (quote_index, 0, quote_amount, false),
+        (0, asset_index, usdc_amount, true),
+    ];
+
+    let mut ixs = Vec::new();
+
+    for (input_index, output_index, amount, allow_borrow) in legs {
+        if input_index == output_index || amount == 0 {
+            continue;
+        }
+
+        match jupiter.rebalance_ixs(
+            program,
+            payer_pubkey,
+            state,
+            state_key,
+            state_signer,
+            liqor_margin_key,
+            &liqor_margin.control,
+            input_index,
+            output_index,
+            amount,
+            allow_borrow,
+        )? {
+            Some(x) => ixs.push(x),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(ixs))
+}
+
+/// The indices of the `k` largest non-zero positions by notional,
+/// largest first.
+fn largest_positions(notionals: &[I80F48], k: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..notionals.len())
+        .filter(|&i| !notionals[i].is_zero())
+        .collect();
+
+    indices.sort_by_key(|&i| std::cmp::Reverse(notionals[i].abs()));
+    indices.truncate(k);
+    indices
+}
+
+/// The lots to liquidate of each position, from the lots it needs and the
+/// most the liqor could take on of it alone. Positions take what they
+/// need of the liqor's capacity in order, so what one leaves goes to the
+/// next rather than the capacity being split evenly between them.
+fn allocate_capacity(wants: &[(u64, i64)]) -> Vec<u64> {
+    // The share of the liqor's capacity left.
+    let mut left = I80F48::ONE;
+
+    wants
+        .iter()
+        .map(|&(lots, capacity)| {
+            if capacity <= 0 {
+                return 0;
+            }
+
+            let capacity = I80F48::from_num(capacity);
+            let x = (capacity * left).floor().to_num::<u64>().min(lots);
+            left -= I80F48::from_num(x) / capacity;
+            x
+        })
+        .collect()
+}
+
+/// Sizes to attempt a liquidation with: `start`, then scaled by `factor`
+/// after each rejection for over-exposure, for at most `max` attempts.
+fn reduced_sizes(
+    start: I80F48,
+    factor: I80F48,
+    max: usize,
+) -> impl Iterator<Item = I80F48> {
+    std::iter::successors(Some(start), move |&x| Some(x * factor)).take(max)
+}
+
+/// Attempts a liquidation with each of `sizes` in turn, usually from
+/// `reduced_sizes`, until `attempt` lands one, returning what it gives.
+/// Over-exposure moves on to the next size, and any other error is
+/// returned. `attempt` returns `None` once a size is below what can be
+/// liquidated, which stops the retries as running out of sizes does, with
+/// `None`.
+fn retry_reduced<S, T>(
+    sizes: impl IntoIterator<Item = S>,
+    mut attempt: impl FnMut(S) -> Result<Option<T>, ErrorCode>,
+) -> Result<Option<T>, ErrorCode> {
+    for size in sizes {
+        match attempt(size) {
+            Ok(Some(x)) => return Ok(Some(x)),
+            Ok(None) => break,
+            Err(ErrorCode::LiquidationOverExposure) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(None)
+}
+
+/// The result of an attempt that sent `error`, keeping the errors the
+/// retries act on and folding the rest into a failure.
+fn sent_result(error: Option<ErrorCode>) -> Result<(), ErrorCode> {
+    match error {
+        None => Ok(()),
+        Some(e @ ErrorCode::LiquidationOverExposure)
+        | Some(e @ ErrorCode::UnrecoverableTransactionError) => Err(e),
+        Some(_) => Err(ErrorCode::LiquidationFailure),
+    }
+}
+
+/// The result of sending a bundle built by `rebalanced_bundle`, of which
+/// `landed` groups landed. Once the liquidation has landed, a failed
+/// rebalance is only logged, since retrying would liquidate again.
+fn liquidation_result(
+    landed: usize,
+    error: Option<ErrorCode>,
+) -> Result<(), ErrorCode> {
+    match error {
+        Some(e) if landed > 0 => {
+            warn!("Liquidation landed, but its rebalance failed: {:?}", e);
+            Ok(())
+        }
+        e => sent_result(e),
+    }
+}
+
+/// Whether the account owes collateral `i`. `WrappedI80F48` is only a
+/// wrapper around the bytes, so it's compared as an `I80F48`.
+fn is_negative_collateral(margin: &Margin, i: usize) -> bool {
+    I80F48::from(margin.collateral[i]) < I80F48::ZERO
 }
 
 fn settle_bankruptcy(
+    st: &crate::AppState,
+    cfg: &LiquidatorConfig,
     program: &Program,
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
+    cache: &Cache,
+    oracles: &OracleIndex,
     cache_key: &Pubkey,
     liqor_key: &Pubkey,
     liqor_margin_key: &Pubkey,
@@ -808,9 +1723,9 @@ fn settle_bankruptcy(
     liqee_margin: &Margin,
     liqee_margin_key: &Pubkey,
     liqee_colls: Vec<I80F48>,
-    serum_markets: HashMap<usize, SerumMarketState>,
+    serum_markets: &HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
-    serum_vault_signers: HashMap<usize, Pubkey>,
+    serum_vault_signers: &HashMap<usize, Pubkey>,
 ) -> Result<(), ErrorCode> {
     let span = error_span!(
         "settle_bankruptcy",
@@ -821,7 +1736,7 @@ fn settle_bankruptcy(
         Vec::with_capacity(MAX_COLLATERALS as usize);
 
     for (i, mint) in state.collaterals.iter().map(|c| &c.mint).enumerate() {
-        if { liqee_margin.collateral[i] } >= WrappedI80F48::zero()
+        if !is_negative_collateral(liqee_margin, i)
             || mint.eq(&Pubkey::default())
         {
             continue;
@@ -835,6 +1750,14 @@ fn settle_bankruptcy(
                 if amount == 0 || amount <= 2 * serum_market.coin_lot_size {
                     None
                 } else {
+                    let price: I80F48 = match oracles.collateral(cache, i) {
+                        Some(oracle) => oracle.price.into(),
+                        None => {
+                            error!("No oracle found for collateral {}", i);
+                            return Err(ErrorCode::SettlementFailure);
+                        }
+                    };
+
                     Some(swap::make_swap_ix(
                         program,
                         liqor_key,
@@ -849,31 +1772,40 @@ fn settle_bankruptcy(
                         amount,
                         true,
                         i,
+                        price,
+                        I80F48::from_num(cfg.max_swap_slippage),
                     )?)
                 }
             } else {
                 None
             };
 
+        let units = SETTLE_BANKRUPTCY_CU
+            + if swap.is_some() { SWAP_CU } else { 0 };
+
         signature_results.push((
             i,
             retry_send(
+                st,
                 || {
-                    let request_builder = program
-                        .request()
-                        .accounts(ix_accounts::SettleBankruptcy {
-                            state: *state_key,
-                            state_signer: *state_signer,
-                            cache: *cache_key,
-                            liqor: *liqor_key,
-                            liqor_margin: *liqor_margin_key,
-                            liqor_control: *liqor_control_key,
-                            liqee_margin: *liqee_margin_key,
-                            liqee_control: liqee_margin.control,
-                            asset_mint: *mint,
-                        })
-                        .args(instruction::SettleBankruptcy {})
-                        .options(CommitmentConfig::confirmed());
+                    let request_builder = with_compute_budget(
+                        program.request(),
+                        units,
+                        cfg.priority_fee,
+                    )
+                    .accounts(ix_accounts::SettleBankruptcy {
+                        state: *state_key,
+                        state_signer: *state_signer,
+                        cache: *cache_key,
+                        liqor: *liqor_key,
+                        liqor_margin: *liqor_margin_key,
+                        liqor_control: *liqor_control_key,
+                        liqee_margin: *liqee_margin_key,
+                        liqee_control: liqee_margin.control,
+                        asset_mint: *mint,
+                    })
+                    .args(instruction::SettleBankruptcy {})
+                    .options(CommitmentConfig::confirmed());
 
                     match swap.clone() {
                         Some(ix) => request_builder.instruction(ix),
@@ -881,13 +1813,29 @@ fn settle_bankruptcy(
                     }
                 },
                 5,
+                cfg.simulate_first,
             ),
         ));
     }
 
     for (i, signature) in signature_results.iter() {
+        metrics::record(
+            metrics::Kind::Bankruptcy,
+            &String::from(state.collaterals[*i].oracle_symbol),
+            match signature {
+                Ok(_) => metrics::Outcome::Success,
+                Err(_) => metrics::Outcome::Failure,
+            },
+        );
+
         match signature {
             Ok(tx) => {
+                metrics::record_tx(
+                    metrics::Kind::Bankruptcy,
+                    &liqee_margin.authority,
+                    liqor_key,
+                    tx,
+                );
                 span.in_scope(|| {
                     info!(
                         "Settled margin {}'s {} collateral. tx: {:?}",
@@ -909,3 +1857,297 @@ fn settle_bankruptcy(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spot_branch_at_dust_threshold() {
+        use bytemuck::Zeroable;
+
+        let mut state = State::zeroed();
+        state.collaterals[0].weight = 1000;
+        state.collaterals[1].weight = 900;
+        let dust = I80F48::from_num(100);
+        let x = |v: i64| I80F48::from_num(v);
+
+        assert_eq!(
+            spot_branch(&state, &[x(-500), x(99)], dust),
+            SpotBranch::Bankruptcy
+        );
+        // Collateral at the threshold isn't dust, so is liquidated against
+        // rather than the account settled.
+        assert_eq!(
+            spot_branch(&state, &[x(-500), x(100)], dust),
+            SpotBranch::Spot { asset: 0, quote: 1 }
+        );
+        // Nor is a borrow at the threshold.
+        assert_eq!(
+            spot_branch(&state, &[x(-100), x(500)], dust),
+            SpotBranch::Spot { asset: 0, quote: 1 }
+        );
+        assert_eq!(
+            spot_branch(&state, &[x(-99), x(500)], dust),
+            SpotBranch::None
+        );
+        assert_eq!(
+            spot_branch(&state, &[x(-50), x(99)], dust),
+            SpotBranch::None
+        );
+    }
+
+    #[test]
+    fn test_describe_outcome() {
+        use bytemuck::Zeroable;
+
+        let mut state = State::zeroed();
+        state.perp_markets[1].symbol = "SOL-PERP".try_into().unwrap();
+        state.collaterals[0].oracle_symbol = "USDC".try_into().unwrap();
+        state.collaterals[2].oracle_symbol = "SOL".try_into().unwrap();
+
+        assert_eq!(
+            LiquidationOutcome::Perp { index: 1, lots: 40 }.describe(&state),
+            "liquidated 40 lots of SOL-PERP"
+        );
+        assert_eq!(
+            LiquidationOutcome::Spot {
+                asset: 2,
+                quote: 0,
+                usdc: I80F48::from_num(12_345_678),
+            }
+            .describe(&state),
+            "liquidated SOL against USDC, worth 12.35 USD"
+        );
+    }
+
+    #[test]
+    fn test_is_negative_collateral() {
+        use bytemuck::Zeroable;
+
+        let mut margin = Margin::zeroed();
+        margin.collateral[1] = (-I80F48::DELTA).into();
+        margin.collateral[2] = I80F48::DELTA.into();
+        margin.collateral[3] = I80F48::from_num(-1_000_000).into();
+
+        assert!(!is_negative_collateral(&margin, 0));
+        assert!(is_negative_collateral(&margin, 1));
+        assert!(!is_negative_collateral(&margin, 2));
+        assert!(is_negative_collateral(&margin, 3));
+    }
+
+    #[test]
+    fn test_market_at() {
+        use bytemuck::Zeroable;
+
+        let markets = [Some(MarketState::zeroed()), None];
+
+        assert!(market_at(&markets, 0).is_ok());
+        assert!(matches!(
+            market_at(&markets, 1),
+            Err(ErrorCode::UnsupportedState)
+        ));
+        assert!(matches!(
+            market_at(&markets, 2),
+            Err(ErrorCode::UnsupportedState)
+        ));
+    }
+
+    #[test]
+    fn test_reduced_sizes_budget() {
+        let sizes: Vec<I80F48> = reduced_sizes(
+            I80F48::from_num(1000),
+            I80F48::from_num(0.5f64),
+            5,
+        )
+        .collect();
+
+        let expected: Vec<I80F48> = [1000f64, 500., 250., 125., 62.5]
+            .iter()
+            .map(|&x| I80F48::from_num(x))
+            .collect();
+
+        assert_eq!(sizes, expected);
+    }
+
+    /// Retries `reduced_sizes` of `start`, as whole lots, against a
+    /// program accepting up to `accepted` lots, or failing with `error`
+    /// if set. Returns what landed and the lots of each attempt sent.
+    fn retry(
+        start: u64,
+        factor: f64,
+        max: usize,
+        accepted: u64,
+        error: Option<ErrorCode>,
+    ) -> (Result<Option<u64>, ErrorCode>, Vec<u64>) {
+        let mut sent = Vec::new();
+        let sizes = reduced_sizes(
+            I80F48::from_num(start),
+            I80F48::from_num(factor),
+            max,
+        );
+
+        let landed = retry_reduced(sizes, |lots| {
+            let lots: u64 = lots.to_num();
+            if lots == 0 {
+                return Ok(None);
+            }
+
+            sent.push(lots);
+            match error {
+                Some(ErrorCode::UnrecoverableTransactionError) => {
+                    sent_result(Some(ErrorCode::UnrecoverableTransactionError))
+                }
+                Some(_) => sent_result(Some(ErrorCode::LiquidationFailure)),
+                None if lots > accepted => {
+                    sent_result(Some(ErrorCode::LiquidationOverExposure))
+                }
+                None => sent_result(None),
+            }
+            .map(|()| Some(lots))
+        });
+
+        (landed, sent)
+    }
+
+    #[test]
+    fn test_retry_reduced_rejections() {
+        // Two rejections for over-exposure, then 250 lands.
+        let (landed, sent) = retry(1000, 0.5, 5, 300, None);
+        assert_eq!(landed.unwrap(), Some(250));
+        assert_eq!(sent, vec![1000, 500, 250]);
+
+        // A gentler factor takes more attempts, six rejections, but lands
+        // closer to what's accepted.
+        let (landed, sent) = retry(1000, 0.8, 10, 300, None);
+        assert!(landed.unwrap().unwrap() > 250);
+        assert_eq!(sent.len(), 7);
+    }
+
+    #[test]
+    fn test_retry_reduced_floor() {
+        // Every size is rejected, and the retries stop once the size
+        // rounds down to nothing, before the budget runs out.
+        let (landed, sent) = retry(4, 0.5, 10, 0, None);
+        assert_eq!(landed.unwrap(), None);
+        assert_eq!(sent, vec![4, 2, 1]);
+    }
+
+    #[test]
+    fn test_retry_reduced_gives_up() {
+        // Out of attempts.
+        let (landed, sent) = retry(1000, 0.5, 3, 10, None);
+        assert_eq!(landed.unwrap(), None);
+        assert_eq!(sent, vec![1000, 500, 250]);
+
+        // Any other error stops at the first attempt.
+        let (landed, sent) =
+            retry(1000, 0.5, 5, 10, Some(ErrorCode::LiquidationFailure));
+        assert!(matches!(landed, Err(ErrorCode::LiquidationFailure)));
+        assert_eq!(sent, vec![1000]);
+
+        let (landed, sent) = retry(
+            1000,
+            0.5,
+            5,
+            10,
+            Some(ErrorCode::UnrecoverableTransactionError),
+        );
+        assert!(matches!(
+            landed,
+            Err(ErrorCode::UnrecoverableTransactionError)
+        ));
+        assert_eq!(sent, vec![1000]);
+    }
+
+    #[test]
+    fn test_largest_positions() {
+        let notionals: Vec<I80F48> = [10f64, -40., 0., 25., -5.]
+            .iter()
+            .map(|&x| I80F48::from_num(x))
+            .collect();
+
+        assert_eq!(largest_positions(&notionals, 3), vec![1, 3, 0]);
+        assert_eq!(largest_positions(&notionals, 1), vec![1]);
+        assert_eq!(largest_positions(&notionals, 10), vec![1, 3, 0, 4]);
+    }
+
+    #[test]
+    fn test_allocate_capacity_uneven() {
+        // A small position leaves what it doesn't need to the next, where
+        // an even split would have capped that at 50.
+        assert_eq!(allocate_capacity(&[(10, 100), (150, 100)]), vec![10, 90]);
+
+        // Half the capacity of the first market, then all of the rest in
+        // the second, leaving none for the third.
+        assert_eq!(
+            allocate_capacity(&[(30, 60), (100, 200), (50, 40)]),
+            vec![30, 100, 0]
+        );
+
+        // A liqor without capacity in a market takes none of it.
+        assert_eq!(allocate_capacity(&[(10, 0), (10, 20)]), vec![0, 10]);
+    }
+
+    #[test]
+    fn test_payer_capacity() {
+        let x = |v: i64| I80F48::from_num(v);
+        let capacity = PayerCapacity::default();
+
+        // Liquidations sized at once share the capacity rather than each
+        // taking all of it.
+        assert_eq!(capacity.reserve(x(100), x(60)), x(60));
+        assert_eq!(capacity.reserve(x(100), x(60)), x(40));
+        assert_eq!(capacity.reserve(x(100), x(10)), x(0));
+
+        // A failed liquidation gives its reservation back.
+        capacity.release(x(40));
+        assert_eq!(capacity.reserve(x(100), x(50)), x(40));
+
+        // A payer refetched with less capacity caps what is left.
+        capacity.release(x(40));
+        assert_eq!(capacity.reserve(x(20), x(50)), x(20));
+        assert_eq!(capacity.reserve(x(100), x(-5)), x(0));
+    }
+
+    #[test]
+    fn test_rebalanced_bundle_splits() {
+        use anchor_lang::solana_program::instruction::AccountMeta;
+
+        let payer = Pubkey::new_unique();
+        let shared: Vec<Pubkey> =
+            (0..8).map(|_| Pubkey::new_unique()).collect();
+
+        // Like the zo instructions, each uses the shared state, cache and
+        // margin accounts, and `own` accounts of its market.
+        let ix = |own: usize, units: u32| {
+            let accounts = shared
+                .iter()
+                .copied()
+                .chain((0..own).map(|_| Pubkey::new_unique()))
+                .map(|k| AccountMeta::new(k, false))
+                .collect();
+            let ix = Instruction {
+                program_id: shared[0],
+                accounts,
+                data: vec![0; 40],
+            };
+
+            (ix, units)
+        };
+
+        let liq = vec![ix(1, LIQUIDATE_SPOT_CU)];
+        let swaps = (0..3).map(|_| vec![ix(12, SWAP_CU)]).collect();
+        let bundle = rebalanced_bundle(liq.clone(), swaps);
+
+        // A spot liquidation and its three serum swaps are over the packet
+        // size in one transaction.
+        let whole: Atomic = bundle.iter().flatten().cloned().collect();
+        assert!(!fits_transaction(&payer, Some(1), None, &[whole]));
+
+        let txns = split_bundle(&payer, Some(1), None, bundle).unwrap();
+        assert!(txns.len() > 1);
+        assert_eq!(txns.iter().map(|t| t.len()).sum::<usize>(), 4);
+        assert_eq!(txns[0][0], liq);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{liquidator::accounts::DbWrapper, Error};
+use crate::{
+    liquidator::accounts::DbWrapper, utils::decode_account_data, Error,
+};
 use anchor_client::solana_client::rpc_config::{
     RpcAccountInfoConfig, RpcProgramAccountsConfig,
 };
@@ -6,13 +8,26 @@ use anchor_lang::Discriminator;
 use bytemuck::Pod;
 use futures::StreamExt;
 use jsonrpc_core_client::transports::ws;
-use solana_account_decoder::{UiAccountData, UiAccountEncoding};
+use parking_lot::{const_mutex, Mutex};
+use solana_account_decoder::UiAccountEncoding;
 use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
 use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
 use tracing::{debug, info, warn};
 use zo_abi::{Cache, Control, Margin, State};
 
+// When the listener last received an account update.
+static LAST_UPDATE: Mutex<Option<Instant>> = const_mutex(None);
+
+/// Time since the listener last received an account update, or `None` if
+/// it hasn't received any yet.
+pub fn since_last_update() -> Option<Duration> {
+    LAST_UPDATE.lock().map(|t| t.elapsed())
+}
+
 fn load_buf<T: Pod + Discriminator>(b: &[u8]) -> Option<&T> {
     match b.len() == 8 + std::mem::size_of::<T>()
         && b[..8] == T::discriminator()
@@ -27,6 +42,8 @@ pub async fn start_listener(
     pid: &Pubkey,
     ws_url: String,
     db: DbWrapper,
+    stall_after: Duration,
+    commitment: CommitmentConfig,
 ) {
     let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
@@ -36,7 +53,7 @@ pub async fn start_listener(
         account_config: RpcAccountInfoConfig {
             encoding: Some(UiAccountEncoding::Base64),
             data_slice: None,
-            commitment: Some(CommitmentConfig::confirmed()),
+            commitment: Some(commitment),
             min_context_slot: None,
         },
         with_context: Some(false),
@@ -62,7 +79,26 @@ pub async fn start_listener(
             }
         };
 
-        while let Some(resp) = sub.next().await {
+        info!("connected");
+
+        // The subscription can stay open while delivering nothing, so
+        // reconnect if it has been quiet for too long. The cache alone is
+        // updated every few seconds, so silence means a stall.
+        loop {
+            let resp = match tokio::time::timeout(stall_after, sub.next()).await
+            {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(_) => {
+                    warn!(
+                        "no updates in {} s, reconnecting",
+                        stall_after.as_secs()
+                    );
+                    break;
+                }
+            };
+            *LAST_UPDATE.lock() = Some(Instant::now());
+
             let resp = match resp {
                 Ok(x) => x,
                 Err(e) => {
@@ -71,26 +107,26 @@ pub async fn start_listener(
                 }
             };
 
-            let buf = &match resp.value.account.data {
-                UiAccountData::Binary(b, _) => base64::decode(b).unwrap(),
-                _ => panic!(),
-            };
             let pk = &resp.value.pubkey;
+            let buf = &match decode_account_data(pk, resp.value.account.data) {
+                Some(x) => x,
+                None => continue,
+            };
 
             if let Some(a) = load_buf::<Control>(buf) {
                 debug!("got control data: {}", pk);
                 let pk = Pubkey::from_str(pk).unwrap();
-                db.get().lock().unwrap().update_control(pk, *a);
+                db.get().write().update_control(pk, *a);
             } else if let Some(a) = load_buf::<Margin>(buf) {
                 debug!("got margin data: {}", pk);
                 let pk = Pubkey::from_str(pk).unwrap();
-                db.get().lock().unwrap().update_margin(pk, *a);
+                db.get().write().update_margin(pk, *a);
             } else if let Some(a) = load_buf::<Cache>(buf) {
                 debug!("got cache data: {}", pk);
-                db.get().lock().unwrap().update_cache(*a);
+                db.get().write().update_cache(*a);
             } else if let Some(a) = load_buf::<State>(buf) {
                 debug!("got state data: {}", pk);
-                db.get().lock().unwrap().update_state(*a);
+                db.get().write().update_state(*a);
             } else {
                 debug!("unknown account type, skipping");
             }
@@ -2,6 +2,8 @@ use fixed::types::I80F48;
 
 use std::cell::Ref;
 
+use tracing::error;
+
 use zo_abi::{
     Cache, Control, FractionType, Margin, PerpType, State, MAX_COLLATERALS,
     MAX_MARKETS, SPOT_INITIAL_MARGIN_REQ, SPOT_MAINT_MARGIN_REQ,
@@ -10,7 +12,7 @@ use zo_abi::{
 use crate::liquidator::{error::ErrorCode, math::*, utils::*};
 
 #[derive(Clone, Copy)]
-enum MfReturnOption {
+pub enum MfReturnOption {
     Mf,
     Imf,
     Mmf,
@@ -18,10 +20,40 @@ enum MfReturnOption {
     Cmf,
 }
 
+/// The number of markets in use, checked against the size of the state's
+/// arrays, so that a state from a newer program is an error rather than
+/// an out of bounds panic.
+pub fn active_markets(state: &State) -> Result<usize, ErrorCode> {
+    let n = state.total_markets as usize;
+
+    if n > MAX_MARKETS {
+        error!("State has {} markets, at most {} supported", n, MAX_MARKETS);
+        return Err(ErrorCode::UnsupportedState);
+    }
+
+    Ok(n)
+}
+
+/// The number of collaterals in use, see `active_markets`.
+pub fn active_collaterals(state: &State) -> Result<usize, ErrorCode> {
+    let n = state.total_collaterals as usize;
+
+    if n > MAX_COLLATERALS {
+        error!(
+            "State has {} collaterals, at most {} supported",
+            n, MAX_COLLATERALS
+        );
+        return Err(ErrorCode::UnsupportedState);
+    }
+
+    Ok(n)
+}
+
 pub fn get_actual_collateral_vec(
     margin: &Margin,
     state: &Ref<State>,
     cache: &Ref<Cache>,
+    oracles: &OracleIndex,
     is_weighted: bool,
 ) -> Result<Vec<I80F48>, ErrorCode> {
     let mut vec = Vec::with_capacity({ margin.collateral }.len());
@@ -44,21 +76,22 @@ pub fn get_actual_collateral_vec(
             margin,
             borrow.supply_multiplier.into(),
             borrow.borrow_multiplier.into(),
-        )
-        .unwrap();
+        )?;
 
-        let oracle_cache = get_oracle(cache, &info.oracle_symbol).unwrap();
+        let oracle_cache = oracles
+            .collateral(cache, i)
+            .ok_or(ErrorCode::MissingOracle)?;
         let price: I80F48 = oracle_cache.price.into();
 
         // Price is only weighted when collateral is non-negative.
         let weighted_price = match is_weighted && v >= 0u64 {
-            true => safe_mul_i80f48(
+            true => try_mul_i80f48(
                 price,
                 I80F48::from_num(info.weight as f64 / 1000.0),
-            ),
+            )?,
             false => price,
         };
-        vec.push(safe_mul_i80f48(weighted_price, v));
+        vec.push(try_mul_i80f48(weighted_price, v)?);
     }
 
     Ok(vec)
@@ -80,9 +113,9 @@ pub fn calc_actual_collateral(
     borrow_multiplier: I80F48,
 ) -> Result<I80F48, ErrorCode> {
     if initial_col > I80F48::ZERO {
-        Ok(safe_mul_i80f48(initial_col, supply_multiplier))
+        try_mul_i80f48(initial_col, supply_multiplier)
     } else {
-        Ok(safe_mul_i80f48(initial_col, borrow_multiplier))
+        try_mul_i80f48(initial_col, borrow_multiplier)
     }
 }
 
@@ -148,8 +181,9 @@ fn get_position_open_vector(
 pub fn get_price_vector(
     state: &State,
     cache: &Cache,
+    oracles: &OracleIndex,
     position: &[I80F48; MAX_COLLATERALS + MAX_MARKETS], // Needed to determine interest rates
-) -> [I80F48; MAX_COLLATERALS + MAX_MARKETS] {
+) -> Result<[I80F48; MAX_COLLATERALS + MAX_MARKETS], ErrorCode> {
     // In sUSD/sAsset
     let mut price = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
 
@@ -161,24 +195,24 @@ pub fn get_price_vector(
             cache.borrow_cache[i].supply_multiplier.into()
         };
 
-        let unadjusted_price =
-            get_oracle(cache, &state.collaterals[i].oracle_symbol)
-                .unwrap()
-                .price
-                .into();
+        let unadjusted_price = oracles
+            .collateral(cache, i)
+            .ok_or(ErrorCode::MissingOracle)?
+            .price
+            .into();
 
-        price[i] = safe_mul_i80f48(unadjusted_price, adjustment);
+        price[i] = try_mul_i80f48(unadjusted_price, adjustment)?;
     }
 
     for i in 0..state.total_markets {
         let i = i as usize;
         match state.perp_markets[i].perp_type {
             PerpType::Future => {
-                price[i + MAX_COLLATERALS] =
-                    get_oracle(cache, &state.perp_markets[i].oracle_symbol)
-                        .unwrap()
-                        .price
-                        .into();
+                price[i + MAX_COLLATERALS] = oracles
+                    .market(cache, i)
+                    .ok_or(ErrorCode::MissingOracle)?
+                    .price
+                    .into();
             }
             PerpType::Square => {
                 price[i + MAX_COLLATERALS] = cache.marks[i].price.into();
@@ -189,18 +223,40 @@ pub fn get_price_vector(
         }
     }
 
-    price
+    Ok(price)
+}
+
+/// The cached funding index of each market, in smol quote per whole
+/// asset. It accumulates the market's funding over its lifetime, which
+/// for any realistic market stays many orders of magnitude below the
+/// integer range of I80F48, about ±6e23. A value outside of it can only
+/// come from a corrupt cache, so it's an error rather than a wrap or a
+/// panic, and the account is skipped.
+pub fn get_funding_vector(
+    cache: &Cache,
+) -> Result<[I80F48; MAX_MARKETS], ErrorCode> {
+    let mut funding = [I80F48::ZERO; MAX_MARKETS];
+
+    for (f, &x) in funding.iter_mut().zip({ cache.funding_cache }.iter()) {
+        *f = I80F48::checked_from_num(x).ok_or(ErrorCode::MathFailure)?;
+    }
+
+    Ok(funding)
 }
 
 pub fn get_pnl_vectors(
     control: &Control,
     state: &State,
     cache: &Cache,
+    oracles: &OracleIndex,
     funding_cache: &[I80F48; MAX_MARKETS], // In smol for the asset
-) -> (
-    [I80F48; MAX_COLLATERALS + MAX_MARKETS],
-    [I80F48; MAX_COLLATERALS + MAX_MARKETS],
-) {
+) -> Result<
+    (
+        [I80F48; MAX_COLLATERALS + MAX_MARKETS],
+        [I80F48; MAX_COLLATERALS + MAX_MARKETS],
+    ),
+    ErrorCode,
+> {
     let mut unrealized_pnls = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
     let mut realized_pnls = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
 
@@ -209,22 +265,25 @@ pub fn get_pnl_vectors(
             continue;
         }
         // Realized pnl calcs
-        let funding_diff = I80F48::from_num(info.funding_index)
-            .unwrapped_sub(funding_cache[i]);
-        let unrealized_funding =
-            safe_mul_i80f48(funding_diff, I80F48::from_num(info.pos_size))
-                .unwrapped_div(I80F48::from_num(
-                    10u64.pow(state.perp_markets[i].asset_decimals as u32),
-                )); // In smol asset
+        let funding_diff = try_sub_i80f48(
+            I80F48::checked_from_num(info.funding_index)
+                .ok_or(ErrorCode::MathFailure)?,
+            funding_cache[i],
+        )?;
+        let unrealized_funding = try_div_i80f48(
+            try_mul_i80f48(funding_diff, I80F48::from_num(info.pos_size))?,
+            I80F48::from_num(
+                10u64.pow(state.perp_markets[i].asset_decimals as u32),
+            ),
+        )?; // In smol asset
 
         // Unrealized pnl calcs
         let price = match state.perp_markets[i].perp_type {
-            PerpType::Future => {
-                get_oracle(cache, &state.perp_markets[i].oracle_symbol)
-                    .unwrap()
-                    .price
-                    .into()
-            }
+            PerpType::Future => oracles
+                .market(cache, i)
+                .ok_or(ErrorCode::MissingOracle)?
+                .price
+                .into(),
             PerpType::Square => cache.marks[i].price.into(),
             _ => {
                 println!("Not implemented bruh");
@@ -232,16 +291,19 @@ pub fn get_pnl_vectors(
             }
         };
 
-        let unrealized_pnl =
-            safe_mul_i80f48(I80F48::from_num(info.pos_size), price)
-                .unwrapped_add(I80F48::from_num(info.native_pc_total));
+        let unrealized_pnl = try_add_i80f48(
+            try_mul_i80f48(I80F48::from_num(info.pos_size), price)?,
+            I80F48::from_num(info.native_pc_total),
+        )?;
 
         unrealized_pnls[i + MAX_COLLATERALS] = unrealized_pnl;
 
-        realized_pnls[i + MAX_COLLATERALS] =
-            unrealized_funding + I80F48::from_num(info.realized_pnl);
+        realized_pnls[i + MAX_COLLATERALS] = try_add_i80f48(
+            unrealized_funding,
+            I80F48::from_num(info.realized_pnl),
+        )?;
     }
-    (realized_pnls, unrealized_pnls)
+    Ok((realized_pnls, unrealized_pnls))
 }
 /// Get weight vector
 pub fn get_base_weight_vector(
@@ -355,21 +417,25 @@ fn get_mf(
     realized_pnl: &[I80F48; MAX_COLLATERALS + MAX_MARKETS],
     unrealized_pnl: &[I80F48; MAX_COLLATERALS + MAX_MARKETS],
     base_weight: &[I80F48; MAX_COLLATERALS + MAX_MARKETS],
-) -> I80F48 {
+) -> Result<I80F48, ErrorCode> {
     let mut mf_value = I80F48::ZERO;
 
-    let total_realized_pnl: I80F48 = realized_pnl.iter().sum();
-    let total_unrealized_pnl: I80F48 = unrealized_pnl.iter().sum();
+    let sum = |x: &[I80F48]| {
+        x.iter()
+            .try_fold(I80F48::ZERO, |acc, &x| try_add_i80f48(acc, x))
+    };
+    let total_realized_pnl = sum(realized_pnl)?;
+    let total_unrealized_pnl = sum(unrealized_pnl)?;
 
     for i in 0..(MAX_COLLATERALS + MAX_MARKETS) {
         let mut current_position = position[i];
 
         if i == 0 {
-            let pnl = safe_add_i80f48(total_realized_pnl, total_unrealized_pnl);
-            current_position = safe_add_i80f48(
+            let pnl = try_add_i80f48(total_realized_pnl, total_unrealized_pnl)?;
+            current_position = try_add_i80f48(
                 current_position,
-                safe_div_i80f48(pnl, prices[i]),
-            );
+                try_div_i80f48(pnl, prices[i])?,
+            )?;
         }
 
         let weight = weight_conversion(
@@ -378,44 +444,40 @@ fn get_mf(
             &base_weight[i],
             i < MAX_COLLATERALS,
         );
-        let weighted_price = safe_mul_i80f48(prices[i], weight);
+        let weighted_price = try_mul_i80f48(prices[i], weight)?;
 
-        mf_value = safe_add_i80f48(
+        mf_value = try_add_i80f48(
             mf_value,
-            safe_mul_i80f48(current_position, weighted_price),
-        );
+            try_mul_i80f48(current_position, weighted_price)?,
+        )?;
     }
 
-    mf_value
+    Ok(mf_value)
 }
 
-fn get_mf_wrapped(
+pub fn get_mf_wrapped(
     mf: MfReturnOption,
     margin: &Margin,
     control: &Control,
     state: &State,
     cache: &Cache,
-) -> I80F48 {
+    oracles: &OracleIndex,
+) -> Result<I80F48, ErrorCode> {
     let position_vector = match mf {
         MfReturnOption::Imf => get_position_open_vector(margin, control),
         MfReturnOption::Cmf => get_position_open_vector(margin, control),
         _ => get_position_vector(margin, control),
     };
 
-    let price_vector = get_price_vector(state, cache, &position_vector);
+    let price_vector =
+        get_price_vector(state, cache, oracles, &position_vector)?;
 
     let weight_vector = get_base_weight_vector(state);
 
-    let funding_cache: [I80F48; MAX_MARKETS] = { cache.funding_cache }
-        .iter()
-        .map(|x| I80F48::from_num(*x)) //  i128 to I80 might not be ideal.
-        // Think if dividing here instead of in pnl and using pos_size in pnl
-        .collect::<Vec<I80F48>>()
-        .try_into()
-        .unwrap(); // This is a bruh moment
+    let funding_cache = get_funding_vector(cache)?;
 
     let (realized_pnl, unrealized_pnl) =
-        get_pnl_vectors(control, state, cache, &funding_cache);
+        get_pnl_vectors(control, state, cache, oracles, &funding_cache)?;
 
     get_mf(
         mf,
@@ -433,8 +495,9 @@ pub fn check_mf(
     control: &Control,
     state: &State,
     cache: &Cache,
+    oracles: &OracleIndex,
     tolerance: I80F48, // for making sure the account is liquidatable, should be less than 1.0
-) -> bool {
+) -> Result<bool, ErrorCode> {
     let position_vector = match check {
         FractionType::Initial | FractionType::Cancel => {
             get_position_open_vector(margin, control)
@@ -442,20 +505,15 @@ pub fn check_mf(
         _ => get_position_vector(margin, control),
     };
 
-    let price_vector = get_price_vector(state, cache, &position_vector);
+    let price_vector =
+        get_price_vector(state, cache, oracles, &position_vector)?;
 
     let weight_vector = get_base_weight_vector(state);
 
-    let funding_cache: [I80F48; MAX_MARKETS] = { cache.funding_cache }
-        .iter()
-        .map(|x| I80F48::from_num(*x)) //  i128 to I80 might not be ideal.
-        // Think if dividing here instead of in pnl and using pos_size in pnl
-        .collect::<Vec<I80F48>>()
-        .try_into()
-        .unwrap(); // This is a bruh moment
+    let funding_cache = get_funding_vector(cache)?;
 
     let (realized_pnl, unrealized_pnl) =
-        get_pnl_vectors(control, state, cache, &funding_cache);
+        get_pnl_vectors(control, state, cache, oracles, &funding_cache)?;
 
     match check {
         FractionType::Initial => {
@@ -466,7 +524,7 @@ pub fn check_mf(
                 &realized_pnl,
                 &unrealized_pnl,
                 &weight_vector,
-            );
+            )?;
             let imf = get_mf(
                 MfReturnOption::Imf,
                 &position_vector,
@@ -474,8 +532,8 @@ pub fn check_mf(
                 &realized_pnl,
                 &unrealized_pnl,
                 &weight_vector,
-            );
-            omf >= safe_mul_i80f48(imf, tolerance)
+            )?;
+            Ok(omf >= try_mul_i80f48(imf, tolerance)?)
         }
         FractionType::Cancel => {
             let omf = get_mf(
@@ -485,7 +543,7 @@ pub fn check_mf(
                 &realized_pnl,
                 &unrealized_pnl,
                 &weight_vector,
-            );
+            )?;
             let cmf = get_mf(
                 MfReturnOption::Cmf,
                 &position_vector,
@@ -493,8 +551,8 @@ pub fn check_mf(
                 &realized_pnl,
                 &unrealized_pnl,
                 &weight_vector,
-            );
-            omf >= safe_mul_i80f48(cmf, tolerance)
+            )?;
+            Ok(omf >= try_mul_i80f48(cmf, tolerance)?)
         }
         FractionType::Maintenance => {
             let mf = get_mf(
@@ -504,7 +562,7 @@ pub fn check_mf(
                 &realized_pnl,
                 &unrealized_pnl,
                 &weight_vector,
-            );
+            )?;
             let mmf = get_mf(
                 MfReturnOption::Mmf,
                 &position_vector,
@@ -512,19 +570,104 @@ pub fn check_mf(
                 &realized_pnl,
                 &unrealized_pnl,
                 &weight_vector,
-            );
-            mf >= safe_mul_i80f48(mmf, tolerance)
+            )?;
+            Ok(mf >= try_mul_i80f48(mmf, tolerance)?)
         }
     }
 }
 
+/// The smallest fraction, in tenths, of the perp positions in `markets`
+/// that has to be liquidated to bring the account back above maintenance.
+/// The liquidation is estimated as closing that fraction at the mark, so
+/// the penalty is ignored. Returns one if even that isn't enough.
+pub fn perp_reduction_fraction(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    oracles: &OracleIndex,
+    markets: &[usize],
+) -> Result<I80F48, ErrorCode> {
+    for tenths in 1..10 {
+        let fraction = I80F48::from_num(tenths) / I80F48::from_num(10);
+        let mut reduced = *control;
+
+        for &i in markets {
+            let mut info = reduced.open_orders_agg[i];
+            let pos_size = I80F48::from_num(info.pos_size);
+            let mark: I80F48 = cache.marks[i].price.into();
+            let closed = safe_mul_i80f48(pos_size, fraction);
+
+            info.pos_size = (pos_size - closed).to_num();
+            info.native_pc_total = (I80F48::from_num(info.native_pc_total)
+                + safe_mul_i80f48(closed, mark))
+            .to_num();
+            reduced.open_orders_agg[i] = info;
+        }
+
+        if check_mf(
+            FractionType::Maintenance,
+            margin,
+            &reduced,
+            state,
+            cache,
+            oracles,
+            I80F48::ONE,
+        )? {
+            return Ok(fraction);
+        }
+    }
+
+    Ok(I80F48::ONE)
+}
+
+/// Tolerance used for both checks when none is configured.
+pub const DEFAULT_TOLERANCE: f64 = 0.99995;
+
+/// Whether an account's open orders should be cancelled, and whether it
+/// should be liquidated, respectively.
+pub fn check_liquidatable(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    oracles: &OracleIndex,
+    cancel_tolerance: I80F48,
+    maintenance_tolerance: I80F48,
+) -> Result<(bool, bool), ErrorCode> {
+    let has_oo = has_open_orders(cache, control)?;
+
+    let is_above_cancel = check_mf(
+        FractionType::Cancel,
+        margin,
+        control,
+        state,
+        cache,
+        oracles,
+        cancel_tolerance,
+    )?;
+
+    let is_above_maintenance = check_mf(
+        FractionType::Maintenance,
+        margin,
+        control,
+        state,
+        cache,
+        oracles,
+        maintenance_tolerance,
+    )?;
+
+    Ok((!is_above_cancel && has_oo, !is_above_maintenance))
+}
+
 pub fn get_total_account_value(
     margin: &Margin,
     control: &Control,
     state: &State,
     cache: &Cache,
-) -> I80F48 {
-    get_mf_wrapped(MfReturnOption::Mf, margin, control, state, cache)
+    oracles: &OracleIndex,
+) -> Result<I80F48, ErrorCode> {
+    get_mf_wrapped(MfReturnOption::Mf, margin, control, state, cache, oracles)
 }
 
 pub fn largest_open_order(
@@ -572,32 +715,42 @@ pub fn has_open_orders(
     Ok(result.is_some())
 }
 
+/// Below this, in smol USD per smol asset, `estimate_spot_liquidation_size`
+/// gives up rather than divide by a denominator that is mostly rounding.
+pub const SPOT_DENOM_EPSILON: f64 = 0.0001;
+
 /// The estimate of how much asset will be liquidated in spot.
 /// This is a negative number (we are lending the i'th asset).
 /// We want to buy this asset afterwards (with USDC), so we want
 /// to denominate the result of this function is sUSD.
+///
+/// Repaying `x` smol of the borrowed asset costs `x * price * liq_fee`
+/// of quote. Holding the weights at their current values, the open
+/// margin less the initial margin, which is the numerator below, changes
+/// by `-x * denom`. The estimate is the `x` which brings it back to zero,
+/// in smol USD and clamped to both the borrow and the quote collateral.
+///
+/// Returns `None` if the fees cancel the difference in weights, so that
+/// liquidating doesn't move the account towards initial margin, or if
+/// the account is already above it.
 pub fn estimate_spot_liquidation_size(
     margin: &Margin,
     control: &Control,
     state: &State,
     cache: &Cache,
+    oracles: &OracleIndex,
     asset_index: usize, // The asset index
     quote_index: usize,
 ) -> Option<I80F48> {
     let mut position = get_position_open_vector(margin, control);
 
-    let funding_cache: [I80F48; MAX_MARKETS] = { cache.funding_cache }
-        .iter()
-        .map(|x| I80F48::from_num(*x)) //  i128 to I80 might not be ideal.
-        // Think if dividing here instead of in pnl and using pos_size in pnl
-        .collect::<Vec<I80F48>>()
-        .try_into()
-        .unwrap(); // This is a bruh moment
+    let funding_cache = get_funding_vector(cache).ok()?;
 
-    let price_vector = get_price_vector(state, cache, &position);
+    let price_vector =
+        get_price_vector(state, cache, oracles, &position).ok()?;
 
     let (realized_pnl, unrealized_pnl) =
-        get_pnl_vectors(control, state, cache, &funding_cache);
+        get_pnl_vectors(control, state, cache, oracles, &funding_cache).ok()?;
 
     let total_realized_pnl =
         realized_pnl.iter().sum::<I80F48>() / price_vector[0];
@@ -618,10 +771,7 @@ pub fn estimate_spot_liquidation_size(
     let liq_fee = (I80F48::ONE + asset_fee) / (I80F48::ONE - quote_fee);
 
     let asset_price: I80F48 =
-        get_oracle(cache, &state.collaterals[asset_index].oracle_symbol)
-            .unwrap()
-            .price
-            .into();
+        oracles.collateral(cache, asset_index)?.price.into();
 
     let denom: I80F48 = asset_price
         * (omf_weight[quote_index] * liq_fee
@@ -629,8 +779,7 @@ pub fn estimate_spot_liquidation_size(
             - imf_weight[quote_index]
             + imf_weight[asset_index]);
 
-    if denom.abs() < I80F48::from_num(0.0001f64) {
-        // denom in smol so....
+    if denom.abs() < I80F48::from_num(SPOT_DENOM_EPSILON) {
         return None;
     }
 
@@ -665,8 +814,573 @@ pub fn estimate_spot_liquidation_size(
 mod tests {
     use super::*;
     use anchor_lang::prelude::Pubkey;
+    use bytemuck::Zeroable;
     use solana_client::rpc_client::RpcClient;
     use std::str::FromStr;
+    use zo_abi::Symbol;
+
+    // Tests against live accounts read their RPC endpoints from these
+    // variables, and are skipped when they are unset.
+    const MAINNET_RPC_URL: &str = "TEST_RPC_URL";
+    const DEVNET_RPC_URL: &str = "TEST_DEVNET_RPC_URL";
+
+    const MAINNET_AUTHORITY: &str =
+        "AL8JFS4gjaQx89f9j8wtaNJgV76K8bw1ugvNtgvhgAnb";
+    const MAINNET_AUTHORITY_2: &str =
+        "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz";
+    const DEVNET_AUTHORITY: &str =
+        "76FnoFsGx5axcYoB4Jzxyds2gGJmw7ddbVC7cL4n9fpa";
+
+    fn rpc_client(var: &str) -> Option<RpcClient> {
+        match std::env::var(var) {
+            Ok(url) => Some(RpcClient::new(url)),
+            Err(_) => {
+                eprintln!("{} is not set, skipping", var);
+                None
+            }
+        }
+    }
+
+    /// Loads the state and cache, and the margin and control of
+    /// `authority`, from the cluster in the `var` environment variable.
+    fn load_accounts(
+        var: &str,
+        authority: &str,
+    ) -> Option<(State, Cache, Margin, Control)> {
+        let rpc_client = rpc_client(var)?;
+
+        let state: State =
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
+                [0]
+            .1;
+
+        let cache: Cache =
+            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID).unwrap()
+                [0]
+            .1;
+
+        let authority = Pubkey::from_str(authority).unwrap();
+        let margin: Margin =
+            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+                .unwrap()
+                .into_iter()
+                .map(|(_, margin)| margin)
+                .find(|margin| margin.authority == authority)
+                .expect("margin not found");
+
+        let control: Control =
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .unwrap()
+                .into_iter()
+                .find(|(key, _)| *key == margin.control)
+                .map(|(_, control)| control)
+                .expect("control not found");
+
+        Some((state, cache, margin, control))
+    }
+
+    fn market_index(state: &State, symbol: &str) -> usize {
+        state
+            .perp_markets
+            .iter()
+            .position(|m| m.oracle_symbol == Symbol::try_from(symbol).unwrap())
+            .unwrap_or_else(|| panic!("no {} market", symbol))
+    }
+
+    fn collateral_index(state: &State, symbol: &str) -> usize {
+        state
+            .collaterals
+            .iter()
+            .position(|c| c.oracle_symbol == Symbol::try_from(symbol).unwrap())
+            .unwrap_or_else(|| panic!("no {} collateral", symbol))
+    }
+
+    /// A state with USDC and SOL collaterals and a SOL future, whose
+    /// weights keep the expected fractions exact: a USDC weight of 1, and
+    /// a base imf of 12.5% on the future.
+    fn fixture_state() -> State {
+        let mut state = State::zeroed();
+        state.total_collaterals = 2;
+        state.total_markets = 1;
+
+        state.collaterals[0].mint = Pubkey::new_unique();
+        state.collaterals[0].oracle_symbol = Symbol::try_from("USDC").unwrap();
+        state.collaterals[0].decimals = 6;
+        state.collaterals[0].weight = 1000;
+
+        state.collaterals[1].mint = Pubkey::new_unique();
+        state.collaterals[1].oracle_symbol = Symbol::try_from("SOL").unwrap();
+        state.collaterals[1].decimals = 9;
+        state.collaterals[1].weight = 900;
+        state.collaterals[1].liq_fee = 10;
+
+        state.perp_markets[0].symbol = Symbol::try_from("SOL-PERP").unwrap();
+        state.perp_markets[0].oracle_symbol = Symbol::try_from("SOL").unwrap();
+        state.perp_markets[0].perp_type = PerpType::Future;
+        state.perp_markets[0].base_imf = 125;
+        state.perp_markets[0].asset_decimals = 9;
+
+        state
+    }
+
+    /// A cache pricing USDC at 1 and SOL at `sol_price`, both in smol USD
+    /// per smol asset, with no interest or funding accrued.
+    fn fixture_cache(state: &State, sol_price: f64) -> Cache {
+        let mut cache = Cache::zeroed();
+        let sol = market_index(state, "SOL");
+
+        // The program keeps the oracles sorted by symbol, and they are
+        // binary searched, so the empty entries come first.
+        let mut oracles = { cache.oracles };
+        oracles[0].symbol = Symbol::try_from("USDC").unwrap();
+        oracles[0].price = I80F48::ONE.into();
+        oracles[1].symbol = Symbol::try_from("SOL").unwrap();
+        oracles[1].price = I80F48::from_num(sol_price).into();
+        oracles.sort_by_key(|o| o.symbol);
+        cache.oracles = oracles;
+
+        cache.marks[sol].price = I80F48::from_num(sol_price).into();
+
+        for borrow in cache.borrow_cache.iter_mut().take(2) {
+            borrow.supply_multiplier = I80F48::ONE.into();
+            borrow.borrow_multiplier = I80F48::ONE.into();
+        }
+
+        cache
+    }
+
+    /// A margin and control holding `collateral` smol USDC and a SOL
+    /// position of `pos_size` smol opened for `native_pc_total`.
+    fn fixture_accounts(
+        state: &State,
+        collateral: i64,
+        pos_size: i64,
+        native_pc_total: i64,
+        coin_on_bids: u64,
+    ) -> (Margin, Control) {
+        let mut margin = Margin::zeroed();
+        let mut control = Control::zeroed();
+        let sol = market_index(state, "SOL");
+
+        let mut collaterals = { margin.collateral };
+        collaterals[0] = I80F48::from_num(collateral).into();
+        margin.collateral = collaterals;
+
+        let mut open_orders = { control.open_orders_agg };
+        open_orders[sol].pos_size = pos_size;
+        open_orders[sol].native_pc_total = native_pc_total;
+        open_orders[sol].coin_on_bids = coin_on_bids;
+        control.open_orders_agg = open_orders;
+
+        (margin, control)
+    }
+
+    /// The scenarios saved under `tests/fixtures/margin`, each as the
+    /// SOL price of its cache and the arguments of `fixture_accounts`.
+    const SCENARIOS: [(&str, f64, i64, i64, i64, u64); 5] = {
+        const SOL: i64 = 1_000_000_000;
+        const USD: i64 = 1_000_000;
+
+        [
+            // 100 USDC backing a 1 SOL long opened at the mark.
+            ("healthy", 0.125, 100 * USD, SOL, -125 * USD, 0),
+            ("at_maintenance", 0.125, 7_812_500, SOL, -125 * USD, 0),
+            ("underwater", 0.125, 7 * USD, SOL, -125 * USD, 0),
+            // SOL halved since the long was opened at 125 USD.
+            ("bankrupt", 0.0625, 10 * USD, SOL, -125 * USD, 0),
+            // 1 USDC backing a 1 SOL bid, with no position.
+            ("open_orders_only", 0.125, USD, 0, 0, SOL as u64),
+        ]
+    };
+
+    fn fixture_path(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/margin")
+            .join(name)
+    }
+
+    fn load_fixture<T: bytemuck::Pod>(name: &str) -> T {
+        let bytes = std::fs::read(fixture_path(name))
+            .unwrap_or_else(|e| panic!("{}: {}", name, e));
+        bytemuck::try_pod_read_unaligned(&bytes).unwrap_or_else(|_| {
+            panic!("{} is stale, rerun write_fixtures", name)
+        })
+    }
+
+    /// The state, cache, margin and control of scenario `name`.
+    fn scenario(name: &str) -> (State, Cache, (Margin, Control)) {
+        (
+            load_fixture("state.bin"),
+            load_fixture(&format!("{}/cache.bin", name)),
+            (
+                load_fixture(&format!("{}/margin.bin", name)),
+                load_fixture(&format!("{}/control.bin", name)),
+            ),
+        )
+    }
+
+    /// Rewrites the blobs under `tests/fixtures/margin` from `SCENARIOS`
+    /// and the fixture builders. Run with `cargo test write_fixtures --
+    /// --ignored` after changing either.
+    #[test]
+    #[ignore]
+    fn write_fixtures() {
+        let write = |name: &str, bytes: &[u8]| {
+            let path = fixture_path(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, bytes).unwrap();
+        };
+
+        let state = fixture_state();
+        write("state.bin", bytemuck::bytes_of(&state));
+
+        for (name, sol_price, collateral, pos_size, pc_total, coin_on_bids) in
+            SCENARIOS
+        {
+            let cache = fixture_cache(&state, sol_price);
+            let (margin, control) = fixture_accounts(
+                &state,
+                collateral,
+                pos_size,
+                pc_total,
+                coin_on_bids,
+            );
+
+            write(&format!("{}/cache.bin", name), bytemuck::bytes_of(&cache));
+            write(&format!("{}/margin.bin", name), bytemuck::bytes_of(&margin));
+            write(
+                &format!("{}/control.bin", name),
+                bytemuck::bytes_of(&control),
+            );
+        }
+    }
+
+    fn mf(
+        x: MfReturnOption,
+        (margin, control): &(Margin, Control),
+        state: &State,
+        cache: &Cache,
+    ) -> I80F48 {
+        let oracles = OracleIndex::new(state, cache);
+        get_mf_wrapped(x, margin, control, state, cache, &oracles).unwrap()
+    }
+
+    fn is_above(
+        x: FractionType,
+        (margin, control): &(Margin, Control),
+        state: &State,
+        cache: &Cache,
+    ) -> bool {
+        let oracles = OracleIndex::new(state, cache);
+        check_mf(x, margin, control, state, cache, &oracles, I80F48::ONE)
+            .unwrap()
+    }
+
+    fn decision(
+        (margin, control): &(Margin, Control),
+        state: &State,
+        cache: &Cache,
+    ) -> (bool, bool) {
+        let tolerance = I80F48::from_num(DEFAULT_TOLERANCE);
+        let oracles = OracleIndex::new(state, cache);
+        check_liquidatable(
+            margin, control, state, cache, &oracles, tolerance, tolerance,
+        )
+        .unwrap()
+    }
+
+    /// USDC collateral of `collateral` smol against a borrow of 100 USD of
+    /// SOL collateral at 31.25 USD, with SOL's liquidation fee in tenths of
+    /// a percent. Both collateral weights are 1, so the SOL borrow has an
+    /// imf weight of -0.1.
+    fn fixture_spot(
+        collateral: i64,
+        sol_liq_fee: u16,
+    ) -> (State, Cache, Margin, Control) {
+        let mut state = fixture_state();
+        let sol = collateral_index(&state, "SOL");
+        state.collaterals[sol].weight = 1000;
+        state.collaterals[sol].liq_fee = sol_liq_fee as _;
+
+        let cache = fixture_cache(&state, 0.03125);
+
+        let (mut margin, control) =
+            fixture_accounts(&state, collateral, 0, 0, 0);
+        let mut collaterals = { margin.collateral };
+        collaterals[sol] = I80F48::from_num(-3_200_000_000i64).into();
+        margin.collateral = collaterals;
+
+        (state, cache, margin, control)
+    }
+
+    fn spot_size(
+        (state, cache, margin, control): &(State, Cache, Margin, Control),
+    ) -> Option<I80F48> {
+        let oracles = OracleIndex::new(state, cache);
+        let sol = collateral_index(state, "SOL");
+        estimate_spot_liquidation_size(
+            margin, control, state, cache, &oracles, sol, 0,
+        )
+    }
+
+    #[test]
+    fn test_active_bounds() {
+        let mut state = fixture_state();
+        assert_eq!(
+            active_markets(&state).unwrap(),
+            state.total_markets as usize
+        );
+        assert_eq!(
+            active_collaterals(&state).unwrap(),
+            state.total_collaterals as usize
+        );
+
+        state.total_markets = (MAX_MARKETS + 1) as _;
+        state.total_collaterals = (MAX_COLLATERALS + 1) as _;
+        assert!(matches!(
+            active_markets(&state),
+            Err(ErrorCode::UnsupportedState)
+        ));
+        assert!(matches!(
+            active_collaterals(&state),
+            Err(ErrorCode::UnsupportedState)
+        ));
+    }
+
+    #[test]
+    fn test_funding_out_of_range() {
+        let state = fixture_state();
+        let mut cache = fixture_cache(&state, 0.125);
+        let (margin, control) =
+            fixture_accounts(&state, 7_000_000, 1_000_000_000, -125_000_000, 0);
+
+        let sol = market_index(&state, "SOL");
+        let mut funding = { cache.funding_cache };
+        funding[sol] = (1i128 << 79) - 1;
+        cache.funding_cache = funding;
+        assert!(get_funding_vector(&cache).is_ok());
+
+        funding[sol] = 1i128 << 79;
+        cache.funding_cache = funding;
+        assert!(matches!(
+            get_funding_vector(&cache),
+            Err(ErrorCode::MathFailure)
+        ));
+        let oracles = OracleIndex::new(&state, &cache);
+        assert!(get_mf_wrapped(
+            MfReturnOption::Mf,
+            &margin,
+            &control,
+            &state,
+            &cache,
+            &oracles,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_perp_reduction_fraction() {
+        let state = fixture_state();
+        let cache = fixture_cache(&state, 0.125);
+        let oracles = OracleIndex::new(&state, &cache);
+        let sol = market_index(&state, "SOL");
+
+        // 7.8125 USD maintenance on 7 USD of margin. Closing a tenth still
+        // leaves 7.03125 USD maintenance, so two tenths have to go.
+        let (margin, control) =
+            fixture_accounts(&state, 7_000_000, 1_000_000_000, -125_000_000, 0);
+        assert_eq!(
+            perp_reduction_fraction(
+                &margin,
+                &control,
+                &state,
+                &cache,
+                &oracles,
+                &[sol],
+            )
+            .unwrap(),
+            I80F48::from_num(2) / I80F48::from_num(10)
+        );
+
+        // Bankrupt accounts stay under whatever is liquidated.
+        let (margin, control) = fixture_accounts(
+            &state,
+            -1_000_000,
+            1_000_000_000,
+            -125_000_000,
+            0,
+        );
+        assert_eq!(
+            perp_reduction_fraction(
+                &margin,
+                &control,
+                &state,
+                &cache,
+                &oracles,
+                &[sol],
+            )
+            .unwrap(),
+            I80F48::ONE
+        );
+    }
+
+    #[test]
+    fn test_fixture_healthy() {
+        let (state, cache, accs) = scenario("healthy");
+
+        let mf_ = mf(MfReturnOption::Mf, &accs, &state, &cache);
+        assert_eq!(mf_, I80F48::from_num(100_000_000));
+        // 125 USD notional at half of the 12.5% base imf.
+        let mmf = mf(MfReturnOption::Mmf, &accs, &state, &cache);
+        assert_eq!(mmf, I80F48::from_num(7_812_500));
+        let imf = mf(MfReturnOption::Imf, &accs, &state, &cache);
+        assert_eq!(imf, I80F48::from_num(15_625_000));
+        let cmf = mf(MfReturnOption::Cmf, &accs, &state, &cache);
+        assert_eq!(cmf, I80F48::from_num(9_765_625));
+
+        assert!(is_above(FractionType::Maintenance, &accs, &state, &cache));
+        assert!(is_above(FractionType::Cancel, &accs, &state, &cache));
+        assert!(is_above(FractionType::Initial, &accs, &state, &cache));
+        assert_eq!(decision(&accs, &state, &cache), (false, false));
+    }
+
+    #[test]
+    fn test_fixture_at_maintenance() {
+        let (state, cache, accs) = scenario("at_maintenance");
+
+        assert_eq!(
+            mf(MfReturnOption::Mf, &accs, &state, &cache),
+            mf(MfReturnOption::Mmf, &accs, &state, &cache),
+        );
+        assert!(is_above(FractionType::Maintenance, &accs, &state, &cache));
+        assert!(!is_above(FractionType::Initial, &accs, &state, &cache));
+        assert_eq!(decision(&accs, &state, &cache), (false, false));
+    }
+
+    #[test]
+    fn test_fixture_underwater() {
+        let (state, cache, accs) = scenario("underwater");
+
+        assert_eq!(
+            mf(MfReturnOption::Mf, &accs, &state, &cache),
+            I80F48::from_num(7_000_000),
+        );
+        assert!(!is_above(FractionType::Maintenance, &accs, &state, &cache));
+        assert_eq!(decision(&accs, &state, &cache), (false, true));
+    }
+
+    #[test]
+    fn test_fixture_bankrupt() {
+        let (state, cache, accs) = scenario("bankrupt");
+
+        let mf_ = mf(MfReturnOption::Mf, &accs, &state, &cache);
+        assert_eq!(mf_, I80F48::from_num(-52_500_000));
+        // 62.5 USD notional at half of the 12.5% base imf, and 3% on the
+        // 52.5 USD of USDC the loss has to borrow. The spot requirement
+        // isn't exact in binary, so neither is the sum.
+        let mmf = mf(MfReturnOption::Mmf, &accs, &state, &cache);
+        assert!((mmf - I80F48::from_num(5_481_250)).abs() < I80F48::ONE);
+
+        assert!(!is_above(FractionType::Maintenance, &accs, &state, &cache));
+        assert_eq!(decision(&accs, &state, &cache), (false, true));
+    }
+
+    #[test]
+    fn test_fixture_open_orders_only() {
+        let (state, cache, accs) = scenario("open_orders_only");
+
+        assert_eq!(
+            mf(MfReturnOption::Omf, &accs, &state, &cache),
+            I80F48::from_num(1_000_000),
+        );
+        assert_eq!(
+            mf(MfReturnOption::Cmf, &accs, &state, &cache),
+            I80F48::from_num(9_765_625),
+        );
+        assert_eq!(
+            mf(MfReturnOption::Mmf, &accs, &state, &cache),
+            I80F48::ZERO,
+        );
+
+        assert!(!is_above(FractionType::Cancel, &accs, &state, &cache));
+        assert!(is_above(FractionType::Maintenance, &accs, &state, &cache));
+        assert_eq!(decision(&accs, &state, &cache), (true, false));
+    }
+
+    #[test]
+    fn test_missing_oracle() {
+        let (state, mut cache, (margin, control)) = scenario("healthy");
+        cache.oracles = Cache::zeroed().oracles;
+        let oracles = OracleIndex::new(&state, &cache);
+
+        // A cache without an account's oracles is an error rather than a
+        // panic in the liquidation task.
+        assert!(matches!(
+            get_mf_wrapped(
+                MfReturnOption::Mf,
+                &margin,
+                &control,
+                &state,
+                &cache,
+                &oracles,
+            ),
+            Err(ErrorCode::MissingOracle)
+        ));
+    }
+
+    #[test]
+    fn test_calc_actual_collateral_overflow() {
+        let x = I80F48::from_num;
+
+        assert_eq!(calc_actual_collateral(x(10), x(2), x(3)).ok(), Some(x(20)));
+        assert_eq!(
+            calc_actual_collateral(x(-10), x(2), x(3)).ok(),
+            Some(x(-30))
+        );
+
+        // An overflowing multiplier is an account that can't be valued,
+        // rather than a panic in the liquidation task.
+        assert!(calc_actual_collateral(I80F48::MAX, x(2), x(3)).is_err());
+    }
+
+    #[test]
+    fn test_spot_size_denom_near_zero() {
+        // A 10% fee on SOL makes repaying it cost exactly what the
+        // repayment frees up, so no size helps.
+        assert_eq!(spot_size(&fixture_spot(105_000_000, 100)), None);
+    }
+
+    #[test]
+    fn test_spot_size_above_initial() {
+        assert_eq!(spot_size(&fixture_spot(120_000_000, 10)), None);
+    }
+
+    #[test]
+    fn test_spot_size_normal() {
+        // Initial margin is short by 105 - 100 * 1.1 = 5 USD, and each
+        // USD repaid recovers 1.1 - 1.01 = 0.09 of it.
+        let size = spot_size(&fixture_spot(105_000_000, 10)).unwrap();
+        let expected = I80F48::from_num(5_000_000) / I80F48::from_num(0.09);
+
+        assert!((size - expected).abs() < I80F48::ONE);
+    }
+
+    #[test]
+    fn test_spot_size_clamped() {
+        // Short by 15 USD needs 166.67 USD repaid, but there is only 95
+        // USD of quote to pay with.
+        assert_eq!(
+            spot_size(&fixture_spot(95_000_000, 10)),
+            Some(I80F48::from_num(95_000_000)),
+        );
+
+        // Short by 9.5 USD needs 105.56 USD repaid, but only 100 USD
+        // is borrowed.
+        assert_eq!(
+            spot_size(&fixture_spot(100_500_000, 10)),
+            Some(I80F48::from_num(100_000_000)),
+        );
+    }
 
     #[test]
     fn it_works() {
@@ -701,40 +1415,13 @@ mod tests {
 
     #[test]
     fn test_get_position_vector() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "AL8JFS4gjaQx89f9j8wtaNJgV76K8bw1ugvNtgvhgAnb",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (_state, _cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY) {
+                Some(x) => x,
+                None => return,
+            };
 
-        let position =
-            get_position_vector(&test_margin.unwrap(), &test_control.unwrap());
+        let position = get_position_vector(&margin, &control);
         let mut true_position = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
 
         true_position[0] = I80F48::from_num(1.604205999948498f64);
@@ -747,225 +1434,95 @@ mod tests {
 
     #[test]
     fn test_get_account_value() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "AL8JFS4gjaQx89f9j8wtaNJgV76K8bw1ugvNtgvhgAnb",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let mf = get_mf_wrapped(
             MfReturnOption::Mf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
         println!("{}", mf)
     }
 
     #[test]
     fn test_get_mmf() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "AL8JFS4gjaQx89f9j8wtaNJgV76K8bw1ugvNtgvhgAnb",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let mmf = get_mf_wrapped(
             MfReturnOption::Mmf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
         println!("{}", mmf)
     }
 
     #[test]
     fn test_get_imf() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "AL8JFS4gjaQx89f9j8wtaNJgV76K8bw1ugvNtgvhgAnb",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let imf = get_mf_wrapped(
             MfReturnOption::Imf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
         println!("{}", imf);
     }
 
     #[test]
     fn test_imf_cmf() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "AL8JFS4gjaQx89f9j8wtaNJgV76K8bw1ugvNtgvhgAnb",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let cmf = get_mf_wrapped(
             MfReturnOption::Cmf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
 
         let imf = get_mf_wrapped(
             MfReturnOption::Imf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
 
         assert!(
             cmf.unwrapped_sub(safe_mul_i80f48(
@@ -979,175 +1536,78 @@ mod tests {
 
     #[test]
     fn test_check_mf_maintenance() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY_2) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let is_ok = check_mf(
             FractionType::Maintenance,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
+            &oracles,
             I80F48::from_num(0.99f64),
-        );
+        )
+        .unwrap();
         // The liquidator is ok
         assert!(is_ok);
     }
 
     #[test]
     fn test_check_mf_cancel() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY_2) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let is_ok = check_mf(
             FractionType::Cancel,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
+            &oracles,
             I80F48::from_num(0.99f64),
-        );
+        )
+        .unwrap();
         assert!(is_ok);
     }
 
     #[test]
     fn test_check_mf_initial() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY_2) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let is_ok = check_mf(
             FractionType::Initial,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
+            &oracles,
             I80F48::from_num(0.99f64),
-        );
+        )
+        .unwrap();
         // The liquidator is ok
         assert!(is_ok);
     }
 
     #[test]
     fn test_get_base_weights() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
+        let rpc_client = match rpc_client(MAINNET_RPC_URL) {
+            Some(x) => x,
+            None => return,
+        };
         let state: State =
             load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
                 [0]
@@ -1175,66 +1635,21 @@ mod tests {
 
     #[test]
     fn test_estimate_spot_liq_size() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY_2) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let amount = estimate_spot_liquidation_size(
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
-            &state,
-            &cache,
-            2,
-            0,
+            &margin, &control, &state, &cache, &oracles, 2, 0,
         );
 
         assert!(amount.is_none());
 
         let t2 = estimate_spot_liquidation_size(
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
-            &state,
-            &cache,
-            0,
-            2,
+            &margin, &control, &state, &cache, &oracles, 0, 2,
         );
 
         assert!(t2.is_some());
@@ -1242,66 +1657,21 @@ mod tests {
 
     #[test]
     fn test_estimate_spot_liq_size2() {
-        let rpc_client =
-            RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY_2) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let amount = estimate_spot_liquidation_size(
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
-            &state,
-            &cache,
-            1,
-            0,
+            &margin, &control, &state, &cache, &oracles, 1, 0,
         );
 
         assert_eq!(amount.unwrap(), I80F48::from_num(382370000.0f64));
 
         let t2 = estimate_spot_liquidation_size(
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
-            &state,
-            &cache,
-            0,
-            2,
+            &margin, &control, &state, &cache, &oracles, 0, 2,
         );
 
         assert!(t2.is_some());
@@ -1309,146 +1679,88 @@ mod tests {
 
     #[test]
     fn test_check_mf_maintenance_main() {
-        let rpc_client = RpcClient::new(
-            "https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string(),
-        );
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "53qyL9jgfsABQAsn3ZUSstd5fQv2Kqf1KeAMVgscmDBz",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(MAINNET_RPC_URL, MAINNET_AUTHORITY_2) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let mf = get_mf_wrapped(
             MfReturnOption::Mf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
 
         let mmf = get_mf_wrapped(
             MfReturnOption::Mmf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
 
         println!("{} {}", mf, mmf);
         let is_ok = check_mf(
             FractionType::Maintenance,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
+            &oracles,
             I80F48::from_num(0.99f64),
-        );
+        )
+        .unwrap();
         // The liquidator is ok
         assert!(is_ok);
     }
 
     #[test]
     fn test_check_mf_maintenance_dev() {
-        let rpc_client = RpcClient::new(
-            "https://psytrbhymqlkfrhudd.dev.genesysgo.net:8899/".to_string(),
-        );
-
-        let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
-        let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
-
-        let mut test_margin: Option<Margin> = None;
-        for (_key, margin) in margins.iter() {
-            if margin.authority.eq(&Pubkey::from_str(
-                "76FnoFsGx5axcYoB4Jzxyds2gGJmw7ddbVC7cL4n9fpa",
-            )
-            .unwrap())
-            {
-                test_margin = Some(margin.clone());
-                break;
-            }
-        }
-
-        assert!(test_margin.is_some());
-
-        let mut test_control: Option<Control> = None;
-        for (key, control) in controls.iter() {
-            if key.eq(&test_margin.unwrap().control) {
-                test_control = Some(control.clone());
-                break;
-            }
-        }
-
-        assert!(test_control.is_some());
+        let (state, cache, margin, control) =
+            match load_accounts(DEVNET_RPC_URL, DEVNET_AUTHORITY) {
+                Some(x) => x,
+                None => return,
+            };
+        let oracles = OracleIndex::new(&state, &cache);
 
         let mf = get_mf_wrapped(
             MfReturnOption::Mf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
 
         let mmf = get_mf_wrapped(
             MfReturnOption::Mmf,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
-        );
+            &oracles,
+        )
+        .unwrap();
 
         println!("{} {}", mf, mmf);
         let is_ok = check_mf(
             FractionType::Maintenance,
-            &test_margin.unwrap(),
-            &test_control.unwrap(),
+            &margin,
+            &control,
             &state,
             &cache,
+            &oracles,
             I80F48::from_num(0.99f64),
-        );
+        )
+        .unwrap();
         // The liquidator is ok
         assert!(is_ok);
     }
@@ -3,7 +3,7 @@
 use crate::liquidator::error::ErrorCode::{self, *};
 use az::{CheckedAs, CheckedCast};
 use fixed::types::I80F48;
-use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub};
+use num_traits::{CheckedAdd, CheckedDiv, CheckedSub};
 
 pub trait SafeOp<T>
 where
@@ -11,7 +11,6 @@ where
 {
     fn safe_add(&self, x: T) -> Result<Self, ErrorCode>;
     fn safe_sub(&self, x: T) -> Result<Self, ErrorCode>;
-    fn safe_mul(&self, x: T) -> Result<Self, ErrorCode>;
     fn safe_div(&self, x: T) -> Result<Self, ErrorCode>;
 }
 
@@ -25,27 +24,71 @@ macro_rules! safe_impl {
 
 impl<T, U> SafeOp<U> for T
 where
-    T: CheckedAdd + CheckedSub + CheckedMul + CheckedDiv,
+    T: CheckedAdd + CheckedSub + CheckedDiv,
     U: CheckedCast<T>,
 {
     safe_impl!(safe_add, checked_add);
     safe_impl!(safe_sub, checked_sub);
-    safe_impl!(safe_mul, checked_mul);
     safe_impl!(safe_div, checked_div);
 }
 
 // I80F48
-pub fn safe_add_i80f48(a: I80F48, b: I80F48) -> I80F48 {
-    let c = a.checked_add(b).ok_or(MathFailure);
-    c.unwrap()
+//
+// The `try_` variants are for the margin math, where an overflow means an
+// account the bot can't value, which is skipped rather than panicking the
+// liquidation task. `safe_mul_i80f48` panics, and is for values bounded by
+// the program, where an overflow can only be a bug.
+pub fn try_add_i80f48(a: I80F48, b: I80F48) -> Result<I80F48, ErrorCode> {
+    a.checked_add(b).ok_or(MathFailure)
+}
+
+pub fn try_sub_i80f48(a: I80F48, b: I80F48) -> Result<I80F48, ErrorCode> {
+    a.checked_sub(b).ok_or(MathFailure)
+}
+
+pub fn try_mul_i80f48(a: I80F48, b: I80F48) -> Result<I80F48, ErrorCode> {
+    a.checked_mul(b).ok_or(MathFailure)
+}
+
+pub fn try_div_i80f48(a: I80F48, b: I80F48) -> Result<I80F48, ErrorCode> {
+    a.checked_div(b).ok_or(MathFailure)
 }
 
 pub fn safe_mul_i80f48(a: I80F48, b: I80F48) -> I80F48 {
-    let c = a.checked_mul(b).ok_or(MathFailure);
-    c.unwrap()
+    try_mul_i80f48(a, b).unwrap()
 }
 
-pub fn safe_div_i80f48(a: I80F48, b: I80F48) -> I80F48 {
-    let c = a.checked_div(b).ok_or(MathFailure);
-    c.unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_i80f48_overflow() {
+        let two = I80F48::from_num(2);
+
+        assert_eq!(
+            try_add_i80f48(I80F48::MAX, I80F48::ZERO).ok(),
+            Some(I80F48::MAX)
+        );
+        assert!(try_add_i80f48(I80F48::MAX, I80F48::DELTA).is_err());
+        assert_eq!(
+            try_sub_i80f48(I80F48::MIN, I80F48::ZERO).ok(),
+            Some(I80F48::MIN)
+        );
+        assert!(try_sub_i80f48(I80F48::MIN, I80F48::DELTA).is_err());
+
+        assert_eq!(
+            try_mul_i80f48(I80F48::MAX, I80F48::ONE).ok(),
+            Some(I80F48::MAX)
+        );
+        assert!(try_mul_i80f48(I80F48::MAX, two).is_err());
+        assert!(try_mul_i80f48(I80F48::MIN, -I80F48::ONE).is_err());
+
+        assert_eq!(
+            try_div_i80f48(I80F48::MAX, two).ok(),
+            Some(I80F48::MAX / two)
+        );
+        assert!(try_div_i80f48(I80F48::ONE, I80F48::ZERO).is_err());
+        assert!(try_div_i80f48(I80F48::MAX, I80F48::DELTA).is_err());
+    }
 }
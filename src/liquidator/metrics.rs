@@ -0,0 +1,245 @@
+/*
+ * This file counts liquidation outcomes per market and collateral, so
+ * operators can see what drives liquidations when tuning the bot. The
+ * counters are kept for the lifetime of the process and logged as a
+ * summary by the liquidation loop.
+ *
+ * It also keeps the durations of the liquidation loop, summarised every
+ * minute, to show when the account table has grown past what can be
+ * checked within a tick.
+ *
+ * Accounts found below the cancel or maintenance thresholds are counted
+ * too, with the reason for each that wasn't acted on, so operators can
+ * see the opportunities their filters leave to other bots. An account
+ * is counted on every loop it's found in.
+ *
+ * Every liquidation that lands is also logged on its own target,
+ * `zo_keeper::liquidations`, with its signature as fields, so they can be
+ * collected without the recorder, e.g. with `--log-format json` and
+ * `RUST_LOG=zo_keeper::liquidations=info`.
+*/
+use parking_lot::{const_mutex, Mutex};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Perp,
+    Spot,
+    Bankruptcy,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Success,
+    /// Rejected for over-exposure, and retried with a smaller size.
+    Reduced,
+    Failure,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Counts {
+    pub success: u64,
+    pub reduced: u64,
+    pub failure: u64,
+}
+
+// There are few enough markets and collaterals that a linear scan is
+// fine, and a `Vec` can be built in a static.
+static COUNTS: Mutex<Vec<(Kind, String, Counts)>> = const_mutex(Vec::new());
+
+pub fn record(kind: Kind, symbol: &str, outcome: Outcome) {
+    let mut counts = COUNTS.lock();
+
+    let i = match counts.iter().position(|(k, s, _)| *k == kind && s == symbol)
+    {
+        Some(i) => i,
+        None => {
+            counts.push((kind, symbol.to_string(), Counts::default()));
+            counts.len() - 1
+        }
+    };
+
+    let c = &mut counts[i].2;
+    match outcome {
+        Outcome::Success => c.success += 1,
+        Outcome::Reduced => c.reduced += 1,
+        Outcome::Failure => c.failure += 1,
+    }
+}
+
+/// Logs a landed liquidation of `liqee`'s account by `liqor`.
+pub fn record_tx(
+    kind: Kind,
+    liqee: &Pubkey,
+    liqor: &Pubkey,
+    signature: &Signature,
+) {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    info!(
+        target: "zo_keeper::liquidations",
+        liqee = %liqee,
+        liqor = %liqor,
+        kind = ?kind,
+        signature = %signature,
+        time,
+        "Liquidation landed"
+    );
+}
+
+/// Why an account past a threshold wasn't acted on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Skip {
+    /// Worth less than `--min-account-value`.
+    MinValue,
+    /// Has a position or orders in a market whose mark is unstable.
+    UnstableMark,
+    /// Left to another bot by `--only-markets` or `--only-collaterals`.
+    Filtered,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Opportunities {
+    pub found: u64,
+    pub acted: u64,
+    pub min_value: u64,
+    pub unstable_mark: u64,
+    pub filtered: u64,
+}
+
+static OPPORTUNITIES: Mutex<Opportunities> = const_mutex(Opportunities {
+    found: 0,
+    acted: 0,
+    min_value: 0,
+    unstable_mark: 0,
+    filtered: 0,
+});
+
+/// Counts an account found past a threshold, and what became of it.
+pub fn record_opportunity(skip: Option<Skip>) {
+    let mut o = OPPORTUNITIES.lock();
+
+    o.found += 1;
+    match skip {
+        None => o.acted += 1,
+        Some(Skip::MinValue) => o.min_value += 1,
+        Some(Skip::UnstableMark) => o.unstable_mark += 1,
+        Some(Skip::Filtered) => o.filtered += 1,
+    }
+}
+
+/// Logs the opportunities counted since the last summary, then clears
+/// them.
+pub fn log_opportunities() {
+    let o = std::mem::take(&mut *OPPORTUNITIES.lock());
+
+    if o.found == 0 {
+        return;
+    }
+
+    info!(
+        "{} opportunities: {} acted on, {} under min value, \
+        {} in unstable markets, {} filtered",
+        o.found, o.acted, o.min_value, o.unstable_mark, o.filtered
+    );
+}
+
+pub fn snapshot() -> Vec<(Kind, String, Counts)> {
+    COUNTS.lock().clone()
+}
+
+pub fn log_summary() {
+    for (kind, symbol, c) in snapshot() {
+        info!(
+            "{:?} {}: {} succeeded, {} reduced, {} failed",
+            kind, symbol, c.success, c.reduced, c.failure
+        );
+    }
+}
+
+/// Durations of the liquidation loop since the last summary.
+pub struct LoopTimes {
+    tick: Duration,
+    durations: Vec<Duration>,
+}
+
+impl LoopTimes {
+    pub fn new(tick: Duration) -> Self {
+        Self {
+            tick,
+            durations: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        self.durations.push(d);
+    }
+
+    /// The `p`th percentile, by nearest rank, of sorted `durations`.
+    fn percentile(durations: &[Duration], p: usize) -> Duration {
+        let rank = (durations.len() * p + 99) / 100;
+        durations[rank.max(1) - 1]
+    }
+
+    /// Logs the percentiles of the recorded durations, and how many
+    /// overran the tick and so caused the next to be skipped, then
+    /// clears them.
+    pub fn log_summary(&mut self) {
+        if self.durations.is_empty() {
+            return;
+        }
+
+        self.durations.sort_unstable();
+        let d = &self.durations;
+        let over = d.iter().filter(|x| **x > self.tick).count();
+
+        info!(
+            "{} loops: p50 {} ms, p90 {} ms, p99 {} ms, max {} ms, \
+            {} over {} ms",
+            d.len(),
+            Self::percentile(d, 50).as_millis(),
+            Self::percentile(d, 90).as_millis(),
+            Self::percentile(d, 99).as_millis(),
+            d[d.len() - 1].as_millis(),
+            over,
+            self.tick.as_millis(),
+        );
+
+        self.durations.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_opportunity() {
+        record_opportunity(None);
+        record_opportunity(Some(Skip::MinValue));
+        record_opportunity(Some(Skip::Filtered));
+        record_opportunity(Some(Skip::Filtered));
+
+        let o = *OPPORTUNITIES.lock();
+        assert_eq!(o.found, 4);
+        assert_eq!(o.acted, 1);
+        assert_eq!(o.min_value, 1);
+        assert_eq!(o.unstable_mark, 0);
+        assert_eq!(o.filtered, 2);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let d: Vec<Duration> = (1..=200).map(Duration::from_millis).collect();
+
+        assert_eq!(LoopTimes::percentile(&d, 50), Duration::from_millis(100));
+        assert_eq!(LoopTimes::percentile(&d, 99), Duration::from_millis(198));
+        assert_eq!(LoopTimes::percentile(&d, 100), Duration::from_millis(200));
+        assert_eq!(LoopTimes::percentile(&d[..1], 1), Duration::from_millis(1));
+    }
+}
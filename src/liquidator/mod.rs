@@ -1,28 +1,230 @@
 mod accounts;
 mod error;
+mod health;
+mod jupiter;
 mod liquidation;
 mod listener;
 mod margin_utils;
 mod math;
+mod metrics;
+mod server;
 mod swap;
 mod utils;
 
-use crate::{AppState, Error};
+pub use server::serve_health;
+
+use crate::{utils::get_account, AppState, Error};
+use anchor_client::solana_sdk::{
+    commitment_config::CommitmentConfig, pubkey::Pubkey,
+};
+use fixed::types::I80F48;
+use margin_utils::MfReturnOption;
+use std::{str::FromStr, time::Duration};
+use tracing::{error, info, warn};
+use zo_abi::{Cache, Control, FractionType, Margin, State};
+
+/// Where liquidated inventory is swapped back to USDC.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RebalanceVenue {
+    /// The zo serum spot markets.
+    Serum,
+    /// The Jupiter aggregator, falling back to serum when it has no route.
+    Jupiter,
+}
+
+impl FromStr for RebalanceVenue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "serum" => Ok(Self::Serum),
+            "jupiter" => Ok(Self::Jupiter),
+            _ => Err(format!("unknown rebalance venue {}", s)),
+        }
+    }
+}
+
+pub struct LiquidatorConfig {
+    /// The total number of bots run.
+    pub worker_count: u8,
+    /// The slice of addresses this bot is responsible for.
+    pub worker_index: u8,
+    /// Priority fee for liquidation transactions, in micro-lamports per
+    /// compute unit.
+    pub priority_fee: Option<u64>,
+    /// Factor a liquidation's size is scaled by when it is rejected for
+    /// over-exposure.
+    pub reduction_factor: f64,
+    /// The most attempts made at liquidating a position.
+    pub reduction_max: usize,
+    /// Fraction of the cancel margin fraction an account must fall below
+    /// to have its orders cancelled. Values below 1.0 only cancel orders
+    /// of accounts safely over the line.
+    pub cancel_tolerance: f64,
+    /// Fraction of the maintenance margin fraction an account must fall
+    /// below to be liquidated. Values below 1.0 only attempt liquidations
+    /// that are safely over the line.
+    pub maintenance_tolerance: f64,
+    /// Accounts worth less than this, in USDC, are left alone unless they
+    /// are bankrupt.
+    pub min_account_value: f64,
+    /// If set, accounts with positions in a square market are not acted
+    /// on while its mark is more than this fraction away from its recent
+    /// median.
+    pub mark_band: Option<f64>,
+    /// Collateral and borrows worth less than this, in native USDC, are
+    /// dust. Dust borrows aren't spot liquidated, and an account left with
+    /// only dust collateral is settled as bankrupt. Defaults to the
+    /// program's `DUST_THRESHOLD`.
+    pub dust_threshold: I80F48,
+    /// Where spot liquidations are rebalanced, each swap in a transaction
+    /// after the liquidation's.
+    pub rebalance_venue: RebalanceVenue,
+    /// Base URL of the Jupiter v6 swap API.
+    pub jupiter_url: String,
+    /// Slippage allowed on Jupiter swaps, in basis points.
+    pub jupiter_slippage_bps: u16,
+    /// How much worse than the oracle a serum rebalance may fill, as a
+    /// fraction of the oracle price.
+    pub max_swap_slippage: f64,
+    /// How far from the top of the book the order closing a liquidated
+    /// perp position may fill, in basis points.
+    pub close_slippage_bps: u16,
+    /// Alert when the payer's balance falls below this many SOL.
+    pub min_payer_sol: Option<f64>,
+    /// Alert when the payer's margin account is worth less than this, in
+    /// USD.
+    pub min_payer_value: Option<f64>,
+    /// Simulate transactions before sending them, classifying a program
+    /// error the same way as a failed send.
+    pub simulate_first: bool,
+    /// Liquidate up to this many of an account's largest perp positions in
+    /// one bundle. At one, only the largest is liquidated.
+    pub perp_top_k: usize,
+    /// Perp markets liquidated by this bot, by symbol. If empty, all are.
+    pub only_markets: Vec<String>,
+    /// Collaterals whose borrows are liquidated by this bot, by oracle
+    /// symbol. If empty, all are.
+    ///
+    /// Cancels and bankruptcies span every market and collateral, so are
+    /// only done when neither list is set.
+    pub only_collaterals: Vec<String>,
+    /// Reconnect the account listener when it has received no updates for
+    /// this long, as the subscription can stall without disconnecting.
+    pub listener_stall_after: Duration,
+    /// Commitment level of the account subscription.
+    pub subscription_commitment: CommitmentConfig,
+    /// The most liquidation and cancel tasks run at once.
+    pub max_concurrent_liquidations: std::num::NonZeroUsize,
+    /// Check every account once, acting on those that need it, and
+    /// return, rather than keep the table up to date and check it on
+    /// every tick.
+    pub once: bool,
+}
+
+/// The tolerances accounts are checked at by `check_account` and the
+/// health server, which should be the liquidator's.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerances {
+    /// See `LiquidatorConfig::cancel_tolerance`.
+    pub cancel: f64,
+    /// See `LiquidatorConfig::maintenance_tolerance`.
+    pub maintenance: f64,
+}
+
+impl Tolerances {
+    /// The tolerance fraction `x` is checked at, the same as in
+    /// `check_liquidatable`, so whether an account is above a fraction
+    /// agrees with the decisions. The initial fraction isn't decided on,
+    /// so is checked exactly.
+    fn of(&self, x: FractionType) -> I80F48 {
+        match x {
+            FractionType::Cancel => I80F48::from_num(self.cancel),
+            FractionType::Maintenance => I80F48::from_num(self.maintenance),
+            _ => I80F48::ONE,
+        }
+    }
+}
+
+impl LiquidatorConfig {
+    /// Whether this bot liquidates positions in perp market `index`.
+    fn market_allowed(&self, state: &State, index: usize) -> bool {
+        self.only_markets.is_empty()
+            || self
+                .only_markets
+                .contains(&String::from(state.perp_markets[index].symbol))
+    }
+
+    /// Whether this bot liquidates borrows of collateral `index`.
+    fn collateral_allowed(&self, state: &State, index: usize) -> bool {
+        self.only_collaterals.is_empty()
+            || self
+                .only_collaterals
+                .contains(&String::from(state.collaterals[index].oracle_symbol))
+    }
+
+    /// Whether this bot handles only some markets or collaterals.
+    fn is_filtered(&self) -> bool {
+        !self.only_markets.is_empty() || !self.only_collaterals.is_empty()
+    }
+}
 
 pub async fn run(
     st: &'static AppState,
-    worker_count: u8,
-    worker_index: u8,
+    cfg: LiquidatorConfig,
 ) -> Result<(), Error> {
-    let database = accounts::DbWrapper::new(st, worker_index, worker_count);
+    for s in cfg.only_markets.iter() {
+        if !st.zo_indices.market_symbols.contains_key(s) {
+            warn!("unknown market {}, ignoring", s);
+        }
+    }
+
+    for s in cfg.only_collaterals.iter() {
+        if !st.zo_indices.collateral_symbols.contains_key(s) {
+            warn!("unknown collateral {}, ignoring", s);
+        }
+    }
+
+    let cfg: &'static _ = Box::leak(Box::new(cfg));
+    let database =
+        accounts::DbWrapper::new(st, cfg.worker_index, cfg.worker_count)?;
+
+    if cfg.once {
+        let (n, failed) = database
+            .check_all_accounts(
+                st,
+                cfg,
+                &zo_abi::ZO_DEX_PID,
+                &zo_abi::SERUM_DEX_PID,
+            )
+            .await
+            .map_err(|e| {
+                error!("Failed to check accounts: {:?}", e);
+                Error::PassFailed(1)
+            })?;
+        info!("Checked {} accounts, {} actions failed", n, failed);
+
+        return match failed {
+            0 => Ok(()),
+            n => Err(Error::PassFailed(n)),
+        };
+    }
 
     let f = tokio::spawn(self::listener::start_listener(
         &zo_abi::ID,
         st.cluster.ws_url().to_string(),
         database.clone(),
+        cfg.listener_stall_after,
+        cfg.subscription_commitment,
+    ));
+
+    let g = tokio::spawn(self::liquidation::liquidate_loop(
+        st,
+        cfg,
+        database.clone(),
     ));
 
-    let g = tokio::spawn(self::liquidation::liquidate_loop(&st, database));
+    tokio::spawn(self::health::watch_payer(st, cfg, database));
 
     // Propagate panic.
     tokio::select! {
@@ -32,3 +234,109 @@ pub async fn run(
 
     Ok(())
 }
+
+/// Loads the account table the way the liquidator does at startup, and
+/// writes a summary of it to `path` as JSON. See `AccountTable::dump`.
+pub fn dump_state(
+    st: &AppState,
+    path: &std::path::Path,
+    fractions: bool,
+) -> Result<(), Error> {
+    let table = accounts::AccountTable::new(st, 0, 1)?;
+    let json = serde_json::to_vec_pretty(&table.dump(fractions))?;
+    std::fs::write(path, json)?;
+
+    println!("wrote {}", path.display());
+
+    Ok(())
+}
+
+/// The margin account of `authority`, its key and control account, and
+/// the current state and cache to value it against.
+fn load_account(
+    st: &AppState,
+    authority: &Pubkey,
+) -> Result<(Pubkey, Margin, Control, State, Cache), Error> {
+    let (margin_key, _) = Pubkey::find_program_address(
+        &[authority.as_ref(), st.zo_state_pubkey.as_ref(), b"marginv1"],
+        &zo_abi::ID,
+    );
+
+    let margin: Margin = get_account(&st.rpc, &margin_key)?;
+    let control: Control = get_account(&st.rpc, &margin.control)?;
+    let state: State = get_account(&st.rpc, &st.zo_state_pubkey)?;
+    let cache: Cache = get_account(&st.rpc, &st.zo_cache_pubkey)?;
+
+    Ok((margin_key, margin, control, state, cache))
+}
+
+/// Runs the liquidator's margin fraction math against the margin account
+/// of a single authority, printing the fractions and the decisions the
+/// liquidator would make.
+pub fn check_account(
+    st: &AppState,
+    authority: &Pubkey,
+    tolerances: Tolerances,
+) -> Result<(), Error> {
+    let (margin_key, margin, control, state, cache) =
+        load_account(st, authority)?;
+    let oracles = utils::OracleIndex::new(&state, &cache);
+
+    let mf = |x| {
+        match margin_utils::get_mf_wrapped(
+            x, &margin, &control, &state, &cache, &oracles,
+        ) {
+            Ok(v) => v.to_string(),
+            Err(e) => format!("{:?}", e),
+        }
+    };
+    let is_above = |x| {
+        match margin_utils::check_mf(
+            x,
+            &margin,
+            &control,
+            &state,
+            &cache,
+            &oracles,
+            tolerances.of(x),
+        ) {
+            Ok(v) => v.to_string(),
+            Err(e) => format!("{:?}", e),
+        }
+    };
+
+    println!("margin:  {}", margin_key);
+    println!("control: {}", margin.control);
+    println!();
+    println!("mf:  {}", mf(MfReturnOption::Mf));
+    println!("mmf: {}", mf(MfReturnOption::Mmf));
+    println!("imf: {}", mf(MfReturnOption::Imf));
+    println!("omf: {}", mf(MfReturnOption::Omf));
+    println!("cmf: {}", mf(MfReturnOption::Cmf));
+    println!();
+    println!(
+        "above maintenance: {}",
+        is_above(FractionType::Maintenance)
+    );
+    println!("above cancel:      {}", is_above(FractionType::Cancel));
+    println!("above initial:     {}", is_above(FractionType::Initial));
+    println!();
+
+    match margin_utils::check_liquidatable(
+        &margin,
+        &control,
+        &state,
+        &cache,
+        &oracles,
+        tolerances.of(FractionType::Cancel),
+        tolerances.of(FractionType::Maintenance),
+    ) {
+        Ok((cancel, liquidate)) => {
+            println!("cancel orders: {}", cancel);
+            println!("liquidate:     {}", liquidate);
+        }
+        Err(e) => println!("failed to check account: {:?}", e),
+    }
+
+    Ok(())
+}
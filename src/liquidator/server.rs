@@ -0,0 +1,203 @@
+/*
+ * This file serves the liquidator's view of any account over HTTP, so
+ * dashboards and alerting can query account health without
+ * reimplementing the margin math. It answers `GET /health/<authority>`
+ * with the same fractions and decisions as `check_account`, as JSON,
+ * valued against the cache at the time of the request.
+ *
+ * Requests are few and each costs a handful of RPC reads, so they are
+ * served one at a time on a plain TCP listener.
+*/
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{info, warn};
+use zo_abi::FractionType;
+
+use crate::{
+    liquidator::{
+        margin_utils::{self, MfReturnOption},
+        utils::OracleIndex,
+        Tolerances,
+    },
+    AppState, Error,
+};
+
+/// Clients that don't send their request within this are dropped, so
+/// they can't hold up the others.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Response = (&'static str, serde_json::Value);
+
+fn error(status: &'static str, msg: impl ToString) -> Response {
+    (status, serde_json::json!({ "error": msg.to_string() }))
+}
+
+/// The authority requested by the request line, e.g.
+/// `GET /health/<authority> HTTP/1.1`.
+fn parse_request(line: &str) -> Result<Pubkey, Response> {
+    let mut parts = line.split_whitespace();
+
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return Err(error("400 Bad Request", "malformed request")),
+    };
+
+    let authority = match path.strip_prefix("/health/") {
+        Some(x) => x,
+        None => return Err(error("404 Not Found", "not found")),
+    };
+
+    if method != "GET" {
+        return Err(error("405 Method Not Allowed", "only GET is allowed"));
+    }
+
+    Pubkey::from_str(authority)
+        .map_err(|_| error("400 Bad Request", "invalid authority"))
+}
+
+/// The margin fractions, total value and decisions for the account of
+/// `authority`. Fractions and values are strings to keep their
+/// precision, and null if they couldn't be computed.
+fn account_health(
+    st: &AppState,
+    authority: &Pubkey,
+    tolerances: Tolerances,
+) -> Result<serde_json::Value, Error> {
+    let (margin_key, margin, control, state, cache) =
+        super::load_account(st, authority)?;
+    let oracles = OracleIndex::new(&state, &cache);
+
+    let mf = |x| {
+        margin_utils::get_mf_wrapped(
+            x, &margin, &control, &state, &cache, &oracles,
+        )
+        .map(|x| x.to_string())
+        .ok()
+    };
+    let is_above = |x| {
+        margin_utils::check_mf(
+            x,
+            &margin,
+            &control,
+            &state,
+            &cache,
+            &oracles,
+            tolerances.of(x),
+        )
+        .ok()
+    };
+
+    let decision = margin_utils::check_liquidatable(
+        &margin,
+        &control,
+        &state,
+        &cache,
+        &oracles,
+        tolerances.of(FractionType::Cancel),
+        tolerances.of(FractionType::Maintenance),
+    )
+    .ok();
+
+    Ok(serde_json::json!({
+        "authority": authority.to_string(),
+        "margin": margin_key.to_string(),
+        "mf": mf(MfReturnOption::Mf),
+        "mmf": mf(MfReturnOption::Mmf),
+        "imf": mf(MfReturnOption::Imf),
+        "omf": mf(MfReturnOption::Omf),
+        "cmf": mf(MfReturnOption::Cmf),
+        "value": margin_utils::get_total_account_value(
+            &margin, &control, &state, &cache, &oracles,
+        )
+        .map(|x| x.to_string())
+        .ok(),
+        "aboveMaintenance": is_above(FractionType::Maintenance),
+        "aboveCancel": is_above(FractionType::Cancel),
+        "aboveInitial": is_above(FractionType::Initial),
+        "cancel": decision.map(|x| x.0),
+        "liquidate": decision.map(|x| x.1),
+    }))
+}
+
+fn handle(
+    st: &AppState,
+    tolerances: Tolerances,
+    stream: &mut TcpStream,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    // Only the request line matters, the headers are ignored.
+    let mut line = String::new();
+    BufReader::new(&*stream).read_line(&mut line)?;
+
+    let (status, body) = match parse_request(&line) {
+        Ok(authority) => match account_health(st, &authority, tolerances) {
+            Ok(body) => ("200 OK", body),
+            Err(e) => {
+                warn!("Failed to check {}: {}", authority, e);
+                error("502 Bad Gateway", e)
+            }
+        },
+        Err(res) => res,
+    };
+
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n\
+        {}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serves `GET /health/<authority>` on `addr` until the process exits,
+/// deciding at `tolerances`.
+pub fn serve_health(
+    st: &AppState,
+    addr: SocketAddr,
+    tolerances: Tolerances,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving account health on {}", addr);
+
+    for stream in listener.incoming() {
+        let res =
+            stream.and_then(|mut stream| handle(st, tolerances, &mut stream));
+
+        if let Err(e) = res {
+            warn!("Failed to serve request: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let key = Pubkey::new_unique();
+
+        let line = format!("GET /health/{} HTTP/1.1\r\n", key);
+        assert_eq!(parse_request(&line).ok(), Some(key));
+
+        let status = |line: &str| parse_request(line).unwrap_err().0;
+        let post = format!("POST /health/{} HTTP/1.1\r\n", key);
+        assert_eq!(status(&post), "405 Method Not Allowed");
+        assert_eq!(status("GET /health/xyz HTTP/1.1\r\n"), "400 Bad Request");
+        assert_eq!(status("GET /metrics HTTP/1.1\r\n"), "404 Not Found");
+        assert_eq!(status("\r\n"), "400 Bad Request");
+    }
+}
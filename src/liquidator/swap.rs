@@ -16,6 +16,8 @@ use serum_dex::{
     state::MarketState as SerumMarketState,
 };
 
+use solana_client::rpc_client::RpcClient;
+
 use solana_sdk::{
     commitment_config::CommitmentConfig, pubkey::Pubkey,
     sysvar::rent::ID as RENT_ID,
@@ -27,8 +29,8 @@ use std::cell::RefMut;
 use tracing::{error, error_span, info, warn};
 
 use zo_abi::{
-    accounts, dex::ZoDexMarket as MarketState, instruction, Control, Margin,
-    OrderType, State,
+    accounts, dex::ZoDexMarket as MarketState, instruction, Cache, Control,
+    Margin, OrderType, State,
 };
 
 use crate::liquidator::{error::ErrorCode, math::SafeOp, utils::*};
@@ -36,6 +38,7 @@ use crate::liquidator::{error::ErrorCode, math::SafeOp, utils::*};
 #[deprecated]
 #[allow(dead_code)]
 pub fn swap_asset(
+    st: &crate::AppState,
     program: &Program,
     payer: &Pubkey,
     state: &State,
@@ -47,6 +50,8 @@ pub fn swap_asset(
     serum_dex_program: &Pubkey,
     serum_vault_signer: &Pubkey,
     asset_index: usize,
+    cache: &Cache,
+    max_slippage: I80F48,
 ) -> Result<(), ErrorCode> {
     let span = error_span!("swap_asset", asset = asset_index);
 
@@ -59,12 +64,28 @@ pub fn swap_asset(
 
     let margin_account = client.get_account(payer_margin).unwrap();
     let col_index = 41 + asset_index * 16;
-    let collateral: [u8; 16] = margin_account.data[col_index..col_index + 16]
-        .to_vec()
-        .try_into()
-        .unwrap();
+    let collateral: [u8; 16] = account_slice(
+        payer_margin,
+        &margin_account.data,
+        col_index..col_index + 16,
+    )?
+    .try_into()
+    .unwrap();
     let collateral_amount: I80F48 = I80F48::from_le_bytes(collateral);
 
+    let oracle_price: I80F48 = match get_oracle(
+        cache,
+        &state.collaterals[asset_index].oracle_symbol,
+    ) {
+        Some(oracle) => oracle.price.into(),
+        None => {
+            span.in_scope(|| {
+                error!("No oracle found for swapping {}", asset_index)
+            });
+            return Err(ErrorCode::SwapError);
+        }
+    };
+
     let buy = collateral_amount.is_negative();
     let swap_amount: u64 = if buy {
         let asks_key = array_to_pubkey(&{ serum_market.asks });
@@ -102,6 +123,19 @@ pub fn swap_asset(
             .checked_mul(factor)
             .unwrap();
 
+        // Refuse to buy from a book whose best ask is too far above the
+        // oracle, rather than send a swap that will fail its min_rate.
+        let max_price = max_buy_price(oracle_price, max_slippage);
+        if price > max_price {
+            span.in_scope(|| {
+                warn!(
+                    "Rejected swap of {} at {}, above {} ({} slippage from oracle {})",
+                    asset_index, price, max_price, max_slippage, oracle_price
+                )
+            });
+            return Err(ErrorCode::SwapError);
+        }
+
         collateral_amount
             .abs()
             .checked_mul(I80F48::from(
@@ -117,6 +151,24 @@ pub fn swap_asset(
         999_999_999_999_999u64
     };
 
+    let min_rate = match min_swap_rate(
+        oracle_price,
+        buy,
+        swap_decimals(state, asset_index, buy),
+        max_slippage,
+    ) {
+        Some(x) => x,
+        None => {
+            span.in_scope(|| {
+                error!(
+                    "No rate for swapping {} at {}",
+                    asset_index, oracle_price
+                )
+            });
+            return Err(ErrorCode::SwapError);
+        }
+    };
+
     if swap_amount <= 50 * 1000000 {
         // 50 USDC
         span.in_scope(|| warn!("No coins to swap for asset {}", asset_index));
@@ -124,6 +176,7 @@ pub fn swap_asset(
     }
 
     let result = retry_send(
+        st,
         || {
             program
                 .request()
@@ -165,11 +218,12 @@ pub fn swap_asset(
                     buy,
                     allow_borrow: false,
                     amount: swap_amount,
-                    min_rate: 1u64, // WARNING: this can have a lot of slippage
+                    min_rate,
                 })
                 .options(CommitmentConfig::confirmed())
         },
         5,
+        false,
     );
 
     match result {
@@ -181,6 +235,48 @@ pub fn swap_asset(
     }
 }
 
+/// The highest price a buy may fill at, `max_slippage` being a fraction
+/// of the oracle price.
+fn max_buy_price(oracle_price: I80F48, max_slippage: I80F48) -> I80F48 {
+    oracle_price.saturating_mul(I80F48::ONE + max_slippage)
+}
+
+/// The `min_rate` of a swap, in smol of the collateral bought per whole
+/// unit of the one sold, `max_slippage` worse than the oracle. `price` is
+/// the asset's oracle price in smol USDC per smol, and `decimals` those of
+/// the collateral sold. `None` if the price can't be turned into a rate.
+fn min_swap_rate(
+    price: I80F48,
+    buy: bool,
+    decimals: u8,
+    max_slippage: I80F48,
+) -> Option<u64> {
+    if price <= I80F48::ZERO {
+        return None;
+    }
+
+    let unit = I80F48::from_num(10u64.checked_pow(decimals as u32)?);
+    let rate = match buy {
+        true => unit.checked_div(price)?,
+        false => unit.checked_mul(price)?,
+    };
+
+    rate.checked_mul(I80F48::ONE - max_slippage)?
+        .checked_to_num::<u64>()
+}
+
+/// The decimals of the collateral sold in a swap of `asset_index`, which
+/// is USDC when buying.
+fn swap_decimals(state: &State, asset_index: usize, buy: bool) -> u8 {
+    match buy {
+        true => state.collaterals[0].decimals,
+        false => state.collaterals[asset_index].decimals,
+    }
+}
+
+/// A swap of `asset_index` on its serum market, buying it with USDC if
+/// `buy_asset`, else selling it for USDC. The swap fails rather than fill
+/// more than `max_slippage` worse than the asset's oracle `price`.
 pub fn make_swap_ix(
     program: &Program,
     payer: &Pubkey,
@@ -195,7 +291,22 @@ pub fn make_swap_ix(
     max_transfer_amount: u64,
     buy_asset: bool,
     asset_index: usize,
+    price: I80F48,
+    max_slippage: I80F48,
 ) -> Result<Instruction, ErrorCode> {
+    let min_rate = match min_swap_rate(
+        price,
+        buy_asset,
+        swap_decimals(state, asset_index, buy_asset),
+        max_slippage,
+    ) {
+        Some(x) => x,
+        None => {
+            error!("No rate for swapping {} at {}", asset_index, price);
+            return Err(ErrorCode::SwapError);
+        }
+    };
+
     let quote_mint = state.collaterals[0].mint;
     let quote_vault = state.vaults[0];
     let asset_mint = state.collaterals[asset_index].mint;
@@ -240,7 +351,7 @@ pub fn make_swap_ix(
             buy: buy_asset,
             allow_borrow: false,
             amount: max_transfer_amount,
-            min_rate: 1u64, // WARNING: this can have a lot of slippage
+            min_rate,
         }.data(),
         program_id: program.id(),
     };
@@ -250,6 +361,7 @@ pub fn make_swap_ix(
 
 #[allow(dead_code)]
 pub fn close_position(
+    st: &crate::AppState,
     program: &Program,
     state: &State,
     state_key: &Pubkey,
@@ -269,8 +381,13 @@ pub fn close_position(
         .get_account(&control.open_orders_agg[index].key)
         .unwrap();
 
-    let native_coin_total_bytes: [u8; 8] =
-        oo_account.data[85..93].to_vec().try_into().unwrap();
+    let native_coin_total_bytes: [u8; 8] = account_slice(
+        &control.open_orders_agg[index].key,
+        &oo_account.data,
+        85..93,
+    )?
+    .try_into()
+    .unwrap();
 
     let native_coin_total = i64::from_le_bytes(native_coin_total_bytes);
     let span = error_span!("close_position", index = index);
@@ -283,6 +400,7 @@ pub fn close_position(
     let result = if native_coin_total < 0 {
         // Short order
         retry_send(
+            st,
             || {
                 program
                     .request()
@@ -316,10 +434,12 @@ pub fn close_position(
                     .options(CommitmentConfig::confirmed())
             },
             5,
+            false,
         )
     } else {
         // Long order
         retry_send(
+            st,
             || {
                 program
                     .request()
@@ -353,6 +473,7 @@ pub fn close_position(
                     .options(CommitmentConfig::confirmed())
             },
             5,
+            false,
         )
     };
 
@@ -370,7 +491,100 @@ pub fn close_position(
     }
 }
 
+/// The highest price and quantity an order can have, for limits that
+/// shouldn't bind.
+const UNBOUNDED: u64 = 999_999_999_999_999;
+
+/// Bytes before a dex book's slab, the head padding and account flags,
+/// and after it, the tail padding.
+const BOOK_HEAD_LEN: usize = 13;
+const BOOK_TAIL_LEN: usize = 7;
+
+/// The best price in `dex_market`'s book that a buy (`is_long`) or sell
+/// fills against, in price lots, or `None` if the book can't be read or
+/// that side is empty.
+fn best_price(
+    rpc: &RpcClient,
+    dex_market: &MarketState,
+    is_long: bool,
+) -> Option<u64> {
+    let key = if is_long {
+        dex_market.asks
+    } else {
+        dex_market.bids
+    };
+    let data = rpc.get_account_data(&key).ok()?;
+    let end = data.len().checked_sub(BOOK_TAIL_LEN)?;
+    let bytes = data.get(BOOK_HEAD_LEN..end)?;
+
+    // The slab's nodes are read in place, so it's copied to be aligned.
+    let mut buf = vec![0u64; (bytes.len() + 7) / 8];
+    let aligned =
+        &mut bytemuck::cast_slice_mut::<u64, u8>(&mut buf)[..bytes.len()];
+    aligned.copy_from_slice(bytes);
+    let slab = Slab::new(aligned);
+
+    let handle = if is_long {
+        slab.find_min()
+    } else {
+        slab.find_max()
+    }?;
+    Some(slab.get(handle)?.as_leaf()?.price().into())
+}
+
+/// The limit price of the order closing a position, `slippage_bps` from
+/// the book's best price. Without a best price, it's the extreme so the
+/// order fills at whatever the book offers.
+fn close_limit_price(
+    best_price: Option<u64>,
+    is_long: bool,
+    slippage_bps: u16,
+) -> u64 {
+    let best = match best_price {
+        Some(x) => x as u128,
+        None if is_long => return UNBOUNDED,
+        None => return 1,
+    };
+    let bps = slippage_bps as u128;
+
+    if is_long {
+        let x = (best * (10_000 + bps) + 9_999) / 10_000;
+        u64::try_from(x).map_or(UNBOUNDED, |x| x.min(UNBOUNDED))
+    } else {
+        let x = best * 10_000u128.saturating_sub(bps) / 10_000;
+        (x as u64).max(1)
+    }
+}
+
+/// The lots the liqor closes after taking on `lots` of the liqee's
+/// position, on top of its own `pos_size` smol, or `None` if the
+/// liqor's position is then flat or on the other side.
+fn close_base_lots(
+    pos_size: i64,
+    coin_lot_size: u64,
+    liqee_was_long: bool,
+    lots: u64,
+) -> Option<u64> {
+    let held = pos_size.checked_div(i64::try_from(coin_lot_size).ok()?)?;
+    let taken = i64::try_from(lots).ok()?;
+    let after = if liqee_was_long {
+        held.checked_add(taken)?
+    } else {
+        held.checked_sub(taken)?
+    };
+
+    match after {
+        x if liqee_was_long && x > 0 => Some(x as u64),
+        x if !liqee_was_long && x < 0 => Some(x.unsigned_abs()),
+        _ => None,
+    }
+}
+
+/// The order closing the position the liqor takes on by liquidating
+/// `lots`, priced within `slippage_bps` of the book's best price, or `None`
+/// if there's no position left to close.
 pub fn close_position_ix(
+    rpc: &RpcClient,
     program: &Program,
     state: &State,
     state_key: &Pubkey,
@@ -382,15 +596,30 @@ pub fn close_position_ix(
     dex_program: &Pubkey,
     index: usize,
     liqee_was_long: bool,
-) -> Result<Instruction, ErrorCode> {
-
-    // Close all perp positions
-    let limit: u64 = if !liqee_was_long {
-        999_999_999_999_999
-    } else {
-        1
+    lots: u64,
+    slippage_bps: u16,
+) -> Result<Option<Instruction>, ErrorCode> {
+    // Place the opposite order to close.
+    let is_long = !liqee_was_long;
+
+    let max_base_quantity = match close_base_lots(
+        control.open_orders_agg[index].pos_size,
+        dex_market.coin_lot_size,
+        liqee_was_long,
+        lots,
+    ) {
+        Some(x) => x,
+        None => return Ok(None),
     };
 
+    let best = best_price(rpc, dex_market, is_long);
+    if best.is_none() {
+        warn!(
+            "Failed to read the book of market {}, closing without a limit",
+            index
+        );
+    }
+
     let close_ix = Instruction {
         accounts: accounts::PlacePerpOrder {
             state: *state_key,
@@ -410,10 +639,11 @@ pub fn close_position_ix(
         }
         .to_account_metas(None),
         data: instruction::PlacePerpOrder {
-            is_long: !liqee_was_long,   // Place opposite order to close
-            limit_price: limit, // TODO: make this more principled
-            max_base_quantity: 999_999_999_999_999u64,
-            max_quote_quantity: 999_999_999_999_999u64,
+            is_long,
+            limit_price: close_limit_price(best, is_long, slippage_bps),
+            max_base_quantity,
+            // Bounded by the price and base quantity.
+            max_quote_quantity: UNBOUNDED,
             order_type: OrderType::ReduceOnlyIoc,
             limit: 10,
             client_id: 0u64,
@@ -422,5 +652,78 @@ pub fn close_position_ix(
         program_id: program.id(),
     };
 
-    Ok(close_ix)
+    Ok(Some(close_ix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_limit_price() {
+        assert_eq!(close_limit_price(Some(1000), true, 100), 1010);
+        assert_eq!(close_limit_price(Some(1000), false, 100), 990);
+        assert_eq!(close_limit_price(Some(999), true, 100), 1009);
+        assert_eq!(close_limit_price(Some(999), false, 100), 989);
+        assert_eq!(close_limit_price(Some(1000), true, 0), 1000);
+
+        // Never past the order's bounds.
+        assert_eq!(close_limit_price(Some(1), false, 100), 1);
+        assert_eq!(close_limit_price(Some(1000), false, 20_000), 1);
+        assert_eq!(close_limit_price(Some(UNBOUNDED), true, 100), UNBOUNDED);
+
+        assert_eq!(close_limit_price(None, true, 100), UNBOUNDED);
+        assert_eq!(close_limit_price(None, false, 100), 1);
+    }
+
+    #[test]
+    fn test_min_swap_rate() {
+        // An asset with 9 decimals worth 15.625 USDC, or 1/64 smol USDC
+        // per smol.
+        let price = I80F48::from_num(0.015625);
+        let quarter = I80F48::from_num(0.25);
+
+        // Selling one asset for at least 75% of 15.625 USDC.
+        assert_eq!(min_swap_rate(price, false, 9, quarter), Some(11_718_750));
+        // Buying with one USDC for at least 75% of 64 asset.
+        assert_eq!(min_swap_rate(price, true, 6, quarter), Some(48_000_000));
+        assert_eq!(
+            min_swap_rate(price, false, 9, I80F48::ZERO),
+            Some(15_625_000)
+        );
+
+        // Inexact prices and slippages are rounded down.
+        let rate = min_swap_rate(
+            I80F48::from_num(0.02),
+            false,
+            9,
+            I80F48::from_num(0.01),
+        )
+        .unwrap();
+        assert!((19_799_999..=19_800_000).contains(&rate));
+
+        // No rate without a price.
+        assert_eq!(min_swap_rate(I80F48::ZERO, true, 6, quarter), None);
+        assert_eq!(min_swap_rate(-price, false, 9, quarter), None);
+        assert_eq!(min_swap_rate(I80F48::DELTA, true, 30, quarter), None);
+    }
+
+    #[test]
+    fn test_close_base_lots() {
+        // Flat liqor taking on a long, then a short.
+        assert_eq!(close_base_lots(0, 100, true, 5), Some(5));
+        assert_eq!(close_base_lots(0, 100, false, 5), Some(5));
+
+        // Already long, taking on more.
+        assert_eq!(close_base_lots(300, 100, true, 5), Some(8));
+
+        // Short, taking on a long that nets to flat or stays short.
+        assert_eq!(close_base_lots(-500, 100, true, 5), None);
+        assert_eq!(close_base_lots(-800, 100, true, 5), None);
+
+        // Short, taking on a long that flips it.
+        assert_eq!(close_base_lots(-300, 100, true, 5), Some(2));
+
+        assert_eq!(close_base_lots(0, 0, true, 5), None);
+    }
 }
@@ -3,7 +3,7 @@ use anchor_lang::{
     Owner, ZeroCopy,
 };
 
-use anchor_client::{ClientError::SolanaClientError, RequestBuilder};
+use anchor_client::{ClientError::SolanaClientError, Program, RequestBuilder};
 
 use solana_account_decoder::UiAccountEncoding;
 use solana_client::{
@@ -14,16 +14,28 @@ use solana_client::{
     rpc_request::{RpcError, RpcResponseErrorData},
 };
 use solana_sdk::{
-    account::Account, commitment_config::CommitmentConfig,
-    instruction::InstructionError, pubkey::Pubkey, signature::Signature,
-    transaction::TransactionError,
+    account::Account,
+    commitment_config::CommitmentConfig,
+    compute_budget::ComputeBudgetInstruction,
+    instruction::{Instruction, InstructionError},
+    message::Message,
+    packet::PACKET_DATA_SIZE,
+    pubkey::Pubkey,
+    signature::Signature,
+    system_instruction,
+    transaction::{Transaction, TransactionError},
 };
 
-use std::ops::Deref;
+use rand::Rng;
 
-use tracing::{error, warn};
+use std::{ops::Range, time::Duration};
 
-use zo_abi::{Cache, OpenOrdersInfo, OracleCache, Symbol, MAX_MARKETS};
+use tracing::{debug, error, info, warn};
+
+use zo_abi::{
+    Cache, OpenOrdersInfo, OracleCache, State, Symbol, MAX_COLLATERALS,
+    MAX_MARKETS,
+};
 
 use crate::liquidator::error::ErrorCode;
 
@@ -40,19 +52,26 @@ pub fn get_account_info<'a>(
     level = "error",
     fields(key = %key, ty = %std::any::type_name::<T>())
 )]
-pub fn get_type_from_account<T>(key: &Pubkey, account: &mut Account) -> T
+pub fn get_type_from_account<T>(
+    key: &Pubkey,
+    account: &mut Account,
+) -> Option<T>
 where
     T: ZeroCopy + Owner,
 {
     let account_info: AccountInfo<'_> = get_account_info(key, account);
-    let loader: AccountLoader<'_, T> =
-        AccountLoader::try_from(&account_info).unwrap();
-    let value = loader.load();
-    match value {
-        Ok(x) => *x.deref(),
+    let load = || -> anchor_lang::Result<T> {
+        let loader: AccountLoader<'_, T> =
+            AccountLoader::try_from(&account_info)?;
+        let value = *loader.load()?;
+        Ok(value)
+    };
+
+    match load() {
+        Ok(x) => Some(x),
         Err(e) => {
             error!("Failed to get type from {}: {:?}.", key, e);
-            panic!()
+            None
         }
     }
 }
@@ -86,7 +105,9 @@ where
         .get_program_accounts_with_config(program_address, config)
         .map(|v| {
             v.into_iter()
-                .map(|(k, mut a)| (k, get_type_from_account::<T>(&k, &mut a)))
+                .filter_map(|(k, mut a)| {
+                    Some((k, get_type_from_account::<T>(&k, &mut a)?))
+                })
                 .collect()
         })
 }
@@ -103,6 +124,53 @@ pub fn get_oracle<'a>(cache: &'a Cache, s: &Symbol) -> Option<&'a OracleCache> {
     Some(&cache.oracles[get_oracle_index(cache, s)?])
 }
 
+/// The oracle of each collateral and market, as indices into
+/// `cache.oracles`. The margin math prices every account with the same
+/// oracles, so they are looked up once per cache update rather than
+/// binary searched for each account.
+#[derive(Clone, Copy)]
+pub struct OracleIndex {
+    collaterals: [Option<usize>; MAX_COLLATERALS],
+    markets: [Option<usize>; MAX_MARKETS as usize],
+}
+
+impl OracleIndex {
+    pub fn new(state: &State, cache: &Cache) -> Self {
+        let mut index = Self {
+            collaterals: [None; MAX_COLLATERALS],
+            markets: [None; MAX_MARKETS as usize],
+        };
+
+        for (i, c) in state.collaterals.iter().enumerate() {
+            index.collaterals[i] = get_oracle_index(cache, &c.oracle_symbol);
+        }
+
+        for (i, m) in state.perp_markets.iter().enumerate() {
+            index.markets[i] = get_oracle_index(cache, &m.oracle_symbol);
+        }
+
+        index
+    }
+
+    /// The oracle of collateral `i`.
+    pub fn collateral<'a>(
+        &self,
+        cache: &'a Cache,
+        i: usize,
+    ) -> Option<&'a OracleCache> {
+        Some(&cache.oracles[self.collaterals[i]?])
+    }
+
+    /// The oracle of perp market `i`.
+    pub fn market<'a>(
+        &self,
+        cache: &'a Cache,
+        i: usize,
+    ) -> Option<&'a OracleCache> {
+        Some(&cache.oracles[self.markets[i]?])
+    }
+}
+
 pub fn get_oo_keys(
     agg: &[OpenOrdersInfo; MAX_MARKETS as usize],
 ) -> [Pubkey; MAX_MARKETS as usize] {
@@ -135,6 +203,33 @@ pub fn is_right_remainder(key: &Pubkey, modulus: u8, remainder: u8) -> bool {
     sum % modulus == remainder
 }
 
+/// Whether a market's lot sizes can be divided by. A market that hasn't
+/// been set up yet has zero lot sizes, and sizing orders for it would
+/// divide by zero.
+pub fn has_lot_sizes(coin_lot_size: u64, pc_lot_size: u64) -> bool {
+    coin_lot_size != 0 && pc_lot_size != 0
+}
+
+/// `data[range]` of the account `key`, or `MalformedAccount` if the data
+/// is too short, e.g. because the RPC returned a partial account.
+pub fn account_slice<'a>(
+    key: &Pubkey,
+    data: &'a [u8],
+    range: Range<usize>,
+) -> Result<&'a [u8], ErrorCode> {
+    let end = range.end;
+
+    data.get(range).ok_or_else(|| {
+        error!(
+            "Account {} is malformed: expected at least {} bytes, got {}",
+            key,
+            end,
+            data.len()
+        );
+        ErrorCode::MalformedAccount
+    })
+}
+
 pub fn array_to_le_bytes(array: &[u64; 4]) -> [u8; 32] {
     let mut bytes = [0u8; 32];
     for (i, x) in array.iter().enumerate() {
@@ -160,11 +255,7 @@ pub fn get_preflight_error_code(error: &RpcError) -> Option<&u32> {
             data
         {
             if let Some(tx_err) = &result.err {
-                if let TransactionError::InstructionError(_, ix_err) = tx_err {
-                    if let InstructionError::Custom(code) = ix_err {
-                        error_code = Some(code);
-                    }
-                }
+                error_code = get_custom_error_code(tx_err);
             }
         }
     }
@@ -172,55 +263,340 @@ pub fn get_preflight_error_code(error: &RpcError) -> Option<&u32> {
     error_code
 }
 
+/// The program error code of a failed instruction.
+pub fn get_custom_error_code(error: &TransactionError) -> Option<&u32> {
+    match error {
+        TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(code),
+        ) => Some(code),
+        _ => None,
+    }
+}
+
+/// The most compute units a single transaction may request.
+pub const MAX_COMPUTE_UNITS: u32 = 1_400_000;
+
+/// Compute budget instructions raising the limit to `units` (capped at
+/// `MAX_COMPUTE_UNITS`) and optionally setting a priority fee in
+/// micro-lamports per compute unit.
+fn compute_budget_ixs(
+    units: u32,
+    priority_fee: Option<u64>,
+) -> Vec<Instruction> {
+    let mut ixs = vec![ComputeBudgetInstruction::set_compute_unit_limit(
+        units.min(MAX_COMPUTE_UNITS),
+    )];
+
+    if let Some(fee) = priority_fee {
+        ixs.push(ComputeBudgetInstruction::set_compute_unit_price(fee));
+    }
+
+    ixs
+}
+
+/// Prepends compute budget instructions to the request.
+pub fn with_compute_budget<'a>(
+    request_builder: RequestBuilder<'a>,
+    units: u32,
+    priority_fee: Option<u64>,
+) -> RequestBuilder<'a> {
+    compute_budget_ixs(units, priority_fee)
+        .into_iter()
+        .fold(request_builder, |b, ix| b.instruction(ix))
+}
+
+/// Serialized size of a transaction with the given instructions, signed
+/// only by `payer`.
+fn transaction_size(payer: &Pubkey, ixs: &[Instruction]) -> usize {
+    let message = Message::new(ixs, Some(payer));
+    let sigs = message.header.num_required_signatures as usize;
+
+    // The signatures are prefixed by a compact-u16 length, which is one
+    // byte for fewer than 128 signatures.
+    1 + 64 * sigs + message.serialize().len()
+}
+
+/// Instructions, each paired with its compute unit budget, that must
+/// land in the same transaction, e.g. a liquidation and the order closing
+/// the position it takes on.
+pub type Atomic = Vec<(Instruction, u32)>;
+
+/// Whether the groups `txn` fit in one transaction along with their
+/// compute budget instructions, and the instruction advancing the durable
+/// nonce `nonce` if set.
+pub fn fits_transaction(
+    payer: &Pubkey,
+    priority_fee: Option<u64>,
+    nonce: Option<&Pubkey>,
+    txn: &[Atomic],
+) -> bool {
+    let units: u32 = txn.iter().flatten().map(|x| x.1).sum();
+    let ixs: Vec<Instruction> = nonce
+        .map(|n| system_instruction::advance_nonce_account(n, payer))
+        .into_iter()
+        .chain(compute_budget_ixs(units, priority_fee))
+        .chain(txn.iter().flatten().map(|x| x.0.clone()))
+        .collect();
+
+    units <= MAX_COMPUTE_UNITS
+        && transaction_size(payer, &ixs) <= PACKET_DATA_SIZE
+}
+
+/// Splits a bundle of atomic groups of instructions into consecutive
+/// transactions that each fit along with their compute budget
+/// instructions, and the instruction advancing the durable nonce `nonce`
+/// if set. A group is never split, so one too large to fit on its own
+/// fails the whole bundle.
+pub fn split_bundle(
+    payer: &Pubkey,
+    priority_fee: Option<u64>,
+    nonce: Option<&Pubkey>,
+    bundle: Vec<Atomic>,
+) -> Result<Vec<Vec<Atomic>>, ErrorCode> {
+    let fits =
+        |txn: &[Atomic]| fits_transaction(payer, priority_fee, nonce, txn);
+
+    let mut txns = Vec::new();
+    let mut txn: Vec<Atomic> = Vec::new();
+
+    for group in bundle {
+        if !fits(std::slice::from_ref(&group)) {
+            error!("{} instructions don't fit in one transaction", group.len());
+            return Err(ErrorCode::TransactionTooLarge);
+        }
+
+        txn.push(group);
+
+        if txn.len() > 1 && !fits(&txn) {
+            let group = txn.pop().unwrap();
+            txns.push(std::mem::replace(&mut txn, vec![group]));
+        }
+    }
+
+    if !txn.is_empty() {
+        txns.push(txn);
+    }
+
+    Ok(txns)
+}
+
+/// What landed of a bundle sent by `send_bundle`.
+#[derive(Debug)]
+pub struct SentBundle {
+    /// Signatures of the transactions that landed, in order.
+    pub signatures: Vec<Signature>,
+    /// How many of the bundle's atomic groups landed, from the start.
+    pub landed: usize,
+    /// Why the rest of the bundle didn't land, if it didn't.
+    pub error: Option<ErrorCode>,
+}
+
+/// Sends a bundle of atomic groups of instructions in as few
+/// transactions as fit. Transactions are sent in order, stopping at the
+/// first failure, so the groups that landed are always a prefix of the
+/// bundle.
+#[tracing::instrument(skip_all, level = "error")]
+pub fn send_bundle(
+    st: &crate::AppState,
+    program: &Program,
+    payer: &Pubkey,
+    priority_fee: Option<u64>,
+    bundle: Vec<Atomic>,
+    retries: usize,
+    simulate_first: bool,
+) -> SentBundle {
+    let len = bundle.len();
+    let nonce = st.nonce().map(|n| n.key());
+    let mut sent = SentBundle {
+        signatures: Vec::new(),
+        landed: 0,
+        error: None,
+    };
+
+    let txns = match split_bundle(payer, priority_fee, nonce.as_ref(), bundle) {
+        Ok(txns) => txns,
+        Err(e) => {
+            sent.error = Some(e);
+            return sent;
+        }
+    };
+
+    if txns.len() > 1 {
+        info!(
+            "Splitting bundle of {} groups into {} transactions",
+            len,
+            txns.len()
+        );
+    }
+
+    for txn in txns {
+        let units = txn.iter().flatten().map(|x| x.1).sum();
+
+        let result = retry_send(
+            st,
+            || {
+                txn.iter().flatten().fold(
+                    with_compute_budget(program.request(), units, priority_fee)
+                        .options(CommitmentConfig::confirmed()),
+                    |b, (ix, _)| b.instruction(ix.clone()),
+                )
+            },
+            retries,
+            simulate_first,
+        );
+
+        match result {
+            Ok(sig) => {
+                sent.signatures.push(sig);
+                sent.landed += txn.len();
+            }
+            Err(e) => {
+                sent.error = Some(e);
+                break;
+            }
+        }
+    }
+
+    sent
+}
+
+/// How `retry_send` handles a program error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RetryClass {
+    /// The liquidation is too large, so it's retried with a smaller size.
+    Reduce,
+    /// Sending again can't succeed, usually because another liquidator
+    /// got to the account first.
+    Unrecoverable,
+    /// Sent again as is.
+    Retry,
+}
+
+/// Program error codes that aren't simply retried, with the reason. These
+/// follow the order of the zo program's error enum, so they need to be
+/// checked whenever the program is upgraded.
+const RETRY_CLASSES: &[(u32, RetryClass, &str)] = &[
+    (6006, RetryClass::Reduce, "Liquidation exceeds exposure"),
+    (6007, RetryClass::Unrecoverable, "Account is not liquidatable"),
+    (6011, RetryClass::Unrecoverable, "Account is not liquidatable"),
+    (6012, RetryClass::Unrecoverable, "Account is not liquidatable"),
+    (6016, RetryClass::Reduce, "Liquidation exceeds exposure"),
+    (6017, RetryClass::Unrecoverable, "Account was already liquidated"),
+    (6046, RetryClass::Reduce, "Liquidation exceeds exposure"),
+    (
+        6052,
+        RetryClass::Unrecoverable,
+        "Account has unliquidated spot, possibly already liquidated",
+    ),
+];
+
+/// The `RetryClass` of a program error code, and the reason for it.
+pub fn retry_class(code: u32) -> (RetryClass, &'static str) {
+    RETRY_CLASSES
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .map(|&(_, class, reason)| (class, reason))
+        .unwrap_or((RetryClass::Retry, "Unknown program error"))
+}
+
+/// The error to stop retrying with if the program error `code` isn't
+/// simply retried.
+fn stop_retrying(code: u32, detail: &dyn std::fmt::Debug) -> Option<ErrorCode> {
+    match retry_class(code) {
+        (RetryClass::Reduce, reason) => {
+            warn!("Retrying with smaller liquidation: {}", reason);
+            Some(ErrorCode::LiquidationOverExposure)
+        }
+        (RetryClass::Unrecoverable, reason) => {
+            warn!("{}: {:?}", reason, detail);
+            Some(ErrorCode::UnrecoverableTransactionError)
+        }
+        (RetryClass::Retry, _) => None,
+    }
+}
+
+/// The error the request fails with in simulation. Failing to simulate
+/// is only logged, leaving the send to surface the problem.
+fn simulate(
+    st: &crate::AppState,
+    request_builder: &RequestBuilder,
+) -> Option<TransactionError> {
+    let ixs = request_builder.instructions().ok()?;
+    let blockhash = st.rpc.get_latest_blockhash().ok()?;
+    let tx = Transaction::new_signed_with_payer(
+        &ixs,
+        Some(&st.payer()),
+        &[st.payer_key()],
+        blockhash,
+    );
+
+    match st.rpc.simulate_transaction(&tx) {
+        Ok(res) => res.value.err,
+        Err(e) => {
+            debug!("Failed to simulate: {}", e);
+            None
+        }
+    }
+}
+
+/// First delay between attempts after a transient error, doubled after
+/// each attempt up to `RETRY_BACKOFF_MAX`.
+const RETRY_BACKOFF_BASE: Duration = Duration::from_millis(200);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(2);
+
+/// The delay before retrying after the `attempt`th failure, between half
+/// and all of the exponential backoff so that bots don't retry in step.
+fn retry_backoff(attempt: usize) -> Duration {
+    let backoff = RETRY_BACKOFF_BASE
+        .saturating_mul(1 << attempt.min(16))
+        .min(RETRY_BACKOFF_MAX);
+
+    backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
 // TODO: Refactor to take vector of ixs
 #[tracing::instrument(skip_all, level = "error")]
 pub fn retry_send<'a>(
+    st: &crate::AppState,
     make_builder: impl Fn() -> RequestBuilder<'a>,
     retries: usize,
+    simulate_first: bool,
 ) -> Result<Signature, ErrorCode> {
     let mut last_error: Option<_> = None;
 
-    for _i in 0..retries {
+    for i in 0..retries {
         let request_builder = make_builder();
 
-        match request_builder.send() {
+        if simulate_first {
+            let err = simulate(st, &request_builder);
+
+            if let Some(&code) = err.as_ref().and_then(get_custom_error_code) {
+                if let Some(e) = stop_retrying(code, &err) {
+                    return Err(e);
+                }
+            }
+        }
+
+        match st.send(request_builder) {
             Ok(response) => {
                 return Ok(response);
             }
             Err(e) => {
                 if let SolanaClientError(ClientError { request: _, kind }) = e {
+                    // Every send fails the same way until the payer is
+                    // topped up, so don't use up the retries.
+                    if crate::funds::is_client_insufficient_funds(&kind) {
+                        crate::funds::report(st);
+                        return Err(ErrorCode::InsufficientFunds);
+                    }
+
                     match &kind {
                         ClientErrorKind::RpcError(e) => {
                             match get_preflight_error_code(e) {
                                 Some(&code) => {
-                                    if code == 6006
-                                        || code == 6016
-                                        || code == 6046
-                                    {
-                                        warn!(
-                                            "Retrying with smaller liquidation"
-                                        );
-                                        return Err(
-                                            ErrorCode::LiquidationOverExposure,
-                                        );
-                                    } else if code == 6007
-                                        || code == 6012
-                                        || code == 6011
-                                    {
-                                        warn!("Account is not liquidatable");
-                                        return Err(
-                                            ErrorCode::UnrecoverableTransactionError,
-                                        );
-                                    } else if code == 6017 {
-                                        warn!("Account was already liquidated");
-                                        return Err(
-                                            ErrorCode::UnrecoverableTransactionError,
-                                        );
-                                    } else if code == 6052 {
-                                        warn!("Account has unliquidated spot, possibly already liquidated. {:?}", e);
-                                        return Err(
-                                            ErrorCode::UnrecoverableTransactionError,
-                                        );
+                                    if let Some(e) = stop_retrying(code, e) {
+                                        return Err(e);
                                     }
                                 }
                                 None => {
@@ -233,6 +609,14 @@ pub fn retry_send<'a>(
                         }
                         ClientErrorKind::Reqwest(e) => {
                             warn!("Got reqwest error: {:?}", e);
+
+                            // The RPC may be briefly down, so give it time
+                            // before using up the retries.
+                            if i + 1 < retries {
+                                let backoff = retry_backoff(i);
+                                debug!("Retrying in {:?}", backoff);
+                                std::thread::sleep(backoff);
+                            }
                         }
                         ClientErrorKind::TransactionError(e) => {
                             warn!("Got transaction error: {:?}", e);
@@ -270,3 +654,160 @@ pub fn retry_send<'a>(
 
     Err(ErrorCode::TimeoutExceeded)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::instruction::AccountMeta;
+
+    fn ix(data_len: usize, units: u32) -> (Instruction, u32) {
+        let ix = Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: vec![AccountMeta::new(Pubkey::new_unique(), false)],
+            data: vec![0; data_len],
+        };
+
+        (ix, units)
+    }
+
+    fn lens(txns: &[Vec<Atomic>]) -> Vec<usize> {
+        txns.iter().map(|t| t.len()).collect()
+    }
+
+    #[test]
+    fn test_split_bundle_fits() {
+        let payer = Pubkey::new_unique();
+        let txns = split_bundle(
+            &payer,
+            Some(1),
+            None,
+            vec![
+                vec![ix(100, 100_000)],
+                vec![ix(100, 100_000), ix(100, 100_000)],
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(lens(&txns), vec![2]);
+    }
+
+    #[test]
+    fn test_split_bundle_by_size() {
+        let payer = Pubkey::new_unique();
+        let txns = split_bundle(
+            &payer,
+            None,
+            None,
+            vec![
+                vec![ix(400, 1)],
+                vec![ix(400, 1)],
+                vec![ix(400, 1)],
+                vec![ix(200, 1), ix(200, 1)],
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(lens(&txns), vec![2, 2]);
+
+        for t in &txns {
+            let ixs: Vec<_> = t.iter().flatten().map(|x| x.0.clone()).collect();
+            assert!(transaction_size(&payer, &ixs) <= PACKET_DATA_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_split_bundle_by_units() {
+        let payer = Pubkey::new_unique();
+        let txns = split_bundle(
+            &payer,
+            None,
+            None,
+            vec![
+                vec![ix(10, 800_000)],
+                vec![ix(10, 400_000), ix(10, 400_000)],
+                vec![ix(10, 400_000)],
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(lens(&txns), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_split_bundle_keeps_groups_whole() {
+        let payer = Pubkey::new_unique();
+
+        // Each instruction fits on its own, but not together.
+        let too_large = split_bundle(
+            &payer,
+            None,
+            None,
+            vec![vec![ix(10, 1)], vec![ix(800, 1), ix(800, 1)]],
+        );
+        assert!(matches!(too_large, Err(ErrorCode::TransactionTooLarge)));
+
+        let too_many_units = split_bundle(
+            &payer,
+            None,
+            None,
+            vec![vec![ix(10, 800_000), ix(10, 800_000)]],
+        );
+        assert!(matches!(
+            too_many_units,
+            Err(ErrorCode::TransactionTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_retry_class() {
+        for code in [6006, 6016, 6046] {
+            assert_eq!(retry_class(code).0, RetryClass::Reduce, "{}", code);
+        }
+
+        for code in [6007, 6011, 6012, 6017, 6052] {
+            assert_eq!(
+                retry_class(code).0,
+                RetryClass::Unrecoverable,
+                "{}",
+                code
+            );
+        }
+
+        for code in [0, 6000, 6008, 6053] {
+            assert_eq!(retry_class(code).0, RetryClass::Retry, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_retry_classes_unique() {
+        let mut codes: Vec<u32> = RETRY_CLASSES.iter().map(|x| x.0).collect();
+        codes.sort_unstable();
+        codes.dedup();
+
+        assert_eq!(codes.len(), RETRY_CLASSES.len());
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        for attempt in 0..20 {
+            let d = retry_backoff(attempt);
+            let full = RETRY_BACKOFF_BASE
+                .saturating_mul(1 << attempt.min(16))
+                .min(RETRY_BACKOFF_MAX);
+
+            assert!(d >= full / 2 && d <= full);
+        }
+    }
+
+    #[test]
+    fn test_account_slice() {
+        let key = Pubkey::new_unique();
+        let data = [1u8, 2, 3, 4];
+
+        assert_eq!(account_slice(&key, &data, 1..3).unwrap(), &[2, 3]);
+        assert!(matches!(
+            account_slice(&key, &data, 2..5),
+            Err(ErrorCode::MalformedAccount)
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * This file forwards warnings and errors to a Discord webhook, so
+ * operators hear about problems without watching the logs. Events are
+ * queued by a tracing layer and posted by a worker on the runtime, so
+ * logging never waits on Discord. Each call site is forwarded at most
+ * once a minute, so a warning repeated on every tick doesn't flood the
+ * channel.
+*/
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context, Layer};
+
+/// Messages queued beyond this are dropped until the worker catches up.
+const QUEUE_LEN: usize = 100;
+
+/// Discord rejects longer messages.
+const MAX_MESSAGE_LEN: usize = 2000;
+
+/// Discord allows about five webhook messages every two seconds.
+const POST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Events from the same call site within this long of a forwarded one
+/// are counted rather than forwarded.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct DiscordWebhookLayer {
+    tx: mpsc::Sender<String>,
+    throttle: Mutex<Throttle>,
+}
+
+/// When each call site was last forwarded, and how many of its events
+/// have been suppressed since.
+#[derive(Default)]
+struct Throttle(HashMap<&'static str, (Instant, usize)>);
+
+impl Throttle {
+    /// Whether an event from `site` at `now` is forwarded, and if so the
+    /// number suppressed before it.
+    fn admit(&mut self, site: &'static str, now: Instant) -> Option<usize> {
+        match self.0.get_mut(site) {
+            Some((last, n)) if now.duration_since(*last) < REPEAT_INTERVAL => {
+                *n += 1;
+                None
+            }
+            Some((last, n)) => {
+                *last = now;
+                Some(std::mem::take(n))
+            }
+            None => {
+                self.0.insert(site, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+/// The layer, and the receiving end of its queue to pass to
+/// `notify_worker`.
+pub fn init() -> (DiscordWebhookLayer, mpsc::Receiver<String>) {
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    let throttle = Mutex::new(Throttle::default());
+    (DiscordWebhookLayer { tx, throttle }, rx)
+}
+
+/// Posts the messages queued by the layer to the webhook at `url`.
+pub async fn notify_worker(url: String, mut rx: mpsc::Receiver<String>) {
+    let client = reqwest::Client::new();
+
+    while let Some(msg) = rx.recv().await {
+        let res = client
+            .post(&url)
+            .json(&serde_json::json!({ "content": msg }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+
+        // Events from this module aren't forwarded, so this can't loop.
+        if let Err(e) = res {
+            tracing::warn!("Failed to post to Discord: {}", e);
+        }
+
+        tokio::time::sleep(POST_INTERVAL).await;
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, "{:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
+
+impl<S: Subscriber> Layer<S> for DiscordWebhookLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+
+        if *meta.level() > Level::WARN || meta.target() == module_path!() {
+            return;
+        }
+
+        // The name of an event is unique to its call site.
+        let suppressed =
+            match self.throttle.lock().admit(meta.name(), Instant::now()) {
+                Some(n) => n,
+                None => return,
+            };
+
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+
+        let mut msg =
+            format!("**{}** {}: {}", meta.level(), meta.target(), visitor.0);
+        if suppressed > 0 {
+            let _ = write!(msg, " ({} more since last sent)", suppressed);
+        }
+        if msg.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !msg.is_char_boundary(end) {
+                end -= 1;
+            }
+            msg.truncate(end);
+        }
+
+        // Drop the message rather than block when Discord is behind.
+        let _ = self.tx.try_send(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::default();
+        let start = Instant::now();
+        let after = |s| start + Duration::from_secs(s);
+
+        assert_eq!(throttle.admit("a", start), Some(0));
+        assert_eq!(throttle.admit("a", after(1)), None);
+        assert_eq!(throttle.admit("a", after(30)), None);
+        // Other call sites aren't held back.
+        assert_eq!(throttle.admit("b", after(30)), Some(0));
+        assert_eq!(throttle.admit("a", after(60)), Some(2));
+        assert_eq!(throttle.admit("a", after(61)), None);
+    }
+}
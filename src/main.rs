@@ -1,9 +1,14 @@
 use anchor_client::{
-    solana_sdk::{commitment_config::CommitmentConfig, signer::keypair},
+    solana_sdk::{
+        commitment_config::CommitmentConfig, pubkey::Pubkey,
+        signature::Signature, signer::keypair,
+    },
     Cluster,
 };
 use clap::{Parser, Subcommand};
-use std::{env, time::Duration};
+use fixed::types::I80F48;
+use std::{env, ffi::OsString, time::Duration};
+use toml::value::{Table, Value};
 use zo_keeper as lib;
 
 #[derive(Parser)]
@@ -17,6 +22,45 @@ struct Cli {
     #[clap(long, env = "SOLANA_WS_URL")]
     ws_url: String,
 
+    /// RPC endpoint transactions are sent through. If not set, the RPC
+    /// endpoint is used.
+    #[clap(long, env = "SOLANA_TX_RPC_URL")]
+    tx_rpc_url: Option<String>,
+
+    /// Timeout for RPC reads, in seconds.
+    #[clap(long, default_value = "30", parse(try_from_str = parse_seconds))]
+    rpc_timeout: Duration,
+
+    /// Durable nonce account to sign transactions with instead of a
+    /// recent blockhash, so they don't expire under congestion. The payer
+    /// must be its authority. Each transaction pays for an extra
+    /// instruction, and since a nonce can only be used once, transactions
+    /// are sent one at a time, each waiting for the last to land.
+    #[clap(long)]
+    nonce_account: Option<Pubkey>,
+
+    /// Commitment level for reads and transaction confirmation, one of
+    /// processed, confirmed or finalized. Defaults to processed for the
+    /// crank, and confirmed otherwise.
+    #[clap(long)]
+    commitment: Option<CommitmentConfig>,
+
+    /// Log format, one of text or json. JSON logs carry span fields such
+    /// as the market and authority as separate fields.
+    #[clap(long, env = "LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
+    /// Discord webhook warnings and errors are posted to.
+    #[clap(long, env = "DISCORD_WEBHOOK_URL")]
+    discord_webhook_url: Option<String>,
+
+    /// TOML file to read flags from, keyed by their long names. Global
+    /// flags go at the top level, and a subcommand's flags in a table
+    /// named after it, e.g. [liquidator]. Flags given on the command line
+    /// or through their environment variable take precedence.
+    #[clap(long, parse(from_os_str))]
+    config: Option<std::path::PathBuf>,
+
     /// Path to keypair. If not set, the JSON encoded keypair is read
     /// from $SOLANA_PAYER_KEY instead.
     #[clap(short, long)]
@@ -41,6 +85,24 @@ enum Command {
         /// Interval for update funding, in seconds
         #[clap(long, default_value = "15", parse(try_from_str = parse_seconds))]
         update_funding_interval: Duration,
+
+        /// Comma-separated list of tasks to run, of oracle, interest and
+        /// funding. Lets the tasks be split across processes
+        #[clap(
+            long,
+            use_value_delimiter = true,
+            default_value = "oracle,interest,funding"
+        )]
+        tasks: Vec<lib::crank::CrankTask>,
+
+        /// Times to resend a transaction after a retryable error, such as
+        /// an expired blockhash or a node behind the cluster
+        #[clap(long, default_value = "2")]
+        dispatch_retries: usize,
+
+        /// Run each task once and exit, failing if any of them failed
+        #[clap(long)]
+        once: bool,
     },
 
     /// Consume events for each market
@@ -57,8 +119,59 @@ enum Command {
         #[clap(long, default_value = "12")]
         max_queue_length: usize,
 
-        #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
+        /// Time between event queue polls, in seconds
+        #[clap(
+            long,
+            alias = "poll-interval",
+            default_value = "5",
+            parse(try_from_str = parse_seconds)
+        )]
         poll_period: Duration,
+
+        /// Comma-separated list of markets to consume, e.g.
+        /// SOL-PERP,BTC-PERP. If empty, all markets are consumed.
+        #[clap(long, use_value_delimiter = true)]
+        markets: Vec<String>,
+
+        /// Polls without a non-empty queue's head moving before warning
+        /// that it is stuck
+        #[clap(long, default_value = "12")]
+        stuck_polls: usize,
+
+        /// Time without a non-empty queue's head moving before warning
+        /// that it is stuck, and between warnings while it stays stuck, in
+        /// seconds
+        #[clap(long, default_value = "120", parse(try_from_str = parse_seconds))]
+        stuck_after: Duration,
+
+        /// On startup, consume each market's events until its queue is
+        /// shorter than this, before waiting on the limits above
+        #[clap(long, default_value = "12")]
+        catch_up_below: usize,
+
+        /// Commitment level the event queues are read at, one of
+        /// processed, confirmed or finalized. At processed, events are
+        /// consumed sooner, but events from transactions that are later
+        /// dropped may be cranked. The program ignores those, so this
+        /// only wastes a transaction
+        #[clap(long, default_value = "confirmed")]
+        event_queue_commitment: CommitmentConfig,
+
+        /// Whether to crank the PnL of the accounts whose events are
+        /// consumed. If false, only events are consumed
+        #[clap(long, default_value = "true", parse(try_from_str))]
+        crank_pnl: bool,
+
+        /// Interval to crank PnL on, in seconds, for the accounts whose
+        /// events were consumed since. If unset, PnL is cranked right
+        /// after consuming events
+        #[clap(long, parse(try_from_str = parse_seconds))]
+        crank_pnl_interval: Option<Duration>,
+
+        /// Consume each market's events once, however few, and exit,
+        /// failing if any market failed
+        #[clap(long)]
+        once: bool,
     },
 
     /// Find liquidatable accounts and liquidate them
@@ -70,45 +183,465 @@ enum Command {
         /// The slice of addresses this bot is responsible for
         #[clap(long, default_value = "0")]
         worker_index: u8,
+
+        /// Priority fee for liquidation transactions, in micro-lamports
+        /// per compute unit
+        #[clap(long)]
+        priority_fee: Option<u64>,
+
+        /// Factor a liquidation's size is scaled by when it is rejected
+        /// for over-exposure, between 0 and 1 exclusive
+        #[clap(
+            long,
+            default_value = "0.5",
+            parse(try_from_str = parse_fraction)
+        )]
+        reduction_factor: f64,
+
+        /// The most attempts made at liquidating a position
+        #[clap(long, default_value = "5")]
+        reduction_max: usize,
+
+        /// Fraction of the cancel margin fraction an account must fall
+        /// below to have its orders cancelled, in (0, 1]. Values below 1
+        /// only act on accounts safely over the line
+        #[clap(
+            long,
+            default_value = "0.99995",
+            parse(try_from_str = parse_tolerance)
+        )]
+        cancel_tolerance: f64,
+
+        /// Fraction of the maintenance margin fraction an account must
+        /// fall below to be liquidated, in (0, 1]. Values below 1 only act
+        /// on accounts safely over the line
+        #[clap(
+            long,
+            default_value = "0.99995",
+            parse(try_from_str = parse_tolerance)
+        )]
+        maintenance_tolerance: f64,
+
+        /// Skip accounts worth less than this, in USDC, unless they are
+        /// bankrupt
+        #[clap(long, default_value = "0")]
+        min_account_value: f64,
+
+        /// Skip accounts exposed to a square market whose mark is more
+        /// than this fraction away from its recent median, in (0, 1),
+        /// e.g. 0.05
+        #[clap(long, parse(try_from_str = parse_fraction))]
+        mark_band: Option<f64>,
+
+        /// Treat collateral and borrows worth less than this, in native
+        /// USDC, as dust: dust borrows aren't spot liquidated, and accounts
+        /// with only dust collateral are settled as bankrupt. Defaults to
+        /// the program's threshold
+        #[clap(long)]
+        dust_threshold: Option<f64>,
+
+        /// Where liquidated spot inventory is swapped back to USDC, one of
+        /// serum or jupiter
+        #[clap(long, default_value = "serum")]
+        rebalance_venue: lib::liquidator::RebalanceVenue,
+
+        /// Base URL of the Jupiter v6 swap API
+        #[clap(long, default_value = "https://quote-api.jup.ag/v6")]
+        jupiter_url: String,
+
+        /// Slippage allowed on Jupiter swaps, in basis points
+        #[clap(long, default_value = "50")]
+        jupiter_slippage_bps: u16,
+
+        /// How much worse than the oracle a serum rebalance may fill, as a
+        /// fraction of the oracle price, e.g. 0.05
+        #[clap(
+            long,
+            default_value = "0.05",
+            parse(try_from_str = parse_fraction)
+        )]
+        max_swap_slippage: f64,
+
+        /// Slippage allowed on the order closing a liquidated perp
+        /// position, from the top of the book, in basis points
+        #[clap(long, default_value = "100")]
+        close_slippage_bps: u16,
+
+        /// Alert when the payer's balance falls below this many SOL
+        #[clap(long)]
+        min_payer_sol: Option<f64>,
+
+        /// Alert when the payer's margin account is worth less than this,
+        /// in USD
+        #[clap(long)]
+        min_payer_value: Option<f64>,
+
+        /// Simulate liquidation transactions before sending them, to skip
+        /// accounts that are no longer liquidatable without a failed send
+        #[clap(long)]
+        simulate_first: bool,
+
+        /// Liquidate up to this many of an account's largest perp positions
+        /// in one go, sized to bring it back over maintenance
+        #[clap(long, default_value = "1")]
+        perp_top_k: usize,
+
+        /// Comma-separated list of perp markets to liquidate, e.g. SOL-PERP.
+        /// If empty, all markets are liquidated. Combines with the worker
+        /// sharding, which picks the accounts checked
+        #[clap(long, use_value_delimiter = true)]
+        only_markets: Vec<String>,
+
+        /// Comma-separated list of collaterals whose borrows to liquidate,
+        /// by oracle symbol, e.g. SOL. If empty, all are liquidated
+        #[clap(long, use_value_delimiter = true)]
+        only_collaterals: Vec<String>,
+
+        /// Reconnect the account listener when it has received no updates for
+        /// this long, in seconds
+        #[clap(long, default_value = "60", parse(try_from_str = parse_seconds))]
+        listener_stall_after: Duration,
+
+        /// Commitment level of the account subscription, one of processed,
+        /// confirmed or finalized. Processed sees accounts fall underwater
+        /// soonest, but may act on updates that are then rolled back
+        #[clap(long, default_value = "confirmed")]
+        subscription_commitment: CommitmentConfig,
+
+        /// The most liquidation and cancel transactions in flight at once. The
+        /// rest are queued, most underwater account first
+        #[clap(long, default_value = "16")]
+        max_concurrent_liquidations: std::num::NonZeroUsize,
+
+        /// Check every account once, acting on those that need it, and
+        /// exit, failing if any liquidation or cancel failed
+        #[clap(long)]
+        once: bool,
+    },
+
+    /// Print the liquidator's margin fractions and decisions for one account
+    CheckAccount {
+        /// The authority of the margin account to check
+        authority: Pubkey,
+
+        /// Cancel tolerance to decide at, as for the liquidator
+        #[clap(
+            long,
+            default_value = "0.99995",
+            parse(try_from_str = parse_tolerance)
+        )]
+        cancel_tolerance: f64,
+
+        /// Maintenance tolerance to decide at, as for the liquidator
+        #[clap(
+            long,
+            default_value = "0.99995",
+            parse(try_from_str = parse_tolerance)
+        )]
+        maintenance_tolerance: f64,
+    },
+
+    /// Serve the liquidator's margin fractions and decisions for any
+    /// account as JSON, at GET /health/<authority>
+    HealthServer {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        addr: std::net::SocketAddr,
+
+        /// Cancel tolerance to decide at, as for the liquidator
+        #[clap(
+            long,
+            default_value = "0.99995",
+            parse(try_from_str = parse_tolerance)
+        )]
+        cancel_tolerance: f64,
+
+        /// Maintenance tolerance to decide at, as for the liquidator
+        #[clap(
+            long,
+            default_value = "0.99995",
+            parse(try_from_str = parse_tolerance)
+        )]
+        maintenance_tolerance: f64,
+    },
+
+    /// Write the liquidator's account table, as loaded at startup, to a
+    /// JSON file
+    DumpState {
+        /// File to write to
+        path: std::path::PathBuf,
+
+        /// Include each account's margin fractions and the liquidator's
+        /// decisions on them
+        #[clap(long)]
+        fractions: bool,
     },
 
     /// Listen and store events into a database
-    Recorder,
+    Recorder {
+        /// Directory where events are buffered while the database is
+        /// unreachable, to be inserted once it recovers
+        #[clap(long, env = "SPOOL_DIR", default_value = "spool")]
+        spool_dir: std::path::PathBuf,
+
+        /// Commitment level of the log subscription, one of processed,
+        /// confirmed or finalized. Lower levels record events sooner, but
+        /// may record events from transactions that are rolled back
+        #[clap(long, default_value = "finalized")]
+        subscription_commitment: CommitmentConfig,
+
+        /// Slots the polled transactions can fall behind the finalized
+        /// slot before warning that the recorder can't keep up
+        #[clap(long, default_value = "150")]
+        max_slot_lag: u64,
+    },
+
+    /// Print the transactions between two times that are missing from
+    /// the recorder's database
+    VerifyGaps {
+        /// Start of the range, as a unix timestamp
+        from: i64,
+
+        /// End of the range, as a unix timestamp
+        to: i64,
+
+        /// Signature to stop at, excluded from the range, such as the
+        /// last transaction known to be recorded. The range ends at
+        /// whichever of this and the start is reached first
+        #[clap(long)]
+        until_signature: Option<Signature>,
+    },
 
     /// Trigger special orders.
-    Trigger,
+    Trigger {
+        /// Age of the cached prices past which orders aren't triggered,
+        /// in seconds
+        #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
+        max_cache_age: Duration,
+
+        /// Priority fee for trigger transactions, in micro-lamports per
+        /// compute unit. Stop orders are only useful if they execute
+        /// close to their trigger price, so set this when the cluster is
+        /// congested
+        #[clap(long)]
+        priority_fee: Option<u64>,
+
+        /// Commitment level of the account subscription, one of processed,
+        /// confirmed or finalized. Lower levels see price moves sooner,
+        /// but may trigger on a cache update that is rolled back
+        #[clap(long, default_value = "confirmed")]
+        subscription_commitment: CommitmentConfig,
+    },
 }
 
-fn main() -> Result<(), lib::Error> {
-    dotenv::dotenv().ok();
+#[derive(Clone, Copy)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Flags read from the `--config` file, see `Cli::config`.
+#[derive(serde::Deserialize)]
+struct KeeperConfig {
+    #[serde(default)]
+    crank: Table,
+    #[serde(default)]
+    consumer: Table,
+    #[serde(default)]
+    liquidator: Table,
+    #[serde(flatten)]
+    global: Table,
+}
+
+/// The command line arguments, followed by the flags from the `--config`
+/// file that weren't given on the command line or in the environment.
+fn args_with_config() -> Result<Vec<OsString>, lib::Error> {
+    with_config(env::args_os().collect())
+}
+
+/// `args`, followed by the flags from the `--config` file they name that
+/// they don't give, see `args_with_config`.
+fn with_config(mut args: Vec<OsString>) -> Result<Vec<OsString>, lib::Error> {
+    let app = <Cli as clap::CommandFactory>::command();
+
+    // Required flags may be left to the config, so what's on the command
+    // line is matched without checking it's complete. Anything else that
+    // fails, like --help, is left to the full parse.
+    let matches = match app
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(args.clone())
     {
-        use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
-
-        tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            // https://no-color.org/
-            .with_ansi(env::var_os("NO_COLOR").is_none())
-            .finish()
-            .init();
+        Ok(m) => m,
+        Err(_) => return Ok(args),
+    };
+
+    let path = match matches.value_of_os("config") {
+        Some(p) => p.to_os_string(),
+        None => return Ok(args),
+    };
+
+    let config: KeeperConfig = std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| toml::from_str(&s).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            lib::Error::Config(format!("{}: {}", path.to_string_lossy(), e))
+        })?;
+
+    let global = config_args(&app, &config.global, &matches)?;
+    let mut sub = Vec::new();
+
+    for (name, table) in [
+        ("crank", &config.crank),
+        ("consumer", &config.consumer),
+        ("liquidator", &config.liquidator),
+    ] {
+        if let Some(m) = matches.subcommand_matches(name) {
+            let cmd = app.find_subcommand(name).unwrap();
+            sub = config_args(cmd, table, m)?;
+        }
+    }
+
+    // Global flags must come before the subcommand, and the subcommand's
+    // after it.
+    args.splice(1..1, global);
+    args.extend(sub);
+    Ok(args)
+}
+
+/// The flags of `app` set in `table`, skipping those given on the command
+/// line, by any of their names, or whose environment variable is set.
+fn config_args(
+    app: &clap::Command,
+    table: &Table,
+    matches: &clap::ArgMatches,
+) -> Result<Vec<OsString>, lib::Error> {
+    let mut out = Vec::new();
+
+    for (key, value) in table {
+        let arg = app
+            .get_arguments()
+            .find(|a| a.get_long() == Some(key.as_str()))
+            .ok_or_else(|| {
+                lib::Error::Config(format!("unknown key {}", key))
+            })?;
+
+        let given = matches.occurrences_of(arg.get_id()) > 0;
+        let in_env = arg.get_env().map_or(false, |e| env::var_os(e).is_some());
+
+        if given || in_env {
+            continue;
+        }
+
+        // Switches are given bare, but booleans that take a value, like
+        // --crank-pnl, are given it.
+        let flag = format!("--{}", key);
+        match value {
+            Value::Boolean(true) if !arg.is_takes_value_set() => {
+                out.push(flag.into())
+            }
+            Value::Boolean(false) if !arg.is_takes_value_set() => {}
+            Value::Table(_) => {
+                return Err(lib::Error::Config(format!(
+                    "{} can't be a table",
+                    key
+                )))
+            }
+            v => {
+                out.push(flag.into());
+                out.push(config_value(v).into());
+            }
+        }
     }
 
+    Ok(out)
+}
+
+fn config_value(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Array(xs) => {
+            xs.iter().map(config_value).collect::<Vec<_>>().join(",")
+        }
+        v => v.to_string(),
+    }
+}
+
+fn main() -> Result<(), lib::Error> {
+    dotenv::dotenv().ok();
+
     {
-        // Ensure that a panic in a spawned thread exits the main process.
+        // Ensure that a panic in a spawned thread exits the main process,
+        // unless it's a supervised worker, which is logged and restarted.
         // Unfortunately, other threads' resources are not necessarily freed.
         let hook = std::panic::take_hook();
         std::panic::set_hook(Box::new(move |x| {
             hook(x);
-            std::process::exit(255);
+
+            if !lib::is_supervised() {
+                std::process::exit(255);
+            }
         }));
     }
 
     let Cli {
         rpc_url,
         ws_url,
+        tx_rpc_url,
+        rpc_timeout,
+        nonce_account,
+        commitment,
+        log_format,
+        discord_webhook_url,
+        config: _,
         payer,
         command,
-    } = Cli::parse();
+    } = Cli::parse_from(args_with_config()?);
+
+    // The worker is spawned once the runtime is up.
+    let (discord_layer, notify_worker) = match discord_webhook_url {
+        Some(url) => {
+            let (layer, rx) = lib::log::init();
+            (Some(layer), Some(lib::log::notify_worker(url, rx)))
+        }
+        None => (None, None),
+    };
+
+    {
+        use tracing_subscriber::{
+            layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+        };
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env());
+
+        match log_format {
+            LogFormat::Text => subscriber
+                // https://no-color.org/
+                .with_ansi(env::var_os("NO_COLOR").is_none())
+                .finish()
+                .with(discord_layer)
+                .init(),
+            LogFormat::Json => subscriber
+                .json()
+                .flatten_event(true)
+                .finish()
+                .with(discord_layer)
+                .init(),
+        }
+    }
 
     let payer = match payer {
         Some(p) => keypair::read_keypair_file(&p).unwrap_or_else(|_| {
@@ -121,41 +654,112 @@ fn main() -> Result<(), lib::Error> {
         },
     };
 
+    let tx_cluster = Cluster::Custom(
+        tx_rpc_url.unwrap_or_else(|| rpc_url.clone()),
+        ws_url.clone(),
+    );
     let cluster = Cluster::Custom(rpc_url, ws_url);
-    let commitment = match command {
+    let commitment = commitment.unwrap_or_else(|| match command {
         Command::Crank { .. } => CommitmentConfig::processed(),
         _ => CommitmentConfig::confirmed(),
-    };
+    });
 
-    let app_state: &'static _ =
-        Box::leak(Box::new(lib::AppState::new(cluster, commitment, payer)));
+    let app_state: &'static _ = Box::leak(Box::new(lib::AppState::new(
+        cluster,
+        tx_cluster,
+        rpc_timeout,
+        commitment,
+        payer,
+        nonce_account,
+    )));
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
 
+    if let Some(worker) = notify_worker {
+        rt.spawn(worker);
+    }
+
     match command {
         Command::Liquidator {
             worker_count,
             worker_index,
+            priority_fee,
+            reduction_factor,
+            reduction_max,
+            cancel_tolerance,
+            maintenance_tolerance,
+            min_account_value,
+            mark_band,
+            dust_threshold,
+            rebalance_venue,
+            jupiter_url,
+            jupiter_slippage_bps,
+            max_swap_slippage,
+            close_slippage_bps,
+            min_payer_sol,
+            min_payer_value,
+            simulate_first,
+            perp_top_k,
+            only_markets,
+            only_collaterals,
+            listener_stall_after,
+            subscription_commitment,
+            max_concurrent_liquidations,
+            once,
         } => {
             rt.block_on(lib::liquidator::run(
                 app_state,
-                worker_count,
-                worker_index,
+                lib::liquidator::LiquidatorConfig {
+                    worker_count,
+                    worker_index,
+                    priority_fee,
+                    reduction_factor,
+                    reduction_max,
+                    cancel_tolerance,
+                    maintenance_tolerance,
+                    min_account_value,
+                    mark_band,
+                    dust_threshold: dust_threshold.map_or(
+                        I80F48::from_num(zo_abi::DUST_THRESHOLD),
+                        I80F48::from_num,
+                    ),
+                    rebalance_venue,
+                    jupiter_url,
+                    jupiter_slippage_bps,
+                    max_swap_slippage,
+                    close_slippage_bps,
+                    min_payer_sol,
+                    min_payer_value,
+                    simulate_first,
+                    perp_top_k,
+                    only_markets,
+                    only_collaterals,
+                    listener_stall_after,
+                    subscription_commitment,
+                    max_concurrent_liquidations,
+                    once,
+                },
             ))?;
         }
         Command::Crank {
             cache_oracle_interval,
             cache_interest_interval,
             update_funding_interval,
+            tasks,
+            dispatch_retries,
+            once,
         } => rt.block_on(lib::crank::run(
             app_state,
             lib::crank::CrankConfig {
                 cache_oracle_interval,
                 cache_interest_interval,
                 update_funding_interval,
+                tasks,
+                dispatch_retries,
+                once,
             },
         ))?,
         Command::Consumer {
@@ -163,6 +767,14 @@ fn main() -> Result<(), lib::Error> {
             max_wait,
             max_queue_length,
             poll_period,
+            markets,
+            stuck_polls,
+            stuck_after,
+            catch_up_below,
+            event_queue_commitment,
+            crank_pnl,
+            crank_pnl_interval,
+            once,
         } => rt.block_on(lib::consumer::run(
             app_state,
             lib::consumer::ConsumerConfig {
@@ -170,10 +782,77 @@ fn main() -> Result<(), lib::Error> {
                 max_wait,
                 max_queue_length,
                 poll_period,
+                markets,
+                stuck_polls,
+                stuck_after,
+                catch_up_below,
+                event_queue_commitment,
+                crank_pnl,
+                crank_pnl_interval,
+                once,
+            },
+        ))?,
+        Command::CheckAccount {
+            authority,
+            cancel_tolerance,
+            maintenance_tolerance,
+        } => lib::liquidator::check_account(
+            app_state,
+            &authority,
+            lib::liquidator::Tolerances {
+                cancel: cancel_tolerance,
+                maintenance: maintenance_tolerance,
+            },
+        )?,
+        Command::HealthServer {
+            addr,
+            cancel_tolerance,
+            maintenance_tolerance,
+        } => lib::liquidator::serve_health(
+            app_state,
+            addr,
+            lib::liquidator::Tolerances {
+                cancel: cancel_tolerance,
+                maintenance: maintenance_tolerance,
+            },
+        )?,
+        Command::DumpState { path, fractions } => {
+            lib::liquidator::dump_state(app_state, &path, fractions)?
+        }
+        Command::Recorder {
+            spool_dir,
+            subscription_commitment,
+            max_slot_lag,
+        } => rt.block_on(lib::recorder::run(
+            app_state,
+            lib::recorder::RecorderConfig {
+                spool_dir,
+                subscription_commitment,
+                max_slot_lag,
             },
         ))?,
-        Command::Recorder => rt.block_on(lib::recorder::run(app_state))?,
-        Command::Trigger => lib::trigger::run(app_state)?,
+        Command::VerifyGaps {
+            from,
+            to,
+            until_signature,
+        } => rt.block_on(lib::recorder::verify_gaps(
+            app_state,
+            from,
+            to,
+            until_signature,
+        ))?,
+        Command::Trigger {
+            max_cache_age,
+            priority_fee,
+            subscription_commitment,
+        } => lib::trigger::run(
+            app_state,
+            lib::trigger::TriggerConfig {
+                max_cache_age,
+                priority_fee,
+                subscription_commitment,
+            },
+        )?,
     };
 
     Ok(())
@@ -182,3 +861,145 @@ fn main() -> Result<(), lib::Error> {
 fn parse_seconds(s: &str) -> Result<Duration, std::num::ParseFloatError> {
     <f64 as std::str::FromStr>::from_str(s).map(Duration::from_secs_f64)
 }
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x > 0.0 && x < 1.0 => Ok(x),
+        Ok(_) => Err("must be between 0 and 1 exclusive".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_tolerance(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x > 0.0 && x <= 1.0 => Ok(x),
+        Ok(_) => Err("must be greater than 0 and at most 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The arguments `args` are parsed from, with `config` as the
+    /// `--config` file.
+    fn parse(name: &str, config: &str, args: &[&str]) -> Cli {
+        let path = env::temp_dir().join(format!(
+            "zo-keeper-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, config).unwrap();
+
+        let args = ["zo-keeper", "--rpc-url", "rpc", "--ws-url", "ws"]
+            .into_iter()
+            .map(OsString::from)
+            .chain([OsString::from("--config"), path.clone().into()])
+            .chain(args.iter().map(OsString::from))
+            .collect();
+        let args = with_config(args);
+        std::fs::remove_file(&path).unwrap();
+
+        Cli::try_parse_from(args.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let config = r#"
+            rpc-timeout = 10
+            commitment = "finalized"
+
+            [liquidator]
+            worker-count = 3
+            worker-index = 1
+        "#;
+        let cli = parse(
+            "precedence",
+            config,
+            &["--rpc-timeout", "5", "liquidator", "--worker-count", "2"],
+        );
+
+        assert_eq!(cli.rpc_timeout, Duration::from_secs(5));
+        assert_eq!(cli.commitment, Some(CommitmentConfig::finalized()));
+
+        match cli.command {
+            Command::Liquidator {
+                worker_count,
+                worker_index,
+                ..
+            } => {
+                assert_eq!(worker_count, 2);
+                assert_eq!(worker_index, 1);
+            }
+            _ => panic!("not the liquidator"),
+        }
+    }
+
+    #[test]
+    fn test_config_subcommand_table() {
+        // Only the table of the subcommand run is read, so the others may
+        // hold flags it doesn't have.
+        let config = r#"
+            [crank]
+            dispatch-retries = 7
+
+            [liquidator]
+            worker-count = 3
+        "#;
+
+        match parse("subcommand", config, &["crank"]).command {
+            Command::Crank {
+                dispatch_retries, ..
+            } => assert_eq!(dispatch_retries, 7),
+            _ => panic!("not the crank"),
+        }
+    }
+
+    #[test]
+    fn test_config_booleans() {
+        let config = r#"
+            [liquidator]
+            simulate-first = true
+            once = false
+        "#;
+
+        match parse("switches", config, &["liquidator"]).command {
+            Command::Liquidator {
+                simulate_first,
+                once,
+                ..
+            } => {
+                assert!(simulate_first);
+                assert!(!once);
+            }
+            _ => panic!("not the liquidator"),
+        }
+
+        // A boolean that takes a value is given it.
+        let config = r#"
+            [consumer]
+            crank-pnl = false
+        "#;
+
+        match parse("values", config, &["consumer"]).command {
+            Command::Consumer { crank_pnl, .. } => assert!(!crank_pnl),
+            _ => panic!("not the consumer"),
+        }
+    }
+
+    #[test]
+    fn test_config_lists() {
+        let config = r#"
+            [liquidator]
+            only-markets = ["SOL-PERP", "BTC-PERP"]
+        "#;
+
+        match parse("lists", config, &["liquidator"]).command {
+            Command::Liquidator { only_markets, .. } => {
+                assert_eq!(only_markets, ["SOL-PERP", "BTC-PERP"])
+            }
+            _ => panic!("not the liquidator"),
+        }
+    }
+}
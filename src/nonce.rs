@@ -0,0 +1,172 @@
+use anchor_client::solana_sdk::{
+    hash::Hash, instruction::Instruction, pubkey::Pubkey, system_instruction,
+};
+use parking_lot::Mutex;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    nonce_utils,
+    rpc_client::RpcClient,
+};
+
+/// A durable nonce account, signed with in place of a recent blockhash so
+/// that transactions don't expire while the cluster is congested.
+///
+/// A nonce value can only be used by one transaction, so transactions
+/// sharing the account are sent one at a time.
+pub struct DurableNonce {
+    key: Pubkey,
+    // The current nonce value, fetched on first use and after each send.
+    hash: Mutex<Option<Hash>>,
+}
+
+impl DurableNonce {
+    pub fn new(key: Pubkey) -> Self {
+        Self {
+            key,
+            hash: Mutex::new(None),
+        }
+    }
+
+    pub fn key(&self) -> Pubkey {
+        self.key
+    }
+
+    fn fetch(&self, rpc: &RpcClient) -> Result<Hash, ClientError> {
+        let account = rpc
+            .get_account_with_commitment(&self.key, rpc.commitment())?
+            .value
+            .ok_or_else(|| {
+                ClientErrorKind::Custom(format!(
+                    "Nonce account {} not found",
+                    self.key
+                ))
+            })?;
+
+        nonce_utils::data_from_account(&account)
+            .map(|d| d.blockhash())
+            .map_err(|e| ClientErrorKind::Custom(e.to_string()).into())
+    }
+
+    /// Runs `f` with the instruction advancing the nonce, which must be
+    /// the first of the transaction, and the nonce value to sign with.
+    /// The nonce is held until `f` returns, so `f` should wait for its
+    /// transaction to land. The nonce is refreshed afterwards, at the
+    /// commitment of `rpc`, which should be the one `f` waits for.
+    ///
+    /// The nonce authority must be `authority`.
+    pub fn with<T>(
+        &self,
+        rpc: &RpcClient,
+        authority: &Pubkey,
+        f: impl FnOnce(Instruction, Hash) -> T,
+    ) -> Result<T, ClientError> {
+        let mut hash = self.hash.lock();
+
+        let current = match *hash {
+            Some(h) => h,
+            None => self.fetch(rpc)?,
+        };
+
+        let res = f(
+            system_instruction::advance_nonce_account(&self.key, authority),
+            current,
+        );
+
+        // If the fetch fails, try again on the next send.
+        *hash = self.fetch(rpc).ok();
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_cached_hash() {
+        // Every request to the mock fails, so the nonce can't be refreshed.
+        let rpc = RpcClient::new_mock("fails".to_string());
+        let nonce = DurableNonce::new(Pubkey::new_unique());
+        let authority = Pubkey::new_unique();
+        let current = Hash::new_unique();
+        *nonce.hash.lock() = Some(current);
+
+        let (ix, hash) = nonce.with(&rpc, &authority, |ix, h| (ix, h)).unwrap();
+
+        assert_eq!(hash, current);
+        assert_eq!(
+            ix,
+            system_instruction::advance_nonce_account(&nonce.key(), &authority)
+        );
+        // The used value is dropped rather than signed with again.
+        assert_eq!(*nonce.hash.lock(), None);
+    }
+
+    #[test]
+    fn test_with_refreshes() {
+        use anchor_client::solana_sdk::{
+            account::Account,
+            nonce::state::{DurableNonce as NonceValue, State, Versions},
+            system_program,
+        };
+        use solana_account_decoder::{UiAccount, UiAccountEncoding};
+        use solana_client::{rpc_client::Mocks, rpc_request::RpcRequest};
+
+        let key = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let next = Hash::new_unique();
+        let account = Account::new_data(
+            1,
+            &Versions::new(
+                State::new_initialized(
+                    &authority,
+                    NonceValue::from_blockhash(&next, false),
+                    5000,
+                ),
+                false,
+            ),
+            &system_program::id(),
+        )
+        .unwrap();
+        let mut mocks = Mocks::default();
+        // Commitments are mapped to the node's version before use.
+        mocks.insert(
+            RpcRequest::GetVersion,
+            serde_json::json!({ "solana-core": "1.10.31" }),
+        );
+        mocks.insert(
+            RpcRequest::GetAccountInfo,
+            serde_json::json!({
+                "context": { "slot": 1 },
+                "value": UiAccount::encode(
+                    &key,
+                    &account,
+                    UiAccountEncoding::Base64,
+                    None,
+                    None,
+                ),
+            }),
+        );
+        let rpc = RpcClient::new_mock_with_mocks("fails".to_string(), mocks);
+        let nonce = DurableNonce::new(key);
+        *nonce.hash.lock() = Some(Hash::new_unique());
+
+        nonce.with(&rpc, &authority, |_, _| ()).unwrap();
+
+        // The value after the send is signed with next.
+        assert_eq!(*nonce.hash.lock(), Some(next));
+    }
+
+    #[test]
+    fn test_with_unfetched() {
+        let rpc = RpcClient::new_mock("fails".to_string());
+        let nonce = DurableNonce::new(Pubkey::new_unique());
+        let mut called = false;
+
+        let res = nonce.with(&rpc, &Pubkey::new_unique(), |_, _| called = true);
+
+        assert!(res.is_err());
+        assert!(!called);
+    }
+}
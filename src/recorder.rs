@@ -1,19 +1,28 @@
-use crate::{db, error::Error, AppState};
+use crate::{db, error::Error, spool::Spool, AppState};
 use anchor_client::{
-    solana_client::rpc_config::{
-        RpcTransactionConfig, RpcTransactionLogsConfig,
-        RpcTransactionLogsFilter,
+    solana_client::{
+        client_error::{ClientError, ClientErrorKind},
+        rpc_client::GetConfirmedSignaturesForAddress2Config,
+        rpc_config::{
+            RpcTransactionConfig, RpcTransactionLogsConfig,
+            RpcTransactionLogsFilter,
+        },
     },
     solana_sdk::{commitment_config::CommitmentConfig, signature::Signature},
 };
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use jsonrpc_core_client::transports::ws;
+use mongodb::bson::{doc, Bson};
 use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
-use solana_transaction_status::UiTransactionEncoding;
+use solana_transaction_status::{
+    EncodedConfirmedTransactionWithStatusMeta, UiTransactionEncoding,
+};
 use std::{
     cell::Cell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
+    path::PathBuf,
+    str::FromStr,
     time::{Duration, SystemTime},
 };
 use tracing::{debug, info, trace, warn, Instrument};
@@ -24,25 +33,46 @@ static DB_NAME: &str = "keeper";
 #[cfg(feature = "devnet")]
 static DB_NAME: &str = "keeper-devnet";
 
-pub async fn run(st: &'static AppState) -> Result<(), Error> {
+const HOURS_PER_YEAR: f64 = 24.0 * 365.0;
+
+#[derive(Clone)]
+pub struct RecorderConfig {
+    pub spool_dir: PathBuf,
+    /// Commitment level of the log subscription.
+    pub subscription_commitment: CommitmentConfig,
+    /// Slots polling can fall behind the finalized slot before warning.
+    pub max_slot_lag: u64,
+}
+
+pub async fn run(
+    st: &'static AppState,
+    cfg: RecorderConfig,
+) -> Result<(), Error> {
     let db = mongodb::Client::with_uri_str(env::var("DATABASE_URL")?)
         .await?
         .database(DB_NAME);
 
     let db: &'static _ = Box::leak(Box::new(db));
+    let spool: &'static _ = Box::leak(Box::new(Spool::new(cfg.spool_dir)?));
 
     futures::join!(
-        listen_logs(st, db),
-        poll_logs(st, db),
+        listen_logs(st, db, spool, cfg.subscription_commitment),
+        poll_logs(st, db, spool, cfg.max_slot_lag),
         poll_update_funding(st, db),
         poll_open_interest(st, db),
+        crate::spool::drain(db, spool),
     );
 
     Ok(())
 }
 
 #[tracing::instrument(skip_all, level = "error")]
-async fn listen_logs(st: &'static AppState, db: &'static mongodb::Database) {
+async fn listen_logs(
+    st: &'static AppState,
+    db: &'static mongodb::Database,
+    spool: &'static Spool,
+    commitment: CommitmentConfig,
+) {
     let mut interval = tokio::time::interval(Duration::from_secs(5));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
@@ -61,7 +91,7 @@ async fn listen_logs(st: &'static AppState, db: &'static mongodb::Database) {
                         zo_abi::ID.to_string()
                     ]),
                     Some(RpcTransactionLogsConfig {
-                        commitment: Some(CommitmentConfig::finalized()),
+                        commitment: Some(commitment),
                     }),
                 )
             });
@@ -85,30 +115,72 @@ async fn listen_logs(st: &'static AppState, db: &'static mongodb::Database) {
                 continue;
             }
 
-            let time = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64;
+            let slot = resp.context.slot;
 
             tokio::spawn(
-                crate::events::process(
-                    st,
-                    db,
-                    resp.value.logs,
-                    resp.value.signature,
-                    time,
-                )
+                async move {
+                    // The notification has no block time, so it's fetched
+                    // separately. If the block isn't available yet, the
+                    // transaction is left to polling, which records it
+                    // with its block time. Inserting it with an
+                    // approximate time instead would keep polling's
+                    // document out, as the unique indices ignore the time.
+                    let block_time = tokio::task::spawn_blocking(move || {
+                        st.rpc.get_block_time(slot)
+                    })
+                    .await
+                    .unwrap();
+
+                    let block_time = match block_time {
+                        Ok(t) => t,
+                        Err(e) => {
+                            debug!(
+                                "no block time for {}, left to polling: {}",
+                                slot, e
+                            );
+                            return;
+                        }
+                    };
+
+                    crate::events::process(
+                        st,
+                        db,
+                        spool,
+                        resp.value.logs,
+                        resp.value.signature,
+                        slot,
+                        Some(block_time),
+                    )
+                    .await
+                }
                 .instrument(tracing::Span::current()),
             );
         }
     }
 }
 
+/// Signatures fetched per poll. If this many are newer than the last
+/// slot polled, older ones may have been missed.
+const POLL_LOGS_PAGE: usize = 200;
+
+/// How often the lag of the polled signatures behind the finalized slot
+/// is checked.
+const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
 #[tracing::instrument(skip_all, level = "error")]
-async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
+async fn poll_logs(
+    st: &'static AppState,
+    db: &'static mongodb::Database,
+    spool: &'static Spool,
+    max_slot_lag: u64,
+) {
     let mut interval = tokio::time::interval(Duration::from_millis(250));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
+    let mut lag_interval = tokio::time::interval(LAG_CHECK_INTERVAL);
+    lag_interval
+        .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
     let mut last_slot: u64 = st
         .rpc
         .get_account_with_commitment(
@@ -119,31 +191,68 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
         .context
         .slot;
 
+    // The slot as of the last successful poll. Unlike `last_slot`, which
+    // only moves when there are new transactions, this keeps up with the
+    // cluster while the program is idle.
+    let mut polled_slot = last_slot;
+
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = lag_interval.tick() => {
+                check_lag(st, polled_slot, max_slot_lag).await;
+                continue;
+            }
+        }
 
         // > The result field will be an array of transaction signature
         // > information, ordered from newest to oldest transaction.
         //
         // https://docs.solana.com/developing/clients/jsonrpc-api#getsignaturesforaddress
-        let sigs = tokio::task::spawn_blocking(move || {
-            st.rpc.get_signatures_for_address(&st.zo_state_pubkey)
+        //
+        // The slot is read first, so that every signature up to it is in
+        // the response.
+        let res = tokio::task::spawn_blocking(move || {
+            let slot = st.rpc.get_slot()?;
+            let sigs =
+                st.rpc.get_signatures_for_address(&st.zo_state_pubkey)?;
+            Ok::<_, ClientError>((slot, sigs))
         })
         .await
         .unwrap();
 
-        let sigs = match sigs {
-            Ok(x) => x
-                .into_iter()
-                .take(200)
-                .filter(|sg| sg.err.is_none() && sg.slot > last_slot)
-                .collect::<Vec<_>>(),
+        let mut sigs = match res {
+            Ok((slot, sigs)) => {
+                polled_slot = std::cmp::max(polled_slot, slot);
+                sigs
+            }
             Err(e) => {
                 let e = Error::from(e);
                 warn!("{}", e);
                 continue;
             }
         };
+        sigs.truncate(POLL_LOGS_PAGE);
+
+        // Nothing new since the last poll, so there's nothing to fetch.
+        if sigs.first().map_or(true, |sg| sg.slot <= last_slot) {
+            trace!("no new slots, skipping");
+            continue;
+        }
+
+        if sigs.len() == POLL_LOGS_PAGE
+            && sigs.last().map_or(false, |sg| sg.slot > last_slot)
+        {
+            warn!(
+                "all {} signatures are past slot {}, some may be missed",
+                POLL_LOGS_PAGE, last_slot
+            );
+        }
+
+        let sigs: Vec<_> = sigs
+            .into_iter()
+            .filter(|sg| sg.err.is_none() && sg.slot > last_slot)
+            .collect();
 
         if sigs.is_empty() {
             trace!("0 signatures, skipping");
@@ -155,11 +264,6 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
         let handle = tokio::runtime::Handle::try_current().unwrap();
         let span = tracing::Span::current();
 
-        let time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
         for sg in sigs {
             let handle = handle.clone();
             let span = span.clone();
@@ -167,17 +271,12 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
             last_slot = std::cmp::max(last_slot, sg.slot);
 
             tokio::task::spawn_blocking(move || {
-                use std::str::FromStr;
                 let _g = span.enter();
                 debug!("processing: {}", sg.signature);
 
-                let res = st.rpc.get_transaction_with_config(
+                let res = get_transaction_with_backoff(
+                    st,
                     &Signature::from_str(&sg.signature).unwrap(),
-                    RpcTransactionConfig {
-                        encoding: Some(UiTransactionEncoding::Base64),
-                        commitment: Some(CommitmentConfig::finalized()),
-                        max_supported_transaction_version: None,
-                    },
                 );
 
                 match res {
@@ -189,9 +288,11 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
                                 crate::events::process(
                                     st,
                                     db,
+                                    spool,
                                     ss,
                                     sg.signature,
-                                    time,
+                                    tx.slot,
+                                    tx.block_time,
                                 )
                                 .instrument(span.clone()),
                             );
@@ -208,6 +309,84 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
     }
 }
 
+/// Attempts at fetching a transaction while the RPC is rate limiting.
+const RATE_LIMIT_RETRIES: u32 = 5;
+
+/// Delay after the first rate limited attempt, doubled after each.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Whether the RPC rejected the request for exceeding its rate limit, as
+/// opposed to failing it, e.g. because the transaction isn't found.
+fn is_rate_limited(e: &ClientError) -> bool {
+    match e.kind() {
+        ClientErrorKind::Reqwest(e) => {
+            e.status().map(|s| s.as_u16()) == Some(429)
+        }
+        _ => false,
+    }
+}
+
+/// Fetches the finalized transaction, retrying with backoff while the
+/// RPC is rate limiting so that throttling doesn't leave gaps in the
+/// recorded history. Other errors are returned straight away.
+fn get_transaction_with_backoff(
+    st: &AppState,
+    sg: &Signature,
+) -> Result<EncodedConfirmedTransactionWithStatusMeta, ClientError> {
+    let config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: Some(CommitmentConfig::finalized()),
+        max_supported_transaction_version: None,
+    };
+
+    let mut attempt = 0;
+    loop {
+        match st.rpc.get_transaction_with_config(sg, config) {
+            Err(e) if is_rate_limited(&e) && attempt < RATE_LIMIT_RETRIES => {
+                let backoff = RATE_LIMIT_BACKOFF * 2u32.pow(attempt);
+                debug!(
+                    "rate limited fetching {}, retrying in {:?}",
+                    sg, backoff
+                );
+                std::thread::sleep(backoff);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Logs the slots between `polled_slot`, the slot of the last successful
+/// poll, and the finalized slot, warning if it's over `max_slot_lag`.
+async fn check_lag(st: &'static AppState, polled_slot: u64, max_slot_lag: u64) {
+    let finalized = tokio::task::spawn_blocking(move || {
+        st.rpc
+            .get_slot_with_commitment(CommitmentConfig::finalized())
+    })
+    .await
+    .unwrap();
+
+    let finalized = match finalized {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("{}", Error::from(e));
+            return;
+        }
+    };
+
+    // Polls are confirmed, so can be ahead of finalized.
+    let lag = finalized.saturating_sub(polled_slot);
+
+    if lag > max_slot_lag {
+        warn!(
+            "{} slots behind finalized slot {}, polling can't keep up",
+            lag, finalized
+        );
+    } else {
+        debug!("{} slots behind finalized slot {}", lag, finalized);
+    }
+}
+
 #[tracing::instrument(skip_all, level = "error", name = "update_funding")]
 async fn poll_update_funding(
     st: &'static AppState,
@@ -294,10 +473,16 @@ async fn poll_update_funding(
                 // big/big -> small/big
                 price *= I80F48::from(10u64.pow(6));
 
+                let hourly = hourly_rate(
+                    (delta / price).to_num(),
+                    m.last_updated as i64 - prev_m.last_updated as i64,
+                );
+
                 db::Funding {
                     symbol: symbol.clone(),
                     funding_index: { m.funding_index }.to_string(),
-                    hourly: (delta / price).to_num::<f64>(),
+                    hourly,
+                    apr: hourly * HOURS_PER_YEAR,
                     time: m.last_updated as i64,
                 }
             })
@@ -337,24 +522,18 @@ async fn poll_open_interest(
             .as_secs() as i64;
 
         let val: Result<_, Error> = tokio::task::spawn_blocking(move || {
-            let mut r = vec![0i64; st.zo_state.total_markets as usize];
-
-            crate::utils::load_program_accounts::<zo_abi::Control>(&st.rpc)?
-                .into_iter()
-                .for_each(|(_, a)| {
-                    for (i, e) in r.iter_mut().enumerate() {
-                        let x = a.open_orders_agg[i].pos_size;
-                        if x > 0 {
-                            *e += x;
-                        }
-                    }
-                });
+            let controls: Vec<(_, zo_abi::Control)> =
+                crate::utils::load_program_accounts(&st.rpc)?;
+            let r = compute_open_interest(
+                controls.iter().map(|(_, a)| a),
+                st.zo_state.total_markets as usize,
+            );
 
             Ok(st
                 .iter_markets()
                 .enumerate()
                 .map(|(i, m)| (m.symbol.into(), r[i]))
-                .collect::<HashMap<String, i64>>())
+                .collect::<HashMap<String, db::OiRow>>())
         })
         .await
         .unwrap();
@@ -373,3 +552,206 @@ async fn poll_open_interest(
         }
     }
 }
+
+/// Collections keyed by transaction signature, which a recorded
+/// transaction has at least one document in.
+const SIG_COLLS: [&str; 7] = [
+    db::Trade::COLL,
+    db::RealizedPnl::COLL,
+    db::Liquidation::COLL,
+    db::Bankruptcy::COLL,
+    db::BalanceChange::COLL,
+    db::Swap::COLL,
+    db::OtcFill::COLL,
+];
+
+/// Prints the successful transactions on the zo state between the unix
+/// timestamps `from` and `to` that have no document in the recorder's
+/// collections, so they can be recorded again. Transactions that emit
+/// no recorded events, e.g. cranks, are listed too.
+///
+/// If `until` is set, transactions from it back are ignored, so a range
+/// can end exactly at the last transaction known to be recorded.
+pub async fn verify_gaps(
+    st: &'static AppState,
+    from: i64,
+    to: i64,
+    until: Option<Signature>,
+) -> Result<(), Error> {
+    let db = mongodb::Client::with_uri_str(env::var("DATABASE_URL")?)
+        .await?
+        .database(DB_NAME);
+
+    let sigs = tokio::task::spawn_blocking(move || {
+        signatures_between(st, from, to, until)
+    })
+    .await
+    .unwrap()?;
+
+    let mut recorded = HashSet::new();
+
+    // Query in chunks to keep the `$in` filters reasonably sized.
+    for chunk in sigs.chunks(1000) {
+        for coll in SIG_COLLS {
+            let mut cursor = db
+                .collection::<mongodb::bson::Document>(coll)
+                .find(
+                    doc! { "sig": { "$in": chunk.to_vec() } },
+                    mongodb::options::FindOptions::builder()
+                        .projection(doc! { "sig": 1 })
+                        .build(),
+                )
+                .await?;
+
+            while let Some(d) = cursor.try_next().await? {
+                if let Some(Bson::String(s)) = d.get("sig") {
+                    recorded.insert(s.clone());
+                }
+            }
+        }
+    }
+
+    let missing: Vec<_> =
+        sigs.iter().filter(|s| !recorded.contains(*s)).collect();
+
+    println!(
+        "{} of {} transactions between {} and {} are not recorded",
+        missing.len(),
+        sigs.len(),
+        from,
+        to
+    );
+
+    for s in missing {
+        println!("{}", s);
+    }
+
+    Ok(())
+}
+
+/// Successful transactions on the zo state with a block time between
+/// `from` and `to`, newest first. The walk back stops at whichever comes
+/// first of `from` and the signature `until`, which is excluded.
+fn signatures_between(
+    st: &AppState,
+    from: i64,
+    to: i64,
+    until: Option<Signature>,
+) -> Result<Vec<String>, Error> {
+    let mut sigs = Vec::new();
+    let mut before = None;
+
+    loop {
+        let page = st.rpc.get_signatures_for_address_with_config(
+            &st.zo_state_pubkey,
+            GetConfirmedSignaturesForAddress2Config {
+                before,
+                until,
+                limit: None,
+                commitment: Some(CommitmentConfig::finalized()),
+            },
+        )?;
+
+        let last = match page.last() {
+            Some(x) => x,
+            None => break,
+        };
+
+        before = Some(Signature::from_str(&last.signature).unwrap());
+        let done = last.block_time.map_or(false, |t| t < from);
+
+        sigs.extend(
+            page.into_iter()
+                .filter(|s| s.err.is_none())
+                .filter(|s| {
+                    s.block_time.map_or(false, |t| from <= t && t <= to)
+                })
+                .map(|s| s.signature),
+        );
+
+        if done {
+            break;
+        }
+    }
+
+    Ok(sigs)
+}
+
+/// Scales the funding `rate` accrued over `elapsed` seconds to an hourly
+/// rate, as funding isn't necessarily updated exactly once an hour.
+fn hourly_rate(rate: f64, elapsed: i64) -> f64 {
+    rate * 3600.0 / elapsed as f64
+}
+
+/// Long, short and net open interest of the first `num_markets` markets,
+/// summed over the positions in `controls`.
+pub fn compute_open_interest<'a>(
+    controls: impl IntoIterator<Item = &'a zo_abi::Control>,
+    num_markets: usize,
+) -> Vec<db::OiRow> {
+    let mut r = vec![db::OiRow::default(); num_markets];
+
+    for c in controls {
+        for (e, o) in r.iter_mut().zip(c.open_orders_agg.iter()) {
+            match { o.pos_size } {
+                x if x > 0 => e.long += x,
+                x => e.short -= x,
+            }
+        }
+    }
+
+    for e in r.iter_mut() {
+        e.net = e.long - e.short;
+    }
+
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn control(pos: &[i64]) -> zo_abi::Control {
+        let mut c = zo_abi::Control::zeroed();
+        for (i, &x) in pos.iter().enumerate() {
+            c.open_orders_agg[i].pos_size = x;
+        }
+        c
+    }
+
+    #[test]
+    fn test_hourly_rate() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+
+        assert!(close(hourly_rate(0.001, 3600), 0.001));
+        // Funding accrued over half an hour is half the hourly rate.
+        assert!(close(hourly_rate(0.001, 1800), 0.002));
+        assert!(close(hourly_rate(-0.003, 3 * 3600), -0.001));
+    }
+
+    #[test]
+    fn test_compute_open_interest() {
+        let cs = [control(&[5, -3]), control(&[-2, -4]), control(&[1, 0])];
+        let oi = compute_open_interest(cs.iter(), 3);
+
+        let row = |long, short, net| db::OiRow { long, short, net };
+        assert_eq!(oi, vec![row(6, 2, 4), row(0, 7, -7), row(0, 0, 0)]);
+    }
+
+    #[test]
+    fn test_compute_open_interest_market_count() {
+        let cs = [control(&[5, -3, 8])];
+        let row = |long, short, net| db::OiRow { long, short, net };
+
+        // Markets past num_markets are ignored.
+        assert_eq!(
+            compute_open_interest(cs.iter(), 2),
+            vec![row(5, 0, 5), row(0, 3, -3)]
+        );
+        assert_eq!(
+            compute_open_interest(std::iter::empty(), 2),
+            vec![row(0, 0, 0); 2]
+        );
+    }
+}
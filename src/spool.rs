@@ -0,0 +1,196 @@
+//! Write-ahead buffer for documents that failed to be inserted into the
+//! database. Failed batches are appended as JSON lines to one file per
+//! collection, and drained back into the database once it is reachable.
+//! A file being drained is moved aside, and only removed once its
+//! documents have been inserted.
+
+use crate::{db, error::Error};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+pub struct Spool {
+    dir: PathBuf,
+    // Serializes writers with the drainer, so a batch is never
+    // half-written to a file that is being taken.
+    lock: Mutex<()>,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, coll: &str) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", coll))
+    }
+
+    fn draining_path(&self, coll: &str) -> PathBuf {
+        self.dir.join(format!("{}.draining", coll))
+    }
+
+    pub fn write<T: Serialize>(
+        &self,
+        coll: &str,
+        xs: &[T],
+    ) -> Result<(), Error> {
+        let lines = xs
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.write_lines(coll, &lines)
+    }
+
+    fn write_lines(&self, coll: &str, lines: &[String]) -> Result<(), Error> {
+        let _g = self.lock.lock();
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(coll))?;
+
+        for l in lines {
+            writeln!(f, "{}", l)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves every spooled file aside to be drained, returning the
+    /// collection names and their documents. They stay on disk until
+    /// `drained` is called for the collection, so are retried if inserting
+    /// them fails. Until then, newer documents of the collection are left
+    /// spooled.
+    fn take(&self) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let _g = self.lock.lock();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            if let Some(coll) = spooled_coll(&path, "jsonl") {
+                let draining = self.draining_path(&coll);
+
+                if !draining.exists() {
+                    fs::rename(&path, draining)?;
+                }
+            }
+        }
+
+        let mut res = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let coll = match spooled_coll(&path, "draining") {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let lines: Vec<String> = fs::read_to_string(&path)?
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect();
+
+            res.push((coll, lines));
+        }
+
+        Ok(res)
+    }
+
+    /// Removes the collection's taken documents, once they're inserted.
+    fn drained(&self, coll: &str) -> Result<(), Error> {
+        let _g = self.lock.lock();
+        fs::remove_file(self.draining_path(coll))?;
+        Ok(())
+    }
+}
+
+fn spooled_coll(path: &Path, extension: &str) -> Option<String> {
+    if path.extension()? == extension {
+        path.file_stem()?.to_str().map(String::from)
+    } else {
+        None
+    }
+}
+
+#[tracing::instrument(skip_all, level = "error", name = "spool")]
+pub async fn drain(db: &'static mongodb::Database, spool: &'static Spool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let spooled = match spool.take() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+
+        for (coll, lines) in spooled {
+            if !lines.is_empty() {
+                if let Err(e) = db::update_spooled(db, &coll, &lines).await {
+                    // They're left on disk, to retry on the next tick.
+                    warn!("{}", Error::from(e));
+                    continue;
+                }
+
+                info!("drained {} {} documents", lines.len(), coll);
+            }
+
+            if let Err(e) = spool.drained(&coll) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_keeps_documents_until_drained() {
+        let dir = std::env::temp_dir()
+            .join(format!("zo-keeper-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let spool = Spool::new(dir.clone()).unwrap();
+
+        spool.write("trades", &[1, 2]).unwrap();
+        assert_eq!(
+            spool.take().unwrap(),
+            vec![("trades".to_string(), vec!["1".into(), "2".into()])]
+        );
+
+        // Not drained, so taken again, and newer documents wait for it.
+        spool.write("trades", &[3]).unwrap();
+        assert_eq!(
+            spool.take().unwrap(),
+            vec![("trades".to_string(), vec!["1".into(), "2".into()])]
+        );
+
+        spool.drained("trades").unwrap();
+        assert_eq!(
+            spool.take().unwrap(),
+            vec![("trades".to_string(), vec!["3".into()])]
+        );
+
+        spool.drained("trades").unwrap();
+        assert_eq!(spool.take().unwrap(), vec![]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
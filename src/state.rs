@@ -1,18 +1,27 @@
+use crate::nonce::DurableNonce;
 use anchor_client::{
     solana_client::rpc_client::RpcClient,
     solana_sdk::{
-        commitment_config::CommitmentConfig, pubkey::Pubkey,
-        signer::keypair::Keypair,
+        commitment_config::CommitmentConfig, hash::Hash,
+        instruction::Instruction, pubkey::Pubkey, signature::Signature,
+        signer::keypair::Keypair, transaction::Transaction,
     },
-    Client, Cluster, Program,
+    Client, ClientError, Cluster, Program, RequestBuilder,
 };
+use std::{collections::HashMap, time::Duration};
 
 pub struct AppState {
     payer: Keypair,
-    commitment: CommitmentConfig,
     pub cluster: Cluster,
+    /// Client for reads, on `cluster`.
     pub rpc: RpcClient,
+    /// Client transactions are sent through, which may be on a dedicated
+    /// transaction-forwarding RPC rather than `cluster`.
+    pub tx_rpc: RpcClient,
+    nonce: Option<DurableNonce>,
     pub zo_state: zo_abi::State,
+    /// Lookups into `zo_state`, built along with it.
+    pub zo_indices: StateIndices,
     pub zo_cache: zo_abi::Cache,
     pub zo_state_pubkey: Pubkey,
     pub zo_cache_pubkey: Pubkey,
@@ -22,20 +31,50 @@ pub struct AppState {
 impl AppState {
     pub fn new(
         cluster: Cluster,
+        tx_cluster: Cluster,
+        rpc_timeout: Duration,
         commitment: CommitmentConfig,
         payer: Keypair,
+        nonce_account: Option<Pubkey>,
     ) -> Self {
-        let program = Client::new_with_options(
-            cluster.clone(),
-            std::rc::Rc::new(Keypair::from_bytes(&payer.to_bytes()).unwrap()),
-            commitment.clone(),
+        let rpc = RpcClient::new_with_timeout_and_commitment(
+            cluster.url().to_string(),
+            rpc_timeout,
+            commitment,
+        );
+        let tx_rpc = RpcClient::new_with_timeout_and_commitment(
+            tx_cluster.url().to_string(),
+            rpc_timeout,
+            commitment,
+        );
+        let zo_state: zo_abi::State =
+            crate::utils::get_account(&rpc, &zo_abi::ZO_STATE_ID).unwrap();
+        let zo_cache: zo_abi::Cache =
+            crate::utils::get_account(&rpc, &zo_state.cache).unwrap();
+
+        Self::from_accounts(
+            cluster,
+            rpc,
+            tx_rpc,
+            payer,
+            nonce_account,
+            zo_state,
+            zo_cache,
         )
-        .program(zo_abi::ID);
+    }
 
-        let rpc = program.rpc();
+    /// The state for already fetched zo state and cache accounts. The
+    /// clients built by `client` share the commitment of `rpc`.
+    fn from_accounts(
+        cluster: Cluster,
+        rpc: RpcClient,
+        tx_rpc: RpcClient,
+        payer: Keypair,
+        nonce_account: Option<Pubkey>,
+        zo_state: zo_abi::State,
+        zo_cache: zo_abi::Cache,
+    ) -> Self {
         let zo_state_pubkey = zo_abi::ZO_STATE_ID;
-        let zo_state: zo_abi::State = program.account(zo_state_pubkey).unwrap();
-        let zo_cache: zo_abi::Cache = program.account(zo_state.cache).unwrap();
         let (zo_state_signer_pubkey, state_signer_nonce) =
             Pubkey::find_program_address(
                 &[zo_state_pubkey.as_ref()],
@@ -48,9 +87,11 @@ impl AppState {
 
         Self {
             payer,
-            commitment: CommitmentConfig::confirmed(),
             cluster,
             rpc,
+            tx_rpc,
+            nonce: nonce_account.map(DurableNonce::new),
+            zo_indices: StateIndices::new(&zo_state),
             zo_state,
             zo_cache,
             zo_state_pubkey,
@@ -68,20 +109,68 @@ impl AppState {
         &self.payer
     }
 
+    /// The durable nonce account transactions are signed with instead of
+    /// a recent blockhash, if any.
+    pub fn nonce(&self) -> Option<&DurableNonce> {
+        self.nonce.as_ref()
+    }
+
+    /// A client on `cluster`, for building requests, which are sent with
+    /// `send`. Its own reads have no timeout, so use `rpc` for those.
+    ///
+    /// A fresh client is built on every call because anchor's `Client`
+    /// holds its signer in an `Rc`, so it is neither `Send` nor `Sync` and
+    /// can't be stored here and shared with the liquidation tasks and
+    /// crank threads. This is cheap: anchor doesn't keep a connection in
+    /// the client, but builds a new `RpcClient` for every request anyway.
     pub fn client(&self) -> Client {
-        Client::new_with_options(
-            self.cluster.clone(),
-            std::rc::Rc::new(
-                Keypair::from_bytes(&self.payer.to_bytes()).unwrap(),
-            ),
-            self.commitment.clone(),
-        )
+        new_client(self.cluster.clone(), &self.payer, self.rpc.commitment())
     }
 
     pub fn program(&self) -> Program {
         self.client().program(zo_abi::ID)
     }
 
+    /// Sends the request through `tx_rpc`, signed by the payer, and waits
+    /// for it to be confirmed. Anchor's `RequestBuilder::send` would send
+    /// it through a client of its own, on the read cluster and without a
+    /// timeout.
+    ///
+    /// The transaction is signed with the durable nonce if one is set, so
+    /// every transaction the keeper sends, not just the liquidator's, goes
+    /// through it. Otherwise it is signed with a recent blockhash.
+    pub fn send(&self, req: RequestBuilder) -> Result<Signature, ClientError> {
+        let ixs = req.instructions()?;
+
+        let nonce = match self.nonce() {
+            Some(nonce) => nonce,
+            None => {
+                let hash = self.tx_rpc.get_latest_blockhash()?;
+                return self.send_signed(&ixs, hash);
+            }
+        };
+
+        nonce.with(&self.rpc, &self.payer(), |advance, hash| {
+            let ixs: Vec<_> = std::iter::once(advance).chain(ixs).collect();
+            self.send_signed(&ixs, hash)
+        })?
+    }
+
+    fn send_signed(
+        &self,
+        ixs: &[Instruction],
+        hash: Hash,
+    ) -> Result<Signature, ClientError> {
+        let tx = Transaction::new_signed_with_payer(
+            ixs,
+            Some(&self.payer()),
+            &[&self.payer],
+            hash,
+        );
+
+        Ok(self.tx_rpc.send_and_confirm_transaction(&tx)?)
+    }
+
     pub fn iter_markets(
         &self,
     ) -> impl Iterator<Item = &zo_abi::PerpMarketInfo> {
@@ -94,7 +183,30 @@ impl AppState {
     pub fn load_dex_markets(
         &self,
     ) -> Result<Vec<(String, zo_abi::dex::ZoDexMarket)>, crate::Error> {
-        self.iter_markets()
+        self.load_dex_markets_of(self.iter_markets())
+    }
+
+    /// Like `load_dex_markets`, but for the markets listed in the zo state
+    /// as it is now, rather than as it was when the keeper started.
+    pub fn fetch_dex_markets(
+        &self,
+    ) -> Result<Vec<(String, zo_abi::dex::ZoDexMarket)>, crate::Error> {
+        let state: zo_abi::State =
+            crate::utils::get_account(&self.rpc, &self.zo_state_pubkey)?;
+
+        self.load_dex_markets_of(
+            state
+                .perp_markets
+                .iter()
+                .filter(|m| m.dex_market != Pubkey::default()),
+        )
+    }
+
+    fn load_dex_markets_of<'a>(
+        &self,
+        markets: impl Iterator<Item = &'a zo_abi::PerpMarketInfo>,
+    ) -> Result<Vec<(String, zo_abi::dex::ZoDexMarket)>, crate::Error> {
+        markets
             .map(|m| {
                 Ok((
                     m.symbol.into(),
@@ -120,3 +232,111 @@ impl AppState {
             .filter(|x| x.mint != Pubkey::default())
     }
 }
+
+/// The index of each listed market and collateral in the zo state, so
+/// that events can be attributed without scanning the state for each.
+#[derive(Default)]
+pub struct StateIndices {
+    /// Markets by their dex market.
+    pub markets: HashMap<Pubkey, usize>,
+    /// Markets by symbol.
+    pub market_symbols: HashMap<String, usize>,
+    /// Collaterals by oracle symbol.
+    pub collateral_symbols: HashMap<String, usize>,
+}
+
+impl StateIndices {
+    pub fn new(state: &zo_abi::State) -> Self {
+        let mut indices = Self::default();
+
+        for (i, m) in state.perp_markets.iter().enumerate() {
+            if m.dex_market != Pubkey::default() {
+                indices.markets.insert(m.dex_market, i);
+                indices.market_symbols.insert(m.symbol.into(), i);
+            }
+        }
+
+        for (i, c) in state.collaterals.iter().enumerate() {
+            if c.mint != Pubkey::default() {
+                indices.collateral_symbols.insert(c.oracle_symbol.into(), i);
+            }
+        }
+
+        indices
+    }
+}
+
+fn new_client(
+    cluster: Cluster,
+    payer: &Keypair,
+    commitment: CommitmentConfig,
+) -> Client {
+    Client::new_with_options(
+        cluster,
+        std::rc::Rc::new(Keypair::from_bytes(&payer.to_bytes()).unwrap()),
+        commitment,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_commitment() {
+        use bytemuck::Zeroable;
+
+        let mut state = zo_abi::State::zeroed();
+        state.signer_nonce = Pubkey::find_program_address(
+            &[zo_abi::ZO_STATE_ID.as_ref()],
+            &zo_abi::ID,
+        )
+        .1;
+
+        for commitment in [
+            CommitmentConfig::processed(),
+            CommitmentConfig::confirmed(),
+            CommitmentConfig::finalized(),
+        ] {
+            let rpc = || {
+                RpcClient::new_with_commitment(
+                    Cluster::Localnet.url().to_string(),
+                    commitment,
+                )
+            };
+            let st = AppState::from_accounts(
+                Cluster::Localnet,
+                rpc(),
+                rpc(),
+                Keypair::new(),
+                None,
+                state,
+                zo_abi::Cache::zeroed(),
+            );
+
+            assert_eq!(st.program().rpc().commitment(), commitment);
+        }
+    }
+
+    #[test]
+    fn test_state_indices() {
+        use bytemuck::Zeroable;
+        use zo_abi::Symbol;
+
+        let mut state = zo_abi::State::zeroed();
+        state.collaterals[0].mint = Pubkey::new_unique();
+        state.collaterals[0].oracle_symbol = Symbol::try_from("USDC").unwrap();
+        state.collaterals[2].mint = Pubkey::new_unique();
+        state.collaterals[2].oracle_symbol = Symbol::try_from("SOL").unwrap();
+        state.perp_markets[1].symbol = Symbol::try_from("SOL-PERP").unwrap();
+        state.perp_markets[1].dex_market = Pubkey::new_unique();
+
+        let indices = StateIndices::new(&state);
+
+        assert_eq!(indices.markets.len(), 1);
+        assert_eq!(indices.markets[&state.perp_markets[1].dex_market], 1);
+        assert_eq!(indices.market_symbols["SOL-PERP"], 1);
+        assert_eq!(indices.collateral_symbols["SOL"], 2);
+        assert_eq!(indices.collateral_symbols.len(), 2);
+    }
+}
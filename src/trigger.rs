@@ -1,4 +1,8 @@
-use crate::{error::Error, AppState};
+use crate::{
+    error::Error,
+    utils::{decode_account_data, get_account, load_program_accounts},
+    AppState,
+};
 use anchor_client::{
     anchor_lang::Discriminator,
     solana_client::{
@@ -6,29 +10,67 @@ use anchor_client::{
         rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
     },
     solana_sdk::{
-        commitment_config::CommitmentConfig, pubkey::Pubkey,
-        signature::Signature, sysvar,
+        commitment_config::CommitmentConfig,
+        compute_budget::ComputeBudgetInstruction, instruction::Instruction,
+        pubkey::Pubkey, signature::Signature, sysvar,
     },
 };
 use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
-use solana_account_decoder::{UiAccountData, UiAccountEncoding};
-use std::{collections::HashMap, str::FromStr};
+use solana_account_decoder::UiAccountEncoding;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use zo_abi as zo;
 
+/// Most special orders executed in one transaction. The orders share all
+/// their accounts, so this is bound by compute rather than size.
+const ORDERS_PER_TX: usize = 4;
+
+/// Compute units budgeted per `ExecuteSpecialOrder`.
+const EXECUTE_SPECIAL_ORDER_CU: u32 = 300_000;
+
+/// Most traders whose accounts are cached. When full, the least recently
+/// used entry is evicted.
+const TRADER_ACCS_CAP: usize = 10_000;
+
+/// Age after which a trader's cached control is fetched again, as it goes
+/// stale once the trader trades.
+const TRADER_ACCS_TTL: Duration = Duration::from_secs(60);
+
+pub struct TriggerConfig {
+    /// Age of the cache's oracle prices past which orders aren't
+    /// triggered.
+    pub max_cache_age: Duration,
+    /// Priority fee, in micro-lamports per compute unit.
+    pub priority_fee: Option<u64>,
+    /// Commitment level of the account subscription.
+    pub subscription_commitment: CommitmentConfig,
+}
+
 struct Accounts {
-    pub zo_cache: Mutex<Option<zo::Cache>>,
+    /// The latest cache, and when the listener received it.
+    pub zo_cache: Mutex<Option<(zo::Cache, Instant)>>,
     pub zo_so: RwLock<HashMap<Pubkey, RwLock<zo::SpecialOrders>>>,
-    /// Mapping from authority key to (margin key, control key, control account).
-    pub zo_trader_accs: RwLock<HashMap<Pubkey, (Pubkey, Pubkey, zo::Control)>>,
+    /// Mapping from authority key to its accounts.
+    pub zo_trader_accs: RwLock<HashMap<Pubkey, TraderAccounts>>,
+}
+
+struct TraderAccounts {
+    margin_key: Pubkey,
+    control_key: Pubkey,
+    control: zo::Control,
+    fetched: Instant,
+    last_used: Mutex<Instant>,
 }
 
 #[tracing::instrument(skip_all, name = "trigger", level = "error")]
-pub fn run(st: &'static AppState) -> Result<(), Error> {
+pub fn run(st: &'static AppState, cfg: TriggerConfig) -> Result<(), Error> {
     let accs = Accounts {
-        zo_cache: Mutex::new(Some(st.zo_cache)),
+        zo_cache: Mutex::new(Some((st.zo_cache, Instant::now()))),
         zo_so: RwLock::new(
-            st.program()
-                .accounts::<zo::SpecialOrders>(vec![])?
+            load_program_accounts::<zo::SpecialOrders>(&st.rpc)?
                 .into_iter()
                 .map(|(k, v)| (k, RwLock::new(v)))
                 .collect(),
@@ -43,20 +85,13 @@ pub fn run(st: &'static AppState) -> Result<(), Error> {
         .collect();
 
     std::thread::scope(|s| {
-        s.spawn(|| listener(st, &accs));
-        s.spawn(|| executer(st, &accs, mkts));
+        s.spawn(|| listener(st, cfg.subscription_commitment, &accs));
+        s.spawn(|| executer(st, &cfg, &accs, mkts));
     });
 
     Ok(())
 }
 
-fn decode_ui_data(b: UiAccountData) -> Vec<u8> {
-    match b {
-        UiAccountData::Binary(b, _) => base64::decode(b).unwrap(),
-        _ => unreachable!(),
-    }
-}
-
 fn load_buf<T>(buf: &[u8]) -> Option<T>
 where
     T: Copy + bytemuck::Pod + Discriminator,
@@ -76,7 +111,11 @@ fn margin_pda(authority: &Pubkey, state: &Pubkey) -> Pubkey {
 }
 
 #[tracing::instrument(skip_all, level = "error")]
-fn listener(st: &'static AppState, accs: &Accounts) {
+fn listener(
+    st: &'static AppState,
+    commitment: CommitmentConfig,
+    accs: &Accounts,
+) {
     loop {
         let r = PubsubClient::program_subscribe(
             st.cluster.ws_url(),
@@ -87,7 +126,7 @@ fn listener(st: &'static AppState, accs: &Accounts) {
                 account_config: RpcAccountInfoConfig {
                     encoding: Some(UiAccountEncoding::Base64),
                     data_slice: None,
-                    commitment: Some(CommitmentConfig::confirmed()),
+                    commitment: Some(commitment),
                     min_context_slot: None,
                 },
             }),
@@ -102,11 +141,17 @@ fn listener(st: &'static AppState, accs: &Accounts) {
         };
 
         while let Ok(r) = rx.recv() {
-            let buf = decode_ui_data(r.value.account.data);
+            let buf = match decode_account_data(
+                &r.value.pubkey,
+                r.value.account.data,
+            ) {
+                Some(x) => x,
+                None => continue,
+            };
 
             if let Some(c) = load_buf::<zo::Cache>(&buf) {
                 tracing::trace!("cache update");
-                *accs.zo_cache.lock() = Some(c);
+                *accs.zo_cache.lock() = Some((c, Instant::now()));
                 continue;
             }
 
@@ -133,9 +178,31 @@ fn listener(st: &'static AppState, accs: &Accounts) {
     }
 }
 
+/// Time since each market's oracle price in `cache` was cached, at unix
+/// time `now`, from the unix time the cache stamps each one with. A
+/// market whose oracle isn't in the cache is as old as can be.
+fn oracle_ages<'a>(
+    markets: impl Iterator<Item = &'a zo::PerpMarketInfo>,
+    cache: &zo::Cache,
+    now: u64,
+) -> Vec<Duration> {
+    markets
+        .map(|m| {
+            let updated = cache
+                .oracles
+                .iter()
+                .find(|o| o.symbol == m.oracle_symbol)
+                .map_or(0, |o| o.last_updated);
+
+            Duration::from_secs(now.saturating_sub(updated))
+        })
+        .collect()
+}
+
 #[tracing::instrument(skip_all, level = "error")]
 fn executer(
     st: &'static AppState,
+    cfg: &TriggerConfig,
     accs: &Accounts,
     mut mkts: HashMap<Pubkey, zo::dex::ZoDexMarket>,
 ) {
@@ -150,8 +217,12 @@ fn executer(
         .map(|(i, m)| (m.dex_market, (i, mkts.remove(&m.dex_market).unwrap())))
         .collect();
 
+    // Whether each market's prices were too old to trigger on at the last
+    // cache, so that a stale market is only warned about once.
+    let mut stale = vec![false; st.iter_markets().count()];
+
     loop {
-        let cache = match accs.zo_cache.lock().take() {
+        let (cache, received) = match accs.zo_cache.lock().take() {
             Some(x) => x,
             None => {
                 std::thread::sleep(std::time::Duration::from_millis(50));
@@ -159,6 +230,41 @@ fn executer(
             }
         };
 
+        // Triggering on an old price can fill the order at a price the
+        // trader didn't ask for, so markets with an old price are skipped
+        // until a fresh cache. The age is that of the market's oracle
+        // price, since a cache received just now can still hold old ones
+        // if the crank is behind, but no less than the cache's own.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let ages = oracle_ages(st.iter_markets(), &cache, now);
+
+        for (i, age) in ages.into_iter().enumerate() {
+            let age = age.max(received.elapsed());
+            let is_stale = age > cfg.max_cache_age;
+            let symbol = st.zo_state.perp_markets[i].symbol;
+
+            match (stale[i], is_stale) {
+                (false, true) => tracing::warn!(
+                    "skipping triggers on {}, its oracle was cached {} s ago",
+                    symbol,
+                    age.as_secs()
+                ),
+                (true, true) => tracing::debug!(
+                    "skipping triggers on {}, its oracle was cached {} s ago",
+                    symbol,
+                    age.as_secs()
+                ),
+                (true, false) => {
+                    tracing::info!("{} oracle is fresh, resuming", symbol)
+                }
+                (false, false) => {}
+            }
+
+            stale[i] = is_stale;
+        }
+
         // Get mark prices in small / big, mapped to index.
         let prices: Vec<u64> = cache
             .marks
@@ -175,18 +281,27 @@ fn executer(
         std::thread::scope(|s| {
             for (k, so) in accs.zo_so.read().iter() {
                 let so = so.read();
+                let authority = { so.authority };
+
+                // Orders triggered on the same market are executed in
+                // the same transactions.
+                let mut triggered: HashMap<Pubkey, Vec<_>> = HashMap::new();
                 for o in so.iter() {
-                    if o.is_triggered(prices[ms[&o.market].0]) {
-                        let (idx, mkt) = ms[&o.market];
-                        let authority = { so.authority };
-                        let k = *k;
-                        let o = *o;
-
-                        s.spawn(move || {
-                            trigger(st, accs, &mkt, idx, authority, k, o)
-                        });
+                    let idx = ms[&o.market].0;
+
+                    if !stale[idx] && o.is_triggered(prices[idx]) {
+                        triggered.entry(o.market).or_default().push(*o);
                     }
                 }
+
+                for (market, orders) in triggered {
+                    let (idx, mkt) = ms[&market];
+                    let k = *k;
+
+                    s.spawn(move || {
+                        trigger(st, cfg, accs, &mkt, idx, authority, k, &orders)
+                    });
+                }
             }
         });
     }
@@ -198,50 +313,100 @@ fn executer(
     fields(
         authority = %authority,
         market = %st.zo_state.perp_markets[idx].symbol,
-        id = %{ order.id },
     ),
 )]
 fn trigger(
     st: &AppState,
+    cfg: &TriggerConfig,
     accs: &Accounts,
     mkt: &zo::dex::ZoDexMarket,
     idx: usize,
     authority: Pubkey,
     special_orders: Pubkey,
-    order: zo::SpecialOrdersInfo,
+    orders: &[zo::SpecialOrdersInfo],
 ) {
-    match trigger_(st, accs, mkt, idx, authority, special_orders, order) {
-        Ok(sg) => tracing::info!("{}", sg),
-        Err(e) => tracing::warn!("{}", e),
+    let send = |orders: &[_]| {
+        trigger_(st, cfg, accs, mkt, idx, authority, special_orders, orders)
+    };
+    let ids = |orders: &[zo::SpecialOrdersInfo]| {
+        orders
+            .iter()
+            .map(|o| { o.id }.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    for chunk in orders.chunks(ORDERS_PER_TX) {
+        match send(chunk) {
+            Ok(sg) => tracing::info!("{:?}: {}", ids(chunk), sg),
+            // The trader's margin or control was closed, so its orders
+            // can never execute. Stop tracking them instead of failing
+            // every time they trigger. They are picked up again if the
+            // special orders account changes.
+            Err(Error::AccountNotFound(_)) => {
+                tracing::warn!(
+                    "margin or control not found, dropping special orders {}",
+                    special_orders
+                );
+                accs.zo_so.write().remove(&special_orders);
+                accs.zo_trader_accs.write().remove(&authority);
+                return;
+            }
+            Err(e) if chunk.len() == 1 => {
+                tracing::warn!("{:?}: {}", ids(chunk), e)
+            }
+            // One order failing fails the whole transaction, so send the
+            // orders separately to execute the rest and see which failed.
+            Err(e) => {
+                tracing::warn!("{:?}: {}, retrying separately", ids(chunk), e);
+
+                for o in chunk.chunks(1) {
+                    match send(o) {
+                        Ok(sg) => tracing::info!("{:?}: {}", ids(o), sg),
+                        Err(e) => tracing::warn!("{:?}: {}", ids(o), e),
+                    }
+                }
+            }
+        }
     }
 }
 
 fn trigger_(
     st: &AppState,
+    cfg: &TriggerConfig,
     accs: &Accounts,
     mkt: &zo::dex::ZoDexMarket,
     idx: usize,
     authority: Pubkey,
     special_orders: Pubkey,
-    order: zo::SpecialOrdersInfo,
+    orders: &[zo::SpecialOrdersInfo],
 ) -> Result<Signature, Error> {
+    use anchor_client::anchor_lang::{InstructionData, ToAccountMetas};
+
     tracing::debug!("triggering");
     let program = st.program();
 
-    // Get the margin and control keys. If they don't
-    // exist, fetch them first, and update the cache.
+    // Get the margin and control keys. If they don't exist
+    // or are stale, fetch them first, and update the cache.
     let (margin, control, oo) = {
         let map = accs.zo_trader_accs.read();
 
-        match map.get(&authority).copied() {
-            Some((margin_key, control_key, control)) => {
-                let oo_key = control.open_orders_agg[idx].key;
+        match map
+            .get(&authority)
+            .filter(|a| a.fetched.elapsed() < TRADER_ACCS_TTL)
+        {
+            Some(a) => {
+                *a.last_used.lock() = Instant::now();
+                let keys = (
+                    a.margin_key,
+                    a.control_key,
+                    a.control.open_orders_agg[idx].key,
+                );
 
                 // Since the other branch drops this, drop it here
                 // too so it doesn't get held for too long
                 drop(map);
 
-                (margin_key, control_key, oo_key)
+                keys
             }
             None => {
                 // First, drop the readlock, since we have to insert
@@ -249,41 +414,110 @@ fn trigger_(
                 drop(map);
 
                 let margin_key = margin_pda(&authority, &st.zo_state_pubkey);
-                let margin = program.account::<zo::Margin>(margin_key)?;
+                let margin: zo::Margin = get_account(&st.rpc, &margin_key)?;
                 let control_key = margin.control;
-                let control = program.account::<zo::Control>(control_key)?;
+                let control: zo::Control = get_account(&st.rpc, &control_key)?;
                 let oo_key = control.open_orders_agg[idx].key;
 
-                accs.zo_trader_accs
-                    .write()
-                    .insert(authority, (margin_key, control_key, control));
+                let mut map = accs.zo_trader_accs.write();
+
+                if map.len() >= TRADER_ACCS_CAP && !map.contains_key(&authority)
+                {
+                    let lru = map
+                        .iter()
+                        .min_by_key(|(_, a)| *a.last_used.lock())
+                        .map(|(k, _)| *k);
+
+                    if let Some(k) = lru {
+                        map.remove(&k);
+                    }
+                }
+
+                let now = Instant::now();
+                map.insert(
+                    authority,
+                    TraderAccounts {
+                        margin_key,
+                        control_key,
+                        control,
+                        fetched: now,
+                        last_used: Mutex::new(now),
+                    },
+                );
 
                 (margin_key, control_key, oo_key)
             }
         }
     };
 
-    program
-        .request()
-        .args(zo::instruction::ExecuteSpecialOrder { id: order.id })
-        .accounts(zo::accounts::ExecuteSpecialOrder {
-            state: st.zo_state_pubkey,
-            state_signer: st.zo_state_signer_pubkey,
-            cache: st.zo_cache_pubkey,
-            payer: st.payer(),
-            authority,
-            margin,
-            control,
-            special_orders,
-            open_orders: oo,
-            dex_market: mkt.own_address,
-            req_q: mkt.req_q,
-            event_q: mkt.event_q,
-            market_bids: mkt.bids,
-            market_asks: mkt.asks,
-            dex_program: zo::ZO_DEX_PID,
-            rent: sysvar::rent::ID,
+    let accounts = zo::accounts::ExecuteSpecialOrder {
+        state: st.zo_state_pubkey,
+        state_signer: st.zo_state_signer_pubkey,
+        cache: st.zo_cache_pubkey,
+        payer: st.payer(),
+        authority,
+        margin,
+        control,
+        special_orders,
+        open_orders: oo,
+        dex_market: mkt.own_address,
+        req_q: mkt.req_q,
+        event_q: mkt.event_q,
+        market_bids: mkt.bids,
+        market_asks: mkt.asks,
+        dex_program: zo::ZO_DEX_PID,
+        rent: sysvar::rent::ID,
+    }
+    .to_account_metas(None);
+
+    let mut req = program.request().instruction(
+        ComputeBudgetInstruction::set_compute_unit_limit(
+            EXECUTE_SPECIAL_ORDER_CU * orders.len() as u32,
+        ),
+    );
+
+    if let Some(fee) = cfg.priority_fee {
+        req = req
+            .instruction(ComputeBudgetInstruction::set_compute_unit_price(fee));
+    }
+
+    let req = orders.iter().fold(req, |req, o| {
+        req.instruction(Instruction {
+            program_id: zo::ID,
+            accounts: accounts.clone(),
+            data: zo::instruction::ExecuteSpecialOrder { id: o.id }.data(),
         })
-        .send()
-        .map_err(Into::into)
+    });
+
+    st.send(req).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn market(oracle: &str) -> zo::PerpMarketInfo {
+        let mut m = zo::State::zeroed().perp_markets[0];
+        m.oracle_symbol = zo::Symbol::try_from(oracle).unwrap();
+        m
+    }
+
+    #[test]
+    fn test_oracle_ages() {
+        let mut cache = zo::Cache::zeroed();
+        cache.oracles[0].symbol = zo::Symbol::try_from("SOL").unwrap();
+        cache.oracles[0].last_updated = 100;
+        cache.oracles[1].symbol = zo::Symbol::try_from("BTC").unwrap();
+        cache.oracles[1].last_updated = 90;
+
+        let markets = [market("SOL"), market("BTC"), market("ETH")];
+        let ages = oracle_ages(markets.iter(), &cache, 102);
+
+        // Each market is aged by its own oracle, so one old price doesn't
+        // hold back the others.
+        assert_eq!(ages[0], Duration::from_secs(2));
+        assert_eq!(ages[1], Duration::from_secs(12));
+        assert_eq!(ages[2], Duration::from_secs(102));
+    }
 }
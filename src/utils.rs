@@ -11,7 +11,8 @@ use anchor_client::{
         commitment_config::CommitmentConfig, pubkey::Pubkey,
     },
 };
-use solana_account_decoder::UiAccountEncoding;
+use solana_account_decoder::{UiAccountData, UiAccountEncoding};
+use std::{cell::Cell, time::Duration};
 
 fn load_account<'a, T>(key: &'a Pubkey, account: &'a mut Account) -> T
 where
@@ -24,6 +25,35 @@ where
     account
 }
 
+/// Fetches the zo account at `key` with `client`, which unlike anchor's
+/// `Program::account` may be one configured with a timeout. Fails with
+/// `Error::AccountNotFound` if there is no account at `key`.
+pub fn get_account<T>(client: &RpcClient, key: &Pubkey) -> Result<T, Error>
+where
+    T: ZeroCopy + Owner,
+{
+    let mut account = client
+        .get_account_with_commitment(key, client.commitment())?
+        .value
+        .ok_or(Error::AccountNotFound(*key))?;
+
+    try_load_account(key, &mut account).ok_or(Error::InvalidAccount(*key))
+}
+
+/// Decodes the zo account `account` at `key`, or `None` if it isn't a `T`
+/// owned by the zo program.
+pub fn try_load_account<T>(key: &Pubkey, account: &mut Account) -> Option<T>
+where
+    T: ZeroCopy + Owner,
+{
+    let account_info: AccountInfo<'_> = (key, account).into();
+    let loader: AccountLoader<'_, T> =
+        AccountLoader::try_from(&account_info).ok()?;
+    let account = *loader.load().ok()?;
+
+    Some(account)
+}
+
 pub fn load_program_accounts<T>(
     client: &RpcClient,
 ) -> Result<Vec<(Pubkey, T)>, Error>
@@ -57,3 +87,163 @@ where
         })
         .map_err(Into::into)
 }
+
+/// Decodes account data from a subscription, in whichever binary encoding
+/// the RPC provider sent it. Logs and returns `None` if it can't be
+/// decoded.
+pub fn decode_account_data(
+    pubkey: &str,
+    data: UiAccountData,
+) -> Option<Vec<u8>> {
+    let res = match data {
+        UiAccountData::LegacyBinary(b)
+        | UiAccountData::Binary(b, UiAccountEncoding::Base58) => {
+            bs58::decode(b).into_vec().map_err(|e| e.to_string())
+        }
+        UiAccountData::Binary(b, UiAccountEncoding::Base64) => {
+            base64::decode(b).map_err(|e| e.to_string())
+        }
+        UiAccountData::Binary(b, UiAccountEncoding::Base64Zstd) => {
+            base64::decode(b).map_err(|e| e.to_string()).and_then(|z| {
+                zstd::decode_all(&z[..]).map_err(|e| e.to_string())
+            })
+        }
+        UiAccountData::Binary(_, encoding) => {
+            Err(format!("unsupported encoding {:?}", encoding))
+        }
+        UiAccountData::Json(_) => Err("not binary encoded".to_string()),
+    };
+
+    res.map_err(|e| tracing::warn!("failed to decode {}: {}", pubkey, e))
+        .ok()
+}
+
+thread_local! {
+    static SUPERVISED: Cell<bool> = Cell::new(false);
+}
+
+/// Whether the current thread is running a supervised worker, whose
+/// panics are handled by its supervisor rather than exiting the process.
+pub fn is_supervised() -> bool {
+    SUPERVISED.with(|s| s.get())
+}
+
+/// Runs `f` marked as supervised, see `is_supervised`. The mark is
+/// cleared when `f` returns or unwinds, as blocking pool threads are
+/// reused for other tasks.
+pub fn supervised<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset;
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            SUPERVISED.with(|s| s.set(false));
+        }
+    }
+
+    SUPERVISED.with(|s| s.set(true));
+    let _reset = Reset;
+    f()
+}
+
+/// Runs `f` on the blocking pool until it returns, restarting it after
+/// `delay` whenever it panics, so that a long-running worker isn't
+/// silently lost for the rest of the process. `name` identifies the
+/// worker in the log.
+pub async fn supervise_blocking<F>(name: &str, delay: Duration, f: F)
+where
+    F: FnOnce() + Send + Clone + 'static,
+{
+    loop {
+        let f = f.clone();
+
+        match tokio::task::spawn_blocking(move || supervised(f)).await {
+            Err(e) if e.is_panic() => {
+                tracing::warn!(
+                    "{} panicked, restarting in {}s: {}",
+                    name,
+                    delay.as_secs(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            _ => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_account_data() {
+        let data = b"some account data".to_vec();
+        let zstd = zstd::encode_all(&data[..], 0).unwrap();
+
+        for (b, encoding) in [
+            (base64::encode(&data), UiAccountEncoding::Base64),
+            (bs58::encode(&data).into_string(), UiAccountEncoding::Base58),
+            (base64::encode(&zstd), UiAccountEncoding::Base64Zstd),
+        ] {
+            let d = UiAccountData::Binary(b, encoding);
+            assert_eq!(decode_account_data("", d), Some(data.clone()));
+        }
+
+        let legacy =
+            UiAccountData::LegacyBinary(bs58::encode(&data).into_string());
+        assert_eq!(decode_account_data("", legacy), Some(data));
+
+        let bad = UiAccountData::Binary("!".into(), UiAccountEncoding::Base64);
+        assert_eq!(decode_account_data("", bad), None);
+    }
+
+    #[test]
+    fn test_get_account_not_found() {
+        // The mock has no accounts.
+        let client = RpcClient::new_mock("succeeds".to_string());
+        let key = Pubkey::new_unique();
+
+        match get_account::<zo_abi::Margin>(&client, &key) {
+            Err(Error::AccountNotFound(k)) => assert_eq!(k, key),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("found an account"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_supervise_blocking() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let r = runs.clone();
+
+        // Panics on the first two runs, then returns.
+        supervise_blocking("test", Duration::ZERO, move || {
+            if r.fetch_add(1, Ordering::SeqCst) < 2 {
+                panic!("test panic");
+            }
+        })
+        .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_supervised() {
+        assert!(!is_supervised());
+        assert!(supervised(is_supervised));
+        assert!(!is_supervised());
+
+        let res = std::panic::catch_unwind(|| {
+            supervised(|| {
+                assert!(is_supervised());
+                panic!("test panic");
+            })
+        });
+        assert!(res.is_err());
+        assert!(!is_supervised());
+    }
+}
@@ -0,0 +1,472 @@
+/*
+ * End to end test of the liquidator against a local validator, checking
+ * that the instructions it builds actually liquidate an account.
+ *
+ * The zo and zo dex programs, the zo state and cache, the collateral
+ * mints, the perp markets and the oracle sources are read from a snapshot
+ * in tests/fixtures/e2e, and the validator is started at the slot they
+ * were fetched at, so the oracles aren't stale. The crank keeps the cache
+ * fresh while the test runs. If there is no snapshot, or
+ * $ZO_E2E_REFRESH is set, it is first fetched from the cluster at
+ * $ZO_E2E_RPC_URL (mainnet if unset), after which the test needs no
+ * network and the snapshot can be committed.
+ *
+ * The test then makes its own accounts: a payer with USDC collateral,
+ * and an account whose USDC collateral is all borrowed against in SOL,
+ * leaving it under maintenance margin. Collaterals are made unswappable
+ * in the local state, so the liquidator doesn't rebalance, and the SOL
+ * it takes on in a liquidation stays in its margin account.
+ *
+ * It needs `solana-test-validator` on the path, and is run with
+ * `cargo test --features e2e --test e2e`.
+*/
+#![cfg(feature = "e2e")]
+
+use anchor_lang::Discriminator;
+use bytemuck::{Pod, Zeroable};
+use fixed::types::I80F48;
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
+    native_token::LAMPORTS_PER_SOL,
+    pubkey::Pubkey,
+    rent::Rent,
+    signer::{
+        keypair::{write_keypair_file, Keypair},
+        Signer,
+    },
+    system_program,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use zo_abi::{Cache, Control, Margin, State};
+
+const RPC_PORT: u16 = 18899;
+
+/// Where the accounts and programs fetched from the cluster are kept.
+const SNAPSHOT: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/e2e");
+
+/// How long the liquidator is given to liquidate the account.
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The collateral the underwater account borrows, by oracle symbol.
+const BORROWED: &str = "SOL";
+
+/// The underwater account's USDC collateral, in native units.
+const LIQEE_USDC: i64 = 1_000_000_000;
+
+/// The payer's USDC collateral, in native units, enough to take on the
+/// underwater account's borrow.
+const PAYER_USDC: i64 = 100_000_000_000;
+
+/// Length of the metadata before the program in a program data account,
+/// that is the `UpgradeableLoaderState` tag, the slot and the optional
+/// upgrade authority.
+const PROGRAMDATA_METADATA_LEN: usize = 4 + 8 + 1 + 32;
+
+/// Kills the process when dropped, so a failing test doesn't leave the
+/// validator or the keeper running.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// What the validator is started with, and the accounts the test reads.
+struct Fixtures {
+    dir: PathBuf,
+    slot: u64,
+    payer: PathBuf,
+    payer_margin: Pubkey,
+    liqee_margin: Pubkey,
+    borrowed: usize,
+}
+
+fn source() -> RpcClient {
+    let url = std::env::var("ZO_E2E_RPC_URL").unwrap_or_else(|_| {
+        if cfg!(feature = "devnet") {
+            "https://api.devnet.solana.com".to_string()
+        } else {
+            "https://api.mainnet-beta.solana.com".to_string()
+        }
+    });
+
+    RpcClient::new_with_commitment(url, CommitmentConfig::confirmed())
+}
+
+fn load<T: Pod>(data: &[u8]) -> T {
+    bytemuck::pod_read_unaligned(&data[8..8 + std::mem::size_of::<T>()])
+}
+
+fn write_account(dir: &Path, key: &Pubkey, account: &Account) {
+    let json = serde_json::json!({
+        "pubkey": key.to_string(),
+        "account": UiAccount::encode(
+            key,
+            account,
+            UiAccountEncoding::Base64,
+            None,
+            None,
+        ),
+    });
+
+    fs::write(dir.join(format!("accounts/{}.json", key)), json.to_string())
+        .unwrap();
+}
+
+fn read_account(dir: &Path, key: &Pubkey) -> Account {
+    let json: serde_json::Value = serde_json::from_slice(
+        &fs::read(dir.join(format!("accounts/{}.json", key))).unwrap(),
+    )
+    .unwrap();
+    let account: UiAccount =
+        serde_json::from_value(json["account"].clone()).unwrap();
+
+    account.decode().unwrap()
+}
+
+/// A rent exempt zo account holding `x`.
+fn zo_account<T: Pod + Discriminator>(x: &T) -> Account {
+    let data: Vec<u8> = T::discriminator()
+        .iter()
+        .chain(bytemuck::bytes_of(x))
+        .copied()
+        .collect();
+
+    Account {
+        lamports: Rent::default().minimum_balance(data.len()),
+        data,
+        owner: zo_abi::ID,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+/// Writes a margin account of `authority` and its control, with the
+/// given raw collateral, returning the margin's key.
+fn write_margin(
+    dir: &Path,
+    authority: &Pubkey,
+    collateral: &[(usize, I80F48)],
+) -> Pubkey {
+    let (key, nonce) = Pubkey::find_program_address(
+        &[
+            authority.as_ref(),
+            zo_abi::ZO_STATE_ID.as_ref(),
+            b"marginv1",
+        ],
+        &zo_abi::ID,
+    );
+    let control_key = Keypair::new().pubkey();
+
+    let mut margin = Margin::zeroed();
+    margin.nonce = nonce;
+    margin.authority = *authority;
+    margin.control = control_key;
+    for &(i, x) in collateral {
+        margin.collateral[i] = x.into();
+    }
+
+    let mut control = Control::zeroed();
+    control.authority = *authority;
+
+    write_account(dir, &key, &zo_account(&margin));
+    write_account(dir, &control_key, &zo_account(&control));
+
+    key
+}
+
+/// Writes the program at `id`, which must be upgradeable, to a file.
+fn dump_program(source: &RpcClient, dir: &Path, id: &Pubkey) {
+    // The program account only holds the `UpgradeableLoaderState` tag
+    // and the address of its program data.
+    let program = source.get_account_data(id).unwrap();
+    let programdata = source
+        .get_account_data(&Pubkey::new(&program[4..36]))
+        .unwrap();
+
+    fs::write(
+        dir.join(format!("programs/{}.so", id)),
+        &programdata[PROGRAMDATA_METADATA_LEN..],
+    )
+    .unwrap();
+}
+
+/// Fetches the programs and the accounts the test reads from the cluster
+/// to `dir`, with the slot they were fetched at.
+fn snapshot(dir: &Path) {
+    let source = source();
+
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("accounts")).unwrap();
+    fs::create_dir_all(dir.join("programs")).unwrap();
+
+    dump_program(&source, dir, &zo_abi::ID);
+    dump_program(&source, dir, &zo_abi::ZO_DEX_PID);
+
+    let state: State =
+        load(&source.get_account_data(&zo_abi::ZO_STATE_ID).unwrap());
+    let cache: Cache = load(&source.get_account_data(&state.cache).unwrap());
+    let keys: Vec<Pubkey> = [zo_abi::ZO_STATE_ID, state.cache]
+        .into_iter()
+        .chain(state.collaterals.iter().map(|c| c.mint))
+        .chain(state.perp_markets.iter().map(|m| m.dex_market))
+        .chain(
+            cache
+                .oracles
+                .iter()
+                .flat_map(|o| o.sources.iter().map(|s| s.key)),
+        )
+        .filter(|k| *k != Pubkey::default())
+        .collect();
+
+    // The accounts are written as of the latest slot they were fetched
+    // at, which the validator then starts from.
+    let mut slot = 0;
+    for chunk in keys.chunks(100) {
+        let res = source
+            .get_multiple_accounts_with_commitment(
+                chunk,
+                CommitmentConfig::confirmed(),
+            )
+            .unwrap();
+        slot = slot.max(res.context.slot);
+
+        // Oracles of delisted symbols may have been closed, and are
+        // left out.
+        for (key, account) in chunk.iter().zip(res.value) {
+            if let Some(account) = account {
+                write_account(dir, key, &account);
+            }
+        }
+    }
+
+    fs::write(dir.join("slot"), slot.to_string()).unwrap();
+}
+
+/// Copies the files in `from` to `to`, which must exist.
+fn copy_dir(from: &Path, to: &Path) {
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, to.join(path.file_name().unwrap())).unwrap();
+    }
+}
+
+fn setup(dir: &Path) -> Fixtures {
+    let snapshot_dir = Path::new(SNAPSHOT);
+    if std::env::var_os("ZO_E2E_REFRESH").is_some() || !snapshot_dir.exists() {
+        snapshot(snapshot_dir);
+    }
+
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("accounts")).unwrap();
+    fs::create_dir_all(dir.join("programs")).unwrap();
+    copy_dir(&snapshot_dir.join("accounts"), &dir.join("accounts"));
+    copy_dir(&snapshot_dir.join("programs"), &dir.join("programs"));
+
+    let slot: u64 = fs::read_to_string(snapshot_dir.join("slot"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+
+    let mut state_account = read_account(dir, &zo_abi::ZO_STATE_ID);
+    let mut state: State = load(&state_account.data);
+
+    // Without serum markets the liquidator doesn't swap, so the payer's
+    // balance only changes by what it liquidates.
+    for c in state.collaterals.iter_mut() {
+        c.is_swappable = false;
+    }
+    state_account.data[8..8 + std::mem::size_of::<State>()]
+        .copy_from_slice(bytemuck::bytes_of(&state));
+    write_account(dir, &zo_abi::ZO_STATE_ID, &state_account);
+
+    let cache: Cache = load(&read_account(dir, &state.cache).data);
+
+    let collateral_index = |symbol: &str| {
+        state
+            .collaterals
+            .iter()
+            .position(|c| String::from(c.oracle_symbol) == symbol)
+            .unwrap_or_else(|| panic!("{} isn't a collateral", symbol))
+    };
+    let price = |i: usize| -> I80F48 {
+        cache
+            .oracles
+            .iter()
+            .find(|o| o.symbol == state.collaterals[i].oracle_symbol)
+            .unwrap()
+            .price
+            .into()
+    };
+    let usdc = collateral_index("USDC");
+    let borrowed = collateral_index(BORROWED);
+
+    // Margin accounts hold collateral divided by the cache's multiplier.
+    let supply_multiplier: I80F48 =
+        cache.borrow_cache[usdc].supply_multiplier.into();
+    let borrow_multiplier: I80F48 =
+        cache.borrow_cache[borrowed].borrow_multiplier.into();
+    let raw_usdc = |x: i64| I80F48::from_num(x) / supply_multiplier;
+
+    // Borrowing all the USDC collateral is worth leaves a margin fraction
+    // of zero, under any maintenance margin.
+    let borrow = I80F48::from_num(LIQEE_USDC) * price(usdc) / price(borrowed);
+
+    let payer = Keypair::new();
+    let payer_path = dir.join("payer.json");
+    write_keypair_file(&payer, &payer_path).unwrap();
+    write_account(
+        dir,
+        &payer.pubkey(),
+        &Account {
+            lamports: 100 * LAMPORTS_PER_SOL,
+            data: Vec::new(),
+            owner: system_program::ID,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let payer_margin =
+        write_margin(dir, &payer.pubkey(), &[(usdc, raw_usdc(PAYER_USDC))]);
+    let liqee_margin = write_margin(
+        dir,
+        &Keypair::new().pubkey(),
+        &[
+            (usdc, raw_usdc(LIQEE_USDC)),
+            (borrowed, -borrow / borrow_multiplier),
+        ],
+    );
+
+    Fixtures {
+        dir: dir.to_path_buf(),
+        slot,
+        payer: payer_path,
+        payer_margin,
+        liqee_margin,
+        borrowed,
+    }
+}
+
+fn start_validator(fixtures: &Fixtures) -> Process {
+    let mut cmd = Command::new("solana-test-validator");
+    cmd.arg("--reset")
+        .arg("--quiet")
+        .arg("--ledger")
+        .arg(fixtures.dir.join("ledger"))
+        .arg("--rpc-port")
+        .arg(RPC_PORT.to_string())
+        .arg("--warp-slot")
+        .arg(fixtures.slot.to_string());
+
+    for entry in fs::read_dir(fixtures.dir.join("programs")).unwrap() {
+        let path = entry.unwrap().path();
+        let id = path.file_stem().unwrap().to_str().unwrap().to_string();
+        cmd.arg("--bpf-program").arg(id).arg(path);
+    }
+
+    for entry in fs::read_dir(fixtures.dir.join("accounts")).unwrap() {
+        let path = entry.unwrap().path();
+        let key = path.file_stem().unwrap().to_str().unwrap().to_string();
+        cmd.arg("--account").arg(key).arg(path);
+    }
+
+    Process(cmd.stdout(Stdio::null()).spawn().unwrap())
+}
+
+fn wait_for_rpc(rpc: &RpcClient) {
+    let start = Instant::now();
+
+    while rpc.get_health().is_err() {
+        assert!(start.elapsed() < TIMEOUT, "validator didn't start");
+        sleep(Duration::from_millis(500));
+    }
+}
+
+fn start_keeper(payer: &Path, args: &[&str]) -> Process {
+    let child = Command::new(env!("CARGO_BIN_EXE_zo-keeper"))
+        .env("RUST_LOG", "zo_keeper=debug")
+        .arg("--rpc-url")
+        .arg(format!("http://127.0.0.1:{}", RPC_PORT))
+        .arg("--ws-url")
+        .arg(format!("ws://127.0.0.1:{}", RPC_PORT + 1))
+        .arg("--payer")
+        .arg(payer)
+        .args(args)
+        .spawn()
+        .unwrap();
+
+    Process(child)
+}
+
+/// The raw collateral `index` of each margin account, read at one slot so
+/// that they can be compared.
+fn collateral(rpc: &RpcClient, keys: &[Pubkey], index: usize) -> Vec<I80F48> {
+    rpc.get_multiple_accounts(keys)
+        .unwrap()
+        .into_iter()
+        .map(|a| {
+            let a = a.unwrap();
+            assert_eq!(a.owner, zo_abi::ID);
+            load::<Margin>(&a.data).collateral[index].into()
+        })
+        .collect()
+}
+
+#[test]
+fn test_liquidates_underwater_account() {
+    let fixtures = setup(&std::env::temp_dir().join("zo-keeper-e2e"));
+    let margins = [fixtures.liqee_margin, fixtures.payer_margin];
+
+    let _validator = start_validator(&fixtures);
+    let rpc = RpcClient::new_with_commitment(
+        format!("http://127.0.0.1:{}", RPC_PORT),
+        CommitmentConfig::confirmed(),
+    );
+    wait_for_rpc(&rpc);
+
+    let _crank =
+        start_keeper(&fixtures.payer, &["crank", "--tasks", "oracle,interest"]);
+    let before = collateral(&rpc, &margins, fixtures.borrowed);
+    let _liquidator = start_keeper(&fixtures.payer, &["liquidator"]);
+
+    let start = Instant::now();
+    let after = loop {
+        let after = collateral(&rpc, &margins, fixtures.borrowed);
+        if after[0] != before[0] {
+            break after;
+        }
+
+        assert!(
+            start.elapsed() < TIMEOUT,
+            "{} wasn't liquidated",
+            fixtures.liqee_margin
+        );
+        sleep(Duration::from_secs(1));
+    };
+
+    // Both balances are borrows, which share a multiplier, so the borrow
+    // the liquidator took on is what the account repaid.
+    let repaid = after[0] - before[0];
+    let taken = before[1] - after[1];
+    assert!(repaid > I80F48::ZERO, "borrow grew by {}", -repaid);
+    assert!(
+        (repaid - taken).abs() <= I80F48::ONE,
+        "account repaid {} but the liquidator took on {}",
+        repaid,
+        taken
+    );
+}